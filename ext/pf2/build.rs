@@ -1,3 +1,8 @@
 fn main() {
     cc::Build::new().file("src/siginfo_t.c").compile("ccode");
+    // Reported by `Pf2.native_version`
+    println!(
+        "cargo:rustc-env=PF2_TARGET={}",
+        std::env::var("TARGET").unwrap()
+    );
 }
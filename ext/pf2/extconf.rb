@@ -7,4 +7,7 @@ create_rust_makefile 'pf2/pf2' do |r|
   if ENV['PF2_FEATURES']
     r.features = ENV['PF2_FEATURES'].split(",")
   end
+  if ENV['PF2_NO_DEFAULT_FEATURES']
+    r.extra_cargo_args += ['--no-default-features']
+  end
 end
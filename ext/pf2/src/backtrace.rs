@@ -2,7 +2,9 @@ use std::ffi::{c_char, c_int, CStr};
 
 use libc::c_void;
 
-#[derive(Debug)]
+use crate::logging;
+
+#[derive(Debug, Clone, Copy)]
 pub struct BacktraceState {
     ptr: *mut backtrace_sys2::backtrace_state,
 }
@@ -122,6 +124,6 @@ impl Backtrace {
         errnum: c_int,
     ) {
         let msg = unsafe { CStr::from_ptr(msg) };
-        log::debug!("backtrace error: {:?} ({})", msg, errnum);
+        logging::debug!("backtrace error: {:?} ({})", msg, errnum);
     }
 }
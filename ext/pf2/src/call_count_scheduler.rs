@@ -0,0 +1,224 @@
+#![deny(unsafe_op_in_unsafe_fn)]
+
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+use rb_sys::*;
+
+use crate::error::Pf2Error;
+use crate::jitter::{next_random, random_seed};
+use crate::logging;
+use crate::profile::{CaptureStats, Profile, TEMPORARY_SAMPLE_BUFFER_HIGH_WATER_MARK};
+use crate::sample::{Sample, ThreadState};
+use crate::scheduler::Scheduler;
+use crate::session::configuration::{self, Configuration};
+use crate::util::*;
+
+/// Captures a sample at every `every` method calls of each target thread (`trigger: :calls`),
+/// instead of at every interval.
+///
+/// Calls are counted by a TracePoint on `call` and `c_call` events, which runs on the calling
+/// thread with the GVL held. Samples are thus taken right as the method is entered, with its
+/// frame on top of the stack. Unlike timers, the same program yields the same samples on every
+/// run, weighted by the number of calls rather than by time.
+///
+/// With `sample_probability`, each call is sampled with that probability instead, so that each
+/// sample stands for 1/p calls on average (an unbiased estimate of the number of calls).
+pub struct CallCountScheduler {
+    configuration: Arc<Configuration>,
+    profile: Arc<RwLock<Profile>>,
+    /// Passed to the TracePoint as its data. Outlives the TracePoint, which is disabled in stop().
+    counter: Arc<CallCounter>,
+    /// The TracePoint counting calls, while enabled.
+    tracepoint: Mutex<Option<VALUE>>,
+}
+
+struct CallCounter {
+    configuration: Arc<Configuration>,
+    profile: Arc<RwLock<Profile>>,
+    /// The profile's capture stats, to count samples dropped without taking the profile lock.
+    capture_stats: Arc<CaptureStats>,
+    calls: Mutex<HashMap<VALUE, ThreadCalls>>,
+    /// Target threads belong to the Ractor which started the scheduler.
+    ruby_ractor_id: Option<u64>,
+}
+
+/// What decides when each target thread is sampled next.
+struct ThreadCalls {
+    /// The number of calls made since the last sample.
+    count: u64,
+    /// The state of the thread's own random generator, for `sample_probability`.
+    random_state: u64,
+}
+
+impl ThreadCalls {
+    fn new(ruby_thread: VALUE) -> Self {
+        Self {
+            count: 0,
+            random_state: random_seed(ruby_thread as u64),
+        }
+    }
+}
+
+impl Scheduler for CallCountScheduler {
+    fn start(&self) -> Result<(), Pf2Error> {
+        if let configuration::Threads::Targeted(threads) = &self.configuration.target_ruby_threads {
+            let mut calls = self.counter.calls.lock().unwrap();
+            for &ruby_thread in threads.iter() {
+                calls.insert(ruby_thread, ThreadCalls::new(ruby_thread));
+            }
+        }
+
+        let tracepoint = unsafe {
+            let tracepoint = rb_tracepoint_new(
+                0, // all threads; targets are filtered in on_call()
+                RUBY_EVENT_CALL | RUBY_EVENT_C_CALL,
+                Some(Self::on_call),
+                Arc::as_ptr(&self.counter) as *mut c_void,
+            );
+            rb_tracepoint_enable(tracepoint);
+            tracepoint
+        };
+        *self.tracepoint.lock().unwrap() = Some(tracepoint);
+        Ok(())
+    }
+
+    fn stop(&self) {
+        if let Some(tracepoint) = self.tracepoint.lock().unwrap().take() {
+            unsafe { rb_tracepoint_disable(tracepoint) };
+        }
+    }
+
+    fn on_new_thread(&self, thread: VALUE) {
+        self.counter
+            .calls
+            .lock()
+            .unwrap()
+            .entry(thread)
+            .or_insert_with(|| ThreadCalls::new(thread));
+    }
+
+    fn dmark(&self) {
+        if let Some(tracepoint) = *self.tracepoint.lock().unwrap() {
+            unsafe { rb_gc_mark(tracepoint) };
+        }
+        match self.profile.read() {
+            Ok(profile) => unsafe {
+                profile.dmark();
+            },
+            Err(_) => {
+                panic!("[pf2 FATAL] dmark: Failed to acquire profile lock.");
+            }
+        }
+    }
+
+    fn dcompact(&self) {
+        match self.profile.write() {
+            Ok(mut profile) => unsafe {
+                profile.dcompact();
+            },
+            Err(_) => {
+                panic!("[pf2 FATAL] dcompact: Failed to acquire profile lock.");
+            }
+        }
+    }
+
+    fn dfree(&self) {
+        // No-op
+    }
+
+    fn dsize(&self) -> size_t {
+        // Best-effort: the Profile is not accounted for if its lock is held elsewhere
+        let profile_size = match self.profile.try_read() {
+            Ok(profile) => profile.memsize(),
+            Err(_) => 0,
+        };
+        (std::mem::size_of::<CallCountScheduler>() + profile_size) as size_t
+    }
+}
+
+impl CallCountScheduler {
+    pub fn new(configuration: &Configuration, profile: Arc<RwLock<Profile>>) -> Self {
+        let configuration = Arc::new(configuration.clone());
+        Self {
+            counter: Arc::new(CallCounter {
+                configuration: Arc::clone(&configuration),
+                profile: Arc::clone(&profile),
+                capture_stats: Arc::clone(&profile.read().unwrap().capture_stats),
+                calls: Mutex::new(HashMap::new()),
+                ruby_ractor_id: current_ractor_id(),
+            }),
+            configuration,
+            profile,
+            tracepoint: Mutex::new(None),
+        }
+    }
+
+    // Ruby does not fire hooks while this one is running, so calls made while capturing
+    // the sample are not counted.
+    unsafe extern "C" fn on_call(_tracepoint: VALUE, data: *mut c_void) {
+        let counter = unsafe { &*(data as *const CallCounter) };
+        let ruby_thread = unsafe { rb_thread_current() };
+
+        {
+            let mut calls = counter.calls.lock().unwrap();
+            let thread_calls = match calls.get_mut(&ruby_thread) {
+                Some(thread_calls) => thread_calls,
+                None => return, // Not a target thread
+            };
+            if let Some(probability) = counter.configuration.sample_probability {
+                if next_random(&mut thread_calls.random_state) >= probability {
+                    return;
+                }
+            } else {
+                thread_calls.count += 1;
+                if thread_calls.count < counter.configuration.every.unwrap_or(1) {
+                    return;
+                }
+                thread_calls.count = 0;
+            }
+        }
+
+        let mut profile = match counter.profile.try_write() {
+            Ok(profile) => profile,
+            Err(_) => {
+                logging::trace!("Failed to acquire profile lock. Dropping sample.");
+                counter.capture_stats.record_dropped_by_lock_contention(1);
+                return;
+            }
+        };
+        // The profile has been stopped (possibly by max_duration), or is still warming up
+        if profile.end_instant.is_some() || Instant::now() < profile.start_instant {
+            return;
+        }
+
+        let capture_started_at = Instant::now();
+        let mut sample = Sample::capture(
+            ruby_thread,
+            &profile.backtrace_state,
+            counter.configuration.max_stack_depth,
+            counter.configuration.clock.clockid(),
+        );
+        sample.ruby_ractor_id = counter.ruby_ractor_id;
+        // The calling thread holds the GVL
+        sample.state = Some(ThreadState::from_gvl(true, sample.during_gc));
+        profile
+            .capture_stats
+            .record(capture_started_at.elapsed(), sample.omitted_frames > 0);
+        if profile.temporary_sample_buffer.push(sample).is_err() {
+            logging::debug!("Temporary sample buffer full. Dropping sample.");
+            profile.capture_stats.record_dropped_by_full_buffer();
+        }
+
+        if counter.configuration.flush_mode == configuration::FlushMode::EventDriven
+            && profile.temporary_sample_buffer.len() >= TEMPORARY_SAMPLE_BUFFER_HIGH_WATER_MARK
+        {
+            // Release the lock first, so that the flusher can take it as soon as it wakes up
+            let flush_signal = Arc::clone(&profile.flush_signal);
+            drop(profile);
+            flush_signal.notify();
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Tells whether a thread has been running on a CPU, from how much CPU time it consumed
+/// between two consecutive observations compared to the wall time which passed.
+///
+/// The thread is considered on-CPU if it was running for at least half of that time.
+/// This is a best-effort estimate: it describes the time since the previous observation
+/// rather than the very instant of capture, and cannot tell until the thread has been
+/// observed twice.
+#[derive(Debug, Default)]
+pub struct CpuActivity {
+    /// `CLOCK_MONOTONIC` at the previous observation, or 0 if none.
+    last_wall_ns: AtomicU64,
+    /// The thread's CPU-time clock at the previous observation.
+    last_cpu_ns: AtomicU64,
+}
+
+impl CpuActivity {
+    // async-signal-safe
+    /// Record the readings of the wall clock and the thread's CPU-time clock, and tell whether
+    /// the thread has been on CPU since the previous call. None on the first call.
+    ///
+    /// Expected to be called by one signal handler at a time for each thread.
+    pub fn observe(&self, wall_ns: u64, cpu_ns: u64) -> Option<bool> {
+        let last_wall_ns = self.last_wall_ns.swap(wall_ns, Ordering::Relaxed);
+        let last_cpu_ns = self.last_cpu_ns.swap(cpu_ns, Ordering::Relaxed);
+        if last_wall_ns == 0 || wall_ns <= last_wall_ns {
+            return None;
+        }
+        let wall_delta = wall_ns - last_wall_ns;
+        let cpu_delta = cpu_ns.saturating_sub(last_cpu_ns);
+        Some(cpu_delta.saturating_mul(2) >= wall_delta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_observe() {
+        let activity = CpuActivity::default();
+        assert_eq!(activity.observe(1_000, 500), None);
+        // Running for the whole interval
+        assert_eq!(activity.observe(2_000, 1_500), Some(true));
+        // Running for a tenth of the interval
+        assert_eq!(activity.observe(3_000, 1_600), Some(false));
+        // Exactly half
+        assert_eq!(activity.observe(4_000, 2_100), Some(true));
+    }
+}
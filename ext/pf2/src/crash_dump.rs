@@ -0,0 +1,235 @@
+#![deny(unsafe_op_in_unsafe_fn)]
+
+use std::ffi::{c_int, c_void, CString};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::sync::{Arc, RwLock};
+
+use rb_sys::*;
+
+use crate::error::Pf2Error;
+use crate::profile::Profile;
+use crate::signal_sampling::RetiredArgs;
+use crate::util::RTEST;
+
+/// The signals on which the profile is dumped (`crash_dump:`).
+const CRASH_SIGNALS: [c_int; 2] = [libc::SIGSEGV, libc::SIGABRT];
+
+/// The size of the buffer lines are assembled in before being written, on the signal stack.
+const CRASH_DUMP_BUFFER_SIZE: usize = 512;
+
+/// The crash dump armed by the running session, if any. Read by the signal handler.
+static CRASH_DUMP: AtomicPtr<CrashDump> = AtomicPtr::new(null_mut());
+
+/// Writes the samples collected so far to a file when the process crashes (`crash_dump:`),
+/// for a post-mortem look at what it was doing.
+///
+/// The dump is written from the signal handler with async-signal-safe calls only: the file is
+/// opened with open(2) and written with write(2) from a fixed-size buffer, and frame labels are
+/// read from the frames without allocating. The format is the "folded stacks" format read by
+/// flamegraph.pl and speedscope: one line per sample, with frames from the root separated by
+/// `;`, followed by the count (1). Samples not yet flushed into the profile (those still in the
+/// sample buffers) are left out, since flushing them is not async-signal-safe. So is everything
+/// if the profile lock is held at the moment.
+///
+/// Signals are then handed on to the handler installed before (e.g. Ruby's own, which prints a
+/// bug report), or to the default action, so that core dumps and debuggers work as usual.
+struct CrashDump {
+    path: CString,
+    profile: Arc<RwLock<Profile>>,
+    previous_actions: [libc::sigaction; CRASH_SIGNALS.len()],
+    /// Dump only once, even if the dump itself crashes.
+    dumped: AtomicBool,
+}
+
+/// The signal handlers of an armed crash dump. Restores the previous handlers on drop.
+#[derive(Debug)]
+pub struct CrashDumpHook {
+    dump: *mut CrashDump,
+}
+
+impl CrashDumpHook {
+    /// Arm a crash dump into `path`. Only one session at a time may arm one.
+    pub fn install(path: &Path, profile: &Arc<RwLock<Profile>>) -> Result<Self, Pf2Error> {
+        let path = CString::new(path.as_os_str().as_bytes()).map_err(|_| {
+            Pf2Error::InvalidOption("crash_dump must not contain NUL bytes".to_owned())
+        })?;
+        let dump = Box::into_raw(Box::new(CrashDump {
+            path,
+            profile: Arc::clone(profile),
+            previous_actions: unsafe { mem::zeroed() },
+            dumped: AtomicBool::new(false),
+        }));
+        if CRASH_DUMP
+            .compare_exchange(null_mut(), dump, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            drop(unsafe { Box::from_raw(dump) });
+            return Err(Pf2Error::InvalidOption(
+                "crash_dump is already armed by another session".to_owned(),
+            ));
+        }
+
+        let mut action: libc::sigaction = unsafe { mem::zeroed() };
+        action.sa_sigaction = Self::signal_handler as usize;
+        // Run on the alternate signal stack Ruby sets up, so that stack overflows are covered
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        unsafe { libc::sigemptyset(&mut action.sa_mask) };
+        for (signal, previous_action) in CRASH_SIGNALS
+            .iter()
+            .zip(unsafe { (*dump).previous_actions.iter_mut() })
+        {
+            if unsafe { libc::sigaction(*signal, &action, previous_action) } != 0 {
+                let error = Pf2Error::last_os_error("sigaction");
+                drop(Self { dump });
+                return Err(error);
+            }
+        }
+        Ok(Self { dump })
+    }
+
+    extern "C" fn signal_handler(
+        signal: c_int,
+        info: *mut libc::siginfo_t,
+        _ucontext: *mut c_void,
+    ) {
+        let dump = CRASH_DUMP.load(Ordering::Acquire);
+        if dump.is_null() {
+            return;
+        }
+        let dump = unsafe { &*dump };
+
+        if !dump.dumped.swap(true, Ordering::Relaxed) {
+            unsafe { dump.write() };
+        }
+
+        // Hand the signal on to the previous handler. A fault re-occurs as soon as this handler
+        // returns, as does abort(). Signals sent by kill(2) and the like are raised again.
+        if let Some(index) = CRASH_SIGNALS.iter().position(|s| *s == signal) {
+            unsafe { libc::sigaction(signal, &dump.previous_actions[index], null_mut()) };
+        }
+        if info.is_null() || unsafe { (*info).si_code } <= 0 {
+            unsafe { libc::raise(signal) };
+        }
+    }
+}
+
+impl Drop for CrashDumpHook {
+    fn drop(&mut self) {
+        let dump = unsafe { &*self.dump };
+        for (signal, previous_action) in CRASH_SIGNALS.iter().zip(dump.previous_actions.iter()) {
+            let mut current: libc::sigaction = unsafe { mem::zeroed() };
+            unsafe { libc::sigaction(*signal, null_mut(), &mut current) };
+            // Leave handlers installed after ours in place
+            if current.sa_sigaction == Self::signal_handler as usize {
+                unsafe { libc::sigaction(*signal, previous_action, null_mut()) };
+            }
+        }
+        let _ =
+            CRASH_DUMP.compare_exchange(self.dump, null_mut(), Ordering::AcqRel, Ordering::Acquire);
+        // A handler may still be running on another thread. Freeing the CrashDump also releases
+        // its reference to the Profile.
+        RetiredArgs(vec![self.dump]).free_after_grace_period();
+    }
+}
+
+impl CrashDump {
+    // async-signal-safe
+    unsafe fn write(&self) {
+        // Never wait for the lock: the crashing thread may be holding it
+        let profile = match self.profile.try_read() {
+            Ok(profile) => profile,
+            Err(_) => return,
+        };
+        let fd = unsafe {
+            libc::open(
+                self.path.as_ptr(),
+                libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC,
+                0o644,
+            )
+        };
+        if fd < 0 {
+            return;
+        }
+        let mut writer = FdWriter::new(fd);
+        for sample in profile.samples.iter() {
+            let frames = &sample.frames[..sample.ruby_frame_count()];
+            for (depth, frame) in frames.iter().rev().enumerate() {
+                if depth > 0 {
+                    writer.write(b";");
+                }
+                unsafe { writer.write_frame_label(*frame) };
+            }
+            writer.write(b" 1\n");
+        }
+        writer.flush();
+        unsafe { libc::close(fd) };
+    }
+}
+
+/// Buffers writes into a file descriptor without allocating.
+struct FdWriter {
+    fd: c_int,
+    buffer: [u8; CRASH_DUMP_BUFFER_SIZE],
+    len: usize,
+}
+
+impl FdWriter {
+    fn new(fd: c_int) -> Self {
+        Self {
+            fd,
+            buffer: [0; CRASH_DUMP_BUFFER_SIZE],
+            len: 0,
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(CRASH_DUMP_BUFFER_SIZE) {
+            if self.len + chunk.len() > CRASH_DUMP_BUFFER_SIZE {
+                self.flush();
+            }
+            self.buffer[self.len..self.len + chunk.len()].copy_from_slice(chunk);
+            self.len += chunk.len();
+        }
+    }
+
+    /// The label of a Ruby frame (e.g. `block in foo`), read as is. Characters which have a
+    /// meaning in the folded format are replaced.
+    unsafe fn write_frame_label(&mut self, frame: VALUE) {
+        let label = unsafe { rb_profile_frame_label(frame) };
+        if !RTEST(label) {
+            self.write(b"[unknown]");
+            return;
+        }
+        let bytes = unsafe {
+            std::slice::from_raw_parts(RSTRING_PTR(label) as *const u8, RSTRING_LEN(label) as usize)
+        };
+        for byte in bytes {
+            self.write(match byte {
+                b';' | b'\n' => b"_",
+                _ => std::slice::from_ref(byte),
+            });
+        }
+    }
+
+    fn flush(&mut self) {
+        let mut written = 0;
+        while written < self.len {
+            let result = unsafe {
+                libc::write(
+                    self.fd,
+                    self.buffer[written..self.len].as_ptr() as *const c_void,
+                    self.len - written,
+                )
+            };
+            if result <= 0 {
+                break;
+            }
+            written += result as usize;
+        }
+        self.len = 0;
+    }
+}
@@ -0,0 +1,87 @@
+#![deny(unsafe_op_in_unsafe_fn)]
+
+use rb_sys::*;
+
+use crate::sample::Sample;
+use crate::serialization::profile::FunctionImplementation;
+use crate::serialization::resolved_frame::ResolvedFrame;
+use crate::serialization::serializer::ProfileSerializer2;
+use crate::session::configuration::Configuration;
+use crate::util::{cstr, rb_str_from_bytes};
+
+/// The `rb_profile_frame_*()` outputs of each Ruby frame of `sample` (leaf first), as Ruby
+/// handed them to Pf2, next to the Function the serializer resolves the frame to:
+/// `[{full_label:, base_label:, label:, path:, absolute_path:, classpath:, method_name:,
+/// first_lineno:, lineno:, function: {name:, filename:, cfunc:, eval:}}, ...]`.
+///
+/// Only built with the `debug` feature. Must be called with the GVL held.
+pub fn sample_frames_to_ruby(sample: &Sample, configuration: &Configuration) -> VALUE {
+    let mut ser = ProfileSerializer2::new(configuration);
+    unsafe {
+        let frames = rb_ary_new();
+        let count = sample.ruby_frame_count();
+        for (&frame, &lineno) in sample.frames[..count].iter().zip(&sample.linenos[..count]) {
+            let hash = rb_hash_new();
+            let raw_attributes: [(*const std::ffi::c_char, VALUE); 8] = [
+                (cstr!("full_label"), rb_profile_frame_full_label(frame)),
+                (cstr!("base_label"), rb_profile_frame_base_label(frame)),
+                (cstr!("label"), rb_profile_frame_label(frame)),
+                (cstr!("path"), rb_profile_frame_path(frame)),
+                (
+                    cstr!("absolute_path"),
+                    rb_profile_frame_absolute_path(frame),
+                ),
+                (cstr!("classpath"), rb_profile_frame_classpath(frame)),
+                (cstr!("method_name"), rb_profile_frame_method_name(frame)),
+                (cstr!("first_lineno"), rb_profile_frame_first_lineno(frame)),
+            ];
+            for (key, value) in raw_attributes {
+                rb_hash_aset(hash, rb_id2sym(rb_intern(key)), value);
+            }
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("lineno"))),
+                rb_int2inum(lineno as isize),
+            );
+
+            let resolved = ResolvedFrame::from_ruby_frame(frame, lineno);
+            let function = ser.function_for_resolved_frame(&resolved);
+            let strings = &ser.profile().strings;
+            let string_or_nil = |index: Option<usize>| match index {
+                Some(index) => rb_str_from_bytes(strings[index].as_bytes()),
+                None => Qnil.into(),
+            };
+            let function_hash = rb_hash_new();
+            rb_hash_aset(
+                function_hash,
+                rb_id2sym(rb_intern(cstr!("name"))),
+                string_or_nil(function.name),
+            );
+            rb_hash_aset(
+                function_hash,
+                rb_id2sym(rb_intern(cstr!("filename"))),
+                string_or_nil(function.filename),
+            );
+            rb_hash_aset(
+                function_hash,
+                rb_id2sym(rb_intern(cstr!("cfunc"))),
+                match function.implementation {
+                    FunctionImplementation::CFunc => Qtrue.into(),
+                    _ => Qfalse.into(),
+                },
+            );
+            rb_hash_aset(
+                function_hash,
+                rb_id2sym(rb_intern(cstr!("eval"))),
+                match function.eval {
+                    true => Qtrue.into(),
+                    false => Qfalse.into(),
+                },
+            );
+            rb_hash_aset(hash, rb_id2sym(rb_intern(cstr!("function"))), function_hash);
+
+            rb_ary_push(frames, hash);
+        }
+        frames
+    }
+}
@@ -0,0 +1,103 @@
+use std::ffi::CString;
+use std::fmt;
+
+use rb_sys::*;
+
+use crate::util::cstr;
+
+/// Errors surfaced to Ruby.
+///
+/// Recoverable conditions are raised as Ruby exceptions through `raise()`.
+/// Only conditions which cannot be reported to Ruby (e.g. during GC) may panic.
+#[derive(Debug)]
+pub enum Pf2Error {
+    /// The Session has not been initialized (e.g. `Session.allocate`).
+    NotInitialized,
+    /// The Session is profiling, and the named operation (e.g. `"reset"`) requires it to be
+    /// stopped.
+    Running(&'static str),
+    /// The Session is already profiling, and cannot be started again.
+    AlreadyRunning,
+    /// The profile lock is held by another thread.
+    ProfileLocked,
+    /// An invalid option was given.
+    InvalidOption(String),
+    /// A system call failed with `errno`.
+    Os { call: &'static str, errno: i32 },
+    /// The profile could not be written.
+    Io(String),
+    /// The profile could not be serialized.
+    Serialization(String),
+    /// A serialized profile given by the user could not be read.
+    InvalidProfile(String),
+}
+
+impl fmt::Display for Pf2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotInitialized => write!(f, "Session is not initialized"),
+            Self::Running(operation) => write!(
+                f,
+                "Cannot {} a running session. Call stop first.",
+                operation
+            ),
+            Self::AlreadyRunning => write!(f, "Session is already running. Call stop first."),
+            Self::ProfileLocked => write!(f, "Failed to acquire profile lock."),
+            Self::InvalidOption(msg) => write!(f, "{}", msg),
+            Self::Os { call, errno } => write!(
+                f,
+                "pf2: {} failed: {} ({})",
+                call,
+                errno_name(*errno),
+                std::io::Error::from_raw_os_error(*errno)
+            ),
+            Self::Io(msg) => write!(f, "{}", msg),
+            Self::Serialization(msg) => write!(f, "Failed to serialize profile: {}", msg),
+            Self::InvalidProfile(msg) => write!(f, "Invalid profile: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Pf2Error {}
+
+impl Pf2Error {
+    /// Build an `Os` error from the current `errno`, for system calls which return -1 on failure.
+    pub fn last_os_error(call: &'static str) -> Self {
+        Self::Os {
+            call,
+            errno: std::io::Error::last_os_error().raw_os_error().unwrap_or(0),
+        }
+    }
+
+    /// Raise the error as a Ruby exception.
+    /// Must be called with the GVL held, after releasing any locks, as this never returns.
+    pub fn raise(&self) -> ! {
+        let class = unsafe {
+            match self {
+                Self::InvalidOption(_) | Self::InvalidProfile(_) => rb_eArgError,
+                Self::Io(_) => rb_eIOError,
+                _ => rb_eRuntimeError,
+            }
+        };
+        // Interior NUL bytes (e.g. in paths) would truncate the message
+        let msg = CString::new(self.to_string().replace('\0', "\\0")).unwrap();
+        unsafe { rb_raise(class, cstr!("%s"), msg.as_ptr()) }
+    }
+}
+
+/// The symbolic name of `errno` (e.g. `EPERM`), for errors likely in restricted environments.
+fn errno_name(errno: i32) -> String {
+    let name = match errno {
+        libc::EPERM => "EPERM",
+        libc::EINTR => "EINTR",
+        libc::EAGAIN => "EAGAIN",
+        libc::ENOMEM => "ENOMEM",
+        libc::EACCES => "EACCES",
+        libc::EFAULT => "EFAULT",
+        libc::EINVAL => "EINVAL",
+        libc::ENOSYS => "ENOSYS",
+        libc::ENOTSUP => "ENOTSUP",
+        _ => return format!("errno {}", errno),
+    };
+    name.to_owned()
+}
@@ -0,0 +1,64 @@
+#![deny(unsafe_op_in_unsafe_fn)]
+
+use std::ffi::c_char;
+
+use rb_sys::*;
+
+use crate::util::cstr;
+
+/// Returns a Hash describing which capabilities are available in this build of Pf2.
+///
+/// Compile-time capabilities are derived from `cfg`s. Some capabilities are additionally
+/// probed at runtime, since they may be unavailable in restricted environments
+/// (e.g. `timer_create(2)` being blocked by seccomp).
+pub unsafe extern "C" fn rb_features(_rbself: VALUE) -> VALUE {
+    let per_thread_timers = probe_per_thread_timers();
+    let features: [(*const c_char, bool); 8] = [
+        // Outside Linux, the signal scheduler falls back to setitimer(2)
+        (
+            cstr!("signal_scheduler"),
+            per_thread_timers || !cfg!(target_os = "linux"),
+        ),
+        (cstr!("per_thread_timers"), per_thread_timers),
+        (cstr!("timer_thread_scheduler"), true),
+        (cstr!("cpu_time"), cfg!(target_os = "linux")),
+        (cstr!("wall_time"), true),
+        (cstr!("native_frames"), true), // libbacktrace is always bundled
+        (cstr!("gc_tracking"), true),
+        (cstr!("debug"), cfg!(feature = "debug")),
+    ];
+
+    unsafe {
+        let hash = rb_hash_new();
+        for (name, available) in features {
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(name)),
+                if available {
+                    Qtrue as VALUE
+                } else {
+                    Qfalse as VALUE
+                },
+            );
+        }
+        hash
+    }
+}
+
+/// Check whether per-thread POSIX timers can actually be created.
+#[cfg(target_os = "linux")]
+fn probe_per_thread_timers() -> bool {
+    let mut sigevent: libc::sigevent = unsafe { std::mem::zeroed() };
+    sigevent.sigev_notify = libc::SIGEV_NONE;
+    let mut timer: libc::timer_t = unsafe { std::mem::zeroed() };
+    if unsafe { libc::timer_create(libc::CLOCK_MONOTONIC, &mut sigevent, &mut timer) } != 0 {
+        return false;
+    }
+    unsafe { libc::timer_delete(timer) };
+    true
+}
+
+#[cfg(not(target_os = "linux"))]
+fn probe_per_thread_timers() -> bool {
+    false
+}
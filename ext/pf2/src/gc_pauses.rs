@@ -0,0 +1,100 @@
+#![deny(unsafe_op_in_unsafe_fn)]
+
+use std::ffi::c_void;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rb_sys::*;
+
+/// Records GC pauses while profiling (`measure_gc_pauses: true`), so that samples captured
+/// during GC can be weighted by how long GC actually took (see
+/// `Profile::weight_gc_samples_by_pause` in the serialization module).
+///
+/// Pauses are measured by an internal TracePoint on `GC_ENTER` and `GC_EXIT`, which fire on the
+/// thread running GC with the GVL held. Incremental marking and lazy sweeping are measured
+/// step by step, as each step is a pause of its own.
+#[derive(Debug, Default)]
+pub struct GcPauses {
+    state: Mutex<GcPausesState>,
+}
+
+#[derive(Debug, Default)]
+struct GcPausesState {
+    /// When the ongoing GC step started, if any.
+    entered_at: Option<Instant>,
+    pauses: Vec<GcPause>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct GcPause {
+    pub started_at: Instant,
+    pub duration: Duration,
+}
+
+/// The TracePoint keeping a `GcPauses` up to date. Disabled on drop.
+#[derive(Debug)]
+pub struct GcPausesHook {
+    tracepoint: VALUE,
+    /// A strong reference to the `GcPauses` passed as the TracePoint's data, released with it.
+    pauses: *const GcPauses,
+}
+
+impl GcPauses {
+    /// The pauses completed so far, in order.
+    pub fn pauses(&self) -> Vec<GcPause> {
+        self.state.lock().unwrap().pauses.clone()
+    }
+
+    pub fn reset(&self) {
+        *self.state.lock().unwrap() = GcPausesState::default();
+    }
+
+    /// Start measuring pauses. Must be called with the GVL held.
+    pub fn watch(self: &Arc<Self>) -> GcPausesHook {
+        let pauses = Arc::into_raw(Arc::clone(self));
+        let tracepoint = unsafe {
+            let tracepoint = rb_tracepoint_new(
+                0, // GC is process-wide
+                RUBY_INTERNAL_EVENT_GC_ENTER | RUBY_INTERNAL_EVENT_GC_EXIT,
+                Some(Self::on_gc_event),
+                pauses as *mut c_void,
+            );
+            rb_tracepoint_enable(tracepoint);
+            tracepoint
+        };
+        GcPausesHook { tracepoint, pauses }
+    }
+
+    // Runs inside GC: must neither allocate Ruby objects nor raise
+    unsafe extern "C" fn on_gc_event(tracepoint: VALUE, data: *mut c_void) {
+        let now = Instant::now();
+        // A strong reference (owned by GcPausesHook) is passed as data
+        let this = unsafe { &*(data as *const GcPauses) };
+        let flag = unsafe { rb_tracearg_event_flag(rb_tracearg_from_tracepoint(tracepoint)) };
+        let mut state = this.state.lock().unwrap();
+        if flag & RUBY_INTERNAL_EVENT_GC_ENTER != 0 {
+            state.entered_at = Some(now);
+        } else if let Some(started_at) = state.entered_at.take() {
+            state.pauses.push(GcPause {
+                started_at,
+                duration: now - started_at,
+            });
+        }
+    }
+}
+
+impl GcPausesHook {
+    pub fn dmark(&self) {
+        unsafe { rb_gc_mark(self.tracepoint) };
+    }
+}
+
+impl Drop for GcPausesHook {
+    fn drop(&mut self) {
+        unsafe {
+            rb_tracepoint_disable(self.tracepoint);
+            // The TracePoint no longer fires, so its reference can be released
+            drop(Arc::from_raw(self.pauses));
+        }
+    }
+}
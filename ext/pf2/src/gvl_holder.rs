@@ -0,0 +1,95 @@
+#![deny(unsafe_op_in_unsafe_fn)]
+
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use rb_sys::*;
+
+/// Tracks which Ruby Thread holds the GVL, so that a signal handler sampling threads other
+/// than the one it runs on (`strategy: :global_timer`) can tell the running thread apart
+/// from those which are sleeping or blocked.
+///
+/// The holder is recorded by a thread event hook: `RESUMED` is fired by a thread right after
+/// it acquires the GVL, and `SUSPENDED` right before it releases it. Both fire while the GVL
+/// is held, so updates are serialized by the GVL itself.
+///
+/// # Async-signal-safety
+///
+/// `current()` is a single atomic load, and is safe to call from a signal handler. Asking Ruby
+/// is not: `rb_thread_current()` reads the VM's current execution context, which may be in the
+/// middle of being switched when the signal arrives, and `ruby_thread_has_gvl_p()` only answers
+/// for the calling thread. The hook itself runs in regular (non-signal) context.
+///
+/// The holder may change between the load and the capture of the sample, so a sample taken
+/// at the moment of a GVL handover can be attributed to the previous holder.
+/// With multiple Ractors, each of which has its own GVL, only the thread which most recently
+/// acquired one is known.
+#[derive(Debug, Default)]
+pub struct GvlHolder {
+    /// The `VALUE` of the holding Ruby Thread, or 0 if none is known.
+    holder: AtomicUsize,
+}
+
+/// The event hook keeping a `GvlHolder` up to date. Removed on drop.
+#[derive(Debug)]
+pub struct GvlHolderHook {
+    event_hook: *mut rb_internal_thread_event_hook_t,
+    /// A strong reference to the `GvlHolder` passed as custom data, released with the hook.
+    holder: *const GvlHolder,
+}
+
+impl GvlHolder {
+    // async-signal-safe
+    /// The Ruby Thread holding the GVL, if any.
+    pub fn current(&self) -> Option<VALUE> {
+        match self.holder.load(Ordering::Acquire) {
+            0 => None,
+            thread => Some(thread as VALUE),
+        }
+    }
+
+    /// Start tracking the holder. Must be called with the GVL held, by the thread holding it.
+    pub fn watch(self: &Arc<Self>) -> GvlHolderHook {
+        self.holder
+            .store(unsafe { rb_thread_current() } as usize, Ordering::Release);
+
+        let holder = Arc::into_raw(Arc::clone(self));
+        let event_hook = unsafe {
+            rb_internal_thread_add_event_hook(
+                Some(Self::on_gvl_event),
+                RUBY_INTERNAL_THREAD_EVENT_RESUMED | RUBY_INTERNAL_THREAD_EVENT_SUSPENDED,
+                holder as *mut c_void,
+            )
+        };
+        GvlHolderHook { event_hook, holder }
+    }
+
+    unsafe extern "C" fn on_gvl_event(
+        flag: rb_event_flag_t,
+        data: *const rb_internal_thread_event_data,
+        custom_data: *mut c_void,
+    ) {
+        let ruby_thread = unsafe { (*data).thread } as usize;
+        // A strong reference (owned by GvlHolderHook) is passed as custom_data
+        let this = unsafe { &*(custom_data as *const GvlHolder) };
+        if flag & RUBY_INTERNAL_THREAD_EVENT_RESUMED != 0 {
+            this.holder.store(ruby_thread, Ordering::Release);
+        } else {
+            // Leave the holder alone if another Ractor's thread has taken over since
+            let _ =
+                this.holder
+                    .compare_exchange(ruby_thread, 0, Ordering::Release, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for GvlHolderHook {
+    fn drop(&mut self) {
+        unsafe {
+            rb_internal_thread_remove_event_hook(self.event_hook);
+            // The hook is gone, so its reference can be released
+            drop(Arc::from_raw(self.holder));
+        }
+    }
+}
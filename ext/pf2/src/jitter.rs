@@ -0,0 +1,99 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Randomizes sampling intervals by up to ±`jitter_pct`% of the nominal interval
+/// (`jitter_pct:`), so that samples do not fall in lockstep with periodic work
+/// (e.g. a loop iterating once per interval), which would be over- or under-counted.
+///
+/// Intervals are drawn from a xorshift64* generator, which is cheap, allocation-free and
+/// async-signal-safe. Drawing from several threads at once may yield the same interval twice,
+/// which is harmless.
+#[derive(Debug)]
+pub struct Jitter {
+    /// The maximum deviation from the nominal interval, as a fraction of it.
+    fraction: f64,
+    state: AtomicU64,
+}
+
+impl Jitter {
+    /// None if `jitter_pct` is 0, i.e. intervals are not to be randomized.
+    /// `salt` tells apart generators created at the same time (e.g. one per thread).
+    pub fn new(jitter_pct: f64, salt: u64) -> Option<Self> {
+        if jitter_pct <= 0.0 {
+            return None;
+        }
+        Some(Self::with_seed(jitter_pct, random_seed(salt)))
+    }
+
+    fn with_seed(jitter_pct: f64, seed: u64) -> Self {
+        Self {
+            fraction: jitter_pct / 100.0,
+            state: AtomicU64::new(seed),
+        }
+    }
+
+    // async-signal-safe
+    /// A random interval within ±`jitter_pct`% of `interval`.
+    pub fn apply(&self, interval: Duration) -> Duration {
+        let mut state = self.state.load(Ordering::Relaxed);
+        // Uniform in [-1, 1)
+        let unit = next_random(&mut state) * 2.0 - 1.0;
+        self.state.store(state, Ordering::Relaxed);
+        interval.mul_f64(1.0 + self.fraction * unit)
+    }
+}
+
+/// A seed for `next_random()` from the current time.
+/// `salt` tells apart generators created at the same time (e.g. one per thread).
+pub fn random_seed(salt: u64) -> u64 {
+    let now_ns = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_nanos() as u64);
+    // The state of xorshift must never be 0
+    (now_ns ^ salt.wrapping_mul(0x9E37_79B9_7F4A_7C15)) | 1
+}
+
+// async-signal-safe
+/// Advance the xorshift64* generator at `state`, returning a number uniform in [0, 1).
+pub fn next_random(state: &mut u64) -> f64 {
+    let mut x = *state;
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    *state = x;
+    // From the upper 53 bits of the output
+    (x.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        assert!(Jitter::new(0.0, 0).is_none());
+
+        let jitter = Jitter::with_seed(10.0, 43);
+        let interval = Duration::from_millis(10);
+        let intervals: Vec<Duration> = (0..1000).map(|_| jitter.apply(interval)).collect();
+        for jittered in intervals.iter() {
+            assert!(*jittered >= Duration::from_millis(9), "{:?}", jittered);
+            assert!(*jittered <= Duration::from_millis(11), "{:?}", jittered);
+        }
+        // Centered around the nominal interval
+        let mean = intervals.iter().sum::<Duration>() / intervals.len() as u32;
+        assert!(mean > Duration::from_micros(9_900), "{:?}", mean);
+        assert!(mean < Duration::from_micros(10_100), "{:?}", mean);
+        assert!(intervals.iter().any(|jittered| *jittered != intervals[0]));
+    }
+
+    #[test]
+    fn test_next_random() {
+        let mut state = random_seed(0);
+        let draws: Vec<f64> = (0..100_000).map(|_| next_random(&mut state)).collect();
+        assert!(draws.iter().all(|draw| (0.0..1.0).contains(draw)));
+        // Keeping draws below p keeps a fraction p of them
+        let kept = draws.iter().filter(|draw| **draw < 0.1).count();
+        assert!((9_000..11_000).contains(&kept), "{}", kept);
+    }
+}
@@ -5,18 +5,39 @@ extern crate serde_derive;
 mod ruby_init;
 
 mod backtrace;
+mod call_count_scheduler;
+#[cfg(target_os = "linux")]
+mod cpu_activity;
+mod crash_dump;
+#[cfg(feature = "debug")]
+mod debug_frames;
+mod error;
+mod features;
+mod gc_pauses;
+mod gvl_holder;
+mod jitter;
+mod logging;
+mod native_version;
 mod profile;
+mod profile_block;
 mod profile_serializer;
 mod ringbuffer;
 mod sample;
+mod sample_filter;
 mod scheduler;
-mod serialization;
+// Usable without the Ruby VM (see `ProfileSerializer2::serialize_resolved`)
+pub mod serialization;
 mod session;
+pub use session::configuration;
+mod signal_sampling;
 #[cfg(target_os = "linux")]
 mod signal_scheduler;
 #[cfg(not(target_os = "linux"))]
-mod signal_scheduler_unsupported_platform;
+mod signal_scheduler_setitimer;
+mod spsc_ringbuffer;
 mod timer_thread_scheduler;
 mod util;
+#[cfg(test)]
+mod vm_tests;
 
 mod ruby_internal_apis;
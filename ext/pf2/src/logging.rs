@@ -0,0 +1,52 @@
+// Logging macros which forward to the `log` crate, or compile to nothing when the
+// `debug-logging` feature is disabled (`--no-default-features`).
+//
+// Disabled macros still type-check their arguments, but no formatting code is emitted.
+
+#[cfg(feature = "debug-logging")]
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        log::debug!($($arg)+)
+    };
+}
+#[cfg(not(feature = "debug-logging"))]
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    };
+}
+pub(crate) use debug;
+
+#[cfg(feature = "debug-logging")]
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        log::trace!($($arg)+)
+    };
+}
+#[cfg(not(feature = "debug-logging"))]
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    };
+}
+pub(crate) use trace;
+
+#[cfg(feature = "debug-logging")]
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        log::warn!($($arg)+)
+    };
+}
+#[cfg(not(feature = "debug-logging"))]
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    };
+}
+pub(crate) use warn;
@@ -0,0 +1,36 @@
+#![deny(unsafe_op_in_unsafe_fn)]
+
+use rb_sys::*;
+
+use crate::serialization::profile::NativeBuild;
+use crate::util::{cstr, rb_str_from_bytes};
+
+/// Returns a frozen Hash identifying this build of the native extension, for bug reports and
+/// compatibility checks: `{version:, target:, ruby_api_version:}`.
+pub unsafe extern "C" fn rb_native_version(_rbself: VALUE) -> VALUE {
+    let hash = native_build_to_ruby_hash(&NativeBuild::current());
+    unsafe { rb_obj_freeze(hash) }
+}
+
+/// Also used for `metadata[:native_build]` in serialized profiles.
+pub fn native_build_to_ruby_hash(native_build: &NativeBuild) -> VALUE {
+    unsafe {
+        let hash = rb_hash_new();
+        rb_hash_aset(
+            hash,
+            rb_id2sym(rb_intern(cstr!("version"))),
+            rb_str_from_bytes(native_build.version.as_bytes()),
+        );
+        rb_hash_aset(
+            hash,
+            rb_id2sym(rb_intern(cstr!("target"))),
+            rb_str_from_bytes(native_build.target.as_bytes()),
+        );
+        rb_hash_aset(
+            hash,
+            rb_id2sym(rb_intern(cstr!("ruby_api_version"))),
+            rb_str_from_bytes(native_build.ruby_api_version.as_bytes()),
+        );
+        hash
+    }
+}
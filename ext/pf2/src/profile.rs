@@ -1,31 +1,389 @@
-use std::time::{Instant, SystemTime};
-use std::{collections::HashSet, ptr::null_mut};
+use std::collections::{hash_map, HashMap, VecDeque};
+use std::mem;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 use rb_sys::*;
 
 use backtrace_sys2::backtrace_create_state;
 
 use super::backtrace::{Backtrace, BacktraceState};
+use super::gc_pauses::GcPauses;
+use super::logging;
 use super::ringbuffer::Ringbuffer;
 use super::sample::Sample;
+use super::sample_filter::SampleFilter;
+use super::session::configuration::MaxSamplesPolicy;
+use super::spsc_ringbuffer::SpscRingbuffer;
+use super::util::read_clock_ns;
 
 // Capacity large enough to hold 1 second worth of samples for 16 threads
 // 16 threads * 20 samples per second * 1 second = 320
 const DEFAULT_RINGBUFFER_CAPACITY: usize = 320;
+// The temporary sample buffer may grow up to this many chunks when flushing falls behind.
+// Each Sample takes about 16 KiB, so the buffer is capped at about 4 * 320 * 16 KiB = 20 MiB.
+const DEFAULT_RINGBUFFER_MAX_CHUNKS: usize = 4;
+// Capacity of each lock-free sample ring (one per signal-handled thread), which is drained
+// at every flush. Samples overflowing a ring fall back to the temporary sample buffer.
+// Kept small, since each thread has its own ring of about 16 KiB per sample.
+const SAMPLE_RING_CAPACITY: usize = 16;
+// A rough size of what serialization builds for each distinct frame: a Function, a Location,
+// and strings for its name and path.
+const ESTIMATED_SERIALIZED_BYTES_PER_FRAME: usize = 256;
+// With `flush_mode: :event_driven`, the flusher is woken up once a buffer holds this many samples.
+pub const SAMPLE_RING_HIGH_WATER_MARK: usize = SAMPLE_RING_CAPACITY / 2;
+pub const TEMPORARY_SAMPLE_BUFFER_HIGH_WATER_MARK: usize = DEFAULT_RINGBUFFER_CAPACITY / 2;
+// The number of samples the temporary sample buffer holds at most before dropping samples.
+pub const TEMPORARY_SAMPLE_BUFFER_MAX_CAPACITY: usize =
+    DEFAULT_RINGBUFFER_CAPACITY * DEFAULT_RINGBUFFER_MAX_CHUNKS;
 
+/// The accumulated cost of capturing samples.
+/// Updated without locking, so that signal handlers can record their own cost.
+#[derive(Debug, Default)]
+pub struct CaptureStats {
+    count: AtomicU64,
+    total_ns: AtomicU64,
+    max_ns: AtomicU64,
+    /// Samples dropped because the profile lock was held elsewhere.
+    dropped_by_lock_contention: AtomicU64,
+    /// Samples dropped because the temporary sample buffer was full.
+    dropped_by_full_buffer: AtomicU64,
+    /// Samples whose Ruby stack was truncated at `max_stack_depth`.
+    truncated: AtomicU64,
+    /// Postponed jobs triggered by TimerThreadScheduler, and those which actually ran. Ruby runs
+    /// a job triggered again before it had the chance to run only once.
+    postponed_jobs_triggered: AtomicU64,
+    postponed_jobs_run: AtomicU64,
+    /// The drop counts as of the last `reset_drop_counts()`, which `drop_counts()` is relative to.
+    /// The counts reported in the profile's metadata are not affected.
+    baseline_buffer_full: AtomicU64,
+    baseline_lock_contention: AtomicU64,
+    baseline_truncated: AtomicU64,
+    baseline_coalesced_jobs: AtomicU64,
+}
+
+/// Drop counts at some point in time, as returned by `Session#drop_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DropCounts {
+    pub buffer_full: u64,
+    pub lock_contention: u64,
+    pub truncated: u64,
+    pub coalesced_jobs: u64,
+}
+
+impl CaptureStats {
+    // async-signal-safe
+    /// Record a capture which took `elapsed`, and whether its Ruby stack was `truncated`.
+    pub fn record(&self, elapsed: Duration, truncated: bool) {
+        let elapsed_ns = elapsed.as_nanos() as u64;
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_ns.fetch_add(elapsed_ns, Ordering::Relaxed);
+        self.max_ns.fetch_max(elapsed_ns, Ordering::Relaxed);
+        if truncated {
+            self.truncated.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The number of samples captured, including those dropped afterwards.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn total_ns(&self) -> u64 {
+        self.total_ns.load(Ordering::Relaxed)
+    }
+
+    pub fn max_ns(&self) -> u64 {
+        self.max_ns.load(Ordering::Relaxed)
+    }
+
+    pub fn mean_ns(&self) -> u64 {
+        self.total_ns().checked_div(self.count()).unwrap_or(0)
+    }
+
+    // async-signal-safe
+    pub fn record_dropped_by_lock_contention(&self, count: u64) {
+        self.dropped_by_lock_contention
+            .fetch_add(count, Ordering::Relaxed);
+    }
+
+    // async-signal-safe
+    pub fn record_dropped_by_full_buffer(&self) {
+        self.dropped_by_full_buffer.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped_by_lock_contention(&self) -> u64 {
+        self.dropped_by_lock_contention.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_by_full_buffer(&self) -> u64 {
+        self.dropped_by_full_buffer.load(Ordering::Relaxed)
+    }
+
+    pub fn record_postponed_job_triggered(&self) {
+        self.postponed_jobs_triggered
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_postponed_job_run(&self) {
+        self.postponed_jobs_run.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The number of ticks lost because Ruby coalesced their postponed job with the previous
+    /// one, which had not run yet (e.g. while the GVL was held by a C extension). A job triggered
+    /// right before stopping may be counted as well.
+    pub fn coalesced_postponed_jobs(&self) -> u64 {
+        self.postponed_jobs_triggered
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.postponed_jobs_run.load(Ordering::Relaxed))
+    }
+
+    /// The drop counts accumulated since the last `reset_drop_counts()`. Lock-free.
+    pub fn drop_counts(&self) -> DropCounts {
+        DropCounts {
+            buffer_full: self
+                .dropped_by_full_buffer()
+                .saturating_sub(self.baseline_buffer_full.load(Ordering::Relaxed)),
+            lock_contention: self
+                .dropped_by_lock_contention()
+                .saturating_sub(self.baseline_lock_contention.load(Ordering::Relaxed)),
+            truncated: self
+                .truncated
+                .load(Ordering::Relaxed)
+                .saturating_sub(self.baseline_truncated.load(Ordering::Relaxed)),
+            coalesced_jobs: self
+                .coalesced_postponed_jobs()
+                .saturating_sub(self.baseline_coalesced_jobs.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Start counting drops from zero again in `drop_counts()`, while profiling.
+    pub fn reset_drop_counts(&self) {
+        self.baseline_buffer_full
+            .store(self.dropped_by_full_buffer(), Ordering::Relaxed);
+        self.baseline_lock_contention
+            .store(self.dropped_by_lock_contention(), Ordering::Relaxed);
+        self.baseline_truncated
+            .store(self.truncated.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.baseline_coalesced_jobs
+            .store(self.coalesced_postponed_jobs(), Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        self.count.store(0, Ordering::Relaxed);
+        self.total_ns.store(0, Ordering::Relaxed);
+        self.max_ns.store(0, Ordering::Relaxed);
+        self.dropped_by_lock_contention.store(0, Ordering::Relaxed);
+        self.dropped_by_full_buffer.store(0, Ordering::Relaxed);
+        self.truncated.store(0, Ordering::Relaxed);
+        self.postponed_jobs_triggered.store(0, Ordering::Relaxed);
+        self.postponed_jobs_run.store(0, Ordering::Relaxed);
+        self.baseline_buffer_full.store(0, Ordering::Relaxed);
+        self.baseline_lock_contention.store(0, Ordering::Relaxed);
+        self.baseline_truncated.store(0, Ordering::Relaxed);
+        self.baseline_coalesced_jobs.store(0, Ordering::Relaxed);
+    }
+}
+
+/// The sampling interval in effect, which may be lengthened while profiling to stay within
+/// `target_overhead_pct`. Schedulers pick up changes at their next tick.
+#[derive(Debug)]
+pub struct SamplingInterval {
+    interval_ns: AtomicU64,
+}
+
+impl SamplingInterval {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval_ns: AtomicU64::new(interval.as_nanos() as u64),
+        }
+    }
+
+    // async-signal-safe
+    pub fn get(&self) -> Duration {
+        Duration::from_nanos(self.interval_ns.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, interval: Duration) {
+        self.interval_ns
+            .store(interval.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+/// A change of the sampling interval while profiling.
+#[derive(Clone, Debug)]
+pub struct IntervalChange {
+    /// The time elapsed since the start of the profile.
+    pub elapsed: Duration,
+    /// The interval in effect from then on.
+    pub interval: Duration,
+    /// The measured overhead (as a percentage of wall time) which caused the change.
+    pub overhead_pct: f64,
+}
+
+/// Wakes up a thread waiting for samples before its interval elapses
+/// (the flusher, or the thread delivering batches to `on_flush`).
+#[derive(Debug, Default)]
+pub struct FlushSignal {
+    pending: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl FlushSignal {
+    // async-signal-safe (never blocks)
+    /// Wake up the waiting thread. The request may be lost if the waiter happens to hold the
+    /// lock at the same time; the waiter then wakes up at the end of its interval anyway.
+    pub fn notify(&self) {
+        if let Ok(mut pending) = self.pending.try_lock() {
+            *pending = true;
+        }
+        self.condvar.notify_one();
+    }
+
+    /// Wait until notified or `timeout` elapses. Returns whether notified.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let pending = self.pending.lock().unwrap();
+        let (mut pending, _) = self
+            .condvar
+            .wait_timeout_while(pending, timeout, |pending| !*pending)
+            .unwrap();
+        mem::take(&mut *pending)
+    }
+}
+
+/// A named point in time recorded with `Session#mark` (e.g. "request start"), for correlating
+/// samples with phases of the program.
+#[derive(Debug)]
+pub struct Marker {
+    pub timestamp: Instant,
+    pub label: String,
+    pub ruby_thread: VALUE,
+}
+
+/// The CPU time consumed by a profiled thread, read from the thread's CPU-time clock
+/// when its timer was installed and when the profile was stopped.
+#[derive(Debug)]
+pub struct ThreadCpuTime {
+    pub ruby_thread: VALUE,
+    clockid: libc::clockid_t,
+    pub start_ns: u64,
+    /// None while running, or if the thread had exited before the profile was stopped.
+    pub end_ns: Option<u64>,
+}
+
+impl ThreadCpuTime {
+    pub fn cpu_time_ns(&self) -> Option<u64> {
+        self.end_ns
+            .map(|end_ns| end_ns.saturating_sub(self.start_ns))
+    }
+}
+
+/// The samples collected by a Session, and what it takes to resolve and serialize them.
+///
+/// Shared as `Arc<RwLock<Profile>>`, whose lock is taken by threads holding the GVL (Session
+/// methods, TracePoint hooks, GC callbacks) and by the flusher, which never holds the GVL.
+/// Signal handlers, which may interrupt a holder of the lock, only ever `try_write()` it.
+///
+/// Lock ordering: the GVL may be held while waiting for the profile lock, but never the other
+/// way round. A holder of the profile lock never waits for the GVL: the flusher does not take
+/// it, and GVL holders do not release it (e.g. by calling into Ruby code such as `IO#write`).
+/// Threads holding the GVL may therefore block on the lock, since its holder always lets go
+/// without needing the GVL.
+///
+/// GC runs `dmark()` (read lock) and `dcompact()` (write lock) on the thread which triggered
+/// it, while the lock may be held by that very thread. Ruby objects are therefore never
+/// allocated while the lock is write-held. Serialization reads frames through Ruby, and so may
+/// allocate while it is read-held, which is fine for marking; compaction is turned off in the
+/// meantime (see `without_auto_compaction`).
 #[derive(Debug)]
 pub struct Profile {
+    /// The wall-clock (`CLOCK_REALTIME`) time at which the profile started, for aligning it with
+    /// external events. Durations are measured from `start_instant` instead.
     pub start_timestamp: SystemTime,
     pub start_instant: Instant,
+    /// The reading of `CLOCK_MONOTONIC` at `start_timestamp`.
+    pub start_monotonic_ns: u64,
     pub end_instant: Option<Instant>,
-    pub samples: Vec<Sample>,
+    pub samples: VecDeque<Sample>,
     pub temporary_sample_buffer: Ringbuffer,
+    /// Lock-free rings through which signal handlers hand samples to the flusher.
+    sample_rings: Vec<Arc<SpscRingbuffer>>,
     pub backtrace_state: BacktraceState,
-    known_values: HashSet<VALUE>,
+    pub capture_stats: Arc<CaptureStats>,
+    /// GC pauses, measured with `measure_gc_pauses: true`. Shared with the Session's GC hook.
+    pub gc_pauses: Arc<GcPauses>,
+    /// Shared with schedulers, which wake up the flusher when samples pile up.
+    pub flush_signal: Arc<FlushSignal>,
+    /// Shared with schedulers, which re-arm their timers when it changes.
+    pub sampling_interval: Arc<SamplingInterval>,
+    /// The interval the profile started with.
+    initial_interval: Duration,
+    /// Changes made to `sampling_interval` so far, in order.
+    pub interval_changes: Vec<IntervalChange>,
+    /// Recorded for each thread profiled in CPU time mode (SignalScheduler only).
+    pub thread_cpu_times: Vec<ThreadCpuTime>,
+    /// Markers recorded so far, in order. Behind their own lock, so that marking only needs
+    /// to read-lock the profile.
+    pub markers: Mutex<Vec<Marker>>,
+    /// Ruby Threads referenced by flushed samples, with the number of samples referencing each.
+    /// These are pinned during GC, since Thread VALUEs are used as stable thread identifiers.
+    pub known_threads: HashMap<VALUE, usize>,
+    /// The native (OS) thread id of each thread, as seen when its sampling was set up.
+    /// None if it was seen to change (e.g. with M:N threads).
+    pub native_thread_ids: HashMap<VALUE, Option<u64>>,
+    /// Frames referenced by flushed samples, with the number of references to each.
+    /// These may be moved by GC compaction.
+    pub known_frames: HashMap<VALUE, usize>,
+    /// The number of samples flushed into `samples` so far, including those evicted since.
+    pub flushed_sample_count: u64,
+    max_samples: Option<usize>,
+    max_samples_policy: MaxSamplesPolicy,
+    /// Samples being flushed. Shared with the flusher, which resolves them without the profile lock.
+    pub flush_batch: Arc<Mutex<FlushBatch>>,
+}
+
+/// Samples taken out of the sample buffers, on their way into `Profile.samples`.
+///
+/// Flushing happens in three steps: the pending samples are swapped out of the buffers
+/// (`Profile::take_pending_samples`), resolved (`resolve`), and appended to the profile
+/// (`Profile::append_flushed_samples`). Resolving is the costly part, and the flusher does it
+/// without holding the profile lock, which samplers and Ruby threads contend for.
+/// Batched samples are still pinned during GC, which therefore waits for an ongoing resolution.
+#[derive(Debug, Default)]
+pub struct FlushBatch {
+    samples: Vec<Sample>,
+    /// The number of samples at the head of `samples` which have been resolved.
+    resolved: usize,
+    /// Samples are flushed only if all of these keep them.
+    sample_filters: Vec<Box<dyn SampleFilter>>,
+}
+
+impl FlushBatch {
+    /// Run the samples not resolved yet through the `sample_filter:`s.
+    pub fn resolve(&mut self) {
+        let unresolved = self.samples.split_off(self.resolved);
+        for sample in unresolved {
+            if self
+                .sample_filters
+                .iter_mut()
+                .all(|sample_filter| sample_filter.keep(&sample))
+            {
+                self.samples.push(sample);
+            }
+        }
+        self.resolved = self.samples.len();
+    }
 }
 
 impl Profile {
-    pub fn new() -> Self {
+    pub fn new(
+        interval: Duration,
+        max_samples: Option<usize>,
+        max_samples_policy: MaxSamplesPolicy,
+        sample_filters: Vec<Box<dyn SampleFilter>>,
+    ) -> Self {
         let backtrace_state = unsafe {
             let ptr = backtrace_create_state(
                 null_mut(),
@@ -39,31 +397,349 @@ impl Profile {
         Self {
             start_timestamp: SystemTime::now(),
             start_instant: Instant::now(),
+            start_monotonic_ns: read_clock_ns(libc::CLOCK_MONOTONIC).unwrap_or(0),
             end_instant: None,
-            samples: vec![],
-            temporary_sample_buffer: Ringbuffer::new(DEFAULT_RINGBUFFER_CAPACITY),
+            samples: VecDeque::new(),
+            temporary_sample_buffer: Ringbuffer::new(
+                DEFAULT_RINGBUFFER_CAPACITY,
+                DEFAULT_RINGBUFFER_MAX_CHUNKS,
+            ),
+            sample_rings: Vec::new(),
             backtrace_state,
-            known_values: HashSet::new(),
+            capture_stats: Arc::new(CaptureStats::default()),
+            gc_pauses: Arc::new(GcPauses::default()),
+            flush_signal: Arc::new(FlushSignal::default()),
+            sampling_interval: Arc::new(SamplingInterval::new(interval)),
+            initial_interval: interval,
+            interval_changes: Vec::new(),
+            thread_cpu_times: Vec::new(),
+            markers: Mutex::new(Vec::new()),
+            known_threads: HashMap::new(),
+            native_thread_ids: HashMap::new(),
+            known_frames: HashMap::new(),
+            flushed_sample_count: 0,
+            max_samples,
+            max_samples_policy,
+            flush_batch: Arc::new(Mutex::new(FlushBatch {
+                sample_filters,
+                ..FlushBatch::default()
+            })),
+        }
+    }
+
+    /// Discard all collected samples and restart the clock, keeping the backtrace state.
+    pub fn reset(&mut self) {
+        self.restart_clock(Duration::ZERO);
+        self.end_instant = None;
+        self.samples.clear();
+        let mut flush_batch = self.flush_batch.lock().unwrap();
+        flush_batch.samples.clear();
+        flush_batch.resolved = 0;
+        drop(flush_batch);
+        while self.temporary_sample_buffer.pop().is_some() {}
+        for ring in self.sample_rings.drain(..) {
+            ring.close();
+            while ring.pop().is_some() {}
+        }
+        self.capture_stats.reset();
+        self.gc_pauses.reset();
+        self.sampling_interval.set(self.initial_interval);
+        self.interval_changes.clear();
+        self.thread_cpu_times.clear();
+        self.markers.get_mut().unwrap().clear();
+        self.known_threads.clear();
+        self.native_thread_ids.clear();
+        self.known_frames.clear();
+        self.flushed_sample_count = 0;
+    }
+
+    /// The wall-clock time at which the profile started, in nanoseconds since the UNIX epoch.
+    pub fn start_timestamp_ns(&self) -> u128 {
+        self.start_timestamp
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    }
+
+    /// The time elapsed from start to stop, or until now if the profile is still running.
+    pub fn duration(&self) -> Duration {
+        self.elapsed_at(self.end_instant.unwrap_or_else(Instant::now))
+    }
+
+    /// The time elapsed from the start of the profile until `instant`.
+    ///
+    /// All durations measured from the start go through here. An `instant` preceding the start
+    /// (e.g. read before the clock was restarted at the end of warmup) is clamped to zero rather
+    /// than underflowing, with a warning.
+    pub fn elapsed_at(&self, instant: Instant) -> Duration {
+        instant
+            .checked_duration_since(self.start_instant)
+            .unwrap_or_else(|| {
+                logging::warn!(
+                    "{:?} before the start of the profile. Clamping the duration to zero.",
+                    self.start_instant - instant
+                );
+                Duration::ZERO
+            })
+    }
+
+    /// Restart the clock at the end of `warmup` from now.
+    /// Samples captured before that are discarded.
+    pub fn restart_clock(&mut self, warmup: Duration) {
+        self.start_timestamp = SystemTime::now() + warmup;
+        self.start_instant = Instant::now() + warmup;
+        self.start_monotonic_ns =
+            read_clock_ns(libc::CLOCK_MONOTONIC).unwrap_or(0) + warmup.as_nanos() as u64;
+    }
+
+    /// Create a lock-free ring for a signal handler to push samples into.
+    /// The ring is drained by `flush_temporary_sample_buffer()`.
+    pub fn register_sample_ring(&mut self) -> Arc<SpscRingbuffer> {
+        let ring = Arc::new(SpscRingbuffer::new(SAMPLE_RING_CAPACITY));
+        self.sample_rings.push(Arc::clone(&ring));
+        ring
+    }
+
+    /// Start accounting the CPU time consumed by `ruby_thread` from now on.
+    pub fn record_thread_cpu_start(&mut self, ruby_thread: VALUE, clockid: libc::clockid_t) {
+        match read_clock_ns(clockid) {
+            Some(start_ns) => self.thread_cpu_times.push(ThreadCpuTime {
+                ruby_thread,
+                clockid,
+                start_ns,
+                end_ns: None,
+            }),
+            None => logging::debug!("Failed to read the CPU time of thread {}", ruby_thread),
+        }
+    }
+
+    /// Remember the native thread id `ruby_thread` runs on. A thread seen on different native
+    /// threads over time has no single id, and is recorded as None.
+    pub fn record_native_thread_id(&mut self, ruby_thread: VALUE, native_thread_id: u64) {
+        self.native_thread_ids
+            .entry(ruby_thread)
+            .and_modify(|recorded| {
+                if *recorded != Some(native_thread_id) {
+                    *recorded = None;
+                }
+            })
+            .or_insert(Some(native_thread_id));
+    }
+
+    /// Mark the profile as stopped, unless it has been stopped already.
+    /// Signal handlers stop pushing samples from then on.
+    pub fn finish(&mut self) {
+        if self.end_instant.is_none() {
+            self.end_instant = Some(Instant::now());
+            for thread_cpu_time in self.thread_cpu_times.iter_mut() {
+                thread_cpu_time.end_ns = read_clock_ns(thread_cpu_time.clockid);
+            }
+        }
+        for ring in self.sample_rings.iter() {
+            ring.close();
         }
     }
 
     pub fn flush_temporary_sample_buffer(&mut self) {
+        let flush_batch = Arc::clone(&self.flush_batch);
+        let mut flush_batch = flush_batch.lock().unwrap();
+        self.take_pending_samples(&mut flush_batch);
+        flush_batch.resolve();
+        self.append_flushed_samples(&mut flush_batch);
+    }
+
+    /// Move the samples pushed since the last flush into `flush_batch`, to be resolved.
+    /// Cheap enough to be done under the profile lock.
+    pub fn take_pending_samples(&mut self, flush_batch: &mut FlushBatch) {
+        for ring in self.sample_rings.iter() {
+            while let Some(sample) = ring.pop() {
+                flush_batch.samples.push(sample);
+            }
+        }
+        // Rings no longer referenced by their producer (e.g. the timer of an exited thread)
+        // will never be pushed to again, and can be dropped once drained
+        self.sample_rings
+            .retain(|ring| Arc::strong_count(ring) > 1 || ring.len() > 0);
+
         while let Some(sample) = self.temporary_sample_buffer.pop() {
-            self.known_values.insert(sample.ruby_thread);
-            for frame in sample.frames.iter() {
-                if frame == &0 {
-                    break;
+            flush_batch.samples.push(sample);
+        }
+        // Prepare room for the samples to be pushed until the next flush
+        self.temporary_sample_buffer.reserve_chunk();
+    }
+
+    /// Append the resolved samples of `flush_batch` to the profile.
+    pub fn append_flushed_samples(&mut self, flush_batch: &mut FlushBatch) {
+        let resolved = flush_batch.resolved;
+        flush_batch.resolved = 0;
+        for sample in flush_batch.samples.drain(..resolved) {
+            self.add_sample(sample);
+        }
+    }
+
+    /// Drop samples captured more than `window` before now (or before the end of the profile,
+    /// if stopped). Samples are ordered by flush, so this stops at the first recent sample;
+    /// each sample is visited once over the lifetime of the profile. Markers are dropped likewise.
+    pub fn evict_samples_older_than(&mut self, window: Duration) {
+        let now = self.end_instant.unwrap_or_else(Instant::now);
+        let cutoff = match now.checked_sub(window) {
+            Some(cutoff) => cutoff,
+            None => return,
+        };
+        while self
+            .samples
+            .front()
+            .is_some_and(|sample| sample.timestamp < cutoff)
+        {
+            self.pop_oldest_sample();
+        }
+        let markers = self.markers.get_mut().unwrap();
+        let recent = markers.partition_point(|marker| marker.timestamp < cutoff);
+        markers.drain(..recent);
+    }
+
+    fn add_sample(&mut self, sample: Sample) {
+        // Drop samples captured during warmup or after the profile has been stopped
+        if sample.timestamp < self.start_instant
+            || self.end_instant.is_some_and(|end| sample.timestamp > end)
+        {
+            return;
+        }
+        if self
+            .max_samples
+            .is_some_and(|max| self.samples.len() >= max)
+        {
+            match self.max_samples_policy {
+                MaxSamplesPolicy::Stop => return,
+                MaxSamplesPolicy::Ring => {
+                    self.pop_oldest_sample();
                 }
-                self.known_values.insert(*frame);
             }
-            self.samples.push(sample);
         }
+
+        *self.known_threads.entry(sample.ruby_thread).or_insert(0) += 1;
+        for frame in sample.frames.iter().take_while(|frame| **frame != 0) {
+            *self.known_frames.entry(*frame).or_insert(0) += 1;
+        }
+        self.samples.push_back(sample);
+        self.flushed_sample_count += 1;
+    }
+
+    /// Drop the oldest sample, along with the threads and frames only it referenced.
+    fn pop_oldest_sample(&mut self) {
+        let sample = match self.samples.pop_front() {
+            Some(sample) => sample,
+            None => return,
+        };
+        Self::release_reference(&mut self.known_threads, sample.ruby_thread);
+        for frame in sample.frames.iter().take_while(|frame| **frame != 0) {
+            Self::release_reference(&mut self.known_frames, *frame);
+        }
+    }
+
+    fn release_reference(references: &mut HashMap<VALUE, usize>, value: VALUE) {
+        if let hash_map::Entry::Occupied(mut entry) = references.entry(value) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+
+    /// Whether a sample captured now would be kept: the profile has neither ended nor reached
+    /// `max_samples` (with `MaxSamplesPolicy::Stop`), and warmup is over.
+    pub fn accepts_samples(&self) -> bool {
+        if self.end_instant.is_some() || Instant::now() < self.start_instant {
+            return false;
+        }
+        match (self.max_samples, &self.max_samples_policy) {
+            (Some(max_samples), MaxSamplesPolicy::Stop) => self.sample_count() < max_samples,
+            _ => true,
+        }
+    }
+
+    /// The number of samples collected so far, including those not flushed yet.
+    pub fn sample_count(&self) -> usize {
+        let count = self.samples.len()
+            + self
+                .flush_batch
+                .try_lock()
+                .map_or(0, |flush_batch| flush_batch.samples.len())
+            + self.temporary_sample_buffer.len()
+            + self
+                .sample_rings
+                .iter()
+                .map(|ring| ring.len())
+                .sum::<usize>();
+        match self.max_samples {
+            Some(max_samples) => count.min(max_samples),
+            None => count,
+        }
+    }
+
+    /// The number of bytes held by this Profile, including heap allocations.
+    pub fn memsize(&self) -> usize {
+        mem::size_of::<Self>()
+            + self.samples.capacity() * mem::size_of::<Sample>()
+            + self.temporary_sample_buffer.memsize()
+            + self
+                .sample_rings
+                .iter()
+                .map(|ring| ring.memsize())
+                .sum::<usize>()
+            + self.thread_cpu_times.capacity() * mem::size_of::<ThreadCpuTime>()
+            + self.known_threads.capacity() * mem::size_of::<(VALUE, usize)>()
+            + self.known_frames.capacity() * mem::size_of::<(VALUE, usize)>()
+    }
+
+    /// An estimate of the memory used by this Profile (see `memsize`), plus markers and the
+    /// tables built when serializing it (functions, locations and strings), in bytes.
+    /// Markers are left out if their lock is held elsewhere.
+    pub fn estimated_memsize(&self) -> usize {
+        let markers_size = match self.markers.try_lock() {
+            Ok(markers) => {
+                markers.capacity() * mem::size_of::<Marker>()
+                    + markers
+                        .iter()
+                        .map(|marker| marker.label.capacity())
+                        .sum::<usize>()
+            }
+            Err(_) => 0,
+        };
+        self.memsize()
+            + markers_size
+            + self.interval_changes.capacity() * mem::size_of::<IntervalChange>()
+            + self.known_frames.len() * ESTIMATED_SERIALIZED_BYTES_PER_FRAME
     }
 
     pub unsafe fn dmark(&self) {
-        for value in self.known_values.iter() {
-            rb_gc_mark(*value);
+        for thread in self.known_threads.keys() {
+            rb_gc_mark(*thread);
         }
+        for frame in self.known_frames.keys() {
+            rb_gc_mark_movable(*frame);
+        }
+        // Samples in the temporary buffer may be pushed by the signal handler at any time,
+        // so they are pinned rather than being updated in dcompact().
         self.temporary_sample_buffer.dmark();
+        for ring in self.sample_rings.iter() {
+            ring.dmark();
+        }
+        // Waits for the flusher to finish resolving the batch, which it does without the GVL
+        for sample in self.flush_batch.lock().unwrap().samples.iter() {
+            sample.dmark();
+        }
+    }
+
+    /// Update references to frames moved by GC compaction.
+    pub unsafe fn dcompact(&mut self) {
+        self.known_frames = self
+            .known_frames
+            .iter()
+            .map(|(frame, references)| (rb_gc_location(*frame), *references))
+            .collect();
+        for sample in self.samples.iter_mut() {
+            sample.dcompact();
+        }
     }
 }
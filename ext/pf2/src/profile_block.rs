@@ -0,0 +1,123 @@
+#![deny(unsafe_op_in_unsafe_fn)]
+
+use std::ffi::c_int;
+
+use rb_sys::*;
+
+use crate::util::{cstr, RTEST};
+
+struct ProfileBlockState {
+    session: VALUE,
+    profile: VALUE,
+}
+
+/// `Pf2.profile_block(**options) { ... }`: Profiles the current thread while running the block,
+/// and returns the serialized profile.
+///
+/// Accepts the same options as `Pf2::Session.new`, except that `threads` is always the current
+/// thread. The profiler is stopped even if the block raises. A dedicated session is used
+/// (not the one behind `Pf2.start`), so the block may start other profiles of its own.
+pub unsafe extern "C" fn rb_profile_block(
+    argc: c_int,
+    argv: *const VALUE,
+    _rbself: VALUE,
+) -> VALUE {
+    unsafe {
+        let mut options: VALUE = Qnil.into();
+        rb_scan_args(argc, argv, cstr!("0:"), &mut options);
+        if rb_block_given_p() == 0 {
+            rb_raise(rb_eArgError, cstr!("block required"));
+        }
+        profile_thread_while_yielding(rb_thread_current(), options)
+    }
+}
+
+/// `Pf2.profile_thread(thread, interval_us: 100, **options) { ... }`: Profiles a single thread
+/// while running the block, typically at a high resolution, and returns the serialized profile.
+///
+/// Like `profile_block`, but for any thread. Only a timer for that thread is armed (on Linux),
+/// alongside any other profile running in the process, and it is deleted afterwards, leaving
+/// the timers of other sessions on that thread as they were. Unless given, `max_sampling_rate_hz`
+/// is raised to the rate requested with `interval_us`, as a single thread is sampled.
+pub unsafe extern "C" fn rb_profile_thread(
+    argc: c_int,
+    argv: *const VALUE,
+    _rbself: VALUE,
+) -> VALUE {
+    unsafe {
+        let mut thread: VALUE = Qnil.into();
+        let mut options: VALUE = Qnil.into();
+        rb_scan_args(argc, argv, cstr!("1:"), &mut thread, &mut options);
+        if rb_block_given_p() == 0 {
+            rb_raise(rb_eArgError, cstr!("block required"));
+        }
+        if !RTEST(rb_obj_is_kind_of(thread, rb_cThread)) {
+            rb_raise(
+                rb_eTypeError,
+                cstr!("thread must be a Thread, not %s"),
+                rb_obj_classname(thread),
+            );
+        }
+
+        let options = if RTEST(options) {
+            rb_hash_dup(options)
+        } else {
+            rb_hash_new()
+        };
+        let interval_us = rb_hash_aref(options, rb_id2sym(rb_intern(cstr!("interval_us"))));
+        let max_sampling_rate_hz = rb_id2sym(rb_intern(cstr!("max_sampling_rate_hz")));
+        if RTEST(rb_obj_is_kind_of(interval_us, rb_cInteger))
+            && !RTEST(rb_hash_lookup2(
+                options,
+                max_sampling_rate_hz,
+                Qfalse as VALUE,
+            ))
+        {
+            let interval_us = rb_num2long(interval_us);
+            if interval_us > 0 {
+                let rate_hz = (1_000_000 + interval_us - 1) / interval_us;
+                rb_hash_aset(options, max_sampling_rate_hz, rb_int2inum(rate_hz as isize));
+            }
+        }
+        profile_thread_while_yielding(thread, options)
+    }
+}
+
+/// Profile `thread` with a dedicated session created with `options`, while yielding the block.
+unsafe fn profile_thread_while_yielding(thread: VALUE, options: VALUE) -> VALUE {
+    unsafe {
+        let session_options = rb_hash_new();
+        if RTEST(options) {
+            rb_funcall(session_options, rb_intern(cstr!("update")), 1, options);
+        }
+        let threads = rb_ary_new();
+        rb_ary_push(threads, thread);
+        rb_hash_aset(
+            session_options,
+            rb_id2sym(rb_intern(cstr!("threads"))),
+            threads,
+        );
+
+        let session_class = rb_path2class(cstr!("Pf2::Session"));
+        let session = rb_class_new_instance(1, &session_options, session_class);
+        rb_funcall(session, rb_intern(cstr!("start")), 0);
+
+        let mut state = ProfileBlockState {
+            session,
+            profile: Qnil.into(),
+        };
+        let state_ptr = &mut state as *mut ProfileBlockState as VALUE;
+        rb_ensure(Some(yield_block), state_ptr, Some(stop_session), state_ptr);
+        state.profile
+    }
+}
+
+unsafe extern "C" fn yield_block(_state: VALUE) -> VALUE {
+    unsafe { rb_yield(Qnil.into()) }
+}
+
+unsafe extern "C" fn stop_session(state: VALUE) -> VALUE {
+    let state = unsafe { &mut *(state as *mut ProfileBlockState) };
+    state.profile = unsafe { rb_funcall(state.session, rb_intern(cstr!("stop")), 0) };
+    Qnil.into()
+}
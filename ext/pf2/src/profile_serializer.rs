@@ -1,16 +1,29 @@
-use std::collections::HashMap;
-use std::ffi::{c_char, CStr};
-use std::hash::Hasher;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
 
 use rb_sys::*;
 
-use crate::backtrace::Backtrace;
-use crate::profile::Profile;
-use crate::util::RTEST;
+use crate::serialization::profile::{FunctionImplementation, LocationIndex, Profile};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ProfileSerializer {
     threads: HashMap<ThreadId, ThreadProfile>,
+    /// Provenance of the profile, from `Metadata` of the canonical profile.
+    #[serde(default)]
+    pid: u32,
+    #[serde(default)]
+    ruby_version: String,
+    /// `"cpu"` or `"wall"`.
+    #[serde(default)]
+    time_mode: String,
+    #[serde(default)]
+    interval_ns: u128,
+    /// The wall-clock time at which the profile started, in nanoseconds since the Unix epoch.
+    #[serde(default)]
+    start_timestamp_ns: u128,
+    /// `Metadata.labels` of the canonical profile. Omitted if there are none.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    labels: BTreeMap<String, String>,
 }
 
 type ThreadId = VALUE;
@@ -22,6 +35,9 @@ struct ThreadProfile {
     #[serde(rename = "frames")]
     frame_table: HashMap<FrameTableId, FrameTableEntry>,
     samples: Vec<ProfileSample>,
+    /// Markers recorded by the thread (`Session#mark`). Omitted if there are none.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    markers: Vec<ProfileMarker>,
 }
 
 impl ThreadProfile {
@@ -36,6 +52,7 @@ impl ThreadProfile {
             },
             frame_table: HashMap::new(),
             samples: vec![],
+            markers: vec![],
         }
     }
 }
@@ -43,9 +60,8 @@ impl ThreadProfile {
 type StackTreeNodeId = i32;
 
 // Arbitary value which is used inside StackTreeNode.
-// This VALUE should not be dereferenced as a pointer; we're merely using its pointer as a unique value.
-// (Probably should be reconsidered)
-type FrameTableId = VALUE;
+// Derived from the index of the Function in the canonical Profile (0 is reserved for the root node).
+type FrameTableId = u64;
 
 #[derive(Debug, Deserialize, Serialize)]
 struct StackTreeNode {
@@ -75,6 +91,12 @@ enum FrameTableEntryType {
     Native,
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+struct ProfileMarker {
+    elapsed_ns: u64,
+    label: String,
+}
+
 // Represents leaf (末端)
 #[derive(Debug, Deserialize, Serialize)]
 struct ProfileSample {
@@ -82,167 +104,156 @@ struct ProfileSample {
     stack_tree_id: StackTreeNodeId,
 }
 
-struct NativeFunctionFrame {
-    pub symbol_name: String,
-    pub address: Option<usize>,
-}
-
+/// The legacy output format consumed by `Pf2::Reporter::FirefoxProfiler`.
+/// This is a view over the canonical `serialization::profile::Profile` built by `ProfileSerializer2`.
 impl ProfileSerializer {
-    pub fn serialize(profile: &Profile) -> String {
+    pub fn serialize(profile: &Profile, pretty: bool) -> Vec<u8> {
+        match pretty {
+            true => serde_json::to_vec_pretty(&Self::build(profile)).unwrap(),
+            false => serde_json::to_vec(&Self::build(profile)).unwrap(),
+        }
+    }
+
+    /// Serialize the profile directly into `writer`, without building the whole JSON in memory.
+    pub fn serialize_to_writer<W: Write>(
+        profile: &Profile,
+        writer: W,
+        pretty: bool,
+    ) -> serde_json::Result<()> {
+        match pretty {
+            true => serde_json::to_writer_pretty(writer, &Self::build(profile)),
+            false => serde_json::to_writer(writer, &Self::build(profile)),
+        }
+    }
+
+    fn build(profile: &Profile) -> ProfileSerializer {
         let mut sequence = 1;
 
         let mut serializer = ProfileSerializer {
             threads: HashMap::new(),
+            pid: profile.metadata.pid,
+            ruby_version: profile.metadata.ruby_version.clone(),
+            time_mode: profile.metadata.time_mode.clone(),
+            interval_ns: profile.metadata.interval_ns,
+            start_timestamp_ns: profile.metadata.start_timestamp_ns,
+            labels: profile.metadata.labels.clone(),
         };
 
-        unsafe {
-            // Process each sample
-            for sample in profile.samples.iter() {
-                let mut merged_stack: Vec<FrameTableEntry> = vec![];
-
-                // Process C-level stack
-
-                let mut c_stack: Vec<NativeFunctionFrame> = vec![];
-                // Rebuild the original backtrace (including inlined functions) from the PC.
-                for i in 0..sample.c_backtrace_pcs[0] {
-                    let pc = sample.c_backtrace_pcs[i + 1];
-                    Backtrace::backtrace_syminfo(
-                        &profile.backtrace_state,
-                        pc,
-                        |_pc: usize, symname: *const c_char, symval: usize, _symsize: usize| {
-                            if symname.is_null() {
-                                c_stack.push(NativeFunctionFrame {
-                                    symbol_name: "(no symbol information)".to_owned(),
-                                    address: None,
-                                });
-                            } else {
-                                c_stack.push(NativeFunctionFrame {
-                                    symbol_name: CStr::from_ptr(symname)
-                                        .to_str()
-                                        .unwrap()
-                                        .to_owned(),
-                                    address: Some(symval),
-                                });
-                            }
-                        },
-                        Some(Backtrace::backtrace_error_callback),
-                    );
-                }
-                for frame in c_stack.iter() {
-                    if frame.symbol_name.contains("pf2") {
-                        // Skip Pf2-related frames
-                        continue;
-                    }
-
-                    merged_stack.push(FrameTableEntry {
-                        id: calculate_id_for_c_frame(&frame.symbol_name),
-                        entry_type: FrameTableEntryType::Native,
-                        full_label: frame.symbol_name.clone(),
-                        file_name: None,
-                        function_first_lineno: None,
-                        callsite_lineno: None,
-                        address: frame.address,
-                    });
-                }
+        // Process each sample
+        for sample in profile.samples.iter() {
+            // The synthetic GC frame (if any) is the leaf of the Ruby stack.
+            // In this format, it is placed at the leaf of the merged stack.
+            let (gc_location, ruby_stack) = if sample.during_gc {
+                (
+                    sample.stack.first(),
+                    sample.stack.get(1..).unwrap_or_default(),
+                )
+            } else {
+                (None, &sample.stack[..])
+            };
 
-                // Process Ruby-level stack
-
-                let ruby_stack_depth = sample.line_count;
-                for i in 0..ruby_stack_depth {
-                    let frame: VALUE = sample.frames[i as usize];
-                    let lineno: i32 = sample.linenos[i as usize];
-                    let address: Option<usize> = {
-                        let cme = frame
-                            as *mut crate::ruby_internal_apis::rb_callable_method_entry_struct;
-                        let cme = &*cme;
-
-                        if (*(cme.def)).type_ == 1 {
-                            // The cme is a Cfunc
-                            Some((*(cme.def)).cfunc.func as usize)
-                        } else {
-                            // The cme is an ISeq (Ruby code) or some other type
-                            None
-                        }
-                    };
-                    let mut frame_full_label: VALUE = rb_profile_frame_full_label(frame);
-                    let frame_full_label: String = if RTEST(frame_full_label) {
-                        CStr::from_ptr(rb_string_value_cstr(&mut frame_full_label))
-                            .to_str()
-                            .unwrap()
-                            .to_owned()
-                    } else {
-                        "(unknown)".to_owned()
-                    };
-                    let mut frame_path: VALUE = rb_profile_frame_path(frame);
-                    let frame_path: String = if RTEST(frame_path) {
-                        CStr::from_ptr(rb_string_value_cstr(&mut frame_path))
-                            .to_str()
-                            .unwrap()
-                            .to_owned()
-                    } else {
-                        "(unknown)".to_owned()
-                    };
-                    let frame_first_lineno: VALUE = rb_profile_frame_first_lineno(frame);
-                    let frame_first_lineno: Option<i32> = if RTEST(frame_first_lineno) {
-                        Some(rb_num2int(frame_first_lineno).try_into().unwrap())
-                    } else {
-                        None
+            // Frames, deep to shallow: the native stack followed by the Ruby stack
+            let mut merged_stack: Vec<FrameTableEntry> = gc_location
+                .iter()
+                .chain(sample.native_stack.iter())
+                .chain(ruby_stack.iter())
+                .filter_map(|&location_index| Self::frame_table_entry(profile, location_index))
+                .collect();
+
+            // Find the Thread profile for this sample
+            let thread_id = sample.ruby_thread_id.unwrap_or(0) as ThreadId;
+            let thread_serializer = serializer
+                .threads
+                .entry(thread_id)
+                .or_insert(ThreadProfile::new(thread_id));
+
+            // Stack frames, shallow to deep
+            let mut stack_tree = &mut thread_serializer.stack_tree;
+
+            while let Some(frame_table_entry) = merged_stack.pop() {
+                stack_tree = stack_tree.children.entry(frame_table_entry.id).or_insert({
+                    let node = StackTreeNode {
+                        children: HashMap::new(),
+                        node_id: sequence,
+                        frame_id: frame_table_entry.id,
                     };
-                    merged_stack.push(FrameTableEntry {
-                        id: frame,
-                        entry_type: FrameTableEntryType::Ruby,
-                        full_label: frame_full_label,
-                        file_name: Some(frame_path),
-                        function_first_lineno: frame_first_lineno,
-                        callsite_lineno: Some(lineno),
-                        address,
-                    });
-                }
+                    sequence += 1;
+                    node
+                });
 
-                // Find the Thread profile for this sample
-                let thread_serializer = serializer
-                    .threads
-                    .entry(sample.ruby_thread)
-                    .or_insert(ThreadProfile::new(sample.ruby_thread));
-
-                // Stack frames, shallow to deep
-                let mut stack_tree = &mut thread_serializer.stack_tree;
-
-                while let Some(frame_table_entry) = merged_stack.pop() {
-                    stack_tree = stack_tree.children.entry(frame_table_entry.id).or_insert({
-                        let node = StackTreeNode {
-                            children: HashMap::new(),
-                            node_id: sequence,
-                            frame_id: frame_table_entry.id,
-                        };
-                        sequence += 1;
-                        node
+                if merged_stack.is_empty() {
+                    // This is the leaf node, record a Sample
+                    thread_serializer.samples.push(ProfileSample {
+                        elapsed_ns: sample.elapsed_ns as u128,
+                        stack_tree_id: stack_tree.node_id,
                     });
-
-                    if merged_stack.is_empty() {
-                        // This is the leaf node, record a Sample
-                        let elapsed_ns = (sample.timestamp - profile.start_instant).as_nanos();
-                        thread_serializer.samples.push(ProfileSample {
-                            elapsed_ns,
-                            stack_tree_id: stack_tree.node_id,
-                        });
-                    }
-
-                    // Register frame metadata to frame table, if not registered yet
-                    thread_serializer
-                        .frame_table
-                        .entry(frame_table_entry.id)
-                        .or_insert(frame_table_entry);
                 }
+
+                // Register frame metadata to frame table, if not registered yet
+                thread_serializer
+                    .frame_table
+                    .entry(frame_table_entry.id)
+                    .or_insert(frame_table_entry);
             }
         }
 
-        serde_json::to_string(&serializer).unwrap()
+        for marker in profile.markers.iter() {
+            let thread_id = marker.ruby_thread_id as ThreadId;
+            serializer
+                .threads
+                .entry(thread_id)
+                .or_insert(ThreadProfile::new(thread_id))
+                .markers
+                .push(ProfileMarker {
+                    elapsed_ns: marker.elapsed_ns,
+                    label: marker.label.clone(),
+                });
+        }
+
+        serializer
     }
-}
 
-fn calculate_id_for_c_frame<T: std::hash::Hash>(t: &T) -> FrameTableId {
-    let mut s = std::collections::hash_map::DefaultHasher::new();
-    t.hash(&mut s);
-    s.finish()
+    /// Build a FrameTableEntry from a Location. Returns None for frames which should be skipped.
+    fn frame_table_entry(
+        profile: &Profile,
+        location_index: LocationIndex,
+    ) -> Option<FrameTableEntry> {
+        let location = &profile.locations[location_index];
+        let function = &profile.functions[location.function_index];
+        let string = |index: Option<usize>| index.map(|index| profile.strings[index].clone());
+        let id = location.function_index as FrameTableId + 1;
+
+        match function.implementation {
+            FunctionImplementation::Native => {
+                let full_label =
+                    string(function.name).unwrap_or_else(|| "(no symbol information)".to_owned());
+                if full_label.contains("pf2") {
+                    // Skip Pf2-related frames
+                    return None;
+                }
+                Some(FrameTableEntry {
+                    id,
+                    entry_type: FrameTableEntryType::Native,
+                    full_label,
+                    file_name: None,
+                    function_first_lineno: None,
+                    callsite_lineno: None,
+                    address: function.start_address,
+                })
+            }
+            FunctionImplementation::Ruby | FunctionImplementation::CFunc => Some(FrameTableEntry {
+                id,
+                entry_type: FrameTableEntryType::Ruby,
+                full_label: match string(function.name) {
+                    Some(name) if function.jit => format!("{} (jit)", name),
+                    Some(name) => name,
+                    None => "(unknown)".to_owned(),
+                },
+                file_name: string(function.filename),
+                function_first_lineno: function.start_lineno,
+                callsite_lineno: function.filename.map(|_| location.lineno),
+                address: function.start_address,
+            }),
+        }
+    }
 }
@@ -1,11 +1,23 @@
+use std::collections::VecDeque;
+
 use crate::sample::Sample;
 
+/// A FIFO buffer of samples, backed by a chain of fixed-capacity chunks.
+///
+/// `push()` never allocates: when the current chunk fills up, it advances to a
+/// chunk reserved in advance by `reserve_chunk()`, which is called outside of
+/// signal handlers. The buffer holds at most `chunk_capacity * max_chunks` samples,
+/// so its memory usage is bounded by `memsize_ceiling()`.
 #[derive(Debug)]
 pub struct Ringbuffer {
-    capacity: usize,
-    buffer: Vec<Option<Sample>>,
+    chunk_capacity: usize,
+    max_chunks: usize,
+    /// Chunks holding samples, oldest first. Reserved to `max_chunks` upfront.
+    chunks: VecDeque<Vec<Option<Sample>>>,
+    /// Empty chunks ready to be used by `push()`. Reserved to `max_chunks` upfront.
+    spare_chunks: Vec<Vec<Option<Sample>>>,
+    /// The position of the next sample to be popped in the first chunk.
     read_index: usize,
-    write_index: usize,
 }
 
 #[derive(Debug, PartialEq)]
@@ -14,40 +26,96 @@ pub enum RingbufferError {
 }
 
 impl Ringbuffer {
-    pub fn new(capacity: usize) -> Self {
-        Self {
-            capacity,
-            buffer: std::iter::repeat_with(|| None)
-                .take(capacity + 1)
-                .collect::<Vec<_>>(),
+    pub fn new(chunk_capacity: usize, max_chunks: usize) -> Self {
+        let mut chunks = VecDeque::with_capacity(max_chunks);
+        chunks.push_back(Vec::with_capacity(chunk_capacity));
+        let mut ringbuffer = Self {
+            chunk_capacity,
+            max_chunks,
+            chunks,
+            spare_chunks: Vec::with_capacity(max_chunks),
             read_index: 0,
-            write_index: 0,
+        };
+        ringbuffer.reserve_chunk();
+        ringbuffer
+    }
+
+    /// Make sure a spare chunk is available for `push()`, unless the buffer has
+    /// reached `max_chunks`. Excess spare chunks are released.
+    ///
+    /// NOT async-signal-safe, as this allocates.
+    pub fn reserve_chunk(&mut self) {
+        self.spare_chunks.truncate(1);
+        if self.spare_chunks.is_empty() && self.chunks.len() < self.max_chunks {
+            self.spare_chunks
+                .push(Vec::with_capacity(self.chunk_capacity));
         }
     }
 
     // async-signal-safe
     pub fn push(&mut self, sample: Sample) -> Result<(), RingbufferError> {
-        let next = (self.write_index + 1) % (self.capacity + 1);
-        if next == self.read_index {
-            return Err(RingbufferError::Full);
+        let needs_new_chunk = match self.chunks.back() {
+            Some(chunk) => chunk.len() == self.chunk_capacity,
+            None => true,
+        };
+        if needs_new_chunk {
+            if self.chunks.len() == self.max_chunks {
+                return Err(RingbufferError::Full);
+            }
+            match self.spare_chunks.pop() {
+                Some(chunk) => self.chunks.push_back(chunk),
+                None => return Err(RingbufferError::Full),
+            }
         }
-        self.buffer[self.write_index] = Some(sample);
-        self.write_index = next;
+        // Neither push allocates, as the capacities are reserved
+        self.chunks.back_mut().unwrap().push(Some(sample));
         Ok(())
     }
 
     pub fn pop(&mut self) -> Option<Sample> {
-        if self.read_index == self.write_index {
+        let chunk = self.chunks.front_mut()?;
+        if self.read_index == chunk.len() {
             return None;
         }
-        let sample = self.buffer[self.read_index].take();
-        self.read_index = (self.read_index + 1) % (self.capacity + 1);
+        let sample = chunk[self.read_index].take();
+        self.read_index += 1;
+
+        // Recycle the chunk once it has been fully consumed
+        if self.read_index == self.chunk_capacity {
+            let mut chunk = self.chunks.pop_front().unwrap();
+            chunk.clear();
+            self.spare_chunks.push(chunk);
+            self.read_index = 0;
+        }
         sample
     }
 
-    // This will call rb_gc_mark() for capacity * Sample::MAX_STACK_DEPTH * 2 times, which is a lot!
+    /// The number of samples currently in the buffer.
+    pub fn len(&self) -> usize {
+        self.chunks.iter().map(|chunk| chunk.len()).sum::<usize>() - self.read_index
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of bytes allocated for the buffer.
+    pub fn memsize(&self) -> usize {
+        self.chunks
+            .iter()
+            .chain(self.spare_chunks.iter())
+            .map(|chunk| chunk.capacity() * std::mem::size_of::<Option<Sample>>())
+            .sum()
+    }
+
+    /// The maximum number of bytes the buffer may allocate.
+    pub fn memsize_ceiling(&self) -> usize {
+        self.max_chunks * self.chunk_capacity * std::mem::size_of::<Option<Sample>>()
+    }
+
+    // This will call rb_gc_mark() for len * Sample::MAX_STACK_DEPTH * 2 times, which is a lot!
     pub fn dmark(&self) {
-        for sample in self.buffer.iter().flatten() {
+        for sample in self.chunks.iter().flatten().flatten() {
             unsafe {
                 sample.dmark();
             }
@@ -62,24 +130,42 @@ mod tests {
 
     #[test]
     fn test_ringbuffer() {
-        let mut ringbuffer = Ringbuffer::new(2);
+        let mut ringbuffer = Ringbuffer::new(2, 1);
         assert_eq!(ringbuffer.pop(), None);
 
         let sample1 = Sample {
             ruby_thread: 1,
+            ruby_ractor_id: None,
+            ruby_fiber_id: 0,
             timestamp: Instant::now(),
+            clock_ns: 0,
             line_count: 0,
+            omitted_frames: 0,
+            during_gc: false,
+            idle: false,
+            state: None,
+            on_cpu: None,
             frames: [0; 500],
             linenos: [0; 500],
             c_backtrace_pcs: [0; 1001],
+            interrupted_pc: None,
         };
         let sample2 = Sample {
             ruby_thread: 2,
+            ruby_ractor_id: None,
+            ruby_fiber_id: 0,
             timestamp: Instant::now(),
+            clock_ns: 0,
             line_count: 0,
+            omitted_frames: 0,
+            during_gc: false,
+            idle: false,
+            state: None,
+            on_cpu: None,
             frames: [0; 500],
             linenos: [0; 500],
             c_backtrace_pcs: [0; 1001],
+            interrupted_pc: None,
         };
 
         ringbuffer.push(sample1).unwrap();
@@ -92,22 +178,40 @@ mod tests {
 
     #[test]
     fn test_ringbuffer_full() {
-        let mut ringbuffer = Ringbuffer::new(1);
+        let mut ringbuffer = Ringbuffer::new(1, 1);
         let sample1 = Sample {
             ruby_thread: 1,
+            ruby_ractor_id: None,
+            ruby_fiber_id: 0,
             timestamp: Instant::now(),
+            clock_ns: 0,
             line_count: 0,
+            omitted_frames: 0,
+            during_gc: false,
+            idle: false,
+            state: None,
+            on_cpu: None,
             frames: [0; 500],
             linenos: [0; 500],
             c_backtrace_pcs: [0; 1001],
+            interrupted_pc: None,
         };
         let sample2 = Sample {
             ruby_thread: 2,
+            ruby_ractor_id: None,
+            ruby_fiber_id: 0,
             timestamp: Instant::now(),
+            clock_ns: 0,
             line_count: 0,
+            omitted_frames: 0,
+            during_gc: false,
+            idle: false,
+            state: None,
+            on_cpu: None,
             frames: [0; 500],
             linenos: [0; 500],
             c_backtrace_pcs: [0; 1001],
+            interrupted_pc: None,
         };
 
         ringbuffer.push(sample1).unwrap();
@@ -116,30 +220,57 @@ mod tests {
 
     #[test]
     fn test_ringbuffer_write_a_lot() {
-        let mut ringbuffer = Ringbuffer::new(2);
+        let mut ringbuffer = Ringbuffer::new(2, 1);
         let sample1 = Sample {
             ruby_thread: 1,
+            ruby_ractor_id: None,
+            ruby_fiber_id: 0,
             timestamp: Instant::now(),
+            clock_ns: 0,
             line_count: 0,
+            omitted_frames: 0,
+            during_gc: false,
+            idle: false,
+            state: None,
+            on_cpu: None,
             frames: [0; 500],
             linenos: [0; 500],
             c_backtrace_pcs: [0; 1001],
+            interrupted_pc: None,
         };
         let sample2 = Sample {
             ruby_thread: 2,
+            ruby_ractor_id: None,
+            ruby_fiber_id: 0,
             timestamp: Instant::now(),
+            clock_ns: 0,
             line_count: 0,
+            omitted_frames: 0,
+            during_gc: false,
+            idle: false,
+            state: None,
+            on_cpu: None,
             frames: [0; 500],
             linenos: [0; 500],
             c_backtrace_pcs: [0; 1001],
+            interrupted_pc: None,
         };
         let sample3 = Sample {
             ruby_thread: 3,
+            ruby_ractor_id: None,
+            ruby_fiber_id: 0,
             timestamp: Instant::now(),
+            clock_ns: 0,
             line_count: 0,
+            omitted_frames: 0,
+            during_gc: false,
+            idle: false,
+            state: None,
+            on_cpu: None,
             frames: [0; 500],
             linenos: [0; 500],
             c_backtrace_pcs: [0; 1001],
+            interrupted_pc: None,
         };
 
         ringbuffer.push(sample1).unwrap();
@@ -149,4 +280,111 @@ mod tests {
         ringbuffer.push(sample3).unwrap();
         assert_eq!(ringbuffer.pop().unwrap().ruby_thread, 3);
     }
+
+    #[test]
+    fn test_ringbuffer_len() {
+        let mut ringbuffer = Ringbuffer::new(2, 2);
+        assert!(ringbuffer.is_empty());
+
+        let sample1 = Sample {
+            ruby_thread: 1,
+            ruby_ractor_id: None,
+            ruby_fiber_id: 0,
+            timestamp: Instant::now(),
+            clock_ns: 0,
+            line_count: 0,
+            omitted_frames: 0,
+            during_gc: false,
+            idle: false,
+            state: None,
+            on_cpu: None,
+            frames: [0; 500],
+            linenos: [0; 500],
+            c_backtrace_pcs: [0; 1001],
+            interrupted_pc: None,
+        };
+        let sample2 = Sample {
+            ruby_thread: 2,
+            ruby_ractor_id: None,
+            ruby_fiber_id: 0,
+            timestamp: Instant::now(),
+            clock_ns: 0,
+            line_count: 0,
+            omitted_frames: 0,
+            during_gc: false,
+            idle: false,
+            state: None,
+            on_cpu: None,
+            frames: [0; 500],
+            linenos: [0; 500],
+            c_backtrace_pcs: [0; 1001],
+            interrupted_pc: None,
+        };
+        let sample3 = Sample {
+            ruby_thread: 3,
+            ruby_ractor_id: None,
+            ruby_fiber_id: 0,
+            timestamp: Instant::now(),
+            clock_ns: 0,
+            line_count: 0,
+            omitted_frames: 0,
+            during_gc: false,
+            idle: false,
+            state: None,
+            on_cpu: None,
+            frames: [0; 500],
+            linenos: [0; 500],
+            c_backtrace_pcs: [0; 1001],
+            interrupted_pc: None,
+        };
+
+        ringbuffer.push(sample1).unwrap();
+        ringbuffer.push(sample2).unwrap();
+        assert_eq!(ringbuffer.len(), 2);
+        ringbuffer.pop().unwrap();
+        ringbuffer.push(sample3).unwrap(); // advances to the next chunk
+        assert_eq!(ringbuffer.len(), 2);
+        ringbuffer.pop().unwrap();
+        ringbuffer.pop().unwrap();
+        assert_eq!(ringbuffer.len(), 0);
+    }
+
+    #[test]
+    fn test_ringbuffer_grow() {
+        let mut ringbuffer = Ringbuffer::new(1, 3);
+        let sample = |ruby_thread| Sample {
+            ruby_thread,
+            ruby_ractor_id: None,
+            ruby_fiber_id: 0,
+            timestamp: Instant::now(),
+            clock_ns: 0,
+            line_count: 0,
+            omitted_frames: 0,
+            during_gc: false,
+            idle: false,
+            state: None,
+            on_cpu: None,
+            frames: [0; 500],
+            linenos: [0; 500],
+            c_backtrace_pcs: [0; 1001],
+            interrupted_pc: None,
+        };
+
+        ringbuffer.push(sample(1)).unwrap();
+        ringbuffer.push(sample(2)).unwrap();
+        // No spare chunk has been reserved since then
+        assert_eq!(ringbuffer.push(sample(3)), Err(RingbufferError::Full));
+
+        ringbuffer.reserve_chunk();
+        ringbuffer.push(sample(3)).unwrap();
+        // max_chunks reached
+        ringbuffer.reserve_chunk();
+        assert_eq!(ringbuffer.push(sample(4)), Err(RingbufferError::Full));
+        assert_eq!(ringbuffer.memsize(), ringbuffer.memsize_ceiling());
+
+        assert_eq!(ringbuffer.pop().unwrap().ruby_thread, 1);
+        assert_eq!(ringbuffer.pop().unwrap().ruby_thread, 2);
+        assert_eq!(ringbuffer.pop().unwrap().ruby_thread, 3);
+        assert_eq!(ringbuffer.pop(), None);
+    }
 }
@@ -2,6 +2,10 @@
 
 use rb_sys::*;
 
+use crate::features;
+use crate::native_version;
+use crate::profile_block;
+use crate::serialization::{diff, downsample, merge, otlp};
 use crate::session::ruby_object::SessionRubyObject;
 use crate::util::*;
 
@@ -18,6 +22,56 @@ extern "C" fn Init_pf2() {
 
     unsafe {
         let rb_mPf2: VALUE = rb_define_module(cstr!("Pf2"));
+        rb_define_module_function(
+            rb_mPf2,
+            cstr!("features"),
+            Some(to_ruby_cfunc_with_no_args(features::rb_features)),
+            0,
+        );
+        rb_define_module_function(
+            rb_mPf2,
+            cstr!("native_version"),
+            Some(to_ruby_cfunc_with_no_args(
+                native_version::rb_native_version,
+            )),
+            0,
+        );
+        rb_define_module_function(
+            rb_mPf2,
+            cstr!("profile_block"),
+            Some(to_ruby_cfunc_with_args(profile_block::rb_profile_block)),
+            -1,
+        );
+        rb_define_module_function(
+            rb_mPf2,
+            cstr!("profile_thread"),
+            Some(to_ruby_cfunc_with_args(profile_block::rb_profile_thread)),
+            -1,
+        );
+        rb_define_module_function(
+            rb_mPf2,
+            cstr!("diff"),
+            Some(to_ruby_cfunc_with_args(diff::rb_diff)),
+            2,
+        );
+        rb_define_module_function(
+            rb_mPf2,
+            cstr!("merge"),
+            Some(to_ruby_cfunc_with_args(merge::rb_merge)),
+            -1,
+        );
+        rb_define_module_function(
+            rb_mPf2,
+            cstr!("downsample"),
+            Some(to_ruby_cfunc_with_args(downsample::rb_downsample)),
+            2,
+        );
+        rb_define_module_function(
+            rb_mPf2,
+            cstr!("to_otlp"),
+            Some(to_ruby_cfunc_with_one_arg(otlp::rb_to_otlp)),
+            1,
+        );
 
         let rb_mPf2_Session = rb_define_class_under(rb_mPf2, cstr!("Session"), rb_cObject);
         rb_define_alloc_func(rb_mPf2_Session, Some(SessionRubyObject::rb_alloc));
@@ -36,7 +90,130 @@ extern "C" fn Init_pf2() {
         rb_define_method(
             rb_mPf2_Session,
             cstr!("stop"),
-            Some(to_ruby_cfunc_with_no_args(SessionRubyObject::rb_stop)),
+            Some(to_ruby_cfunc_with_args(SessionRubyObject::rb_stop)),
+            -1,
+        );
+        rb_define_method(
+            rb_mPf2_Session,
+            cstr!("reset"),
+            Some(to_ruby_cfunc_with_no_args(SessionRubyObject::rb_reset)),
+            0,
+        );
+        rb_define_method(
+            rb_mPf2_Session,
+            cstr!("post_fork"),
+            Some(to_ruby_cfunc_with_no_args(SessionRubyObject::rb_post_fork)),
+            0,
+        );
+        rb_define_method(
+            rb_mPf2_Session,
+            cstr!("running?"),
+            Some(to_ruby_cfunc_with_no_args(SessionRubyObject::rb_running)),
+            0,
+        );
+        rb_define_method(
+            rb_mPf2_Session,
+            cstr!("histogram"),
+            Some(to_ruby_cfunc_with_no_args(SessionRubyObject::rb_histogram)),
+            0,
+        );
+        rb_define_method(
+            rb_mPf2_Session,
+            cstr!("summary"),
+            Some(to_ruby_cfunc_with_args(SessionRubyObject::rb_summary)),
+            -1,
+        );
+        rb_define_method(
+            rb_mPf2_Session,
+            cstr!("snapshot"),
+            Some(to_ruby_cfunc_with_args(SessionRubyObject::rb_snapshot)),
+            -1,
+        );
+        rb_define_method(
+            rb_mPf2_Session,
+            cstr!("samples"),
+            Some(to_ruby_cfunc_with_no_args(SessionRubyObject::rb_samples)),
+            0,
+        );
+        rb_define_method(
+            rb_mPf2_Session,
+            cstr!("sample_thread"),
+            Some(to_ruby_cfunc_with_one_arg(
+                SessionRubyObject::rb_sample_thread,
+            )),
+            1,
+        );
+        rb_define_method(
+            rb_mPf2_Session,
+            cstr!("configuration"),
+            Some(to_ruby_cfunc_with_no_args(
+                SessionRubyObject::rb_configuration,
+            )),
+            0,
+        );
+        rb_define_method(
+            rb_mPf2_Session,
+            cstr!("sample_count"),
+            Some(to_ruby_cfunc_with_no_args(
+                SessionRubyObject::rb_sample_count,
+            )),
+            0,
+        );
+        rb_define_method(
+            rb_mPf2_Session,
+            cstr!("drop_stats"),
+            Some(to_ruby_cfunc_with_no_args(SessionRubyObject::rb_drop_stats)),
+            0,
+        );
+        rb_define_method(
+            rb_mPf2_Session,
+            cstr!("reset_drop_stats"),
+            Some(to_ruby_cfunc_with_no_args(
+                SessionRubyObject::rb_reset_drop_stats,
+            )),
+            0,
+        );
+        rb_define_method(
+            rb_mPf2_Session,
+            cstr!("memsize"),
+            Some(to_ruby_cfunc_with_no_args(SessionRubyObject::rb_memsize)),
+            0,
+        );
+        rb_define_method(
+            rb_mPf2_Session,
+            cstr!("mark"),
+            Some(to_ruby_cfunc_with_one_arg(SessionRubyObject::rb_mark)),
+            1,
+        );
+        rb_define_method(
+            rb_mPf2_Session,
+            cstr!("flush"),
+            Some(to_ruby_cfunc_with_no_args(SessionRubyObject::rb_flush)),
+            0,
+        );
+        #[cfg(feature = "debug")]
+        rb_define_method(
+            rb_mPf2_Session,
+            cstr!("debug_frames"),
+            Some(to_ruby_cfunc_with_one_arg(
+                SessionRubyObject::rb_debug_frames,
+            )),
+            1,
+        );
+        rb_define_method(
+            rb_mPf2_Session,
+            cstr!("start_timestamp_ns"),
+            Some(to_ruby_cfunc_with_no_args(
+                SessionRubyObject::rb_start_timestamp_ns,
+            )),
+            0,
+        );
+        rb_define_method(
+            rb_mPf2_Session,
+            cstr!("duration_ns"),
+            Some(to_ruby_cfunc_with_no_args(
+                SessionRubyObject::rb_duration_ns,
+            )),
             0,
         );
     }
@@ -55,10 +55,33 @@ struct rb_thread_struct {
     _padding_ractor: *mut c_int, // rb_ractor_t
     _padding_vm: *mut c_int,     // rb_vm_t
     nt: *mut rb_native_thread,
+    // rb_execution_context_t
+    ec: *mut c_void,
+    _padding_sched_to_pending_interrupt_mask_stack: [VALUE; 29],
+    _padding_interrupt_lock: libc::pthread_mutex_t, // rb_nativethread_lock_t
+    _padding_unblock_to_stat_insn_usage: [VALUE; 12],
+    // The offset of this field differs across Ruby versions. It is checked against the running
+    // Ruby by `test_root_ec_matches_the_layout_of_the_running_ruby` (vm_tests.rs).
+    // rb_fiber_t. Only set once the root Fiber is made a Ruby object (e.g. by resuming another
+    // Fiber), until which it is the one running.
+    root_fiber: *mut c_void,
     // ...
 }
 type rb_thread_t = rb_thread_struct;
 
+#[repr(C)]
+struct rb_execution_context_struct {
+    vm_stack: *mut VALUE,
+    _padding_vm_stack_size: usize,
+    cfp: *mut c_void, // rb_control_frame_t
+    _padding_tag: *mut c_void,
+    _padding_interrupt_flag: [c_char; 4], // rb_atomic_t
+    _padding_interrupt_mask: [c_char; 4], // rb_atomic_t
+    // rb_fiber_t
+    fiber_ptr: *mut c_void,
+    // ...
+}
+
 /// Reimplementation of the internal RTYPEDDATA_TYPE macro.
 unsafe fn RTYPEDDATA_TYPE(obj: VALUE) -> *const rb_data_type_struct {
     let typed: *mut RTypedData = obj as *mut RTypedData;
@@ -75,3 +98,34 @@ pub unsafe fn rb_thread_getcpuclockid(thread: VALUE) -> clockid_t {
     pthread_getcpuclockid(pthread_id, &mut cid as *mut clockid_t);
     cid
 }
+
+/// The address of the execution context the thread is currently running.
+/// Each Fiber has its own execution context, so this identifies the running Fiber.
+pub unsafe fn rb_thread_current_ec(thread: VALUE) -> usize {
+    unsafe { (*rb_thread_ptr(thread)).ec as usize }
+}
+
+/// The address of the execution context of the thread's root Fiber, which is what
+/// `rb_thread_current_ec()` returns while the root Fiber is running (whichever Fiber is now).
+pub unsafe fn rb_thread_root_ec(thread: VALUE) -> usize {
+    let th = unsafe { rb_thread_ptr(thread) };
+    let ec = unsafe { (*th).ec } as *const rb_execution_context_struct;
+    let root_fiber = unsafe { (*th).root_fiber };
+    if ec.is_null() || root_fiber.is_null() {
+        return ec as usize;
+    }
+    // Each Fiber embeds its execution context at the same offset (rb_fiber_t.cont.saved_ec)
+    let fiber = unsafe { (*ec).fiber_ptr };
+    match (ec as usize).checked_sub(fiber as usize) {
+        Some(offset) if !fiber.is_null() => root_fiber as usize + offset,
+        _ => ec as usize,
+    }
+}
+
+/// Whether the thread has a Ruby stack to walk. A thread which has terminated, or is not fully
+/// set up yet, may have no execution context or VM stack, which `rb_profile_thread_frames()`
+/// would dereference regardless.
+pub unsafe fn rb_thread_has_ruby_stack(thread: VALUE) -> bool {
+    let ec = unsafe { (*rb_thread_ptr(thread)).ec } as *const rb_execution_context_struct;
+    !ec.is_null() && unsafe { !(*ec).vm_stack.is_null() && !(*ec).cfp.is_null() }
+}
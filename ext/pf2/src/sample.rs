@@ -3,26 +3,89 @@ use std::time::Instant;
 use rb_sys::*;
 
 use crate::backtrace::{Backtrace, BacktraceState};
+use crate::ruby_internal_apis::rb_thread_has_ruby_stack;
+use crate::util::read_clock_ns;
 
-const MAX_STACK_DEPTH: usize = 500;
+pub const MAX_STACK_DEPTH: usize = 500;
+/// The Fiber ID reported for the thread's root execution context.
+pub const ROOT_FIBER_ID: u64 = 0;
 const MAX_C_STACK_DEPTH: usize = 1000;
 
+/// The scheduling state of a thread when it was sampled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ThreadState {
+    /// Holding the GVL (or waiting for it)
+    Running,
+    /// Released the GVL: sleeping, blocked (e.g. in IO) or waiting on a mutex
+    Sleeping,
+    /// Running garbage collection
+    Gc,
+}
+
+impl ThreadState {
+    // async-signal-safe
+    /// Derive the state of a thread from whether it holds the GVL.
+    pub fn from_gvl(has_gvl: bool, during_gc: bool) -> Self {
+        match (has_gvl, during_gc) {
+            (true, true) => Self::Gc,
+            (true, false) => Self::Running,
+            (false, _) => Self::Sleeping,
+        }
+    }
+}
+
+/// A sample as captured by a scheduler, before it is serialized.
+///
+/// The layout is fixed-size: frames, line numbers and native pcs are stored inline, up to
+/// `MAX_STACK_DEPTH` and `MAX_C_STACK_DEPTH` entries, with `line_count` and
+/// `c_backtrace_pcs[0]` telling how many are in use. Capturing and buffering a sample thus
+/// never allocates, which is what lets signal handlers push it into the (pre-reserved)
+/// sample buffers. Frames beyond the capacity are counted in `omitted_frames` rather than
+/// spilled elsewhere. The cost of captures is measured in `CaptureStats`.
 #[derive(Debug, PartialEq)]
 pub struct Sample {
     pub ruby_thread: VALUE,
+    /// The Ractor which `ruby_thread` belongs to. Filled in by the scheduler.
+    pub ruby_ractor_id: Option<u64>,
+    /// The Fiber running on `ruby_thread`, or `ROOT_FIBER_ID`. Filled in by the scheduler.
+    pub ruby_fiber_id: u64,
     pub timestamp: Instant,
+    /// The reading of the configured clock (see `Configuration.clock`) at capture time.
+    pub clock_ns: u64,
     pub line_count: i32,
+    /// The number of Ruby frames not captured beyond `max_stack_depth`.
+    pub omitted_frames: u32,
+    /// Whether the VM was running garbage collection at capture time.
+    pub during_gc: bool,
+    /// Whether the thread was idle (sleeping or blocked). Only detected with `include_idle: :tag`.
+    pub idle: bool,
+    /// The scheduling state of the thread. None if it could not be determined safely.
+    /// Filled in by the scheduler.
+    pub state: Option<ThreadState>,
+    /// Whether the thread has been running on a CPU, as opposed to being blocked or descheduled.
+    /// None if it could not be determined. Filled in by the scheduler (see `CpuActivity`).
+    pub on_cpu: Option<bool>,
     pub frames: [VALUE; MAX_STACK_DEPTH],
     pub linenos: [i32; MAX_STACK_DEPTH],
     /// First element represents the backtrace depth.
     pub c_backtrace_pcs: [usize; MAX_C_STACK_DEPTH + 1],
+    /// The exact instruction pointer of the code interrupted by the signal, read from its
+    /// `ucontext_t`. Only set along with the native stack, by the thread receiving the signal.
+    pub interrupted_pc: Option<usize>,
 }
 
 impl Sample {
     // Nearly async-signal-safe
     // (rb_profile_thread_frames isn't defined as a-s-s)
-    pub fn capture(ruby_thread: VALUE, backtrace_state: &BacktraceState) -> Self {
-        let mut c_backtrace_pcs = [0; MAX_C_STACK_DEPTH + 1];
+    /// Capture the Ruby stack of `ruby_thread` and the native stack of the calling thread.
+    pub fn capture(
+        ruby_thread: VALUE,
+        backtrace_state: &BacktraceState,
+        max_stack_depth: usize,
+        clockid: libc::clockid_t,
+    ) -> Self {
+        let mut sample = Self::capture_without_native_stack(ruby_thread, max_stack_depth, clockid);
+        let c_backtrace_pcs = &mut sample.c_backtrace_pcs;
 
         Backtrace::backtrace_simple(
             backtrace_state,
@@ -38,30 +101,177 @@ impl Sample {
             Some(Backtrace::backtrace_error_callback),
         );
 
+        sample
+    }
+
+    /// Capture the Ruby stack of `ruby_thread` only.
+    /// Used when `ruby_thread` may be running on a thread other than the calling one,
+    /// whose native stack cannot be walked.
+    pub fn capture_without_native_stack(
+        ruby_thread: VALUE,
+        max_stack_depth: usize,
+        clockid: libc::clockid_t,
+    ) -> Self {
         let mut sample = Sample {
             ruby_thread,
+            ruby_ractor_id: None,
+            ruby_fiber_id: ROOT_FIBER_ID,
             timestamp: Instant::now(),
+            clock_ns: read_clock_ns(clockid).unwrap_or(0),
             line_count: 0,
+            omitted_frames: 0,
+            during_gc: unsafe { rb_during_gc() } != 0,
+            idle: false,
+            state: None,
+            on_cpu: None,
             frames: [0; MAX_STACK_DEPTH],
             linenos: [0; MAX_STACK_DEPTH],
-            c_backtrace_pcs,
+            c_backtrace_pcs: [0; MAX_C_STACK_DEPTH + 1],
+            interrupted_pc: None,
         };
+        // A dead thread (or one mid-way through being set up) gets an empty sample, which is
+        // serialized with a `(no Ruby frames)` root
+        if !unsafe { rb_thread_has_ruby_stack(ruby_thread) } {
+            return sample;
+        }
+        let limit = max_stack_depth.min(MAX_STACK_DEPTH);
         unsafe {
             sample.line_count = rb_profile_thread_frames(
                 ruby_thread,
                 0,
-                2000,
+                limit as i32,
                 sample.frames.as_mut_ptr(),
                 sample.linenos.as_mut_ptr(),
             );
         };
+        // The stack may have been truncated. Count what was left out, so that the loss is known.
+        if sample.ruby_frame_count() == limit {
+            sample.omitted_frames = Self::count_frames_from(ruby_thread, limit);
+        }
         sample
     }
 
+    // Nearly async-signal-safe, as is capture()
+    /// Count the Ruby frames of `ruby_thread` from the `start`-th one (0 being the leaf).
+    /// `rb_profile_thread_frames()` can only tell the depth by copying frames, so they are
+    /// copied a chunk at a time into a scratch buffer on the stack, and thrown away.
+    fn count_frames_from(ruby_thread: VALUE, start: usize) -> u32 {
+        let mut scratch: [VALUE; MAX_STACK_DEPTH] = [0; MAX_STACK_DEPTH];
+        let mut count = 0;
+        loop {
+            let copied = unsafe {
+                rb_profile_thread_frames(
+                    ruby_thread,
+                    (start + count) as i32,
+                    MAX_STACK_DEPTH as i32,
+                    scratch.as_mut_ptr(),
+                    std::ptr::null_mut(),
+                )
+            };
+            let copied = copied.clamp(0, MAX_STACK_DEPTH as i32) as usize;
+            count += copied;
+            if copied < MAX_STACK_DEPTH {
+                break;
+            }
+        }
+        count as u32
+    }
+
+    /// The number of captured Ruby frames, within the bounds of `frames` whatever
+    /// `rb_profile_thread_frames()` returned.
+    pub fn ruby_frame_count(&self) -> usize {
+        self.line_count.clamp(0, MAX_STACK_DEPTH as i32) as usize
+    }
+
+    /// The pcs of the native stack, leaf first, down to the outermost frame (e.g. `_start`).
+    ///
+    /// With `interrupted_pc`, the frames of the signal handler itself are left out by starting
+    /// at the interrupted frame. If unwinding did not get through the signal frame, the
+    /// interrupted pc is put on top instead, so that the leaf is attributed correctly anyway.
+    pub fn native_pcs(&self) -> Vec<usize> {
+        let depth = self.c_backtrace_pcs[0].min(MAX_C_STACK_DEPTH);
+        let walked = &self.c_backtrace_pcs[1..=depth];
+        let Some(interrupted_pc) = self.interrupted_pc else {
+            return walked.to_vec();
+        };
+        match walked.iter().position(|&pc| pc == interrupted_pc) {
+            Some(position) => walked[position..].to_vec(),
+            None => std::iter::once(interrupted_pc)
+                .chain(walked.iter().copied())
+                .collect(),
+        }
+    }
+
+    /// Record the Fiber running on the sampled thread.
+    /// `root_ec` is the execution context of the thread's root Fiber (see `rb_thread_root_ec`).
+    pub fn set_fiber(&mut self, current_ec: usize, root_ec: usize) {
+        self.ruby_fiber_id = if current_ec == root_ec {
+            ROOT_FIBER_ID
+        } else {
+            current_ec as u64
+        };
+    }
+
     pub unsafe fn dmark(&self) {
         rb_gc_mark(self.ruby_thread);
         for frame in self.frames.iter() {
             rb_gc_mark(*frame);
         }
     }
+
+    /// Update frame references after GC compaction.
+    /// Threads are pinned (see `Profile::dmark`) and never move.
+    pub unsafe fn dcompact(&mut self) {
+        for frame in self.frames[..self.ruby_frame_count()].iter_mut() {
+            *frame = rb_gc_location(*frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_with_native_pcs(pcs: &[usize]) -> Sample {
+        let mut sample = Sample {
+            ruby_thread: 1,
+            ruby_ractor_id: None,
+            ruby_fiber_id: ROOT_FIBER_ID,
+            timestamp: Instant::now(),
+            clock_ns: 0,
+            line_count: 0,
+            omitted_frames: 0,
+            during_gc: false,
+            idle: false,
+            state: None,
+            on_cpu: None,
+            frames: [0; MAX_STACK_DEPTH],
+            linenos: [0; MAX_STACK_DEPTH],
+            c_backtrace_pcs: [0; MAX_C_STACK_DEPTH + 1],
+            interrupted_pc: None,
+        };
+        sample.c_backtrace_pcs[0] = pcs.len();
+        sample.c_backtrace_pcs[1..=pcs.len()].copy_from_slice(pcs);
+        sample
+    }
+
+    #[test]
+    fn test_native_pcs_keep_every_walked_frame() {
+        assert_eq!(
+            sample_with_native_pcs(&[]).native_pcs(),
+            Vec::<usize>::new()
+        );
+        assert_eq!(sample_with_native_pcs(&[0x10]).native_pcs(), vec![0x10]);
+        // The outermost frame is the last one, as in the legacy output format
+        assert_eq!(
+            sample_with_native_pcs(&[0x10, 0x20, 0x30]).native_pcs(),
+            vec![0x10, 0x20, 0x30]
+        );
+
+        let mut sample = sample_with_native_pcs(&[0x10, 0x20, 0x30]);
+        sample.interrupted_pc = Some(0x20);
+        assert_eq!(sample.native_pcs(), vec![0x20, 0x30]);
+        sample.interrupted_pc = Some(0x40);
+        assert_eq!(sample.native_pcs(), vec![0x40, 0x10, 0x20, 0x30]);
+    }
 }
@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::str::FromStr;
+
+use rb_sys::*;
+
+use crate::sample::{Sample, ThreadState};
+use crate::util::{cstr, RTEST};
+
+/// Decides whether a sample is kept in the profile, as it is flushed.
+///
+/// Filters run on the flusher thread, without the GVL. To add one, implement this trait and
+/// give it a name in `SampleFilterKind`.
+pub trait SampleFilter: Debug + Send + Sync {
+    fn keep(&mut self, sample: &Sample) -> bool;
+}
+
+/// The built-in sample filters, selected by name (`sample_filter:` option).
+#[derive(Clone, Debug, PartialEq)]
+pub enum SampleFilterKind {
+    /// Keep samples whose innermost Ruby frame with a path is application code
+    AppCode,
+    /// Drop samples of idle (sleeping or blocked) threads
+    DropIdle,
+}
+
+impl FromStr for SampleFilterKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "app_code" => Ok(Self::AppCode),
+            "drop_idle" => Ok(Self::DropIdle),
+            _ => Err(()),
+        }
+    }
+}
+
+impl SampleFilterKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::AppCode => "app_code",
+            Self::DropIdle => "drop_idle",
+        }
+    }
+
+    /// Create the filter. Must be called with the GVL, as filters may look up Ruby settings.
+    pub fn build(&self) -> Box<dyn SampleFilter> {
+        match self {
+            Self::AppCode => Box::new(AppCodeFilter::new()),
+            Self::DropIdle => Box::new(DropIdleFilter),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DropIdleFilter;
+
+impl SampleFilter for DropIdleFilter {
+    fn keep(&mut self, sample: &Sample) -> bool {
+        !sample.idle && sample.state != Some(ThreadState::Sleeping)
+    }
+}
+
+/// Application code is code under the current directory (at the time the session is created),
+/// except for installed gems and the standard library (e.g. a `vendor/bundle` inside the app).
+#[derive(Debug)]
+pub struct AppCodeFilter {
+    app_root: String,
+    library_paths: Vec<String>,
+    /// Whether each frame seen so far is application code. None if the frame has no path
+    /// (e.g. methods implemented in C), in which case its caller decides.
+    /// Frames of dropped samples are not retained, so one may rarely be confused with another
+    /// frame later allocated at the same address.
+    frames: HashMap<VALUE, Option<bool>>,
+}
+
+impl AppCodeFilter {
+    fn new() -> Self {
+        let (app_root, library_paths) = unsafe {
+            let pwd = rb_funcall(rb_cDir, rb_intern(cstr!("pwd")), 0);
+            let mut library_paths = vec![];
+            if RTEST(rb_const_defined(rb_cObject, rb_intern(cstr!("Gem")))) {
+                let gem_paths = rb_funcall(
+                    rb_const_get(rb_cObject, rb_intern(cstr!("Gem"))),
+                    rb_intern(cstr!("path")),
+                    0,
+                );
+                for i in 0..RARRAY_LEN(gem_paths) {
+                    library_paths.push(Self::to_string(rb_ary_entry(gem_paths, i)));
+                }
+            }
+            let rbconfig = rb_const_get(
+                rb_const_get(rb_cObject, rb_intern(cstr!("RbConfig"))),
+                rb_intern(cstr!("CONFIG")),
+            );
+            let rubylibprefix = rb_hash_aref(rbconfig, rb_str_new_cstr(cstr!("rubylibprefix")));
+            if RTEST(rubylibprefix) {
+                library_paths.push(Self::to_string(rubylibprefix));
+            }
+            (Self::to_string(pwd), library_paths)
+        };
+        Self {
+            app_root,
+            library_paths,
+            frames: HashMap::new(),
+        }
+    }
+
+    unsafe fn to_string(value: VALUE) -> String {
+        let bytes = unsafe {
+            std::slice::from_raw_parts(RSTRING_PTR(value) as *const u8, RSTRING_LEN(value) as usize)
+        };
+        String::from_utf8_lossy(bytes).into_owned()
+    }
+
+    fn is_app_path(&self, path: &str) -> bool {
+        path.starts_with(&self.app_root)
+            && !self
+                .library_paths
+                .iter()
+                .any(|library_path| path.starts_with(library_path.as_str()))
+    }
+
+    /// Like `Sample::capture()`, this reads the frame's path without the GVL. Frames of
+    /// samples waiting to be flushed are pinned, and each frame is looked up only once.
+    fn classify(&self, frame: VALUE) -> Option<bool> {
+        let path = unsafe { rb_profile_frame_path(frame) };
+        if !RTEST(path) {
+            return None;
+        }
+        Some(self.is_app_path(&unsafe { Self::to_string(path) }))
+    }
+}
+
+impl SampleFilter for AppCodeFilter {
+    fn keep(&mut self, sample: &Sample) -> bool {
+        for &frame in sample.frames[..sample.ruby_frame_count()].iter() {
+            let app_code = match self.frames.get(&frame) {
+                Some(app_code) => *app_code,
+                None => {
+                    let app_code = self.classify(frame);
+                    self.frames.insert(frame, app_code);
+                    app_code
+                }
+            };
+            if let Some(app_code) = app_code {
+                return app_code;
+            }
+        }
+        false
+    }
+}
@@ -1,10 +1,14 @@
 use rb_sys::{size_t, VALUE};
 
+use crate::error::Pf2Error;
+
 pub trait Scheduler {
-    fn start(&self) -> VALUE;
-    fn stop(&self) -> VALUE;
+    /// On failure, the scheduler is left stopped.
+    fn start(&self) -> Result<(), Pf2Error>;
+    fn stop(&self);
     fn on_new_thread(&self, thread: VALUE);
     fn dmark(&self);
+    fn dcompact(&self);
     fn dfree(&self);
     fn dsize(&self) -> size_t;
 }
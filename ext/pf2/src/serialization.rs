@@ -1,2 +1,58 @@
+#![deny(unsafe_op_in_unsafe_fn)]
+
+use std::slice;
+
+use rb_sys::*;
+
+use crate::error::Pf2Error;
+use profile::{Profile, SCHEMA_VERSION};
+
+pub mod categories;
+pub mod coalesce;
+pub mod diff;
+pub mod downsample;
+pub mod format;
+pub mod granularity;
+pub mod histogram;
+pub mod jit_code;
+pub mod line_hits;
+pub mod location_stats;
+pub mod merge;
+pub mod otlp;
 pub mod profile;
+pub mod prune;
+pub mod recursion;
+pub mod redact;
+pub mod resolved_frame;
 pub mod serializer;
+pub mod stack_table;
+pub mod state_breakdown;
+pub mod summary;
+pub mod thread_filter;
+pub mod thread_summary;
+pub mod validation;
+pub mod weighting;
+
+/// Parse a profile serialized as JSON by the experimental serializer from a Ruby String.
+/// Deduplicated stacks are expanded back into each sample.
+/// Profiles of an older schema_version are accepted, so that profiles saved by an earlier version
+/// can still be read. Those of a newer one are rejected.
+/// Profiles with an index out of range of its table are rejected, rather than panicking later.
+unsafe fn parse_profile(value: VALUE) -> Result<Profile, Pf2Error> {
+    let bytes = unsafe {
+        let mut value = value;
+        let ptr = rb_string_value_ptr(&mut value);
+        slice::from_raw_parts(ptr as *const u8, RSTRING_LEN(value) as usize)
+    };
+    let mut profile: Profile =
+        serde_json::from_slice(bytes).map_err(|e| Pf2Error::InvalidProfile(e.to_string()))?;
+    if profile.schema_version > SCHEMA_VERSION {
+        return Err(Pf2Error::InvalidProfile(format!(
+            "unsupported schema_version {} (expected {} or older)",
+            profile.schema_version, SCHEMA_VERSION
+        )));
+    }
+    profile.check_indices().map_err(Pf2Error::InvalidProfile)?;
+    profile.expand_stacks();
+    Ok(profile)
+}
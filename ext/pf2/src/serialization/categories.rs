@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+
+use super::profile::{Profile, StringIndex};
+
+impl Profile {
+    /// Set `Function.category` from `categories`, a list of (prefix, label) pairs matched against
+    /// the function's class path (e.g. `ActiveRecord::`) and file path (e.g. `/srv/app/app/views/`).
+    ///
+    /// The longest matching prefix wins. Functions matching no prefix are left uncategorized.
+    pub fn categorize(&mut self, categories: &[(String, String)]) {
+        let labels: Vec<Option<&str>> = self
+            .functions
+            .iter()
+            .map(|function| {
+                let paths: Vec<&str> = [function.class_path, function.filename]
+                    .into_iter()
+                    .flatten()
+                    .map(|index| self.strings[index].as_str())
+                    .collect();
+                categories
+                    .iter()
+                    .filter(|(prefix, _)| {
+                        paths.iter().any(|path| path.starts_with(prefix.as_str()))
+                    })
+                    .max_by_key(|(prefix, _)| prefix.len())
+                    .map(|(_, label)| label.as_str())
+            })
+            .collect();
+
+        let mut label_indices: HashMap<&str, StringIndex> = HashMap::new();
+        for (function, label) in self.functions.iter_mut().zip(labels) {
+            function.category = label.map(|label| {
+                *label_indices.entry(label).or_insert_with(|| {
+                    match self.strings.iter().position(|string| string == label) {
+                        Some(index) => index,
+                        None => {
+                            self.strings.push(label.to_owned());
+                            self.strings.len() - 1
+                        }
+                    }
+                })
+            });
+        }
+    }
+}
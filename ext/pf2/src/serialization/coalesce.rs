@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+
+use super::profile::{Profile, Sample};
+
+impl Profile {
+    /// Merge runs of consecutive samples of a thread with identical stacks into one sample.
+    ///
+    /// A merged sample keeps the `elapsed_ns` of the first sample of the run, and records the
+    /// last one in `end_elapsed_ns`. Its `weight_ns` is the sum of the weights, and `count` the
+    /// number of samples merged. Samples of other threads in between do not break a run, but
+    /// samples of different threads are never merged.
+    pub fn coalesce(&mut self) {
+        let mut samples: Vec<Sample> = Vec::with_capacity(self.samples.len());
+        // The index in `samples` of the last sample of each thread
+        let mut last_indices: HashMap<Option<u64>, usize> = HashMap::new();
+        for sample in std::mem::take(&mut self.samples) {
+            if let Some(&index) = last_indices.get(&sample.ruby_thread_id) {
+                let last = &mut samples[index];
+                if last.can_coalesce(&sample) {
+                    last.weight_ns = last.weight_ns.zip(sample.weight_ns).map(|(a, b)| a + b);
+                    last.count = Some(last.sample_count() + sample.sample_count());
+                    last.end_elapsed_ns = Some(sample.end_elapsed_ns.unwrap_or(sample.elapsed_ns));
+                    continue;
+                }
+            }
+            last_indices.insert(sample.ruby_thread_id, samples.len());
+            samples.push(sample);
+        }
+        self.samples = samples;
+        self.recount_thread_samples();
+    }
+}
+
+impl Sample {
+    /// Whether `next`, the next sample of the same thread, only repeats this one.
+    fn can_coalesce(&self, next: &Sample) -> bool {
+        self.delta.is_none()
+            && next.delta.is_none()
+            && self.stack == next.stack
+            && self.stack_index == next.stack_index
+            && self.native_stack == next.native_stack
+            && self.omitted_frames == next.omitted_frames
+            && self.collapsed_frames == next.collapsed_frames
+            && self.ruby_ractor_id == next.ruby_ractor_id
+            && self.fiber_id == next.fiber_id
+            && self.during_gc == next.during_gc
+            && self.state == next.state
+            && self.on_cpu == next.on_cpu
+    }
+}
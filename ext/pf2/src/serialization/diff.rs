@@ -0,0 +1,160 @@
+#![deny(unsafe_op_in_unsafe_fn)]
+
+use std::collections::{BTreeMap, HashMap};
+
+use rb_sys::*;
+
+use super::parse_profile;
+use super::profile::{
+    Function, FunctionIndex, Location, Profile, Sample, SampleType, StringIndex, SCHEMA_VERSION,
+};
+use crate::error::Pf2Error;
+use crate::util::rb_str_from_bytes;
+
+/// Functions are aligned across profiles by their name and filename.
+type FunctionKey = (Option<String>, Option<String>);
+
+/// Builds a profile holding the difference between two profiles.
+///
+/// Each distinct Ruby stack (as a sequence of functions) becomes a single sample whose
+/// `delta` is the number of samples in `after` minus those in `before`. Stacks present
+/// in only one side are kept with a positive or negative delta; stacks whose counts
+/// cancel out are omitted.
+pub struct ProfileDiff {
+    strings: Vec<String>,
+    string_indices: HashMap<String, StringIndex>,
+    functions: Vec<Function>,
+    function_indices: HashMap<FunctionKey, FunctionIndex>,
+}
+
+impl ProfileDiff {
+    pub fn diff(before: &Profile, after: &Profile) -> Profile {
+        let mut diff = Self {
+            strings: vec![],
+            string_indices: HashMap::new(),
+            functions: vec![],
+            function_indices: HashMap::new(),
+        };
+
+        // Stacks are keyed by the (leaf-first) function indices in the diff profile
+        let mut deltas: BTreeMap<Vec<FunctionIndex>, i64> = BTreeMap::new();
+        for (profile, sign) in [(before, -1), (after, 1)] {
+            for sample in profile.samples.iter() {
+                let stack = sample
+                    .stack
+                    .iter()
+                    .map(|&location_index| {
+                        let function_index = profile.locations[location_index].function_index;
+                        diff.function_index_for(profile, function_index)
+                    })
+                    .collect();
+                *deltas.entry(stack).or_insert(0) += sign;
+            }
+        }
+
+        // Each function gets exactly one location, sharing its index
+        let locations = diff
+            .functions
+            .iter()
+            .enumerate()
+            .map(|(function_index, function)| Location {
+                function_index,
+                lineno: function.start_lineno.unwrap_or(0),
+                address: None,
+                stats: None,
+            })
+            .collect();
+
+        let samples = deltas
+            .into_iter()
+            .filter(|(_, delta)| *delta != 0)
+            .map(|(stack, delta)| Sample {
+                stack,
+                stack_index: None,
+                native_stack: vec![],
+                ruby_thread_id: None,
+                ruby_ractor_id: None,
+                fiber_id: 0,
+                elapsed_ns: 0,
+                clock_ns: 0,
+                during_gc: false,
+                state: None,
+                on_cpu: None,
+                weight_ns: None,
+                omitted_frames: None,
+                collapsed_frames: None,
+                delta: Some(delta),
+                count: None,
+                end_elapsed_ns: None,
+            })
+            .collect();
+
+        Profile {
+            schema_version: SCHEMA_VERSION,
+            samples,
+            stacks: None,
+            locations,
+            functions: diff.functions,
+            strings: diff.strings,
+            start_timestamp_ns: after.start_timestamp_ns,
+            duration_ns: after.duration_ns,
+            metadata: after.metadata.clone(),
+            // Samples carry the difference in the number of samples instead
+            sample_types: vec![SampleType::new("samples", "count")],
+            markers: vec![],
+            line_hits: vec![],
+        }
+    }
+
+    fn function_index_for(&mut self, profile: &Profile, function_index: FunctionIndex) -> usize {
+        let function = &profile.functions[function_index];
+        let string = |index: Option<StringIndex>| index.map(|index| profile.strings[index].clone());
+        let key = (string(function.name), string(function.filename));
+        if let Some(&index) = self.function_indices.get(&key) {
+            return index;
+        }
+
+        let function = Function {
+            implementation: function.implementation.clone(),
+            name: self.string_index_for(string(function.name)),
+            filename: self.string_index_for(string(function.filename)),
+            class_path: self.string_index_for(string(function.class_path)),
+            method_name: self.string_index_for(string(function.method_name)),
+            start_lineno: function.start_lineno,
+            start_address: function.start_address,
+            jit: function.jit,
+            eval: function.eval,
+            category: self.string_index_for(string(function.category)),
+            mapping: self.string_index_for(string(function.mapping)),
+        };
+        let index = self.functions.len();
+        self.functions.push(function);
+        self.function_indices.insert(key, index);
+        index
+    }
+
+    fn string_index_for(&mut self, string: Option<String>) -> Option<StringIndex> {
+        let string = string?;
+        if let Some(&index) = self.string_indices.get(&string) {
+            return Some(index);
+        }
+        let index = self.strings.len();
+        self.strings.push(string.clone());
+        self.string_indices.insert(string, index);
+        Some(index)
+    }
+}
+
+/// `Pf2.diff(before, after)`: Takes two profiles serialized as JSON by the experimental
+/// serializer, and returns their difference serialized in the same format.
+pub(crate) unsafe extern "C" fn rb_diff(_rbself: VALUE, before: VALUE, after: VALUE) -> VALUE {
+    let result = unsafe { parse_profile(before) }.and_then(|before| {
+        let after = unsafe { parse_profile(after) }?;
+        let diff = ProfileDiff::diff(&before, &after);
+        serde_json::to_vec(&diff).map_err(|e| Pf2Error::Serialization(e.to_string()))
+    });
+    match result {
+        Ok(json) => rb_str_from_bytes(&json),
+        Err(e) => e.raise(),
+    }
+}
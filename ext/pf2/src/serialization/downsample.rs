@@ -0,0 +1,102 @@
+#![deny(unsafe_op_in_unsafe_fn)]
+
+use std::collections::HashMap;
+
+use rb_sys::*;
+
+use super::merge::ProfileMerger;
+use super::parse_profile;
+use super::profile::{LocationIndex, Profile, Sample};
+use crate::error::Pf2Error;
+use crate::util::{integer_option, rb_str_from_bytes};
+
+impl Profile {
+    /// Reduce the profile to at most `target` samples.
+    ///
+    /// Samples are split into `target` consecutive windows of (nearly) equal size, and the
+    /// first sample of each window is kept. Since samples are ordered by time, this keeps
+    /// the relative proportions of stacks, threads and phases of the profile.
+    ///
+    /// When `scale_weights` is set, each retained sample's `weight_ns` becomes the sum of
+    /// the weights in its window, so that the total weight of the profile is unchanged.
+    ///
+    /// Functions, locations and strings not referenced by the retained samples are pruned.
+    pub fn downsample(&self, target: usize, scale_weights: bool) -> Profile {
+        let sample_count = self.samples.len();
+        let target = target.min(sample_count);
+        let interval_ns = self.metadata.interval_ns as u64;
+
+        let mut merger = ProfileMerger::default();
+        let mut location_indices: HashMap<LocationIndex, LocationIndex> = HashMap::new();
+        let mut remap = |stack: &[LocationIndex]| -> Vec<LocationIndex> {
+            stack
+                .iter()
+                .map(|&index| {
+                    *location_indices
+                        .entry(index)
+                        .or_insert_with(|| merger.location_index_for(self, &self.locations[index]))
+                })
+                .collect()
+        };
+
+        let mut samples = Vec::with_capacity(target);
+        for window in 0..target {
+            let start = window * sample_count / target;
+            let end = (window + 1) * sample_count / target;
+            let sample = &self.samples[start];
+            let weight_ns = match scale_weights {
+                true => Some(
+                    self.samples[start..end]
+                        .iter()
+                        .map(|sample| sample.weight_ns.unwrap_or(interval_ns))
+                        .sum(),
+                ),
+                false => sample.weight_ns,
+            };
+            samples.push(Sample {
+                stack: remap(&sample.stack),
+                native_stack: remap(&sample.native_stack),
+                weight_ns,
+                ..sample.clone()
+            });
+        }
+
+        let mut profile = Profile {
+            samples,
+            stacks: None,
+            locations: merger.locations,
+            functions: merger.functions,
+            strings: merger.strings,
+            metadata: self.metadata.clone(),
+            sample_types: self.sample_types.clone(),
+            markers: self.markers.clone(),
+            // Derived from the samples, which have changed
+            line_hits: vec![],
+            ..*self
+        };
+        profile.recount_thread_samples();
+        profile
+    }
+}
+
+/// `Pf2.downsample(profile, target)`: Takes a profile serialized as JSON by the experimental
+/// serializer, and returns it reduced to at most `target` samples in the same format.
+/// Weights of the retained samples are scaled up to keep the total weight.
+pub(crate) unsafe extern "C" fn rb_downsample(
+    _rbself: VALUE,
+    profile: VALUE,
+    target: VALUE,
+) -> VALUE {
+    let target = integer_option(target, "target");
+    if target < 0 {
+        Pf2Error::InvalidOption("target must not be negative".to_owned()).raise();
+    }
+    let result = unsafe { parse_profile(profile) }.and_then(|profile| {
+        let downsampled = profile.downsample(target as usize, true);
+        serde_json::to_vec(&downsampled).map_err(|e| Pf2Error::Serialization(e.to_string()))
+    });
+    match result {
+        Ok(json) => rb_str_from_bytes(&json),
+        Err(e) => e.raise(),
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use rb_sys::*;
+
+use super::profile::{LocationIndex, Profile, Sample, ThreadState};
+use super::summary::FunctionSummary;
+use crate::error::Pf2Error;
+use crate::util::rb_str_from_bytes;
+
+/// The formats a serialized profile can be output in (`Pf2.stop(format:)`).
+///
+/// To add a format, name it in `FromStr` and `as_str`, and give it an arm in `serialize`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// The experimental serializer's JSON document
+    Json { pretty: bool },
+    /// "Folded stacks", as read by flamegraph.pl and speedscope
+    Folded,
+    /// The OpenTelemetry profiles protobuf (see `Profile::to_otlp`)
+    Otlp,
+    /// A text table of the hottest functions (see `FunctionSummary::to_top`)
+    Top,
+    /// One self-contained JSON object per sample and line, for log pipelines (see
+    /// `Profile::to_ndjson`)
+    Ndjson,
+}
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json { pretty: false }),
+            "folded" => Ok(Self::Folded),
+            "otlp" => Ok(Self::Otlp),
+            "top" => Ok(Self::Top),
+            "ndjson" => Ok(Self::Ndjson),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Format {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Json { .. } => "json",
+            Self::Folded => "folded",
+            Self::Otlp => "otlp",
+            Self::Top => "top",
+            Self::Ndjson => "ndjson",
+        }
+    }
+}
+
+/// A profile serialized by `serialize`: text, or bytes for binary formats.
+#[derive(Debug, PartialEq)]
+pub enum SerializedOutput {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl SerializedOutput {
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Self::Text(text) => text.as_bytes(),
+            Self::Binary(bytes) => bytes,
+        }
+    }
+
+    /// A UTF-8 String for text, or a binary (ASCII-8BIT) String for bytes.
+    pub fn to_ruby(&self) -> VALUE {
+        match self {
+            Self::Text(text) => unsafe {
+                rb_utf8_str_new(text.as_ptr() as *const std::ffi::c_char, text.len() as _)
+            },
+            Self::Binary(bytes) => rb_str_from_bytes(bytes),
+        }
+    }
+}
+
+/// Serialize `profile` in `format`.
+pub fn serialize(profile: &Profile, format: Format) -> Result<SerializedOutput, Pf2Error> {
+    match format {
+        Format::Json { pretty } => {
+            let result = match pretty {
+                true => serde_json::to_string_pretty(profile),
+                false => serde_json::to_string(profile),
+            };
+            result
+                .map(SerializedOutput::Text)
+                .map_err(|e| Pf2Error::Serialization(e.to_string()))
+        }
+        Format::Folded => Ok(SerializedOutput::Text(profile.to_folded())),
+        Format::Otlp => Ok(SerializedOutput::Binary(profile.to_otlp())),
+        Format::Top => {
+            let rows = FunctionSummary::build(profile);
+            let sample_count: u64 = profile
+                .samples
+                .iter()
+                .map(|sample| sample.sample_count())
+                .sum();
+            Ok(SerializedOutput::Text(FunctionSummary::to_top(
+                &rows,
+                sample_count as usize,
+            )))
+        }
+        Format::Ndjson => profile
+            .to_ndjson()
+            .map(SerializedOutput::Text)
+            .map_err(|e| Pf2Error::Serialization(e.to_string())),
+    }
+}
+
+/// A line of `Profile::to_ndjson`.
+#[derive(Serialize)]
+struct NdjsonSample<'a> {
+    elapsed_ns: u64,
+    ruby_thread_id: Option<u64>,
+    fiber_id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<ThreadState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    weight_ns: Option<u64>,
+    /// The number of captured samples the line stands for
+    count: u64,
+    /// Function names, leaf first
+    stack: Vec<&'a str>,
+}
+
+impl Profile {
+    /// One line per distinct Ruby stack: function names from the root, separated by `;`,
+    /// followed by the number of samples. Lines are sorted.
+    pub fn to_folded(&self) -> String {
+        let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+        for sample in self.samples.iter() {
+            let line = self
+                .stack_of(sample)
+                .iter()
+                .rev()
+                .map(|&location_index| {
+                    self.function_name_at(location_index)
+                        // `;` and newlines delimit frames and lines
+                        .replace([';', '\n'], "_")
+                })
+                .collect::<Vec<_>>()
+                .join(";");
+            *counts.entry(line).or_insert(0) += sample.sample_count();
+        }
+        counts
+            .iter()
+            .map(|(line, count)| format!("{} {}\n", line, count))
+            .collect()
+    }
+
+    /// One JSON object per sample and line (NDJSON), with its Ruby stack inlined as function
+    /// names. Unlike the JSON document, nothing refers to shared tables, so that each line
+    /// stands on its own (e.g. in a log pipeline) at the cost of size. Locations, native stacks
+    /// and metadata are left out.
+    pub fn to_ndjson(&self) -> serde_json::Result<String> {
+        let mut ndjson = String::new();
+        for sample in self.samples.iter() {
+            let line = NdjsonSample {
+                elapsed_ns: sample.elapsed_ns,
+                ruby_thread_id: sample.ruby_thread_id,
+                fiber_id: sample.fiber_id,
+                state: sample.state,
+                weight_ns: sample.weight_ns,
+                count: sample.sample_count(),
+                stack: self
+                    .stack_of(sample)
+                    .iter()
+                    .map(|&location_index| self.function_name_at(location_index))
+                    .collect(),
+            };
+            ndjson.push_str(&serde_json::to_string(&line)?);
+            ndjson.push('\n');
+        }
+        Ok(ndjson)
+    }
+
+    /// The Ruby stack of `sample`, whether stacks are deduplicated or not.
+    fn stack_of<'a>(&'a self, sample: &'a Sample) -> &'a [LocationIndex] {
+        match (&self.stacks, sample.stack_index) {
+            (Some(stacks), Some(stack_index)) => &stacks[stack_index],
+            _ => &sample.stack,
+        }
+    }
+
+    fn function_name_at(&self, location_index: LocationIndex) -> &str {
+        let function = &self.functions[self.locations[location_index].function_index];
+        function
+            .name
+            .map(|name| self.strings[name].as_str())
+            .unwrap_or("(unknown)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::profile::{
+        Function, FunctionImplementation, Location, Metadata, Sample, StringIndex,
+    };
+
+    fn function(name: Option<StringIndex>) -> Function {
+        Function {
+            implementation: FunctionImplementation::Ruby,
+            name,
+            filename: None,
+            class_path: None,
+            method_name: None,
+            start_lineno: None,
+            start_address: None,
+            jit: false,
+            eval: false,
+            category: None,
+            mapping: None,
+        }
+    }
+
+    #[test]
+    fn test_folded() {
+        let location = |function_index| Location {
+            function_index,
+            lineno: 1,
+            address: None,
+            stats: None,
+        };
+        let mut profile = Profile {
+            schema_version: 0,
+            stacks: None,
+            locations: vec![location(0), location(1), location(2)],
+            functions: vec![function(Some(0)), function(Some(1)), function(None)],
+            strings: vec!["main".to_owned(), "a;b".to_owned()],
+            start_timestamp_ns: 0,
+            duration_ns: 0,
+            metadata: Metadata::default(),
+            sample_types: vec![],
+            samples: vec![
+                Sample::with_stack(vec![1, 0]),
+                Sample {
+                    count: Some(2),
+                    ..Sample::with_stack(vec![1, 0])
+                },
+                Sample::with_stack(vec![2, 0]),
+            ],
+            markers: vec![],
+            line_hits: vec![],
+        };
+        let expected = "main;(unknown) 1\nmain;a_b 3\n";
+        assert_eq!(
+            serialize(&profile, Format::Folded).unwrap(),
+            SerializedOutput::Text(expected.to_owned())
+        );
+
+        // The same with deduplicated stacks
+        profile.dedup_stacks();
+        assert_eq!(profile.to_folded(), expected);
+
+        let ndjson = match serialize(&profile, Format::Ndjson).unwrap() {
+            SerializedOutput::Text(text) => text,
+            SerializedOutput::Binary(_) => panic!("NDJSON is text"),
+        };
+        let lines: Vec<serde_json::Value> = ndjson
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["stack"], serde_json::json!(["a;b", "main"]));
+        assert_eq!(lines[1]["count"], 2);
+        assert_eq!(lines[2]["stack"], serde_json::json!(["(unknown)", "main"]));
+        assert_eq!(lines[2]["ruby_thread_id"], 1);
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use super::profile::{FunctionIndex, Location, LocationIndex, Profile};
+
+/// The level of detail of stack frames (`Pf2.stop(granularity:)`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Granularity {
+    /// A location per line hit within each function (default)
+    Line,
+    /// A single location per function
+    Function,
+}
+
+impl FromStr for Granularity {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "line" => Ok(Self::Line),
+            "function" => Ok(Self::Function),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Profile {
+    /// Collapse all locations of each function into one, so that samples are aggregated per
+    /// function rather than per line.
+    ///
+    /// The remaining location of a function points at its first line (`start_lineno`, or 0 if
+    /// unknown) and carries no address. Stacks (including the shared `stacks` table) are
+    /// rewritten to refer to it.
+    pub fn aggregate_by_function(&mut self) {
+        let mut locations: Vec<Location> = vec![];
+        let mut function_locations: HashMap<FunctionIndex, LocationIndex> = HashMap::new();
+        let location_indices: Vec<LocationIndex> = self
+            .locations
+            .iter()
+            .map(|location| {
+                let function_index = location.function_index;
+                *function_locations.entry(function_index).or_insert_with(|| {
+                    locations.push(Location {
+                        function_index,
+                        lineno: self.functions[function_index].start_lineno.unwrap_or(0),
+                        address: None,
+                        stats: None,
+                    });
+                    locations.len() - 1
+                })
+            })
+            .collect();
+
+        let remap = |stack: &mut Vec<LocationIndex>| {
+            for index in stack.iter_mut() {
+                *index = location_indices[*index];
+            }
+        };
+        for sample in self.samples.iter_mut() {
+            remap(&mut sample.stack);
+            remap(&mut sample.native_stack);
+        }
+        for stack in self.stacks.iter_mut().flatten() {
+            remap(stack);
+        }
+        self.locations = locations;
+    }
+}
@@ -0,0 +1,38 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::profile::Profile;
+
+/// A lightweight export of per-thread sample counts, bucketed over time.
+///
+/// Intended for heatmap-style visualizations where full stacks are not needed.
+/// `threads[thread_id][i]` holds the number of samples captured from the thread
+/// during the `i`-th bucket (`[i * resolution_ms, (i + 1) * resolution_ms)` since start).
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SampleHistogram {
+    pub resolution_ms: u128,
+    pub start_timestamp_ns: u128,
+    pub threads: BTreeMap<u64, Vec<u64>>,
+}
+
+impl SampleHistogram {
+    pub fn build(profile: &Profile, resolution: Duration) -> Self {
+        let mut threads: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+        for sample in profile.samples.iter() {
+            let elapsed = profile.elapsed_at(sample.timestamp);
+            let bucket = (elapsed.as_nanos() / resolution.as_nanos()) as usize;
+
+            let counts = threads.entry(sample.ruby_thread).or_default();
+            if counts.len() <= bucket {
+                counts.resize(bucket + 1, 0);
+            }
+            counts[bucket] += 1;
+        }
+
+        Self {
+            resolution_ms: resolution.as_millis(),
+            start_timestamp_ns: profile.start_timestamp_ns(),
+            threads,
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::ops::Range;
+
+/// The address ranges which may hold code generated by YJIT.
+///
+/// YJIT does not tell where its code region lives, but maps it anonymously (not backed by
+/// any file), so executable anonymous mappings are taken as JIT code. Read afresh for each
+/// serialization, since the region grows as code is compiled.
+#[derive(Debug, Default)]
+pub struct JitCodeRanges(Vec<Range<usize>>);
+
+impl JitCodeRanges {
+    /// The executable anonymous mappings of the process, as listed in `/proc/self/maps`.
+    /// Empty on platforms without it.
+    pub fn current() -> Self {
+        match std::fs::read_to_string("/proc/self/maps") {
+            Ok(maps) => Self::parse(&maps),
+            Err(_) => Self::default(),
+        }
+    }
+
+    fn parse(maps: &str) -> Self {
+        let ranges = maps
+            .lines()
+            .filter_map(|line| {
+                // address, permissions, offset, device, inode, and pathname (if any)
+                let mut fields = line.split_whitespace();
+                let (start, end) = fields.next()?.split_once('-')?;
+                let permissions = fields.next()?;
+                // Pseudo-paths such as [vdso] and [heap] are left out as well
+                if !permissions.contains('x') || fields.nth(3).is_some() {
+                    return None;
+                }
+                let start = usize::from_str_radix(start, 16).ok()?;
+                let end = usize::from_str_radix(end, 16).ok()?;
+                Some(start..end)
+            })
+            .collect();
+        Self(ranges)
+    }
+
+    pub fn contains(&self, pc: usize) -> bool {
+        self.0.iter().any(|range| range.contains(&pc))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let ranges = JitCodeRanges::parse(
+            "55d0c0a00000-55d0c0a20000 r-xp 00001000 fd:01 1234 /usr/bin/ruby
+7f0000000000-7f0000004000 r-xp 00000000 00:00 0
+7f0000004000-7f0000008000 rw-p 00000000 00:00 0
+7f0000008000-7f000000c000 ---p 00000000 00:00 0
+7ffd00000000-7ffd00001000 r-xp 00000000 00:00 0                          [vdso]",
+        );
+        assert!(!ranges.contains(0x55d0c0a00100)); // The ruby binary
+        assert!(ranges.contains(0x7f0000000000));
+        assert!(ranges.contains(0x7f0000003fff));
+        assert!(!ranges.contains(0x7f0000004000)); // Not executable
+        assert!(!ranges.contains(0x7f0000008000)); // Reserved
+        assert!(!ranges.contains(0x7ffd00000100)); // [vdso]
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_current_leaves_out_file_backed_code() {
+        let ranges = JitCodeRanges::current();
+        assert!(!ranges.contains(test_current_leaves_out_file_backed_code as usize));
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::{BTreeMap, HashSet};
+
+use super::profile::{LineHit, Profile, StringIndex};
+
+impl Profile {
+    /// Fill in `line_hits` with sample counts per line of source, e.g. to overlay hot lines onto
+    /// files, from the Ruby stacks of the samples (native frames have no lines).
+    ///
+    /// A line counts as exclusive in a sample when the leaf Ruby frame is running it, and as
+    /// inclusive when any frame is (once per sample). Frames without line information
+    /// (`lineno` 0, e.g. synthetic frames) are left out.
+    pub fn compute_line_hits(&mut self) {
+        let mut hits: BTreeMap<(Option<StringIndex>, i32), LineHit> = BTreeMap::new();
+        for sample in self.samples.iter() {
+            let count = sample.sample_count();
+            let stack = match (&self.stacks, sample.stack_index) {
+                (Some(stacks), Some(index)) => &stacks[index],
+                _ => &sample.stack,
+            };
+            let mut lines: HashSet<(Option<StringIndex>, i32)> = HashSet::new();
+            for (depth, &location_index) in stack.iter().enumerate() {
+                let location = &self.locations[location_index];
+                if location.lineno == 0 {
+                    continue;
+                }
+                let line = (
+                    self.functions[location.function_index].filename,
+                    location.lineno,
+                );
+                let hit = hits.entry(line).or_insert(LineHit {
+                    filename: line.0,
+                    lineno: line.1,
+                    exclusive: 0,
+                    inclusive: 0,
+                });
+                if depth == 0 {
+                    hit.exclusive += count;
+                }
+                if lines.insert(line) {
+                    hit.inclusive += count;
+                }
+            }
+        }
+        self.line_hits = hits.into_values().collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::profile::{
+        Function, FunctionImplementation, Location, Metadata, Sample,
+    };
+
+    fn function(filename: Option<StringIndex>) -> Function {
+        Function {
+            implementation: FunctionImplementation::Ruby,
+            name: None,
+            filename,
+            class_path: None,
+            method_name: None,
+            start_lineno: None,
+            start_address: None,
+            jit: false,
+            eval: false,
+            category: None,
+            mapping: None,
+        }
+    }
+
+    fn location(function_index: usize, lineno: i32) -> Location {
+        Location {
+            function_index,
+            lineno,
+            address: None,
+            stats: None,
+        }
+    }
+
+    #[test]
+    fn test_compute_line_hits() {
+        let mut profile = Profile {
+            schema_version: 0,
+            stacks: None,
+            locations: vec![
+                location(0, 1),
+                location(0, 2),
+                // Another function on the same line of the same file
+                location(1, 2),
+                location(2, 7),
+                location(2, 0),
+            ],
+            functions: vec![function(Some(0)), function(Some(0)), function(None)],
+            strings: vec![],
+            start_timestamp_ns: 0,
+            duration_ns: 0,
+            metadata: Metadata::default(),
+            sample_types: vec![],
+            samples: vec![
+                Sample::with_stack(vec![0, 1, 2]),
+                Sample {
+                    count: Some(2),
+                    ..Sample::with_stack(vec![4, 3, 1])
+                },
+            ],
+            markers: vec![],
+            line_hits: vec![],
+        };
+        profile.compute_line_hits();
+
+        assert_eq!(
+            profile.line_hits,
+            vec![
+                LineHit {
+                    filename: None,
+                    lineno: 7,
+                    exclusive: 0,
+                    inclusive: 2,
+                },
+                LineHit {
+                    filename: Some(0),
+                    lineno: 1,
+                    exclusive: 1,
+                    inclusive: 1,
+                },
+                LineHit {
+                    filename: Some(0),
+                    lineno: 2,
+                    exclusive: 0,
+                    inclusive: 3,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::HashSet;
+
+use super::profile::{LocationIndex, LocationStats, Profile};
+
+impl Profile {
+    /// Fill in `Location.stats` from the samples, so that consumers need not walk the stacks.
+    ///
+    /// A location counts as exclusive in a sample when it is the leaf of the sample's Ruby
+    /// stack or native stack, and as inclusive when it appears anywhere in either of them
+    /// (once per sample, however many times it recurs). Coalesced samples count as many times
+    /// as they stand for.
+    pub fn compute_location_stats(&mut self) {
+        let mut stats = vec![LocationStats::default(); self.locations.len()];
+        for sample in self.samples.iter() {
+            let count = sample.sample_count();
+            let stack: &[LocationIndex] = match (&self.stacks, sample.stack_index) {
+                (Some(stacks), Some(index)) => &stacks[index],
+                _ => &sample.stack,
+            };
+            for leaf in [stack.first(), sample.native_stack.first()]
+                .into_iter()
+                .flatten()
+            {
+                stats[*leaf].exclusive += count;
+            }
+            let appearing: HashSet<LocationIndex> = stack
+                .iter()
+                .chain(sample.native_stack.iter())
+                .copied()
+                .collect();
+            for index in appearing {
+                stats[index].inclusive += count;
+            }
+        }
+        for (location, stats) in self.locations.iter_mut().zip(stats) {
+            location.stats = Some(stats);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::profile::{Location, Metadata, Sample};
+
+    fn location(lineno: i32) -> Location {
+        Location {
+            function_index: 0,
+            lineno,
+            address: None,
+            stats: None,
+        }
+    }
+
+    #[test]
+    fn test_compute_location_stats() {
+        let mut profile = Profile {
+            schema_version: 0,
+            stacks: None,
+            locations: vec![location(1), location(2), location(3)],
+            functions: vec![],
+            strings: vec![],
+            start_timestamp_ns: 0,
+            duration_ns: 0,
+            metadata: Metadata::default(),
+            sample_types: vec![],
+            samples: vec![
+                Sample::with_stack(vec![0, 1, 2]),
+                // Recursion counts once
+                Sample::with_stack(vec![1, 1, 2]),
+                Sample {
+                    count: Some(2),
+                    ..Sample::with_stack(vec![2])
+                },
+            ],
+            markers: vec![],
+            line_hits: vec![],
+        };
+        profile.compute_location_stats();
+
+        let stats: Vec<LocationStats> = profile
+            .locations
+            .iter()
+            .map(|location| location.stats.unwrap())
+            .collect();
+        assert_eq!(
+            stats,
+            vec![
+                LocationStats {
+                    exclusive: 1,
+                    inclusive: 1
+                },
+                LocationStats {
+                    exclusive: 1,
+                    inclusive: 2
+                },
+                LocationStats {
+                    exclusive: 2,
+                    inclusive: 4
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,275 @@
+#![deny(unsafe_op_in_unsafe_fn)]
+
+use std::collections::HashMap;
+use std::ffi::c_int;
+use std::slice;
+
+use rb_sys::*;
+
+use super::parse_profile;
+use super::profile::{
+    Function, FunctionImplementation, Location, LocationIndex, Marker, Profile, Sample,
+    StringIndex, ThreadCpuTime, ThreadSummary,
+};
+use crate::error::Pf2Error;
+use crate::util::rb_str_from_bytes;
+
+/// The number of low bits of `ruby_thread_id` kept when namespacing threads by process.
+/// Thread IDs are addresses of Ruby objects, which fit in 48 bits on supported platforms.
+const THREAD_ID_BITS: u32 = 48;
+const THREAD_ID_MASK: u64 = (1 << THREAD_ID_BITS) - 1;
+
+impl Profile {
+    /// Combine this profile with `others` (e.g. one profile per forked worker) into one.
+    ///
+    /// Strings, functions and locations are unioned and re-indexed; identical entries
+    /// across profiles are stored once. Samples and markers are concatenated, with `elapsed_ns`
+    /// rebased on the earliest start of all profiles. Metadata is taken from `self`.
+    ///
+    /// When `namespace_threads` is set, `ruby_thread_id` is tagged with the index of the
+    /// profile it came from (0 for `self`) in its upper bits, so that threads of different
+    /// processes which happen to share an ID are kept apart.
+    pub fn merge(&self, others: &[Profile], namespace_threads: bool) -> Profile {
+        let profiles: Vec<&Profile> = std::iter::once(self).chain(others.iter()).collect();
+
+        let earliest = profiles
+            .iter()
+            .min_by_key(|profile| profile.start_timestamp_ns)
+            .unwrap_or(&self);
+        let start_timestamp_ns = earliest.start_timestamp_ns;
+        let end_timestamp_ns = profiles
+            .iter()
+            .map(|profile| profile.start_timestamp_ns + profile.duration_ns)
+            .max()
+            .unwrap_or(start_timestamp_ns);
+
+        let thread_id = |id: u64, process_index: usize| match namespace_threads {
+            true => ((process_index as u64) << THREAD_ID_BITS) | (id & THREAD_ID_MASK),
+            false => id,
+        };
+
+        let mut merger = ProfileMerger::default();
+        let mut samples = vec![];
+        let mut markers: Vec<Marker> = vec![];
+        for (process_index, profile) in profiles.iter().enumerate() {
+            let location_indices: Vec<LocationIndex> = profile
+                .locations
+                .iter()
+                .map(|location| merger.location_index_for(profile, location))
+                .collect();
+            let offset_ns = (profile.start_timestamp_ns - start_timestamp_ns) as u64;
+
+            for sample in profile.samples.iter() {
+                samples.push(Sample {
+                    stack: sample.stack.iter().map(|&i| location_indices[i]).collect(),
+                    native_stack: sample
+                        .native_stack
+                        .iter()
+                        .map(|&i| location_indices[i])
+                        .collect(),
+                    ruby_thread_id: sample.ruby_thread_id.map(|id| thread_id(id, process_index)),
+                    elapsed_ns: sample.elapsed_ns + offset_ns,
+                    end_elapsed_ns: sample.end_elapsed_ns.map(|ns| ns + offset_ns),
+                    ..sample.clone()
+                });
+            }
+            for marker in profile.markers.iter() {
+                markers.push(Marker {
+                    elapsed_ns: marker.elapsed_ns + offset_ns,
+                    ruby_thread_id: thread_id(marker.ruby_thread_id, process_index),
+                    ..marker.clone()
+                });
+            }
+        }
+
+        let mut metadata = self.metadata.clone();
+        metadata.start_timestamp_ns = start_timestamp_ns;
+        metadata.start_monotonic_ns = earliest.metadata.start_monotonic_ns;
+        metadata.thread_cpu_times = profiles
+            .iter()
+            .enumerate()
+            .flat_map(|(process_index, profile)| {
+                profile
+                    .metadata
+                    .thread_cpu_times
+                    .iter()
+                    .map(move |thread_cpu_time| ThreadCpuTime {
+                        ruby_thread_id: thread_id(thread_cpu_time.ruby_thread_id, process_index),
+                        ..thread_cpu_time.clone()
+                    })
+            })
+            .collect();
+        metadata.thread_summary = profiles
+            .iter()
+            .enumerate()
+            .flat_map(|(process_index, profile)| {
+                profile
+                    .metadata
+                    .thread_summary
+                    .iter()
+                    .map(move |thread| ThreadSummary {
+                        ruby_thread_id: thread_id(thread.ruby_thread_id, process_index),
+                        ..thread.clone()
+                    })
+            })
+            .collect();
+        let overhead = &mut metadata.overhead;
+        for profile in others {
+            metadata.total_thread_cpu_time_ns += profile.metadata.total_thread_cpu_time_ns;
+            metadata.truncated_sample_count += profile.metadata.truncated_sample_count;
+            metadata.overflow_frame_count += profile.metadata.overflow_frame_count;
+            overhead.sample_count += profile.metadata.overhead.sample_count;
+            overhead.total_capture_ns += profile.metadata.overhead.total_capture_ns;
+            overhead.dropped_by_lock_contention +=
+                profile.metadata.overhead.dropped_by_lock_contention;
+            overhead.dropped_by_full_buffer += profile.metadata.overhead.dropped_by_full_buffer;
+            overhead.coalesced_postponed_jobs += profile.metadata.overhead.coalesced_postponed_jobs;
+            overhead.max_capture_ns = overhead
+                .max_capture_ns
+                .max(profile.metadata.overhead.max_capture_ns);
+        }
+        markers.sort_by_key(|marker| marker.elapsed_ns);
+        overhead.mean_capture_ns = match overhead.sample_count {
+            0 => 0,
+            n => overhead.total_capture_ns / n,
+        };
+
+        let mut merged = Profile {
+            schema_version: self.schema_version,
+            samples,
+            stacks: None,
+            locations: merger.locations,
+            functions: merger.functions,
+            strings: merger.strings,
+            start_timestamp_ns,
+            duration_ns: end_timestamp_ns - start_timestamp_ns,
+            metadata,
+            sample_types: self.sample_types.clone(),
+            markers,
+            line_hits: vec![],
+        };
+        merged.measure_state_breakdown();
+        merged
+    }
+}
+
+/// Builds deduplicated string, function and location tables out of entries of other profiles.
+#[derive(Default)]
+pub(super) struct ProfileMerger {
+    pub(super) strings: Vec<String>,
+    string_indices: HashMap<String, StringIndex>,
+    pub(super) functions: Vec<Function>,
+    function_indices: HashMap<Function, usize>,
+    pub(super) locations: Vec<Location>,
+    location_indices: HashMap<Location, LocationIndex>,
+}
+
+impl ProfileMerger {
+    pub(super) fn location_index_for(
+        &mut self,
+        profile: &Profile,
+        location: &Location,
+    ) -> LocationIndex {
+        let function = &profile.functions[location.function_index];
+        let location = Location {
+            function_index: self.function_index_for(profile, function),
+            // Counts of the source profile no longer hold
+            stats: None,
+            ..location.clone()
+        };
+        self.intern_location(location)
+    }
+
+    /// The location of a synthetic function named `name` (e.g. `(other)`), which has no
+    /// counterpart in the source profiles.
+    pub(super) fn synthetic_location_index(&mut self, name: &str) -> LocationIndex {
+        let function = Function {
+            implementation: FunctionImplementation::Ruby,
+            name: Some(self.string_index_for(name)),
+            filename: None,
+            class_path: None,
+            method_name: None,
+            start_lineno: None,
+            start_address: None,
+            jit: false,
+            eval: false,
+            category: None,
+            mapping: None,
+        };
+        let location = Location {
+            function_index: self.intern_function(function),
+            lineno: 0,
+            address: None,
+            stats: None,
+        };
+        self.intern_location(location)
+    }
+
+    fn intern_location(&mut self, location: Location) -> LocationIndex {
+        if let Some(&index) = self.location_indices.get(&location) {
+            return index;
+        }
+        let index = self.locations.len();
+        self.locations.push(location.clone());
+        self.location_indices.insert(location, index);
+        index
+    }
+
+    fn function_index_for(&mut self, profile: &Profile, function: &Function) -> usize {
+        let mut string = |index: Option<StringIndex>| {
+            index.map(|index| self.string_index_for(&profile.strings[index]))
+        };
+        let function = Function {
+            name: string(function.name),
+            filename: string(function.filename),
+            class_path: string(function.class_path),
+            method_name: string(function.method_name),
+            category: string(function.category),
+            mapping: string(function.mapping),
+            ..function.clone()
+        };
+        self.intern_function(function)
+    }
+
+    fn intern_function(&mut self, function: Function) -> usize {
+        if let Some(&index) = self.function_indices.get(&function) {
+            return index;
+        }
+        let index = self.functions.len();
+        self.functions.push(function.clone());
+        self.function_indices.insert(function, index);
+        index
+    }
+
+    fn string_index_for(&mut self, string: &str) -> StringIndex {
+        if let Some(&index) = self.string_indices.get(string) {
+            return index;
+        }
+        let index = self.strings.len();
+        self.strings.push(string.to_owned());
+        self.string_indices.insert(string.to_owned(), index);
+        index
+    }
+}
+
+/// `Pf2.merge(*profiles)`: Takes profiles serialized as JSON by the experimental serializer
+/// (e.g. one per process), and returns them merged into one profile in the same format.
+/// Thread IDs are namespaced by the position of the profile in the arguments.
+pub(crate) unsafe extern "C" fn rb_merge(argc: c_int, argv: *const VALUE, _rbself: VALUE) -> VALUE {
+    if argc == 0 {
+        Pf2Error::InvalidProfile("no profiles given".to_owned()).raise();
+    }
+    let args = unsafe { slice::from_raw_parts(argv, argc as usize) };
+    let result = args
+        .iter()
+        .map(|&value| unsafe { parse_profile(value) })
+        .collect::<Result<Vec<Profile>, Pf2Error>>()
+        .and_then(|profiles| {
+            let merged = profiles[0].merge(&profiles[1..], true);
+            serde_json::to_vec(&merged).map_err(|e| Pf2Error::Serialization(e.to_string()))
+        });
+    match result {
+        Ok(json) => rb_str_from_bytes(&json),
+        Err(e) => e.raise(),
+    }
+}
@@ -0,0 +1,266 @@
+#![deny(unsafe_op_in_unsafe_fn)]
+
+use std::collections::HashMap;
+
+use rb_sys::*;
+
+use super::parse_profile;
+use super::profile::{Profile, SampleType, StringIndex};
+use crate::util::rb_str_from_bytes;
+
+// Wire types
+const VARINT: u64 = 0;
+const LEN: u64 = 2;
+
+/// A minimal Protocol Buffers encoder, covering the field types used by the OTLP profiles proto.
+/// Singular scalar fields holding the default value (zero) are omitted, as in proto3.
+#[derive(Default)]
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u64) {
+        self.varint(((field as u64) << 3) | wire_type);
+    }
+
+    fn uint64(&mut self, field: u32, value: u64) {
+        if value != 0 {
+            self.key(field, VARINT);
+            self.varint(value);
+        }
+    }
+
+    /// Also used for int32 fields, which are sign-extended to 64 bits on the wire.
+    fn int64(&mut self, field: u32, value: i64) {
+        self.uint64(field, value as u64);
+    }
+
+    /// Always emitted, since this is also used for elements of repeated fields.
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, LEN);
+        self.varint(value.len() as u64);
+        self.buf.extend_from_slice(value);
+    }
+
+    fn string(&mut self, field: u32, value: &str) {
+        self.bytes(field, value.as_bytes());
+    }
+
+    fn message(&mut self, field: u32, message: &Message) {
+        self.bytes(field, &message.buf);
+    }
+
+    fn packed_int64(&mut self, field: u32, values: impl IntoIterator<Item = i64>) {
+        let mut packed = Message::default();
+        for value in values {
+            packed.varint(value as u64);
+        }
+        if !packed.buf.is_empty() {
+            self.bytes(field, &packed.buf);
+        }
+    }
+}
+
+/// `KeyValue` of opentelemetry.proto.common.v1 with a string or int value.
+enum AttributeValue<'a> {
+    String(&'a str),
+    Int(i64),
+}
+
+fn key_value(key: &str, value: AttributeValue) -> Message {
+    let mut any_value = Message::default();
+    match value {
+        AttributeValue::String(value) => any_value.string(1, value),
+        AttributeValue::Int(value) => {
+            // Written even if zero, so that the value is not mistaken for an empty AnyValue
+            any_value.key(3, VARINT);
+            any_value.varint(value as u64);
+        }
+    }
+    let mut key_value = Message::default();
+    key_value.string(1, key);
+    key_value.message(2, &any_value);
+    key_value
+}
+
+/// The string table of an OTLP profile, whose first entry must be the empty string.
+struct StringTable {
+    strings: Vec<String>,
+    indices: HashMap<String, i64>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        let mut table = Self {
+            strings: vec![],
+            indices: HashMap::new(),
+        };
+        table.index_for("");
+        table
+    }
+
+    fn index_for(&mut self, string: &str) -> i64 {
+        if let Some(&index) = self.indices.get(string) {
+            return index;
+        }
+        let index = self.strings.len() as i64;
+        self.strings.push(string.to_owned());
+        self.indices.insert(string.to_owned(), index);
+        index
+    }
+}
+
+impl Profile {
+    /// Encode the profile as an OTLP `ExportProfilesServiceRequest`
+    /// (opentelemetry.proto.profiles.v1development, as of opentelemetry-proto v1.5.0).
+    ///
+    /// Each sample carries its weight in nanoseconds as the single value, typed by the time
+    /// mode (`cpu` or `wall`), its timestamp, and a `thread.id` attribute. Samples triggered by
+    /// calls carry the number of calls they stand for instead. Only Ruby stacks
+    /// are exported. Process and runtime information is attached as resource attributes.
+    pub fn to_otlp(&self) -> Vec<u8> {
+        let mut strings = StringTable::new();
+        let string_index = |strings: &mut StringTable, index: Option<StringIndex>| {
+            index.map_or(0, |index| strings.index_for(&self.strings[index]))
+        };
+
+        let mut profile = Message::default();
+
+        // sample_type = 1, period_type = 13, period = 14
+        let sample_type = self
+            .sample_types
+            .first()
+            .cloned()
+            .unwrap_or_else(|| SampleType::from_metadata(&self.metadata));
+        let period = match self.metadata.mean_calls_per_sample() {
+            Some(calls_per_sample) => calls_per_sample.round() as i64,
+            None => self.metadata.interval_ns as i64,
+        };
+        let mut value_type = Message::default();
+        value_type.int64(1, strings.index_for(&sample_type.name));
+        value_type.int64(2, strings.index_for(&sample_type.unit));
+        profile.message(1, &value_type);
+        profile.message(13, &value_type);
+        profile.int64(14, period);
+
+        // function_table = 6
+        for function in self.functions.iter() {
+            let name = string_index(&mut strings, function.name);
+            let mut message = Message::default();
+            message.int64(1, name);
+            message.int64(2, name);
+            message.int64(3, string_index(&mut strings, function.filename));
+            message.int64(4, function.start_lineno.unwrap_or(0) as i64);
+            profile.message(6, &message);
+        }
+
+        // location_table = 4
+        for location in self.locations.iter() {
+            let mut line = Message::default();
+            line.int64(1, location.function_index as i64);
+            line.int64(2, location.lineno as i64);
+            let mut message = Message::default();
+            message.uint64(2, location.address.unwrap_or(0) as u64);
+            message.message(3, &line);
+            profile.message(4, &message);
+        }
+
+        // sample = 2, location_indices = 5, attribute_table = 7
+        let thread_id_key = "thread.id";
+        let mut attribute_indices: HashMap<u64, i64> = HashMap::new();
+        let mut location_indices: Vec<i64> = vec![];
+        let default_value = period as u64;
+        for sample in self.samples.iter() {
+            let mut message = Message::default();
+            message.int64(1, location_indices.len() as i64);
+            let stack = match (&self.stacks, sample.stack_index) {
+                (Some(stacks), Some(stack_index)) => &stacks[stack_index],
+                _ => &sample.stack,
+            };
+            message.int64(2, stack.len() as i64);
+            location_indices.extend(stack.iter().map(|&index| index as i64));
+            message.packed_int64(
+                3,
+                [sample
+                    .weight_ns
+                    .unwrap_or(default_value * sample.sample_count()) as i64],
+            );
+            if let Some(ruby_thread_id) = sample.ruby_thread_id {
+                let next_index = attribute_indices.len() as i64;
+                let index = *attribute_indices.entry(ruby_thread_id).or_insert_with(|| {
+                    let attribute =
+                        key_value(thread_id_key, AttributeValue::Int(ruby_thread_id as i64));
+                    profile.message(7, &attribute);
+                    next_index
+                });
+                message.packed_int64(4, [index]);
+            }
+            message.packed_int64(
+                6,
+                [(self.start_timestamp_ns as u64 + sample.elapsed_ns) as i64],
+            );
+            profile.message(2, &message);
+        }
+        profile.packed_int64(5, location_indices);
+
+        // time_nanos = 11, duration_nanos = 12
+        profile.int64(11, self.start_timestamp_ns as i64);
+        profile.int64(12, self.duration_ns as i64);
+
+        // string_table = 10
+        for string in strings.strings.iter() {
+            profile.string(10, string);
+        }
+
+        let mut scope = Message::default();
+        scope.string(1, "pf2");
+        let mut scope_profiles = Message::default();
+        scope_profiles.message(1, &scope);
+        scope_profiles.message(2, &profile);
+
+        let mut resource = Message::default();
+        for attribute in [
+            key_value("process.pid", AttributeValue::Int(self.metadata.pid as i64)),
+            key_value("process.runtime.name", AttributeValue::String("ruby")),
+            key_value(
+                "process.runtime.version",
+                AttributeValue::String(&self.metadata.ruby_version),
+            ),
+            key_value(
+                "process.runtime.description",
+                AttributeValue::String(&self.metadata.ruby_description),
+            ),
+        ] {
+            resource.message(1, &attribute);
+        }
+        for (key, value) in self.metadata.labels.iter() {
+            resource.message(1, &key_value(key, AttributeValue::String(value)));
+        }
+        let mut resource_profiles = Message::default();
+        resource_profiles.message(1, &resource);
+        resource_profiles.message(2, &scope_profiles);
+
+        let mut request = Message::default();
+        request.message(1, &resource_profiles);
+        request.buf
+    }
+}
+
+/// `Pf2.to_otlp(profile)`: Takes a profile serialized as JSON by the experimental serializer,
+/// and returns it encoded as an OTLP profiles `ExportProfilesServiceRequest` (binary String),
+/// ready to be sent to an OTLP collector.
+pub(crate) unsafe extern "C" fn rb_to_otlp(_rbself: VALUE, profile: VALUE) -> VALUE {
+    match unsafe { parse_profile(profile) } {
+        Ok(profile) => rb_str_from_bytes(&profile.to_otlp()),
+        Err(e) => e.raise(),
+    }
+}
@@ -1,47 +1,477 @@
+use std::collections::BTreeMap;
+
+/// The version of the serialized format.
+/// Bump this only on incompatible changes to `Profile` (or any type reachable from it), i.e.
+/// when a field is removed, renamed, or changes its meaning. Adding a field does not need a bump.
+pub const SCHEMA_VERSION: u32 = 1;
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Profile {
-    pub samples: Vec<Sample>,
+    pub schema_version: u32,
+    /// Ruby stacks shared by samples, present only when stacks have been deduplicated
+    /// (`Pf2.stop(dedup_stacks: true)`). Samples then refer to them by `stack_index`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stacks: Option<Vec<Vec<LocationIndex>>>,
     pub locations: Vec<Location>,
     pub functions: Vec<Function>,
+    /// Interned strings referenced by `StringIndex` fields (e.g. `Function.name`).
+    pub strings: Vec<String>,
+    /// The wall-clock time at which the profile started, in nanoseconds since the Unix epoch.
     pub start_timestamp_ns: u128,
+    /// Measured on a monotonic clock, as is `Sample.elapsed_ns`.
     pub duration_ns: u128,
+    pub metadata: Metadata,
+    /// What the value of each sample (`weight_ns`, or the number of calls with `trigger: :calls`)
+    /// measures, e.g. `("cpu", "nanoseconds")`. Empty in profiles serialized before this field
+    /// was introduced; see `SampleType::from_metadata`.
+    #[serde(default)]
+    pub sample_types: Vec<SampleType>,
+    /// Serialized after the tables and metadata which samples refer to, so that readers
+    /// streaming the document (e.g. written with `Pf2.stop(io:)`) have them at hand.
+    pub samples: Vec<Sample>,
+    /// Named points in time recorded with `Session#mark`, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub markers: Vec<Marker>,
+    /// Sample counts per line of source, with `Pf2.stop(line_hits: true)`.
+    /// Ordered by `filename` and `lineno`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub line_hits: Vec<LineHit>,
+}
+
+/// How many samples hit a line of a file (see `Profile::compute_line_hits`).
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct LineHit {
+    /// None for frames without a file (e.g. some `eval`s), whose lines are bucketed apart.
+    pub filename: Option<StringIndex>,
+    pub lineno: i32,
+    /// Samples in which the line was being run by the leaf Ruby frame.
+    pub exclusive: u64,
+    /// Samples in which the line appears anywhere in the Ruby stack.
+    pub inclusive: u64,
+}
+
+/// The kind and unit of a value carried by samples, as in pprof's `ValueType`.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SampleType {
+    /// e.g. `cpu`, `wall`, `calls` or `samples`
+    pub name: String,
+    /// e.g. `nanoseconds` or `count`
+    pub unit: String,
+}
+
+impl SampleType {
+    pub fn new(name: &str, unit: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            unit: unit.to_owned(),
+        }
+    }
+
+    /// What samples measure in a profile collected as described by `metadata`: time in the
+    /// time mode, or calls with `trigger: :calls`.
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        match metadata.mean_calls_per_sample() {
+            Some(_) => Self::new("calls", "count"),
+            None => Self::new(&metadata.time_mode, "nanoseconds"),
+        }
+    }
+}
+
+/// A named point in time recorded with `Session#mark` (e.g. "request start").
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Marker {
+    /// The time elapsed since the start of the profile, as in `Sample.elapsed_ns`.
+    pub elapsed_ns: u64,
+    pub label: String,
+    /// The thread which recorded the marker.
+    pub ruby_thread_id: u64,
+}
+
+/// Metadata describing the profiled process and how the profile was collected.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Metadata {
+    pub pid: u32,
+    /// `RUBY_VERSION` of the profiled process.
+    pub ruby_version: String,
+    /// `RUBY_DESCRIPTION` of the profiled process.
+    pub ruby_description: String,
+    /// `"cpu"` or `"wall"`.
+    pub time_mode: String,
+    /// The clock `Sample.clock_ns` is read from (e.g. `"monotonic"`, `"thread_cputime"`).
+    pub clock: String,
+    pub interval_ns: u128,
+    /// The wall-clock (`CLOCK_REALTIME`) time at which the profile started, in nanoseconds since
+    /// the Unix epoch. Use this to align the profile with logs or traces. Elapsed times within
+    /// the profile are measured on a monotonic clock, and are not affected by clock adjustments.
+    pub start_timestamp_ns: u128,
+    /// The reading of `CLOCK_MONOTONIC` at `start_timestamp_ns`, relating monotonic readings
+    /// (e.g. `Sample.clock_ns` with the `monotonic` clock) to wall-clock time.
+    #[serde(default)]
+    pub start_monotonic_ns: u64,
+    /// Whether YJIT was enabled when the profile was serialized.
+    pub yjit_enabled: bool,
+    /// The build of the Pf2 native extension which collected the profile.
+    #[serde(default)]
+    pub native_build: NativeBuild,
+    pub overhead: Overhead,
+    /// The CPU time consumed by each profiled thread. Only recorded in CPU time mode.
+    pub thread_cpu_times: Vec<ThreadCpuTime>,
+    /// The sum of `cpu_time_ns` over `thread_cpu_times`.
+    pub total_thread_cpu_time_ns: u64,
+    /// How samples are distributed across threads, ordered by `ruby_thread_id`.
+    pub thread_summary: Vec<ThreadSummary>,
+    /// Changes of the sampling interval while profiling (`target_overhead_pct`), in order.
+    /// `interval_ns` is the interval the profile started with. Each sample's `weight_ns` is
+    /// the interval in effect when it was captured.
+    #[serde(default)]
+    pub interval_changes: Vec<IntervalChange>,
+    /// Arbitrary key/value pairs given by the user (`labels:`), e.g. `env` or `service`.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// What triggered the capture of samples: `"time"` (every `interval_ns`) or `"calls"`
+    /// (every `calls_per_sample` method calls, see `trigger: :calls`).
+    #[serde(default = "default_trigger")]
+    pub trigger: String,
+    /// With the `"calls"` trigger, the number of method calls each sample stands for.
+    /// Samples then carry no `weight_ns`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub calls_per_sample: Option<u64>,
+    /// With the `"calls"` trigger and `sample_probability:`, the probability with which each
+    /// method call was sampled. Each sample then stands for 1/p calls on average.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_probability: Option<f64>,
+    /// The number of samples whose Ruby stack was truncated at `max_stack_depth`
+    /// (see `Sample.omitted_frames`).
+    #[serde(default)]
+    pub truncated_sample_count: u64,
+    /// The number of frames attributed to the `(overflow)` function, as their own function
+    /// would have exceeded `max_functions`.
+    #[serde(default)]
+    pub overflow_frame_count: u64,
+    /// How samples are distributed across thread states, including dropped ones.
+    #[serde(default)]
+    pub state_breakdown: StateBreakdown,
+    /// GC pauses measured while profiling (`measure_gc_pauses: true`), in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gc_pauses: Vec<GcPause>,
+}
+
+impl Metadata {
+    /// With the `"calls"` trigger, the number of method calls each sample stands for
+    /// (on average, with `sample_probability`). None for time-triggered profiles.
+    pub fn mean_calls_per_sample(&self) -> Option<f64> {
+        match (self.calls_per_sample, self.sample_probability) {
+            (Some(calls_per_sample), _) => Some(calls_per_sample as f64),
+            (None, Some(sample_probability)) => Some(1.0 / sample_probability),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Identifies a build of the Pf2 native extension (see `Pf2.native_version`).
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct NativeBuild {
+    /// The version of the `pf2` crate.
+    pub version: String,
+    /// The Rust target triple, e.g. `x86_64-unknown-linux-gnu`.
+    pub target: String,
+    /// The Ruby API version the extension was compiled against, e.g. `3.3.0`.
+    pub ruby_api_version: String,
+}
+
+impl NativeBuild {
+    /// The build this code was compiled into.
+    pub fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            target: env!("PF2_TARGET").to_owned(),
+            ruby_api_version: format!(
+                "{}.{}.{}",
+                rb_sys::RUBY_API_VERSION_MAJOR,
+                rb_sys::RUBY_API_VERSION_MINOR,
+                rb_sys::RUBY_API_VERSION_TEENY
+            ),
+        }
+    }
+}
+
+fn default_trigger() -> String {
+    "time".to_owned()
+}
+
+/// A change of the sampling interval, made because the measured overhead exceeded the target.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct IntervalChange {
+    /// The time elapsed since the start of the profile, as in `Sample.elapsed_ns`.
+    pub elapsed_ns: u64,
+    /// The interval in effect from then on.
+    pub interval_ns: u64,
+    /// The overhead (as a percentage of wall time) which caused the change.
+    pub overhead_pct: f64,
+}
+
+/// A stretch of time during which GC ran, measured from its start to its end.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct GcPause {
+    /// The time elapsed since the start of the profile, as in `Sample.elapsed_ns`.
+    pub elapsed_ns: u64,
+    pub duration_ns: u64,
+}
+
+/// The cost of capturing samples, measured around each capture.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Overhead {
+    /// The number of captured samples, including those dropped afterwards.
+    pub sample_count: u64,
+    pub total_capture_ns: u64,
+    pub mean_capture_ns: u64,
+    pub max_capture_ns: u64,
+    /// Samples dropped because the profile was locked (e.g. while being flushed).
+    pub dropped_by_lock_contention: u64,
+    /// Samples dropped because the buffer handing them over to the profile was full.
+    pub dropped_by_full_buffer: u64,
+    /// With `scheduler: :timer_thread`, ticks lost because Ruby coalesced their postponed job
+    /// with one still pending. Each costs a sample of every target thread.
+    #[serde(default)]
+    pub coalesced_postponed_jobs: u64,
+}
+
+/// The number of samples in each thread state (`Sample.state`) and of dropped samples, with
+/// their fractions of `total`. Tells at a glance whether the program is CPU-bound or
+/// GC-bound, and how complete the profile is.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct StateBreakdown {
+    /// Samples in the profile plus dropped samples.
+    pub total: u64,
+    /// Samples of threads holding the GVL, outside of GC.
+    pub running: u64,
+    pub gc: u64,
+    /// Samples of sleeping or blocked threads.
+    pub idle: u64,
+    /// Samples captured without telling the state of their thread.
+    pub unknown: u64,
+    /// As in `Overhead`.
+    pub dropped_by_lock_contention: u64,
+    pub dropped_by_full_buffer: u64,
+    pub running_fraction: f64,
+    pub gc_fraction: f64,
+    pub idle_fraction: f64,
+    pub dropped_fraction: f64,
+}
+
+/// CPU time read from a thread's CPU-time clock (`CLOCK_THREAD_CPUTIME_ID`) when profiling
+/// of the thread started and when the profile was stopped.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct ThreadCpuTime {
+    pub ruby_thread_id: u64,
+    pub start_ns: u64,
+    /// None if the thread had exited before the profile was stopped.
+    pub end_ns: Option<u64>,
+    pub cpu_time_ns: Option<u64>,
+}
+
+/// The samples and (if recorded) the CPU time of a thread which appears in the profile.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct ThreadSummary {
+    pub ruby_thread_id: u64,
+    /// `Thread#name` when the profile was serialized.
+    pub name: Option<String>,
+    /// The id of the native (OS) thread the Ruby thread ran on (e.g. the TID on Linux), for
+    /// correlating with OS-level tools. None if unknown, or if it changed while profiling.
+    #[serde(default)]
+    pub native_thread_id: Option<u64>,
+    pub sample_count: u64,
+    /// Taken from `Metadata.thread_cpu_times`. Only recorded in CPU time mode.
+    pub cpu_time_ns: Option<u64>,
+    /// The intervals between consecutive samples of the thread, as they were captured.
+    /// None if the thread has fewer than two samples.
+    pub observed_interval: Option<IntervalStats>,
+}
+
+/// Statistics of the time between consecutive samples, compared to `Metadata.interval_ns`
+/// to tell whether the requested sampling rate was achieved.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct IntervalStats {
+    pub mean_ns: u64,
+    pub median_ns: u64,
+    /// The standard deviation of the intervals.
+    pub jitter_ns: u64,
 }
 
 pub type LocationIndex = usize;
 pub type FunctionIndex = usize;
+pub type StringIndex = usize;
 
 /// Sample
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Sample {
     /// The stack leading to this sample.
     /// The leaf node will be stored at `stack[0]`.
+    /// Empty when the stack is stored in `Profile.stacks` instead.
     pub stack: Vec<LocationIndex>,
+    /// The index of this sample's stack in `Profile.stacks`, when stacks have been deduplicated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stack_index: Option<usize>,
     pub native_stack: Vec<LocationIndex>,
     pub ruby_thread_id: Option<u64>,
+    /// The object ID of the Ractor the thread belongs to, if available.
+    pub ruby_ractor_id: Option<u64>,
+    /// Identifies the Fiber running on the thread. The thread's root Fiber is always 0.
+    pub fiber_id: u64,
+    /// The time elapsed since the start of the profile.
+    pub elapsed_ns: u64,
+    /// The reading of the clock named by `Metadata.clock` when the sample was captured.
+    /// Per-thread clocks (`thread_cputime`) are only comparable within a thread.
+    pub clock_ns: u64,
+    /// Whether the sample was captured while garbage collection was in progress.
+    pub during_gc: bool,
+    /// The scheduling state of the thread, if it could be determined.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<ThreadState>,
+    /// Whether the thread had been running on a CPU (as opposed to blocked or waiting) around
+    /// the time of capture, estimated from its CPU time. Absent if unknown.
+    /// Splits wall-time profiles into CPU-time and wait-time views.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_cpu: Option<bool>,
+    /// The weight of this sample in nanoseconds.
+    /// Defaults to the sampling interval, so that the sum of weights approximates the profiled time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weight_ns: Option<u64>,
+    /// The number of Ruby frames left out of `stack` (at its root) because the stack was deeper
+    /// than `max_stack_depth`. Absent if the stack was captured in full.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub omitted_frames: Option<u32>,
+    /// The number of recursive Ruby frames removed from `stack` (`Pf2.stop(collapse_recursion:)`),
+    /// telling how deep the recursion went. Absent if none were removed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collapsed_frames: Option<u32>,
+    /// In profiles produced by `Pf2.diff`, the signed difference in the number of samples
+    /// with this stack (after - before).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delta: Option<i64>,
+    /// The number of consecutive samples of the thread with the same stack merged into this
+    /// one (`Pf2.stop(coalesce: true)`). Absent if the sample stands for itself alone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub count: Option<u64>,
+    /// For merged samples, the `elapsed_ns` of the last sample merged into this one.
+    /// The samples span from `elapsed_ns` to `end_elapsed_ns`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_elapsed_ns: Option<u64>,
+}
+
+impl Sample {
+    /// The number of captured samples this sample stands for (see `count`).
+    pub fn sample_count(&self) -> u64 {
+        self.count.unwrap_or(1)
+    }
+}
+
+#[cfg(test)]
+impl Sample {
+    /// A sample of thread 1 with `stack`, and nothing else recorded.
+    pub fn with_stack(stack: Vec<LocationIndex>) -> Self {
+        Sample {
+            stack,
+            stack_index: None,
+            native_stack: vec![],
+            ruby_thread_id: Some(1),
+            ruby_ractor_id: None,
+            fiber_id: 0,
+            elapsed_ns: 0,
+            clock_ns: 0,
+            during_gc: false,
+            state: None,
+            on_cpu: None,
+            weight_ns: None,
+            omitted_frames: None,
+            collapsed_frames: None,
+            delta: None,
+            count: None,
+            end_elapsed_ns: None,
+        }
+    }
+}
+
+/// The scheduling state of a thread when it was sampled.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThreadState {
+    /// Holding the GVL (or waiting for it)
+    Running,
+    /// Released the GVL: sleeping, blocked (e.g. in IO) or waiting on a mutex
+    Sleeping,
+    /// Running garbage collection
+    Gc,
+}
+
+impl ThreadState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Running => "running",
+            Self::Sleeping => "sleeping",
+            Self::Gc => "gc",
+        }
+    }
 }
 
 /// Location represents a location (line) in the source code when a sample was captured.
-#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Location {
     pub function_index: FunctionIndex,
     pub lineno: i32,
     pub address: Option<usize>,
+    /// Sample counts of the location, with `Pf2.stop(include_stats: true)`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<LocationStats>,
+}
+
+/// How many samples a location appears in (see `Profile::compute_location_stats`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LocationStats {
+    /// Samples in which the location is the leaf of its stack.
+    pub exclusive: u64,
+    /// Samples in which the location appears anywhere in its stack.
+    pub inclusive: u64,
 }
 
 /// Function represents a Ruby method or a C function in the profile.
-#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Function {
     pub implementation: FunctionImplementation,
-    pub name: Option<String>, // unique key
-    pub filename: Option<String>,
+    pub name: Option<StringIndex>, // unique key
+    pub filename: Option<StringIndex>,
+    /// The class path of the method's owner (e.g. `Foo::Bar`), if available.
+    /// `name` holds the full label (e.g. `Foo::Bar#baz`) for backward compatibility.
+    pub class_path: Option<StringIndex>,
+    /// The method name without the receiver (e.g. `baz`), if available.
+    pub method_name: Option<StringIndex>,
     /// The first line number in the method/function definition.
     /// For the actual location (line) which was hit during sample capture, refer to `Location.lineno`.
     pub start_lineno: Option<i32>,
     pub start_address: Option<usize>,
+    /// Whether this represents samples in YJIT-compiled code of the method.
+    pub jit: bool,
+    /// Whether this is code compiled from a String at runtime (`eval`, `instance_eval` and the
+    /// like). Such functions have a synthetic `filename` (e.g. `(eval at app.rb:12)`), which
+    /// is also appended to `name` so that they don't merge with regular functions of the same label.
+    #[serde(default)]
+    pub eval: bool,
+    /// A label assigned by `Pf2.stop(categories:)` (e.g. `ActiveRecord`), for coloring
+    /// flame graphs by category. None if uncategorized.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<StringIndex>,
+    /// The path of the shared object (the executable or a library such as an extension's `.so`)
+    /// a native function was found in, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mapping: Option<StringIndex>,
 }
 
-#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum FunctionImplementation {
+    /// A method defined in Ruby (iseq)
     Ruby,
+    /// A Ruby method implemented in C (e.g. `Array#each`). These have no Ruby source file.
+    CFunc,
+    /// A native function found in the native (C) stack
     Native,
 }
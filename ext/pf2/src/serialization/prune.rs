@@ -0,0 +1,74 @@
+use std::collections::{HashMap, HashSet};
+
+use super::merge::ProfileMerger;
+use super::profile::{FunctionIndex, LocationIndex, Profile, Sample};
+
+impl Profile {
+    /// Drop functions which appear in fewer than `min_samples` samples, to reduce noise.
+    ///
+    /// Frames of dropped functions are removed from stacks, so that their time is attributed
+    /// to the nearest retained caller. Ruby stacks left with no frames at all are attributed
+    /// to a synthetic `(other)` frame. Functions, locations and strings no longer referenced
+    /// are pruned.
+    pub fn prune_rare_functions(&self, min_samples: u64) -> Profile {
+        // The number of samples in which each function appears (at least once)
+        let mut counts: Vec<u64> = vec![0; self.functions.len()];
+        for sample in self.samples.iter() {
+            let functions: HashSet<FunctionIndex> = sample
+                .stack
+                .iter()
+                .chain(sample.native_stack.iter())
+                .map(|&index| self.locations[index].function_index)
+                .collect();
+            for function_index in functions {
+                counts[function_index] += sample.sample_count();
+            }
+        }
+
+        let mut merger = ProfileMerger::default();
+        let mut location_indices: HashMap<LocationIndex, LocationIndex> = HashMap::new();
+        let mut remap = |stack: &[LocationIndex]| -> Vec<LocationIndex> {
+            stack
+                .iter()
+                .filter(|&&index| counts[self.locations[index].function_index] >= min_samples)
+                .map(|&index| {
+                    *location_indices
+                        .entry(index)
+                        .or_insert_with(|| merger.location_index_for(self, &self.locations[index]))
+                })
+                .collect()
+        };
+
+        let mut samples = Vec::with_capacity(self.samples.len());
+        for sample in self.samples.iter() {
+            let stack = remap(&sample.stack);
+            let native_stack = remap(&sample.native_stack);
+            samples.push(Sample {
+                stack,
+                native_stack,
+                ..sample.clone()
+            });
+        }
+        for (sample, original) in samples.iter_mut().zip(self.samples.iter()) {
+            if sample.stack.is_empty() && !original.stack.is_empty() {
+                sample
+                    .stack
+                    .push(merger.synthetic_location_index("(other)"));
+            }
+        }
+
+        Profile {
+            samples,
+            stacks: None,
+            locations: merger.locations,
+            functions: merger.functions,
+            strings: merger.strings,
+            metadata: self.metadata.clone(),
+            sample_types: self.sample_types.clone(),
+            markers: self.markers.clone(),
+            // Derived from the samples, which have changed
+            line_hits: vec![],
+            ..*self
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::str::FromStr;
+
+use super::profile::{FunctionIndex, LocationIndex, Profile};
+
+/// The longest cycle of mutually recursive functions collapsed by `CollapseRecursion::Cycles`.
+const MAX_RECURSION_CYCLE_LENGTH: usize = 8;
+
+/// How recursive frames are collapsed (`Pf2.stop(collapse_recursion:)`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CollapseRecursion {
+    /// Collapse consecutive frames of the same function (A→A→A) (`true`)
+    Direct,
+    /// Also collapse repeated cycles of mutually recursive functions (A→B→A→B)
+    Cycles,
+}
+
+impl FromStr for CollapseRecursion {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "direct" => Ok(Self::Direct),
+            "cycles" => Ok(Self::Cycles),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Profile {
+    /// Collapse recursive frames in Ruby stacks, so that a recursive function appears once per
+    /// stack instead of once per level of recursion.
+    ///
+    /// Frames are compared by function, regardless of the line. Of a run of repeated frames (or
+    /// cycles), the one closest to the leaf is kept. The number of frames removed is recorded in
+    /// `Sample.collapsed_frames`. Native stacks are left as they are.
+    ///
+    /// Must be called before stacks are deduplicated.
+    pub fn collapse_recursion(&mut self, mode: CollapseRecursion) {
+        let max_cycle_length = match mode {
+            CollapseRecursion::Direct => 1,
+            CollapseRecursion::Cycles => MAX_RECURSION_CYCLE_LENGTH,
+        };
+        let mut samples = std::mem::take(&mut self.samples);
+        for sample in samples.iter_mut() {
+            let collapsed = self.collapse_stack(&sample.stack, max_cycle_length);
+            let removed = (sample.stack.len() - collapsed.len()) as u32;
+            if removed > 0 {
+                sample.stack = collapsed;
+                sample.collapsed_frames = Some(sample.collapsed_frames.unwrap_or(0) + removed);
+            }
+        }
+        self.samples = samples;
+        // Derived from the stacks, which have changed
+        self.line_hits.clear();
+    }
+
+    /// Walk the stack from the leaf, dropping each repetition of a sequence of up to
+    /// `max_cycle_length` functions which immediately follows the same sequence.
+    fn collapse_stack(
+        &self,
+        stack: &[LocationIndex],
+        max_cycle_length: usize,
+    ) -> Vec<LocationIndex> {
+        let mut kept: Vec<LocationIndex> = Vec::with_capacity(stack.len());
+        let mut functions: Vec<FunctionIndex> = Vec::with_capacity(stack.len());
+        for &location_index in stack.iter() {
+            kept.push(location_index);
+            functions.push(self.locations[location_index].function_index);
+            // The frames kept so far never repeat, so only a repetition ending here is possible
+            for length in 1..=max_cycle_length.min(functions.len() / 2) {
+                let len = functions.len();
+                if functions[len - 2 * length..len - length] == functions[len - length..] {
+                    kept.truncate(len - length);
+                    functions.truncate(len - length);
+                    break;
+                }
+            }
+        }
+        kept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::profile::{
+        Function, FunctionImplementation, Location, Metadata, Sample,
+    };
+
+    fn function() -> Function {
+        Function {
+            implementation: FunctionImplementation::Ruby,
+            name: None,
+            filename: None,
+            class_path: None,
+            method_name: None,
+            start_lineno: None,
+            start_address: None,
+            jit: false,
+            eval: false,
+            category: None,
+            mapping: None,
+        }
+    }
+
+    fn location(function_index: usize, lineno: i32) -> Location {
+        Location {
+            function_index,
+            lineno,
+            address: None,
+            stats: None,
+        }
+    }
+
+    fn profile(stacks: Vec<Vec<LocationIndex>>) -> Profile {
+        Profile {
+            schema_version: 0,
+            stacks: None,
+            // Locations 0-1 are in function A, 2-3 in B, and 4 in C
+            locations: vec![
+                location(0, 1),
+                location(0, 2),
+                location(1, 10),
+                location(1, 11),
+                location(2, 20),
+            ],
+            functions: vec![function(), function(), function()],
+            strings: vec![],
+            start_timestamp_ns: 0,
+            duration_ns: 0,
+            metadata: Metadata::default(),
+            sample_types: vec![],
+            samples: stacks.into_iter().map(Sample::with_stack).collect(),
+            markers: vec![],
+            line_hits: vec![],
+        }
+    }
+
+    #[test]
+    fn test_collapse_direct_recursion() {
+        // C → A → A → A (leaf), then A → B → A → B (leaf)
+        let mut profile = profile(vec![vec![0, 1, 1, 4], vec![2, 0, 3, 1]]);
+        profile.collapse_recursion(CollapseRecursion::Direct);
+
+        assert_eq!(profile.samples[0].stack, vec![0, 4]);
+        assert_eq!(profile.samples[0].collapsed_frames, Some(2));
+        // Mutual recursion is left alone
+        assert_eq!(profile.samples[1].stack, vec![2, 0, 3, 1]);
+        assert_eq!(profile.samples[1].collapsed_frames, None);
+    }
+
+    #[test]
+    fn test_collapse_recursion_cycles() {
+        // C → A → B → A → B → A → B (leaf), then C → A → A → B (leaf)
+        let mut profile = profile(vec![vec![2, 0, 3, 1, 2, 1, 4], vec![2, 0, 1, 4]]);
+        profile.collapse_recursion(CollapseRecursion::Cycles);
+
+        assert_eq!(profile.samples[0].stack, vec![2, 0, 4]);
+        assert_eq!(profile.samples[0].collapsed_frames, Some(4));
+        assert_eq!(profile.samples[1].stack, vec![2, 0, 4]);
+        assert_eq!(profile.samples[1].collapsed_frames, Some(1));
+    }
+}
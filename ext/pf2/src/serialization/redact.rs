@@ -0,0 +1,46 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use super::profile::{Profile, StringIndex};
+
+impl Profile {
+    /// Replace file paths of functions (and shared objects of native functions) with their
+    /// basenames, so that the profile can be shared without revealing user names or directory
+    /// layouts (e.g. `/home/alice/app/foo.rb` becomes `foo.rb`). Method names and line numbers
+    /// are kept.
+    ///
+    /// Paths are rewritten in the string table itself, so no full path remains in the profile.
+    pub fn redact_paths(&mut self) {
+        let filenames: HashSet<StringIndex> = self
+            .functions
+            .iter()
+            .flat_map(|function| [function.filename, function.mapping])
+            .flatten()
+            .collect();
+        for index in filenames {
+            let path = &self.strings[index];
+            if let Some(basename) = Path::new(path).file_name() {
+                self.strings[index] = basename.to_string_lossy().into_owned();
+            }
+        }
+    }
+
+    /// Rewrite file paths of functions under `root` to be relative to it
+    /// (e.g. `/srv/app/lib/foo.rb` becomes `lib/foo.rb` with `root = /srv/app`).
+    /// Paths outside `root` are left unchanged.
+    pub fn relativize_paths(&mut self, root: &Path) {
+        let filenames: HashSet<StringIndex> = self
+            .functions
+            .iter()
+            .flat_map(|function| [function.filename, function.mapping])
+            .flatten()
+            .collect();
+        for index in filenames {
+            let relative = match Path::new(&self.strings[index]).strip_prefix(root) {
+                Ok(relative) if !relative.as_os_str().is_empty() => relative,
+                _ => continue,
+            };
+            self.strings[index] = relative.to_string_lossy().into_owned();
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use rb_sys::*;
+
+use crate::util::RTEST;
+
+/// A Ruby frame whose attributes have already been read from the VM.
+///
+/// `ProfileSerializer2` builds Functions from these rather than from frame VALUEs, so that
+/// samples from elsewhere (e.g. another profiler, or tests) can be serialized without Ruby.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResolvedFrame {
+    /// `rb_profile_frame_full_label()`, e.g. `Foo::Bar#baz`.
+    pub full_label: Option<String>,
+    pub path: Option<String>,
+    /// The class path of the method's owner, e.g. `Foo::Bar`.
+    pub class_path: Option<String>,
+    /// The method name without the receiver, e.g. `baz`.
+    pub method_name: Option<String>,
+    /// The first line of the method definition.
+    pub first_lineno: Option<i32>,
+    /// The address of the C function backing a method implemented in C.
+    pub cfunc_address: Option<usize>,
+    /// The line being executed when the sample was captured.
+    pub lineno: i32,
+}
+
+/// A sample whose Ruby frames have already been resolved. See `ProfileSerializer2::serialize_resolved`.
+#[derive(Clone, Debug, Default)]
+pub struct ResolvedSample {
+    /// The leaf frame comes first, as in `Sample.stack`.
+    pub frames: Vec<ResolvedFrame>,
+    pub ruby_thread_id: Option<u64>,
+    /// The time elapsed since the start of the profile.
+    pub elapsed_ns: u64,
+}
+
+impl ResolvedFrame {
+    /// Read the attributes of a frame captured by `rb_profile_thread_frames()`.
+    /// Must be called with the GVL held.
+    pub fn from_ruby_frame(frame: VALUE, lineno: i32) -> Self {
+        Self {
+            full_label: Self::string_attribute(unsafe { rb_profile_frame_full_label(frame) }),
+            path: Self::path_of(frame),
+            class_path: Self::string_attribute(unsafe { rb_profile_frame_classpath(frame) }),
+            method_name: Self::string_attribute(unsafe { rb_profile_frame_method_name(frame) }),
+            first_lineno: lineno_from_value(unsafe { rb_profile_frame_first_lineno(frame) }),
+            cfunc_address: Self::cfunc_address_of(frame),
+            lineno,
+        }
+    }
+
+    /// The path of the frame alone, e.g. to tell whether it is excluded before reading the rest.
+    pub fn path_of(frame: VALUE) -> Option<String> {
+        Self::string_attribute(unsafe { rb_profile_frame_path(frame) })
+    }
+
+    /// Read a frame attribute, which is usually a String or nil. Ruby may hand out other
+    /// objects for synthetic frames: Symbols are read by name, and anything else by `to_s`.
+    /// Interior NUL bytes are kept, rather than raising as `rb_string_value_cstr()` would.
+    pub(crate) fn string_attribute(value: VALUE) -> Option<String> {
+        if !RTEST(value) {
+            return None;
+        }
+        let string = unsafe {
+            if RTEST(rb_obj_is_kind_of(value, rb_cString)) {
+                value
+            } else if RTEST(rb_obj_is_kind_of(value, rb_cSymbol)) {
+                rb_sym2str(value)
+            } else {
+                rb_obj_as_string(value)
+            }
+        };
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                RSTRING_PTR(string) as *const u8,
+                RSTRING_LEN(string) as usize,
+            )
+        };
+        Some(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    fn cfunc_address_of(frame: VALUE) -> Option<usize> {
+        unsafe {
+            let cme = frame as *mut crate::ruby_internal_apis::rb_callable_method_entry_struct;
+            let cme = &*cme; // *mut to reference
+
+            if (*(cme.def)).type_ == 1 {
+                // The cme is a Cfunc
+                Some((*(cme.def)).cfunc.func as usize)
+            } else {
+                // The cme is an ISeq (Ruby code) or some other type
+                None
+            }
+        }
+    }
+}
+
+/// Convert a line number returned by Ruby, or None if it is not an Integer or does not fit
+/// in an `i32`. Never raises, unlike `rb_num2int()`.
+fn lineno_from_value(value: VALUE) -> Option<i32> {
+    if !FIXNUM_P(value) {
+        // nil, or a Bignum which is out of range anyway
+        return None;
+    }
+    i32::try_from(unsafe { rb_num2long(value) }).ok()
+}
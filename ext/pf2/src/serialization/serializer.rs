@@ -1,76 +1,658 @@
-use std::ffi::{c_char, CStr, CString};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{c_char, c_long, CStr};
+use std::io::Write;
+use std::path::Path;
 
 use rb_sys::*;
 
+use super::jit_code::JitCodeRanges;
 use super::profile::{
-    Function, FunctionImplementation, FunctionIndex, Location, LocationIndex, Profile, Sample,
+    Function, FunctionImplementation, FunctionIndex, GcPause, IntervalChange, Location,
+    LocationIndex, Marker, Metadata, NativeBuild, Overhead, Profile, Sample, SampleType,
+    StateBreakdown, StringIndex, ThreadCpuTime, ThreadState, ThreadSummary, SCHEMA_VERSION,
 };
+use super::recursion::CollapseRecursion;
+use super::resolved_frame::{ResolvedFrame, ResolvedSample};
 use crate::backtrace::Backtrace;
-use crate::util::{cstr, RTEST};
+use crate::native_version;
+use crate::session::configuration::{Configuration, TimeMode, Trigger};
+use crate::util::{cstr, rb_str_from_bytes, RTEST};
+
+/// The name of the function standing for every function beyond `max_functions`.
+const OVERFLOW_FUNCTION_NAME: &str = "(overflow)";
 
 pub struct ProfileSerializer2 {
+    configuration: Configuration,
     profile: Profile,
+    string_indices: HashMap<String, StringIndex>,
+    /// The function standing for every function beyond `max_functions`, once there is one.
+    overflow_function_index: Option<FunctionIndex>,
+}
+
+/// What `dladdr(3)` tells about a native pc.
+struct SharedObject {
+    path: String,
+    /// The nearest symbol exported by the shared object, if any.
+    symbol: Option<String>,
+    /// The address of `symbol`, or the pc itself if there is none.
+    symbol_address: usize,
 }
 
 impl ProfileSerializer2 {
-    pub fn new() -> ProfileSerializer2 {
+    pub fn new(configuration: &Configuration) -> ProfileSerializer2 {
         ProfileSerializer2 {
-            profile: Profile {
-                start_timestamp_ns: 0,
-                duration_ns: 0,
-                samples: vec![],
-                locations: vec![],
-                functions: vec![],
-            },
+            configuration: configuration.clone(),
+            profile: Self::empty_profile(),
+            string_indices: HashMap::new(),
+            overflow_function_index: None,
+        }
+    }
+
+    fn empty_profile() -> Profile {
+        Profile {
+            schema_version: SCHEMA_VERSION,
+            start_timestamp_ns: 0,
+            duration_ns: 0,
+            samples: vec![],
+            stacks: None,
+            locations: vec![],
+            functions: vec![],
+            strings: vec![],
+            metadata: Metadata::default(),
+            sample_types: vec![],
+            markers: vec![],
+            line_hits: vec![],
         }
     }
 
     pub fn serialize(&mut self, source: &crate::profile::Profile) {
+        self.serialize_latest(source, source.samples.len());
+    }
+
+    /// Like `serialize()`, but only includes the last `count` samples of `source`
+    /// (e.g. those flushed since the previous `on_flush` batch).
+    ///
+    /// Replaces whatever the serializer held before, so that it can be reused.
+    pub fn serialize_latest(&mut self, source: &crate::profile::Profile, count: usize) {
+        self.profile = Self::empty_profile();
+        self.string_indices.clear();
+        self.overflow_function_index = None;
+
         // Fill in meta fields
-        self.profile.start_timestamp_ns = source
-            .start_timestamp
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos();
-        self.profile.duration_ns = source
-            .end_instant
-            .unwrap()
-            .duration_since(source.start_instant)
-            .as_nanos();
+        self.profile.start_timestamp_ns = source.start_timestamp_ns();
+        self.profile.duration_ns = source.duration().as_nanos();
+        self.profile.metadata = self.build_metadata(source);
+        self.profile.sample_types = vec![SampleType::from_metadata(&self.profile.metadata)];
 
-        // Create a Sample for each sample collected
-        for sample in source.samples.iter() {
-            // Iterate over the Ruby stack
-            let mut stack: Vec<LocationIndex> = vec![];
-            let ruby_stack_depth = sample.line_count;
-            for i in 0..ruby_stack_depth {
-                let frame: VALUE = sample.frames[i as usize];
-                let lineno: i32 = sample.linenos[i as usize];
-                let function = Self::extract_function_from_ruby_frame(frame);
+        // Code generated by YJIT lives in anonymous memory, which libbacktrace cannot symbolize
+        let jit_code = match self.profile.metadata.yjit_enabled {
+            true => JitCodeRanges::current(),
+            false => JitCodeRanges::default(),
+        };
 
-                let function_index = self.function_index_for(function);
-                let location_index = self.location_index_for(function_index, lineno);
-                stack.push(location_index);
-            }
+        // Native locations by pc, so that each pc is symbolized only once
+        let mut native_location_indices: HashMap<usize, LocationIndex> = HashMap::new();
 
+        // Create a Sample for each sample collected
+        let skipped = source.samples.len().saturating_sub(count);
+        for sample in source.samples.iter().skip(skipped) {
             // Iterate over the native stack
             let mut native_stack: Vec<LocationIndex> = vec![];
-            let native_stack_depth = sample.c_backtrace_pcs[0];
-            for i in 1..(native_stack_depth - 1) {
-                let pc = sample.c_backtrace_pcs[i];
-                let function = Self::extract_function_from_native_pc(pc, source);
+            let mut in_jit_code = false;
+            for pc in sample.native_pcs() {
+                if let Some(&location_index) = native_location_indices.get(&pc) {
+                    native_stack.push(location_index);
+                    continue;
+                }
+                let function = self.extract_function_from_native_pc(pc, source);
+
+                // Attribute JIT code to the owning Ruby frame instead of an unknown native address
+                if function.name.is_none() && jit_code.contains(pc) {
+                    in_jit_code = true;
+                    continue;
+                }
 
                 let function_index = self.function_index_for(function);
-                let location_index = self.location_index_for(function_index, 0);
+                let location_index = self.location_index_for(function_index, 0, Some(pc));
+                native_location_indices.insert(pc, location_index);
                 native_stack.push(location_index);
             }
 
+            // Iterate over the Ruby stack
+            let frames: Vec<ResolvedFrame> = (0..sample.ruby_frame_count())
+                .map(|i| {
+                    // Taken as is, even if odd (e.g. negative with `eval(..., lineno)`)
+                    ResolvedFrame::from_ruby_frame(sample.frames[i], sample.linenos[i])
+                })
+                .collect();
+            let mut stack = self.ruby_stack_for(&frames, in_jit_code);
+
+            // Samples captured during GC get a synthetic leaf frame, so that GC shows up
+            // as its own node in the flame graph
+            if sample.during_gc {
+                let function = self.synthetic_function("(garbage collection)");
+                let function_index = self.function_index_for(function);
+                let location_index = self.location_index_for(function_index, 0, None);
+                stack.insert(0, location_index);
+            }
+            // Likewise for samples of idle threads (`include_idle: :tag`)
+            if sample.idle {
+                let function = self.synthetic_function("(idle)");
+                let function_index = self.function_index_for(function);
+                let location_index = self.location_index_for(function_index, 0, None);
+                stack.insert(0, location_index);
+            }
+
+            let elapsed_ns = source.elapsed_at(sample.timestamp).as_nanos() as u64;
             self.profile.samples.push(Sample {
                 stack,
+                stack_index: None,
                 native_stack,
                 ruby_thread_id: Some(sample.ruby_thread),
+                ruby_ractor_id: sample.ruby_ractor_id,
+                fiber_id: sample.ruby_fiber_id,
+                elapsed_ns,
+                clock_ns: sample.clock_ns,
+                during_gc: sample.during_gc,
+                state: sample.state.map(|state| match state {
+                    crate::sample::ThreadState::Running => ThreadState::Running,
+                    crate::sample::ThreadState::Sleeping => ThreadState::Sleeping,
+                    crate::sample::ThreadState::Gc => ThreadState::Gc,
+                }),
+                on_cpu: sample.on_cpu,
+                // Samples triggered by calls are not weighted by time
+                weight_ns: match self.configuration.trigger {
+                    Trigger::Time => Some(self.interval_ns_at(elapsed_ns)),
+                    Trigger::Calls => None,
+                },
+                omitted_frames: (sample.omitted_frames > 0).then_some(sample.omitted_frames),
+                collapsed_frames: None,
+                delta: None,
+                count: None,
+                end_elapsed_ns: None,
             });
         }
+
+        // Markers recorded after the samples left out, which earlier batches already covered
+        let since = skipped
+            .checked_sub(1)
+            .and_then(|index| source.samples.get(index))
+            .map(|sample| sample.timestamp);
+        self.profile.markers = source
+            .markers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|marker| !since.is_some_and(|since| marker.timestamp <= since))
+            .map(|marker| Marker {
+                elapsed_ns: source.elapsed_at(marker.timestamp).as_nanos() as u64,
+                label: marker.label.clone(),
+                ruby_thread_id: marker.ruby_thread,
+            })
+            .collect();
+
+        self.profile.metadata.truncated_sample_count = self
+            .profile
+            .samples
+            .iter()
+            .filter(|sample| sample.omitted_frames.is_some())
+            .count() as u64;
+        self.profile.metadata.thread_summary = self.build_thread_summary(source);
+        self.profile.recount_thread_samples();
+        self.profile.measure_sampling_intervals();
+        self.profile.measure_state_breakdown();
+    }
+
+    /// Serialize samples whose frames have already been resolved, without the Ruby VM.
+    ///
+    /// Unlike `serialize()`, nothing is known about the process or the native stacks, so the
+    /// metadata only describes the configuration.
+    /// Replaces whatever the serializer held before, so that it can be reused.
+    pub fn serialize_resolved(&mut self, samples: &[ResolvedSample]) {
+        self.profile = Self::empty_profile();
+        self.string_indices.clear();
+        self.overflow_function_index = None;
+
+        self.profile.duration_ns = samples
+            .iter()
+            .map(|sample| sample.elapsed_ns as u128)
+            .max()
+            .unwrap_or(0);
+        self.profile.metadata = Metadata {
+            pid: std::process::id(),
+            time_mode: self.time_mode_name().to_owned(),
+            clock: self.configuration.clock.as_str().to_owned(),
+            interval_ns: self.configuration.interval.as_nanos(),
+            labels: self.configuration.labels.clone(),
+            trigger: self.configuration.trigger.as_str().to_owned(),
+            calls_per_sample: self.configuration.every,
+            sample_probability: self.configuration.sample_probability,
+            native_build: NativeBuild::current(),
+            ..Metadata::default()
+        };
+        self.profile.sample_types = vec![SampleType::from_metadata(&self.profile.metadata)];
+
+        for sample in samples {
+            let stack = self.ruby_stack_for(&sample.frames, false);
+            self.profile.samples.push(Sample {
+                stack,
+                stack_index: None,
+                native_stack: vec![],
+                ruby_thread_id: sample.ruby_thread_id,
+                ruby_ractor_id: None,
+                fiber_id: 0,
+                elapsed_ns: sample.elapsed_ns,
+                clock_ns: 0,
+                during_gc: false,
+                state: None,
+                on_cpu: None,
+                weight_ns: match self.configuration.trigger {
+                    Trigger::Time => Some(self.configuration.interval.as_nanos() as u64),
+                    Trigger::Calls => None,
+                },
+                omitted_frames: None,
+                collapsed_frames: None,
+                delta: None,
+                count: None,
+                end_elapsed_ns: None,
+            });
+        }
+
+        let mut ruby_thread_ids: Vec<u64> = samples
+            .iter()
+            .filter_map(|sample| sample.ruby_thread_id)
+            .collect();
+        ruby_thread_ids.sort_unstable();
+        ruby_thread_ids.dedup();
+        self.profile.metadata.thread_summary = ruby_thread_ids
+            .into_iter()
+            .map(|ruby_thread_id| ThreadSummary {
+                ruby_thread_id,
+                name: None,
+                native_thread_id: None,
+                sample_count: 0,
+                cpu_time_ns: None,
+                observed_interval: None,
+            })
+            .collect();
+        self.profile.recount_thread_samples();
+        self.profile.measure_sampling_intervals();
+        self.profile.measure_state_breakdown();
+    }
+
+    /// Build the stack of a sample from its Ruby frames, leaf first.
+    /// `in_jit_code` tells whether the sample was taken in code compiled by YJIT.
+    fn ruby_stack_for(
+        &mut self,
+        frames: &[ResolvedFrame],
+        in_jit_code: bool,
+    ) -> Vec<LocationIndex> {
+        let mut in_jit_code = in_jit_code;
+        let mut stack: Vec<LocationIndex> = vec![];
+        for frame in frames {
+            // Omit frames in excluded paths.
+            // Their time will be attributed to the nearest included caller.
+            if self.is_excluded_path(frame.path.as_deref()) {
+                continue;
+            }
+
+            let mut function = self.function_for_resolved_frame(frame);
+
+            // JIT code belongs to the innermost Ruby-defined method
+            if in_jit_code && function.implementation == FunctionImplementation::Ruby {
+                function.jit = true;
+                in_jit_code = false;
+            }
+
+            let function_index = self.function_index_for(function);
+            let location_index = self.location_index_for(function_index, frame.lineno, None);
+            stack.push(location_index);
+        }
+
+        // Empty stacks are rejected by some viewers. A stack consisting only of excluded
+        // frames, or a thread with no Ruby frames at all (e.g. running native code of a
+        // C extension), is attributed to a synthetic root frame instead.
+        if stack.is_empty() {
+            let function = self.synthetic_function(match frames.len() {
+                0 => "(no Ruby frames)",
+                _ => "(filtered)",
+            });
+            let function_index = self.function_index_for(function);
+            let location_index = self.location_index_for(function_index, 0, None);
+            stack.push(location_index);
+        }
+        stack
+    }
+
+    /// Sort `strings`, `functions` and `locations` by stable keys and rewrite every index
+    /// referring to them, so that the output does not depend on the order samples were captured.
+    pub fn sort_deterministically(&mut self) {
+        // Strings are sorted first, so that comparing StringIndexes is equivalent to
+        // comparing the strings themselves
+        let string_remap = sort_with_remap(&mut self.profile.strings, |a, b| a.cmp(b));
+        let remap_string = |index: &mut Option<StringIndex>| {
+            if let Some(index) = index {
+                *index = string_remap[*index];
+            }
+        };
+        for function in self.profile.functions.iter_mut() {
+            remap_string(&mut function.name);
+            remap_string(&mut function.filename);
+            remap_string(&mut function.class_path);
+            remap_string(&mut function.method_name);
+            remap_string(&mut function.category);
+            remap_string(&mut function.mapping);
+        }
+        self.string_indices = self
+            .profile
+            .strings
+            .iter()
+            .enumerate()
+            .map(|(index, string)| (string.clone(), index))
+            .collect();
+
+        let function_remap = sort_with_remap(&mut self.profile.functions, |a, b| {
+            let key = |f: &Function| {
+                (
+                    f.name,
+                    f.filename,
+                    f.class_path,
+                    f.method_name,
+                    f.category,
+                    f.mapping,
+                    f.start_lineno,
+                    &f.implementation,
+                    f.jit,
+                    f.start_address,
+                )
+            };
+            key(a).cmp(&key(b))
+        });
+        for location in self.profile.locations.iter_mut() {
+            location.function_index = function_remap[location.function_index];
+        }
+
+        let location_remap = sort_with_remap(&mut self.profile.locations, |a, b| {
+            (a.function_index, a.lineno, a.address).cmp(&(b.function_index, b.lineno, b.address))
+        });
+        for sample in self.profile.samples.iter_mut() {
+            for index in sample.stack.iter_mut() {
+                *index = location_remap[*index];
+            }
+            for index in sample.native_stack.iter_mut() {
+                *index = location_remap[*index];
+            }
+        }
+    }
+
+    /// Drop functions appearing in fewer than `min_samples` samples
+    /// (see `Profile::prune_rare_functions`).
+    pub fn prune_rare_functions(&mut self, min_samples: u64) {
+        self.profile = self.profile.prune_rare_functions(min_samples);
+        self.string_indices = self
+            .profile
+            .strings
+            .iter()
+            .enumerate()
+            .map(|(index, string)| (string.clone(), index))
+            .collect();
+    }
+
+    /// Keep only the samples of the given threads (see `Profile::retain_threads`).
+    pub fn retain_threads(&mut self, ruby_thread_ids: &HashSet<u64>) {
+        self.profile = self.profile.retain_threads(ruby_thread_ids);
+        self.string_indices = self
+            .profile
+            .strings
+            .iter()
+            .enumerate()
+            .map(|(index, string)| (string.clone(), index))
+            .collect();
+    }
+
+    /// Label functions by class path or file path prefix (see `Profile::categorize`).
+    /// Must be called before paths are rewritten by `relativize_paths` or `redact_paths`.
+    pub fn categorize(&mut self, categories: &[(String, String)]) {
+        self.profile.categorize(categories);
+        for (index, string) in self.profile.strings.iter().enumerate() {
+            self.string_indices.entry(string.clone()).or_insert(index);
+        }
+    }
+
+    /// Make file paths under `root` relative to it (see `Profile::relativize_paths`).
+    /// Must be called before `sort_deterministically`, which orders strings by their contents.
+    pub fn relativize_paths(&mut self, root: &Path) {
+        self.profile.relativize_paths(root);
+    }
+
+    /// Strip directories from file paths (see `Profile::redact_paths`).
+    /// Must be called before `sort_deterministically`, which orders strings by their contents.
+    pub fn redact_paths(&mut self) {
+        self.profile.redact_paths();
+    }
+
+    /// Aggregate samples per function instead of per line (see `Profile::aggregate_by_function`).
+    /// Must be called before `sort_deterministically`, which orders locations by their contents.
+    pub fn aggregate_by_function(&mut self) {
+        self.profile.aggregate_by_function();
+    }
+
+    /// Weight samples by the time until the next sample of their thread
+    /// (see `Profile::weight_by_elapsed_time`).
+    pub fn weight_by_elapsed_time(&mut self) {
+        self.profile.weight_by_elapsed_time();
+    }
+
+    /// Weight samples captured during GC by the GC pause they landed in
+    /// (see `Profile::weight_gc_samples_by_pause`).
+    pub fn weight_gc_samples_by_pause(&mut self) {
+        self.profile.weight_gc_samples_by_pause();
+    }
+
+    /// Merge consecutive samples of a thread with identical stacks (see `Profile::coalesce`).
+    /// Must be called before `dedup_stacks`.
+    pub fn coalesce(&mut self) {
+        self.profile.coalesce();
+    }
+
+    /// Precompute sample counts of each location (see `Profile::compute_location_stats`).
+    pub fn compute_location_stats(&mut self) {
+        self.profile.compute_location_stats();
+    }
+
+    /// Collapse recursive frames in Ruby stacks (see `Profile::collapse_recursion`).
+    pub fn collapse_recursion(&mut self, mode: CollapseRecursion) {
+        self.profile.collapse_recursion(mode);
+    }
+
+    /// Count samples per line of source (see `Profile::compute_line_hits`).
+    pub fn compute_line_hits(&mut self) {
+        self.profile.compute_line_hits();
+    }
+
+    /// Store identical Ruby stacks once in a shared `stacks` table.
+    /// Must be called after `sort_deterministically`, which rewrites `Sample.stack`.
+    pub fn dedup_stacks(&mut self) {
+        self.profile.dedup_stacks();
+    }
+
+    fn build_metadata(&self, source: &crate::profile::Profile) -> Metadata {
+        let thread_cpu_times: Vec<ThreadCpuTime> = source
+            .thread_cpu_times
+            .iter()
+            .map(|thread_cpu_time| ThreadCpuTime {
+                ruby_thread_id: thread_cpu_time.ruby_thread,
+                start_ns: thread_cpu_time.start_ns,
+                end_ns: thread_cpu_time.end_ns,
+                cpu_time_ns: thread_cpu_time.cpu_time_ns(),
+            })
+            .collect();
+        Metadata {
+            pid: std::process::id(),
+            ruby_version: Self::ruby_constant_string(cstr!("RUBY_VERSION")),
+            ruby_description: Self::ruby_constant_string(cstr!("RUBY_DESCRIPTION")),
+            time_mode: self.time_mode_name().to_owned(),
+            clock: self.configuration.clock.as_str().to_owned(),
+            interval_ns: self.configuration.interval.as_nanos(),
+            start_timestamp_ns: self.profile.start_timestamp_ns,
+            start_monotonic_ns: source.start_monotonic_ns,
+            yjit_enabled: Self::yjit_enabled(),
+            native_build: NativeBuild::current(),
+            overhead: Overhead {
+                sample_count: source.capture_stats.count(),
+                total_capture_ns: source.capture_stats.total_ns(),
+                mean_capture_ns: source.capture_stats.mean_ns(),
+                max_capture_ns: source.capture_stats.max_ns(),
+                dropped_by_lock_contention: source.capture_stats.dropped_by_lock_contention(),
+                dropped_by_full_buffer: source.capture_stats.dropped_by_full_buffer(),
+                coalesced_postponed_jobs: source.capture_stats.coalesced_postponed_jobs(),
+            },
+            total_thread_cpu_time_ns: thread_cpu_times
+                .iter()
+                .filter_map(|thread_cpu_time| thread_cpu_time.cpu_time_ns)
+                .sum(),
+            thread_cpu_times,
+            interval_changes: source
+                .interval_changes
+                .iter()
+                .map(|change| IntervalChange {
+                    elapsed_ns: change.elapsed.as_nanos() as u64,
+                    interval_ns: change.interval.as_nanos() as u64,
+                    overhead_pct: change.overhead_pct,
+                })
+                .collect(),
+            labels: self.configuration.labels.clone(),
+            trigger: self.configuration.trigger.as_str().to_owned(),
+            calls_per_sample: self.configuration.every,
+            sample_probability: self.configuration.sample_probability,
+            // Counted once samples have been serialized
+            truncated_sample_count: 0,
+            overflow_frame_count: 0,
+            state_breakdown: StateBreakdown::default(),
+            // Pauses which ended before the start (e.g. during warmup) are left out
+            gc_pauses: source
+                .gc_pauses
+                .pauses()
+                .iter()
+                .filter_map(|pause| {
+                    if pause.started_at + pause.duration < source.start_instant {
+                        return None;
+                    }
+                    Some(GcPause {
+                        elapsed_ns: pause
+                            .started_at
+                            .saturating_duration_since(source.start_instant)
+                            .as_nanos() as u64,
+                        duration_ns: pause.duration.as_nanos() as u64,
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    fn time_mode_name(&self) -> &'static str {
+        match self.configuration.time_mode {
+            TimeMode::CpuTime => "cpu",
+            TimeMode::WallTime => "wall",
+        }
+    }
+
+    /// The sampling interval in effect `elapsed_ns` after the start of the profile.
+    fn interval_ns_at(&self, elapsed_ns: u64) -> u64 {
+        self.profile
+            .metadata
+            .interval_changes
+            .iter()
+            .rev()
+            .find(|change| change.elapsed_ns <= elapsed_ns)
+            .map_or(self.configuration.interval.as_nanos() as u64, |change| {
+                change.interval_ns
+            })
+    }
+
+    /// List the threads which appear in the samples. Sample counts are filled in by the caller.
+    fn build_thread_summary(&self, source: &crate::profile::Profile) -> Vec<ThreadSummary> {
+        let mut ruby_threads: Vec<VALUE> = source
+            .samples
+            .iter()
+            .map(|sample| sample.ruby_thread)
+            .collect();
+        ruby_threads.sort_unstable();
+        ruby_threads.dedup();
+
+        ruby_threads
+            .into_iter()
+            .map(|ruby_thread| ThreadSummary {
+                ruby_thread_id: ruby_thread,
+                name: Self::thread_name(ruby_thread),
+                native_thread_id: Self::native_thread_id(source, ruby_thread),
+                sample_count: 0,
+                cpu_time_ns: self
+                    .profile
+                    .metadata
+                    .thread_cpu_times
+                    .iter()
+                    .find(|thread_cpu_time| thread_cpu_time.ruby_thread_id == ruby_thread)
+                    .and_then(|thread_cpu_time| thread_cpu_time.cpu_time_ns),
+                observed_interval: None,
+            })
+            .collect()
+    }
+
+    /// `Thread#name`, if set.
+    fn thread_name(ruby_thread: VALUE) -> Option<String> {
+        unsafe {
+            let mut name = rb_funcall(ruby_thread, rb_intern(cstr!("name")), 0);
+            if !RTEST(name) {
+                return None;
+            }
+            let ptr = rb_string_value_ptr(&mut name);
+            let bytes = std::slice::from_raw_parts(ptr as *const u8, RSTRING_LEN(name) as usize);
+            Some(String::from_utf8_lossy(bytes).into_owned())
+        }
+    }
+
+    /// The native thread id recorded when sampling of `ruby_thread` was set up, or else its
+    /// current `Thread#native_thread_id`. None if the thread has exited, or has been seen on
+    /// different native threads.
+    fn native_thread_id(source: &crate::profile::Profile, ruby_thread: VALUE) -> Option<u64> {
+        let current = unsafe {
+            let native_thread_id = rb_funcall(ruby_thread, rb_intern(cstr!("native_thread_id")), 0);
+            RTEST(native_thread_id).then(|| rb_num2ull(native_thread_id))
+        };
+        match source.native_thread_ids.get(&ruby_thread) {
+            Some(&recorded) => match current {
+                Some(current) if Some(current) != recorded => None,
+                _ => recorded,
+            },
+            None => current,
+        }
+    }
+
+    /// Whether YJIT is enabled (`RubyVM::YJIT.enabled?`).
+    fn yjit_enabled() -> bool {
+        unsafe {
+            if rb_const_defined(rb_cObject, rb_intern(cstr!("RubyVM"))) == 0 {
+                return false;
+            }
+            let rb_vm = rb_const_get(rb_cObject, rb_intern(cstr!("RubyVM")));
+            if rb_const_defined(rb_vm, rb_intern(cstr!("YJIT"))) == 0 {
+                return false;
+            }
+            let yjit = rb_const_get(rb_vm, rb_intern(cstr!("YJIT")));
+            RTEST(rb_funcall(yjit, rb_intern(cstr!("enabled?")), 0))
+        }
+    }
+
+    /// Read a String constant defined on Object (e.g. `RUBY_VERSION`).
+    fn ruby_constant_string(name: *const c_char) -> String {
+        unsafe {
+            let mut value: VALUE = rb_const_get(rb_cObject, rb_intern(name));
+            CStr::from_ptr(rb_string_value_cstr(&mut value))
+                .to_string_lossy()
+                .into_owned()
+        }
     }
 
     /// Returns the index of the function in `functions`.
@@ -83,6 +665,11 @@ impl ProfileSerializer2 {
             .position(|f| *f == function)
         {
             Some(index) => index,
+            // Bound the size of the profile, e.g. for programs generating unique method names
+            None if self.profile.functions.len() >= self.configuration.max_functions => {
+                self.profile.metadata.overflow_frame_count += 1;
+                self.overflow_function_index()
+            }
             None => {
                 self.profile.functions.push(function);
                 self.profile.functions.len() - 1
@@ -90,14 +677,55 @@ impl ProfileSerializer2 {
         }
     }
 
+    /// Returns the index of the `(overflow)` function, adding it on first use.
+    fn overflow_function_index(&mut self) -> FunctionIndex {
+        if let Some(index) = self.overflow_function_index {
+            return index;
+        }
+        // Interned directly, since `string_index_for` refers back here once functions are capped
+        let name = OVERFLOW_FUNCTION_NAME.to_owned();
+        let name_index = match self.string_indices.get(&name) {
+            Some(&index) => index,
+            None => {
+                self.profile.strings.push(name.clone());
+                self.string_indices
+                    .insert(name, self.profile.strings.len() - 1);
+                self.profile.strings.len() - 1
+            }
+        };
+        self.profile.functions.push(Function {
+            implementation: FunctionImplementation::Ruby,
+            name: Some(name_index),
+            filename: None,
+            class_path: None,
+            method_name: None,
+            start_lineno: None,
+            start_address: None,
+            jit: false,
+            eval: false,
+            category: None,
+            mapping: None,
+        });
+        let index = self.profile.functions.len() - 1;
+        self.overflow_function_index = Some(index);
+        index
+    }
+
     /// Returns the index of the location in `locations`.
     /// Calling this method will modify `self.profile` in place.
-    fn location_index_for(&mut self, function_index: FunctionIndex, lineno: i32) -> LocationIndex {
-        // Build a Location based on (1) the Function and (2) the actual line hit during sampling.
+    fn location_index_for(
+        &mut self,
+        function_index: FunctionIndex,
+        lineno: i32,
+        address: Option<usize>,
+    ) -> LocationIndex {
+        // Build a Location based on (1) the Function and (2) the actual line (or, for native
+        // frames without line information, the pc) hit during sampling.
         let location = Location {
             function_index,
             lineno,
-            address: None,
+            address,
+            stats: None,
         };
         match self
             .profile
@@ -113,96 +741,209 @@ impl ProfileSerializer2 {
         }
     }
 
-    /// Build a Function from a Ruby frame.
-    fn extract_function_from_ruby_frame(frame: VALUE) -> Function {
-        unsafe {
-            let mut frame_full_label: VALUE = rb_profile_frame_full_label(frame);
-            let frame_full_label: Option<String> = if RTEST(frame_full_label) {
-                Some(
-                    CStr::from_ptr(rb_string_value_cstr(&mut frame_full_label))
-                        .to_str()
-                        .unwrap()
-                        .to_owned(),
-                )
-            } else {
-                None
-            };
-
-            let mut frame_path: VALUE = rb_profile_frame_path(frame);
-            let frame_path: Option<String> = if RTEST(frame_path) {
-                Some(
-                    CStr::from_ptr(rb_string_value_cstr(&mut frame_path))
-                        .to_str()
-                        .unwrap()
-                        .to_owned(),
-                )
-            } else {
-                None
-            };
+    /// Returns the index of the string in `strings`.
+    /// Calling this method will modify `self.profile` in place.
+    fn string_index_for(&mut self, string: String) -> StringIndex {
+        if let Some(&index) = self.string_indices.get(&string) {
+            return index;
+        }
+        // Once functions are capped, a string not seen before can only belong to a new function,
+        // which is going to be folded into `(overflow)`. Not interning it keeps the string table
+        // bounded as well.
+        if self.profile.functions.len() >= self.configuration.max_functions {
+            let overflow_function_index = self.overflow_function_index();
+            return self.profile.functions[overflow_function_index]
+                .name
+                .unwrap();
+        }
+        self.profile.strings.push(string.clone());
+        let index = self.profile.strings.len() - 1;
+        self.string_indices.insert(string, index);
+        index
+    }
 
-            let frame_first_lineno: VALUE = rb_profile_frame_first_lineno(frame);
-            let frame_first_lineno: Option<i32> = if RTEST(frame_first_lineno) {
-                Some(rb_num2int(frame_first_lineno).try_into().unwrap())
-            } else {
-                None
-            };
+    /// Build a Function from a Ruby frame.
+    pub(crate) fn function_for_resolved_frame(&mut self, frame: &ResolvedFrame) -> Function {
+        // Methods implemented in C are backed by a C function, and have a label but no path
+        let implementation = if frame.cfunc_address.is_some()
+            || (frame.path.is_none() && frame.full_label.is_some())
+        {
+            FunctionImplementation::CFunc
+        } else {
+            FunctionImplementation::Ruby
+        };
 
-            let start_address = Self::get_underlying_c_function_address(frame);
+        // Code compiled from a String has a synthetic path, e.g. `(eval at app.rb:12)`
+        // (Ruby >= 3.3) or `(eval)`, and often a label shared with unrelated code
+        // (e.g. `<main>`, `block in <main>`). The path tells them apart.
+        let eval = frame
+            .path
+            .as_deref()
+            .is_some_and(|path| path.starts_with("(eval"));
+        let name = match (&frame.full_label, &frame.path) {
+            (Some(label), Some(path)) if eval => format!("{} {}", label, path),
+            (Some(label), _) => label.clone(),
+            // Rather than leaving it to be shown as `(unknown)` along with other such frames
+            (None, Some(path)) => match frame.first_lineno {
+                Some(lineno) => format!("(anonymous at {}:{})", path, lineno),
+                None => format!("(anonymous at {})", path),
+            },
+            (None, None) => "(anonymous)".to_owned(),
+        };
 
-            Function {
-                implementation: FunctionImplementation::Ruby,
-                name: frame_full_label,
-                filename: frame_path,
-                start_lineno: frame_first_lineno,
-                start_address,
-            }
+        Function {
+            implementation,
+            name: Some(self.string_index_for(name)),
+            filename: frame.path.clone().map(|path| self.string_index_for(path)),
+            class_path: frame
+                .class_path
+                .clone()
+                .map(|path| self.string_index_for(path)),
+            method_name: frame
+                .method_name
+                .clone()
+                .map(|name| self.string_index_for(name)),
+            start_lineno: frame.first_lineno,
+            start_address: frame.cfunc_address,
+            jit: false,
+            eval,
+            category: None,
+            mapping: None,
         }
     }
 
-    fn get_underlying_c_function_address(frame: VALUE) -> Option<usize> {
-        unsafe {
-            let cme = frame as *mut crate::ruby_internal_apis::rb_callable_method_entry_struct;
-            let cme = &*cme; // *mut to reference
+    fn is_excluded_path(&self, path: Option<&str>) -> bool {
+        match path {
+            Some(path) => self
+                .configuration
+                .exclude_paths
+                .iter()
+                .any(|pattern| pattern.matches(path)),
+            None => false,
+        }
+    }
 
-            if (*(cme.def)).type_ == 1 {
-                // The cme is a Cfunc
-                Some((*(cme.def)).cfunc.func as usize)
-            } else {
-                // The cme is an ISeq (Ruby code) or some other type
-                None
-            }
+    /// Build a synthetic Function which does not correspond to actual code,
+    /// such as time spent in garbage collection.
+    fn synthetic_function(&mut self, name: &str) -> Function {
+        Function {
+            implementation: FunctionImplementation::Ruby,
+            name: Some(self.string_index_for(name.to_owned())),
+            filename: None,
+            class_path: None,
+            method_name: None,
+            start_lineno: None,
+            start_address: None,
+            jit: false,
+            eval: false,
+            category: None,
+            mapping: None,
         }
     }
 
     /// Build a Function from a PC (program counter) obtained by libbacktrace.
-    fn extract_function_from_native_pc(pc: usize, source: &crate::profile::Profile) -> Function {
+    fn extract_function_from_native_pc(
+        &mut self,
+        pc: usize,
+        source: &crate::profile::Profile,
+    ) -> Function {
         // Obtain the function name and address using libbacktrace
-        let mut function: Option<Function> = None;
+        let mut symbol: Option<(Option<String>, usize)> = None;
         Backtrace::backtrace_syminfo(
             &source.backtrace_state,
             pc,
             |_pc: usize, symname: *const c_char, symval: usize, _symsize: usize| unsafe {
-                function = Some(Function {
-                    implementation: FunctionImplementation::Native,
-                    name: if symname.is_null() {
-                        None
-                    } else {
-                        Some(CStr::from_ptr(symname).to_str().unwrap().to_owned())
-                    },
-                    filename: None,
-                    start_lineno: None,
-                    start_address: Some(symval),
-                });
+                let name = if symname.is_null() {
+                    None
+                } else {
+                    Some(CStr::from_ptr(symname).to_string_lossy().into_owned())
+                };
+                symbol = Some((name, symval));
             },
             Some(Backtrace::backtrace_error_callback),
         );
-        function.unwrap()
+        // The shared object containing the pc, and the nearest exported symbol in it
+        let shared_object = Self::shared_object_of(pc);
+
+        // The callback is not called when symbolization fails
+        let (name, symval) = match (symbol, &shared_object) {
+            (Some(symbol), _) => symbol,
+            (None, Some(shared_object)) => {
+                (shared_object.symbol.clone(), shared_object.symbol_address)
+            }
+            (None, None) => (None, pc),
+        };
+
+        Function {
+            implementation: FunctionImplementation::Native,
+            name: name.map(|name| self.string_index_for(name)),
+            filename: None,
+            class_path: None,
+            method_name: None,
+            start_lineno: None,
+            start_address: Some(symval),
+            jit: false,
+            eval: false,
+            category: None,
+            mapping: shared_object.map(|shared_object| self.string_index_for(shared_object.path)),
+        }
+    }
+
+    /// Look up the shared object `pc` belongs to with `dladdr(3)`.
+    fn shared_object_of(pc: usize) -> Option<SharedObject> {
+        let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+        if unsafe { libc::dladdr(pc as *const libc::c_void, &mut info) } == 0
+            || info.dli_fname.is_null()
+        {
+            return None;
+        }
+        let path = unsafe { CStr::from_ptr(info.dli_fname) }
+            .to_string_lossy()
+            .into_owned();
+        // The main executable may have an empty name, depending on the platform
+        if path.is_empty() {
+            return None;
+        }
+        let symbol = match info.dli_sname.is_null() {
+            true => None,
+            false => Some(
+                unsafe { CStr::from_ptr(info.dli_sname) }
+                    .to_string_lossy()
+                    .into_owned(),
+            ),
+        };
+        Some(SharedObject {
+            path,
+            symbol_address: match symbol {
+                Some(_) => info.dli_saddr as usize,
+                None => pc,
+            },
+            symbol,
+        })
+    }
+
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    /// Write the serialized profile into `writer` as JSON, indented if `pretty` is set.
+    pub fn to_writer<W: Write>(&self, writer: W, pretty: bool) -> serde_json::Result<()> {
+        match pretty {
+            true => serde_json::to_writer_pretty(writer, &self.profile),
+            false => serde_json::to_writer(writer, &self.profile),
+        }
     }
 
     pub fn to_ruby_hash(&self) -> VALUE {
         unsafe {
             let hash: VALUE = rb_hash_new();
 
+            // profile[:schema_version]
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("schema_version"))),
+                rb_int2inum(self.profile.schema_version as isize),
+            );
             // profile[:start_timestamp_ns]
             rb_hash_aset(
                 hash,
@@ -216,6 +957,349 @@ impl ProfileSerializer2 {
                 rb_int2inum(self.profile.duration_ns as isize),
             );
 
+            // profile[:metadata]
+            let metadata = &self.profile.metadata;
+            let metadata_hash: VALUE = rb_hash_new();
+            rb_hash_aset(
+                metadata_hash,
+                rb_id2sym(rb_intern(cstr!("pid"))),
+                rb_int2inum(metadata.pid as isize),
+            );
+            rb_hash_aset(
+                metadata_hash,
+                rb_id2sym(rb_intern(cstr!("ruby_version"))),
+                rb_str_from_bytes(metadata.ruby_version.as_bytes()),
+            );
+            rb_hash_aset(
+                metadata_hash,
+                rb_id2sym(rb_intern(cstr!("ruby_description"))),
+                rb_str_from_bytes(metadata.ruby_description.as_bytes()),
+            );
+            rb_hash_aset(
+                metadata_hash,
+                rb_id2sym(rb_intern(cstr!("time_mode"))),
+                rb_id2sym(rb_intern2(
+                    metadata.time_mode.as_ptr() as *const c_char,
+                    metadata.time_mode.len() as c_long,
+                )),
+            );
+            rb_hash_aset(
+                metadata_hash,
+                rb_id2sym(rb_intern(cstr!("clock"))),
+                rb_id2sym(rb_intern2(
+                    metadata.clock.as_ptr() as *const c_char,
+                    metadata.clock.len() as c_long,
+                )),
+            );
+            rb_hash_aset(
+                metadata_hash,
+                rb_id2sym(rb_intern(cstr!("interval_ns"))),
+                rb_int2inum(metadata.interval_ns as isize),
+            );
+            rb_hash_aset(
+                metadata_hash,
+                rb_id2sym(rb_intern(cstr!("start_timestamp_ns"))),
+                rb_int2inum(metadata.start_timestamp_ns as isize),
+            );
+            rb_hash_aset(
+                metadata_hash,
+                rb_id2sym(rb_intern(cstr!("start_monotonic_ns"))),
+                rb_ull2inum(metadata.start_monotonic_ns),
+            );
+            rb_hash_aset(
+                metadata_hash,
+                rb_id2sym(rb_intern(cstr!("yjit_enabled"))),
+                if metadata.yjit_enabled {
+                    Qtrue as VALUE
+                } else {
+                    Qfalse as VALUE
+                },
+            );
+            rb_hash_aset(
+                metadata_hash,
+                rb_id2sym(rb_intern(cstr!("native_build"))),
+                native_version::native_build_to_ruby_hash(&metadata.native_build),
+            );
+            let overhead_hash: VALUE = rb_hash_new();
+            rb_hash_aset(
+                overhead_hash,
+                rb_id2sym(rb_intern(cstr!("sample_count"))),
+                rb_ull2inum(metadata.overhead.sample_count),
+            );
+            rb_hash_aset(
+                overhead_hash,
+                rb_id2sym(rb_intern(cstr!("total_capture_ns"))),
+                rb_ull2inum(metadata.overhead.total_capture_ns),
+            );
+            rb_hash_aset(
+                overhead_hash,
+                rb_id2sym(rb_intern(cstr!("mean_capture_ns"))),
+                rb_ull2inum(metadata.overhead.mean_capture_ns),
+            );
+            rb_hash_aset(
+                overhead_hash,
+                rb_id2sym(rb_intern(cstr!("max_capture_ns"))),
+                rb_ull2inum(metadata.overhead.max_capture_ns),
+            );
+            rb_hash_aset(
+                overhead_hash,
+                rb_id2sym(rb_intern(cstr!("dropped_by_lock_contention"))),
+                rb_ull2inum(metadata.overhead.dropped_by_lock_contention),
+            );
+            rb_hash_aset(
+                overhead_hash,
+                rb_id2sym(rb_intern(cstr!("dropped_by_full_buffer"))),
+                rb_ull2inum(metadata.overhead.dropped_by_full_buffer),
+            );
+            rb_hash_aset(
+                overhead_hash,
+                rb_id2sym(rb_intern(cstr!("coalesced_postponed_jobs"))),
+                rb_ull2inum(metadata.overhead.coalesced_postponed_jobs),
+            );
+            rb_hash_aset(
+                metadata_hash,
+                rb_id2sym(rb_intern(cstr!("overhead"))),
+                overhead_hash,
+            );
+            let thread_cpu_times: VALUE = rb_ary_new();
+            for thread_cpu_time in metadata.thread_cpu_times.iter() {
+                let optional_ns = |ns: Option<u64>| match ns {
+                    Some(ns) => rb_ull2inum(ns),
+                    None => Qnil as VALUE,
+                };
+                let thread_cpu_time_hash: VALUE = rb_hash_new();
+                rb_hash_aset(
+                    thread_cpu_time_hash,
+                    rb_id2sym(rb_intern(cstr!("ruby_thread_id"))),
+                    rb_ull2inum(thread_cpu_time.ruby_thread_id),
+                );
+                rb_hash_aset(
+                    thread_cpu_time_hash,
+                    rb_id2sym(rb_intern(cstr!("start_ns"))),
+                    rb_ull2inum(thread_cpu_time.start_ns),
+                );
+                rb_hash_aset(
+                    thread_cpu_time_hash,
+                    rb_id2sym(rb_intern(cstr!("end_ns"))),
+                    optional_ns(thread_cpu_time.end_ns),
+                );
+                rb_hash_aset(
+                    thread_cpu_time_hash,
+                    rb_id2sym(rb_intern(cstr!("cpu_time_ns"))),
+                    optional_ns(thread_cpu_time.cpu_time_ns),
+                );
+                rb_ary_push(thread_cpu_times, thread_cpu_time_hash);
+            }
+            rb_hash_aset(
+                metadata_hash,
+                rb_id2sym(rb_intern(cstr!("thread_cpu_times"))),
+                thread_cpu_times,
+            );
+            rb_hash_aset(
+                metadata_hash,
+                rb_id2sym(rb_intern(cstr!("total_thread_cpu_time_ns"))),
+                rb_ull2inum(metadata.total_thread_cpu_time_ns),
+            );
+            let thread_summary: VALUE = rb_ary_new();
+            for thread in metadata.thread_summary.iter() {
+                let thread_hash: VALUE = rb_hash_new();
+                rb_hash_aset(
+                    thread_hash,
+                    rb_id2sym(rb_intern(cstr!("ruby_thread_id"))),
+                    rb_ull2inum(thread.ruby_thread_id),
+                );
+                rb_hash_aset(
+                    thread_hash,
+                    rb_id2sym(rb_intern(cstr!("name"))),
+                    match &thread.name {
+                        Some(name) => rb_str_from_bytes(name.as_bytes()),
+                        None => Qnil as VALUE,
+                    },
+                );
+                rb_hash_aset(
+                    thread_hash,
+                    rb_id2sym(rb_intern(cstr!("native_thread_id"))),
+                    match thread.native_thread_id {
+                        Some(native_thread_id) => rb_ull2inum(native_thread_id),
+                        None => Qnil as VALUE,
+                    },
+                );
+                rb_hash_aset(
+                    thread_hash,
+                    rb_id2sym(rb_intern(cstr!("sample_count"))),
+                    rb_ull2inum(thread.sample_count),
+                );
+                rb_hash_aset(
+                    thread_hash,
+                    rb_id2sym(rb_intern(cstr!("cpu_time_ns"))),
+                    match thread.cpu_time_ns {
+                        Some(cpu_time_ns) => rb_ull2inum(cpu_time_ns),
+                        None => Qnil as VALUE,
+                    },
+                );
+                rb_hash_aset(
+                    thread_hash,
+                    rb_id2sym(rb_intern(cstr!("observed_interval"))),
+                    match &thread.observed_interval {
+                        Some(interval) => {
+                            let interval_hash: VALUE = rb_hash_new();
+                            rb_hash_aset(
+                                interval_hash,
+                                rb_id2sym(rb_intern(cstr!("mean_ns"))),
+                                rb_ull2inum(interval.mean_ns),
+                            );
+                            rb_hash_aset(
+                                interval_hash,
+                                rb_id2sym(rb_intern(cstr!("median_ns"))),
+                                rb_ull2inum(interval.median_ns),
+                            );
+                            rb_hash_aset(
+                                interval_hash,
+                                rb_id2sym(rb_intern(cstr!("jitter_ns"))),
+                                rb_ull2inum(interval.jitter_ns),
+                            );
+                            interval_hash
+                        }
+                        None => Qnil as VALUE,
+                    },
+                );
+                rb_ary_push(thread_summary, thread_hash);
+            }
+            rb_hash_aset(
+                metadata_hash,
+                rb_id2sym(rb_intern(cstr!("thread_summary"))),
+                thread_summary,
+            );
+            let interval_changes: VALUE = rb_ary_new();
+            for change in metadata.interval_changes.iter() {
+                let change_hash: VALUE = rb_hash_new();
+                rb_hash_aset(
+                    change_hash,
+                    rb_id2sym(rb_intern(cstr!("elapsed_ns"))),
+                    rb_ull2inum(change.elapsed_ns),
+                );
+                rb_hash_aset(
+                    change_hash,
+                    rb_id2sym(rb_intern(cstr!("interval_ns"))),
+                    rb_ull2inum(change.interval_ns),
+                );
+                rb_hash_aset(
+                    change_hash,
+                    rb_id2sym(rb_intern(cstr!("overhead_pct"))),
+                    rb_float_new(change.overhead_pct),
+                );
+                rb_ary_push(interval_changes, change_hash);
+            }
+            rb_hash_aset(
+                metadata_hash,
+                rb_id2sym(rb_intern(cstr!("interval_changes"))),
+                interval_changes,
+            );
+            if !metadata.gc_pauses.is_empty() {
+                let gc_pauses: VALUE = rb_ary_new();
+                for pause in metadata.gc_pauses.iter() {
+                    let pause_hash: VALUE = rb_hash_new();
+                    rb_hash_aset(
+                        pause_hash,
+                        rb_id2sym(rb_intern(cstr!("elapsed_ns"))),
+                        rb_ull2inum(pause.elapsed_ns),
+                    );
+                    rb_hash_aset(
+                        pause_hash,
+                        rb_id2sym(rb_intern(cstr!("duration_ns"))),
+                        rb_ull2inum(pause.duration_ns),
+                    );
+                    rb_ary_push(gc_pauses, pause_hash);
+                }
+                rb_hash_aset(
+                    metadata_hash,
+                    rb_id2sym(rb_intern(cstr!("gc_pauses"))),
+                    gc_pauses,
+                );
+            }
+            let labels: VALUE = rb_hash_new();
+            for (key, value) in metadata.labels.iter() {
+                rb_hash_aset(
+                    labels,
+                    rb_str_from_bytes(key.as_bytes()),
+                    rb_str_from_bytes(value.as_bytes()),
+                );
+            }
+            rb_hash_aset(metadata_hash, rb_id2sym(rb_intern(cstr!("labels"))), labels);
+            rb_hash_aset(
+                metadata_hash,
+                rb_id2sym(rb_intern(cstr!("trigger"))),
+                rb_id2sym(rb_intern2(
+                    metadata.trigger.as_ptr() as *const c_char,
+                    metadata.trigger.len() as c_long,
+                )),
+            );
+            rb_hash_aset(
+                metadata_hash,
+                rb_id2sym(rb_intern(cstr!("truncated_sample_count"))),
+                rb_ull2inum(metadata.truncated_sample_count),
+            );
+            rb_hash_aset(
+                metadata_hash,
+                rb_id2sym(rb_intern(cstr!("overflow_frame_count"))),
+                rb_ull2inum(metadata.overflow_frame_count),
+            );
+            let breakdown = &metadata.state_breakdown;
+            let state_breakdown_hash: VALUE = rb_hash_new();
+            for (key, count) in [
+                (cstr!("total"), breakdown.total),
+                (cstr!("running"), breakdown.running),
+                (cstr!("gc"), breakdown.gc),
+                (cstr!("idle"), breakdown.idle),
+                (cstr!("unknown"), breakdown.unknown),
+                (
+                    cstr!("dropped_by_lock_contention"),
+                    breakdown.dropped_by_lock_contention,
+                ),
+                (
+                    cstr!("dropped_by_full_buffer"),
+                    breakdown.dropped_by_full_buffer,
+                ),
+            ] {
+                rb_hash_aset(
+                    state_breakdown_hash,
+                    rb_id2sym(rb_intern(key)),
+                    rb_ull2inum(count),
+                );
+            }
+            for (key, fraction) in [
+                (cstr!("running_fraction"), breakdown.running_fraction),
+                (cstr!("gc_fraction"), breakdown.gc_fraction),
+                (cstr!("idle_fraction"), breakdown.idle_fraction),
+                (cstr!("dropped_fraction"), breakdown.dropped_fraction),
+            ] {
+                rb_hash_aset(
+                    state_breakdown_hash,
+                    rb_id2sym(rb_intern(key)),
+                    rb_float_new(fraction),
+                );
+            }
+            rb_hash_aset(
+                metadata_hash,
+                rb_id2sym(rb_intern(cstr!("state_breakdown"))),
+                state_breakdown_hash,
+            );
+            if let Some(calls_per_sample) = metadata.calls_per_sample {
+                rb_hash_aset(
+                    metadata_hash,
+                    rb_id2sym(rb_intern(cstr!("calls_per_sample"))),
+                    rb_ull2inum(calls_per_sample),
+                );
+            }
+            if let Some(sample_probability) = metadata.sample_probability {
+                rb_hash_aset(
+                    metadata_hash,
+                    rb_id2sym(rb_intern(cstr!("sample_probability"))),
+                    rb_float_new(sample_probability),
+                );
+            }
+            rb_hash_aset(hash, rb_id2sym(rb_intern(cstr!("metadata"))), metadata_hash);
+
             // profile[:samples]
             let samples: VALUE = rb_ary_new();
             for sample in self.profile.samples.iter() {
@@ -248,11 +1332,141 @@ impl ProfileSerializer2 {
                     rb_id2sym(rb_intern(cstr!("ruby_thread_id"))),
                     ruby_thread_id,
                 );
+                // sample[:ruby_ractor_id]
+                rb_hash_aset(
+                    sample_hash,
+                    rb_id2sym(rb_intern(cstr!("ruby_ractor_id"))),
+                    if let Some(ruby_ractor_id) = sample.ruby_ractor_id {
+                        rb_ull2inum(ruby_ractor_id)
+                    } else {
+                        Qnil as VALUE
+                    },
+                );
+                // sample[:fiber_id]
+                rb_hash_aset(
+                    sample_hash,
+                    rb_id2sym(rb_intern(cstr!("fiber_id"))),
+                    rb_ull2inum(sample.fiber_id),
+                );
+                // sample[:elapsed_ns]
+                rb_hash_aset(
+                    sample_hash,
+                    rb_id2sym(rb_intern(cstr!("elapsed_ns"))),
+                    rb_int2inum(sample.elapsed_ns as isize),
+                );
+                // sample[:stack_index]
+                if let Some(stack_index) = sample.stack_index {
+                    rb_hash_aset(
+                        sample_hash,
+                        rb_id2sym(rb_intern(cstr!("stack_index"))),
+                        rb_int2inum(stack_index as isize),
+                    );
+                }
+                // sample[:clock_ns]
+                rb_hash_aset(
+                    sample_hash,
+                    rb_id2sym(rb_intern(cstr!("clock_ns"))),
+                    rb_ull2inum(sample.clock_ns),
+                );
+                // sample[:weight_ns]
+                if let Some(weight_ns) = sample.weight_ns {
+                    rb_hash_aset(
+                        sample_hash,
+                        rb_id2sym(rb_intern(cstr!("weight_ns"))),
+                        rb_int2inum(weight_ns as isize),
+                    );
+                }
+                // sample[:omitted_frames]
+                if let Some(omitted_frames) = sample.omitted_frames {
+                    rb_hash_aset(
+                        sample_hash,
+                        rb_id2sym(rb_intern(cstr!("omitted_frames"))),
+                        rb_ull2inum(omitted_frames as u64),
+                    );
+                }
+                // sample[:collapsed_frames]
+                if let Some(collapsed_frames) = sample.collapsed_frames {
+                    rb_hash_aset(
+                        sample_hash,
+                        rb_id2sym(rb_intern(cstr!("collapsed_frames"))),
+                        rb_ull2inum(collapsed_frames as u64),
+                    );
+                }
+                // sample[:count]
+                if let Some(count) = sample.count {
+                    rb_hash_aset(
+                        sample_hash,
+                        rb_id2sym(rb_intern(cstr!("count"))),
+                        rb_ull2inum(count),
+                    );
+                }
+                // sample[:end_elapsed_ns]
+                if let Some(end_elapsed_ns) = sample.end_elapsed_ns {
+                    rb_hash_aset(
+                        sample_hash,
+                        rb_id2sym(rb_intern(cstr!("end_elapsed_ns"))),
+                        rb_ull2inum(end_elapsed_ns),
+                    );
+                }
+                // sample[:delta]
+                if let Some(delta) = sample.delta {
+                    rb_hash_aset(
+                        sample_hash,
+                        rb_id2sym(rb_intern(cstr!("delta"))),
+                        rb_ll2inum(delta),
+                    );
+                }
+                // sample[:during_gc]
+                rb_hash_aset(
+                    sample_hash,
+                    rb_id2sym(rb_intern(cstr!("during_gc"))),
+                    if sample.during_gc {
+                        Qtrue as VALUE
+                    } else {
+                        Qfalse as VALUE
+                    },
+                );
+                // sample[:state]
+                if let Some(state) = sample.state {
+                    rb_hash_aset(
+                        sample_hash,
+                        rb_id2sym(rb_intern(cstr!("state"))),
+                        rb_id2sym(rb_intern2(
+                            state.as_str().as_ptr() as *const c_char,
+                            state.as_str().len() as c_long,
+                        )),
+                    );
+                }
+                // sample[:on_cpu]
+                if let Some(on_cpu) = sample.on_cpu {
+                    rb_hash_aset(
+                        sample_hash,
+                        rb_id2sym(rb_intern(cstr!("on_cpu"))),
+                        if on_cpu {
+                            Qtrue as VALUE
+                        } else {
+                            Qfalse as VALUE
+                        },
+                    );
+                }
 
                 rb_ary_push(samples, sample_hash);
             }
             rb_hash_aset(hash, rb_id2sym(rb_intern(cstr!("samples"))), samples);
 
+            // profile[:stacks]
+            if let Some(stacks) = &self.profile.stacks {
+                let stacks_ary = rb_ary_new();
+                for stack in stacks.iter() {
+                    let stack_ary = rb_ary_new();
+                    for &location_index in stack.iter() {
+                        rb_ary_push(stack_ary, rb_int2inum(location_index as isize));
+                    }
+                    rb_ary_push(stacks_ary, stack_ary);
+                }
+                rb_hash_aset(hash, rb_id2sym(rb_intern(cstr!("stacks"))), stacks_ary);
+            }
+
             // profile[:locations]
             let locations = rb_ary_new();
             for location in self.profile.locations.iter() {
@@ -279,6 +1493,25 @@ impl ProfileSerializer2 {
                         Qnil as VALUE
                     },
                 );
+                // location[:stats]
+                if let Some(stats) = location.stats {
+                    let stats_hash: VALUE = rb_hash_new();
+                    rb_hash_aset(
+                        stats_hash,
+                        rb_id2sym(rb_intern(cstr!("exclusive"))),
+                        rb_ull2inum(stats.exclusive),
+                    );
+                    rb_hash_aset(
+                        stats_hash,
+                        rb_id2sym(rb_intern(cstr!("inclusive"))),
+                        rb_ull2inum(stats.inclusive),
+                    );
+                    rb_hash_aset(
+                        location_hash,
+                        rb_id2sym(rb_intern(cstr!("stats"))),
+                        stats_hash,
+                    );
+                }
                 rb_ary_push(locations, location_hash);
             }
             rb_hash_aset(hash, rb_id2sym(rb_intern(cstr!("locations"))), locations);
@@ -293,28 +1526,51 @@ impl ProfileSerializer2 {
                     rb_id2sym(rb_intern(cstr!("implementation"))),
                     match function.implementation {
                         FunctionImplementation::Ruby => rb_id2sym(rb_intern(cstr!("ruby"))),
+                        FunctionImplementation::CFunc => rb_id2sym(rb_intern(cstr!("cfunc"))),
                         FunctionImplementation::Native => rb_id2sym(rb_intern(cstr!("native"))),
                     },
                 );
 
                 // function[:name]
-                let name: VALUE = match &function.name {
-                    Some(name) => {
-                        let cstring = CString::new(name.as_str()).unwrap();
-                        rb_str_new_cstr(cstring.as_ptr())
-                    }
-                    None => Qnil as VALUE,
-                };
-                rb_hash_aset(function_hash, rb_id2sym(rb_intern(cstr!("name"))), name);
+                rb_hash_aset(
+                    function_hash,
+                    rb_id2sym(rb_intern(cstr!("name"))),
+                    if let Some(name) = function.name {
+                        rb_int2inum(name as isize)
+                    } else {
+                        Qnil as VALUE
+                    },
+                );
                 // function[:filename]
-                let filename: VALUE = match &function.filename {
-                    Some(filename) => {
-                        let cstring = CString::new(filename.as_str()).unwrap();
-                        rb_str_new_cstr(cstring.as_ptr())
-                    }
-                    None => Qnil as VALUE,
-                };
-                rb_hash_aset(function_hash, rb_id2sym(rb_intern(cstr!("filename"))), filename);
+                rb_hash_aset(
+                    function_hash,
+                    rb_id2sym(rb_intern(cstr!("filename"))),
+                    if let Some(filename) = function.filename {
+                        rb_int2inum(filename as isize)
+                    } else {
+                        Qnil as VALUE
+                    },
+                );
+                // function[:class_path]
+                rb_hash_aset(
+                    function_hash,
+                    rb_id2sym(rb_intern(cstr!("class_path"))),
+                    if let Some(class_path) = function.class_path {
+                        rb_int2inum(class_path as isize)
+                    } else {
+                        Qnil as VALUE
+                    },
+                );
+                // function[:method_name]
+                rb_hash_aset(
+                    function_hash,
+                    rb_id2sym(rb_intern(cstr!("method_name"))),
+                    if let Some(method_name) = function.method_name {
+                        rb_int2inum(method_name as isize)
+                    } else {
+                        Qnil as VALUE
+                    },
+                );
                 // function[:start_lineno]
                 rb_hash_aset(
                     function_hash,
@@ -335,11 +1591,376 @@ impl ProfileSerializer2 {
                         Qnil as VALUE
                     },
                 );
+                // function[:jit]
+                rb_hash_aset(
+                    function_hash,
+                    rb_id2sym(rb_intern(cstr!("jit"))),
+                    if function.jit {
+                        Qtrue as VALUE
+                    } else {
+                        Qfalse as VALUE
+                    },
+                );
+                // function[:eval]
+                rb_hash_aset(
+                    function_hash,
+                    rb_id2sym(rb_intern(cstr!("eval"))),
+                    if function.eval {
+                        Qtrue as VALUE
+                    } else {
+                        Qfalse as VALUE
+                    },
+                );
+                // function[:category]
+                rb_hash_aset(
+                    function_hash,
+                    rb_id2sym(rb_intern(cstr!("category"))),
+                    if let Some(category) = function.category {
+                        rb_int2inum(category as isize)
+                    } else {
+                        Qnil as VALUE
+                    },
+                );
+                // function[:mapping]
+                rb_hash_aset(
+                    function_hash,
+                    rb_id2sym(rb_intern(cstr!("mapping"))),
+                    if let Some(mapping) = function.mapping {
+                        rb_int2inum(mapping as isize)
+                    } else {
+                        Qnil as VALUE
+                    },
+                );
                 rb_ary_push(functions, function_hash);
             }
             rb_hash_aset(hash, rb_id2sym(rb_intern(cstr!("functions"))), functions);
 
+            // profile[:strings]
+            let strings = rb_ary_new();
+            for string in self.profile.strings.iter() {
+                rb_ary_push(strings, rb_str_from_bytes(string.as_bytes()));
+            }
+            rb_hash_aset(hash, rb_id2sym(rb_intern(cstr!("strings"))), strings);
+
+            // profile[:sample_types]
+            let sample_types = rb_ary_new();
+            for sample_type in self.profile.sample_types.iter() {
+                let sample_type_hash = rb_hash_new();
+                rb_hash_aset(
+                    sample_type_hash,
+                    rb_id2sym(rb_intern(cstr!("name"))),
+                    rb_str_from_bytes(sample_type.name.as_bytes()),
+                );
+                rb_hash_aset(
+                    sample_type_hash,
+                    rb_id2sym(rb_intern(cstr!("unit"))),
+                    rb_str_from_bytes(sample_type.unit.as_bytes()),
+                );
+                rb_ary_push(sample_types, sample_type_hash);
+            }
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("sample_types"))),
+                sample_types,
+            );
+
+            // profile[:markers]
+            if !self.profile.markers.is_empty() {
+                let markers = rb_ary_new();
+                for marker in self.profile.markers.iter() {
+                    let marker_hash = rb_hash_new();
+                    rb_hash_aset(
+                        marker_hash,
+                        rb_id2sym(rb_intern(cstr!("elapsed_ns"))),
+                        rb_ull2inum(marker.elapsed_ns),
+                    );
+                    rb_hash_aset(
+                        marker_hash,
+                        rb_id2sym(rb_intern(cstr!("label"))),
+                        rb_str_from_bytes(marker.label.as_bytes()),
+                    );
+                    rb_hash_aset(
+                        marker_hash,
+                        rb_id2sym(rb_intern(cstr!("ruby_thread_id"))),
+                        rb_ull2inum(marker.ruby_thread_id),
+                    );
+                    rb_ary_push(markers, marker_hash);
+                }
+                rb_hash_aset(hash, rb_id2sym(rb_intern(cstr!("markers"))), markers);
+            }
+
+            // profile[:line_hits]
+            if !self.profile.line_hits.is_empty() {
+                let line_hits = rb_ary_new();
+                for line_hit in self.profile.line_hits.iter() {
+                    let line_hit_hash = rb_hash_new();
+                    rb_hash_aset(
+                        line_hit_hash,
+                        rb_id2sym(rb_intern(cstr!("filename"))),
+                        if let Some(filename) = line_hit.filename {
+                            rb_int2inum(filename as isize)
+                        } else {
+                            Qnil as VALUE
+                        },
+                    );
+                    rb_hash_aset(
+                        line_hit_hash,
+                        rb_id2sym(rb_intern(cstr!("lineno"))),
+                        rb_int2inum(line_hit.lineno as isize),
+                    );
+                    rb_hash_aset(
+                        line_hit_hash,
+                        rb_id2sym(rb_intern(cstr!("exclusive"))),
+                        rb_ull2inum(line_hit.exclusive),
+                    );
+                    rb_hash_aset(
+                        line_hit_hash,
+                        rb_id2sym(rb_intern(cstr!("inclusive"))),
+                        rb_ull2inum(line_hit.inclusive),
+                    );
+                    rb_ary_push(line_hits, line_hit_hash);
+                }
+                rb_hash_aset(hash, rb_id2sym(rb_intern(cstr!("line_hits"))), line_hits);
+            }
+
             hash
         }
     }
+
+    /// Build a Ruby Array with a Hash for each sample, holding the labels (`frames`) and line
+    /// numbers (`linenos`) of its Ruby stack (leaf first), `ruby_thread_id` and `elapsed_ns`.
+    ///
+    /// Each function's label is converted into a (frozen) Ruby String once, and shared by
+    /// every sample referring to it.
+    pub fn to_ruby_samples(&self) -> VALUE {
+        let mut labels: Vec<Option<VALUE>> = vec![None; self.profile.functions.len()];
+        unsafe {
+            let samples = rb_ary_new_capa(self.profile.samples.len() as c_long);
+            for sample in self.profile.samples.iter() {
+                let frames = rb_ary_new_capa(sample.stack.len() as c_long);
+                let linenos = rb_ary_new_capa(sample.stack.len() as c_long);
+                for &location_index in sample.stack.iter() {
+                    let location = &self.profile.locations[location_index];
+                    let label = *labels[location.function_index].get_or_insert_with(|| {
+                        let function = &self.profile.functions[location.function_index];
+                        match function.name {
+                            Some(name) => rb_obj_freeze(rb_str_from_bytes(
+                                self.profile.strings[name].as_bytes(),
+                            )),
+                            None => Qnil as VALUE,
+                        }
+                    });
+                    rb_ary_push(frames, label);
+                    rb_ary_push(linenos, rb_int2inum(location.lineno as isize));
+                }
+
+                let sample_hash = rb_hash_new();
+                rb_hash_aset(sample_hash, rb_id2sym(rb_intern(cstr!("frames"))), frames);
+                rb_hash_aset(sample_hash, rb_id2sym(rb_intern(cstr!("linenos"))), linenos);
+                rb_hash_aset(
+                    sample_hash,
+                    rb_id2sym(rb_intern(cstr!("ruby_thread_id"))),
+                    match sample.ruby_thread_id {
+                        Some(ruby_thread_id) => rb_ull2inum(ruby_thread_id),
+                        None => Qnil as VALUE,
+                    },
+                );
+                rb_hash_aset(
+                    sample_hash,
+                    rb_id2sym(rb_intern(cstr!("elapsed_ns"))),
+                    rb_ull2inum(sample.elapsed_ns),
+                );
+                rb_ary_push(samples, sample_hash);
+            }
+            samples
+        }
+    }
+}
+
+/// Stable-sort `items` using `compare`, and return a table mapping old indices to new indices.
+fn sort_with_remap<T>(items: &mut Vec<T>, compare: impl Fn(&T, &T) -> Ordering) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..items.len()).collect();
+    order.sort_by(|&a, &b| compare(&items[a], &items[b]));
+
+    let mut remap = vec![0; items.len()];
+    for (new_index, &old_index) in order.iter().enumerate() {
+        remap[old_index] = new_index;
+    }
+
+    let mut old_items: Vec<Option<T>> = items.drain(..).map(Some).collect();
+    items.extend(order.iter().map(|&i| old_items[i].take().unwrap()));
+    remap
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::configuration::{ConfigurationBuilder, Pattern};
+
+    fn function(implementation: FunctionImplementation, start_address: Option<usize>) -> Function {
+        Function {
+            implementation,
+            name: None,
+            filename: None,
+            class_path: None,
+            method_name: None,
+            start_lineno: None,
+            start_address,
+            jit: false,
+            eval: false,
+            category: None,
+            mapping: None,
+        }
+    }
+
+    #[test]
+    fn test_location_index_for_ruby_and_native_frames() {
+        let configuration = ConfigurationBuilder::new().build().unwrap();
+        let mut serializer = ProfileSerializer2::new(&configuration);
+        let ruby = serializer.function_index_for(function(FunctionImplementation::Ruby, None));
+        let native =
+            serializer.function_index_for(function(FunctionImplementation::Native, Some(0x1000)));
+
+        // Ruby locations are told apart by line
+        let line1 = serializer.location_index_for(ruby, 1, None);
+        let line2 = serializer.location_index_for(ruby, 2, None);
+        assert_ne!(line1, line2);
+        assert_eq!(serializer.location_index_for(ruby, 1, None), line1);
+
+        // Native locations have no line, and are told apart by address
+        let pc1 = serializer.location_index_for(native, 0, Some(0x1010));
+        let pc2 = serializer.location_index_for(native, 0, Some(0x1020));
+        assert_ne!(pc1, pc2);
+        assert_eq!(serializer.location_index_for(native, 0, Some(0x1010)), pc1);
+
+        assert_eq!(serializer.profile.locations.len(), 4);
+        assert_eq!(serializer.profile.locations[pc2].address, Some(0x1020));
+        assert_eq!(serializer.profile.locations[line2].address, None);
+    }
+
+    #[test]
+    fn test_serialize_resolved() {
+        let configuration = ConfigurationBuilder::new()
+            .exclude_paths(vec![Pattern::Substring("/gems/".to_owned())])
+            .build()
+            .unwrap();
+        let method = ResolvedFrame {
+            full_label: Some("Foo#bar".to_owned()),
+            path: Some("app/foo.rb".to_owned()),
+            class_path: Some("Foo".to_owned()),
+            method_name: Some("bar".to_owned()),
+            first_lineno: Some(10),
+            cfunc_address: None,
+            lineno: 12,
+        };
+        let cfunc = ResolvedFrame {
+            full_label: Some("Array#each".to_owned()),
+            ..ResolvedFrame::default()
+        };
+        let gem = ResolvedFrame {
+            full_label: Some("Rack#call".to_owned()),
+            path: Some("/gems/rack.rb".to_owned()),
+            ..ResolvedFrame::default()
+        };
+        let samples = [
+            ResolvedSample {
+                frames: vec![cfunc, method.clone(), gem.clone()],
+                ruby_thread_id: Some(1),
+                elapsed_ns: 1_000,
+            },
+            ResolvedSample {
+                frames: vec![gem],
+                ruby_thread_id: Some(1),
+                elapsed_ns: 2_000,
+            },
+            ResolvedSample {
+                frames: vec![],
+                ruby_thread_id: Some(2),
+                elapsed_ns: 3_000,
+            },
+        ];
+
+        let mut serializer = ProfileSerializer2::new(&configuration);
+        serializer.serialize_resolved(&samples);
+        let profile = serializer.profile();
+        let names: Vec<Vec<&str>> = profile
+            .samples
+            .iter()
+            .map(|sample| {
+                sample
+                    .stack
+                    .iter()
+                    .map(|&location| {
+                        let function =
+                            &profile.functions[profile.locations[location].function_index];
+                        profile.strings[function.name.unwrap()].as_str()
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                vec!["Array#each", "Foo#bar"],
+                vec!["(filtered)"],
+                vec!["(no Ruby frames)"],
+            ]
+        );
+
+        let cfunc =
+            &profile.functions[profile.locations[profile.samples[0].stack[0]].function_index];
+        assert!(cfunc.implementation == FunctionImplementation::CFunc);
+        let method = &profile.locations[profile.samples[0].stack[1]];
+        assert_eq!(method.lineno, 12);
+        assert_eq!(
+            profile.functions[method.function_index].start_lineno,
+            Some(10)
+        );
+        assert_eq!(profile.duration_ns, 3_000);
+        assert_eq!(profile.metadata.thread_summary.len(), 2);
+        assert_eq!(profile.metadata.thread_summary[0].sample_count, 2);
+    }
+
+    #[test]
+    fn test_functions_beyond_max_functions_are_folded() {
+        let configuration = ConfigurationBuilder::new()
+            .max_functions(Some(2))
+            .build()
+            .unwrap();
+        let frame = |label: &str| ResolvedFrame {
+            full_label: Some(label.to_owned()),
+            path: Some("app.rb".to_owned()),
+            lineno: 1,
+            ..ResolvedFrame::default()
+        };
+        let samples: Vec<ResolvedSample> = ["a", "b", "c", "d", "a"]
+            .iter()
+            .map(|&label| ResolvedSample {
+                frames: vec![frame(label)],
+                ruby_thread_id: Some(1),
+                elapsed_ns: 0,
+            })
+            .collect();
+
+        let mut serializer = ProfileSerializer2::new(&configuration);
+        serializer.serialize_resolved(&samples);
+        let profile = serializer.profile();
+        let names: Vec<&str> = profile
+            .samples
+            .iter()
+            .map(|sample| {
+                let function =
+                    &profile.functions[profile.locations[sample.stack[0]].function_index];
+                profile.strings[function.name.unwrap()].as_str()
+            })
+            .collect();
+        assert_eq!(names, vec!["a", "b", "(overflow)", "(overflow)", "a"]);
+        assert_eq!(profile.metadata.overflow_frame_count, 2);
+        // Names of folded functions are not kept either
+        assert_eq!(profile.functions.len(), 3);
+        assert!(!profile
+            .strings
+            .iter()
+            .any(|string| string == "c" || string == "d"));
+    }
 }
@@ -0,0 +1,40 @@
+use std::collections::HashMap;
+
+use super::profile::{LocationIndex, Profile};
+
+impl Profile {
+    /// Move Ruby stacks into the shared `stacks` table, so that identical stacks are stored once.
+    ///
+    /// Each sample's `stack` is emptied and `stack_index` refers to its entry in `stacks`.
+    /// Does nothing if the stacks have already been deduplicated.
+    pub fn dedup_stacks(&mut self) {
+        if self.stacks.is_some() {
+            return;
+        }
+
+        let mut stacks: Vec<Vec<LocationIndex>> = vec![];
+        let mut stack_indices: HashMap<Vec<LocationIndex>, usize> = HashMap::new();
+        for sample in self.samples.iter_mut() {
+            let stack = std::mem::take(&mut sample.stack);
+            let index = *stack_indices.entry(stack).or_insert_with_key(|stack| {
+                stacks.push(stack.clone());
+                stacks.len() - 1
+            });
+            sample.stack_index = Some(index);
+        }
+        self.stacks = Some(stacks);
+    }
+
+    /// The reverse of `dedup_stacks`: copy stacks back into each sample.
+    pub fn expand_stacks(&mut self) {
+        let stacks = match self.stacks.take() {
+            Some(stacks) => stacks,
+            None => return,
+        };
+        for sample in self.samples.iter_mut() {
+            if let Some(index) = sample.stack_index.take() {
+                sample.stack = stacks[index].clone();
+            }
+        }
+    }
+}
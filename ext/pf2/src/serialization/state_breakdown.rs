@@ -0,0 +1,96 @@
+use super::profile::{Profile, StateBreakdown, ThreadState};
+
+impl Profile {
+    /// Fill in `Metadata.state_breakdown` from the states of `samples` and the dropped sample
+    /// counts in `Metadata.overhead`.
+    pub fn measure_state_breakdown(&mut self) {
+        let mut breakdown = StateBreakdown {
+            dropped_by_lock_contention: self.metadata.overhead.dropped_by_lock_contention,
+            dropped_by_full_buffer: self.metadata.overhead.dropped_by_full_buffer,
+            ..StateBreakdown::default()
+        };
+        for sample in self.samples.iter() {
+            let count = sample.sample_count();
+            match sample.state {
+                Some(ThreadState::Running) => breakdown.running += count,
+                Some(ThreadState::Gc) => breakdown.gc += count,
+                Some(ThreadState::Sleeping) => breakdown.idle += count,
+                None => breakdown.unknown += count,
+            }
+        }
+        breakdown.total = breakdown.running
+            + breakdown.gc
+            + breakdown.idle
+            + breakdown.unknown
+            + breakdown.dropped_by_lock_contention
+            + breakdown.dropped_by_full_buffer;
+
+        let fraction = |count: u64| match breakdown.total {
+            0 => 0.0,
+            total => count as f64 / total as f64,
+        };
+        breakdown.running_fraction = fraction(breakdown.running);
+        breakdown.gc_fraction = fraction(breakdown.gc);
+        breakdown.idle_fraction = fraction(breakdown.idle);
+        breakdown.dropped_fraction =
+            fraction(breakdown.dropped_by_lock_contention + breakdown.dropped_by_full_buffer);
+        self.metadata.state_breakdown = breakdown;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::profile::{Metadata, Overhead, Sample};
+
+    fn sample(state: Option<ThreadState>) -> Sample {
+        Sample {
+            during_gc: state == Some(ThreadState::Gc),
+            state,
+            ..Sample::with_stack(vec![])
+        }
+    }
+
+    #[test]
+    fn test_measure_state_breakdown() {
+        let mut profile = Profile {
+            schema_version: 0,
+            stacks: None,
+            locations: vec![],
+            functions: vec![],
+            strings: vec![],
+            start_timestamp_ns: 0,
+            duration_ns: 0,
+            metadata: Metadata {
+                overhead: Overhead {
+                    dropped_by_lock_contention: 1,
+                    ..Overhead::default()
+                },
+                ..Metadata::default()
+            },
+            sample_types: vec![],
+            samples: vec![
+                sample(Some(ThreadState::Running)),
+                Sample {
+                    count: Some(3),
+                    ..sample(Some(ThreadState::Running))
+                },
+                sample(Some(ThreadState::Gc)),
+                sample(Some(ThreadState::Sleeping)),
+                sample(None),
+            ],
+            markers: vec![],
+            line_hits: vec![],
+        };
+        profile.measure_state_breakdown();
+
+        let breakdown = &profile.metadata.state_breakdown;
+        assert_eq!(breakdown.total, 8);
+        assert_eq!(breakdown.running, 4);
+        assert_eq!(breakdown.gc, 1);
+        assert_eq!(breakdown.idle, 1);
+        assert_eq!(breakdown.unknown, 1);
+        assert_eq!(breakdown.running_fraction, 0.5);
+        assert_eq!(breakdown.dropped_fraction, 0.125);
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+
+use super::profile::{FunctionIndex, Profile};
+
+/// A row of the flat "top functions" table.
+///
+/// `self` counts the samples in which the function was the leaf of the Ruby stack,
+/// and `total` counts the samples in which it appeared anywhere in the Ruby stack
+/// (recursive calls are counted once per sample).
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FunctionSummary {
+    pub function: String,
+    pub filename: Option<String>,
+    #[serde(rename = "self")]
+    pub self_samples: u64,
+    #[serde(rename = "total")]
+    pub total_samples: u64,
+    pub self_ns: u64,
+    pub total_ns: u64,
+}
+
+impl FunctionSummary {
+    /// Aggregate samples per function, sorted by self time (then total time) in descending order.
+    pub fn build(profile: &Profile) -> Vec<Self> {
+        let mut rows: HashMap<FunctionIndex, Self> = HashMap::new();
+        let mut seen: HashSet<FunctionIndex> = HashSet::new();
+
+        for sample in profile.samples.iter() {
+            let weight_ns = sample.weight_ns.unwrap_or(0);
+            seen.clear();
+            let stack = match (&profile.stacks, sample.stack_index) {
+                (Some(stacks), Some(stack_index)) => &stacks[stack_index],
+                _ => &sample.stack,
+            };
+            for (depth, &location_index) in stack.iter().enumerate() {
+                let function_index = profile.locations[location_index].function_index;
+                let row = rows
+                    .entry(function_index)
+                    .or_insert_with(|| Self::new(profile, function_index));
+                if depth == 0 {
+                    row.self_samples += sample.sample_count();
+                    row.self_ns += weight_ns;
+                }
+                if seen.insert(function_index) {
+                    row.total_samples += sample.sample_count();
+                    row.total_ns += weight_ns;
+                }
+            }
+        }
+
+        let mut rows: Vec<Self> = rows.into_values().collect();
+        rows.sort_by(|a, b| {
+            b.self_samples
+                .cmp(&a.self_samples)
+                .then(b.total_samples.cmp(&a.total_samples))
+                .then_with(|| a.function.cmp(&b.function))
+        });
+        rows
+    }
+
+    fn new(profile: &Profile, function_index: FunctionIndex) -> Self {
+        let function = &profile.functions[function_index];
+        Self {
+            function: function
+                .name
+                .map(|name| profile.strings[name].clone())
+                .unwrap_or_else(|| "(unknown)".to_owned()),
+            filename: function
+                .filename
+                .map(|filename| profile.strings[filename].clone()),
+            self_samples: 0,
+            total_samples: 0,
+            self_ns: 0,
+            total_ns: 0,
+        }
+    }
+
+    /// Format the rows as a plain text table.
+    pub fn to_text(rows: &[Self], sample_count: usize) -> String {
+        let percentage = |samples: u64| match sample_count {
+            0 => 0.0,
+            n => samples as f64 * 100.0 / n as f64,
+        };
+
+        let mut text = format!(
+            "{:>7} {:>8} {:>7} {:>8}  {}\n",
+            "self%", "self", "total%", "total", "function"
+        );
+        for row in rows {
+            let _ = write!(
+                text,
+                "{:>6.1}% {:>8} {:>6.1}% {:>8}  {}",
+                percentage(row.self_samples),
+                row.self_samples,
+                percentage(row.total_samples),
+                row.total_samples,
+                row.function
+            );
+            if let Some(filename) = &row.filename {
+                let _ = write!(text, " ({})", filename);
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Format the rows like `go tool pprof -top`: self (`flat`) and total (`cum`) sample counts
+    /// with their percentages, and the running sum of `flat%` (`sum%`).
+    /// Columns are as wide as their longest entry, so that the table stays aligned.
+    pub fn to_top(rows: &[Self], sample_count: usize) -> String {
+        let percentage = |samples: u64| match sample_count {
+            0 => 0.0,
+            n => samples as f64 * 100.0 / n as f64,
+        };
+        let count_width = rows
+            .iter()
+            .map(|row| row.total_samples.max(row.self_samples).to_string().len())
+            .chain(["flat".len()])
+            .max()
+            .unwrap_or_default();
+        let function_width = rows
+            .iter()
+            .map(|row| row.function.chars().count())
+            .chain(["function".len()])
+            .max()
+            .unwrap_or_default();
+
+        let mut text = format!(
+            "Showing {} functions, {} samples\n",
+            rows.len(),
+            sample_count
+        );
+        let _ = writeln!(
+            text,
+            "{:>cw$} {:>6} {:>6} {:>cw$} {:>6}  {:<fw$}  file",
+            "flat",
+            "flat%",
+            "sum%",
+            "cum",
+            "cum%",
+            "function",
+            cw = count_width,
+            fw = function_width
+        );
+        let mut sum = 0.0;
+        for row in rows {
+            sum += percentage(row.self_samples);
+            let line = format!(
+                "{:>cw$} {:>5.1}% {:>5.1}% {:>cw$} {:>5.1}%  {:<fw$}  {}",
+                row.self_samples,
+                percentage(row.self_samples),
+                sum,
+                row.total_samples,
+                percentage(row.total_samples),
+                row.function,
+                row.filename.as_deref().unwrap_or(""),
+                cw = count_width,
+                fw = function_width
+            );
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        text
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::{HashMap, HashSet};
+
+use super::merge::ProfileMerger;
+use super::profile::{LocationIndex, Profile, Sample};
+
+impl Profile {
+    /// Keep only the samples (and markers) of the threads in `ruby_thread_ids`, e.g. to export
+    /// one thread of a multi-thread profile.
+    ///
+    /// Functions, locations and strings no longer referenced are pruned. Per-thread metadata
+    /// (`thread_summary`, including thread names, and `thread_cpu_times`) is narrowed down to
+    /// the same threads, and the state breakdown is measured again. Keeping no sample at all
+    /// yields an empty but well-formed profile.
+    pub fn retain_threads(&self, ruby_thread_ids: &HashSet<u64>) -> Profile {
+        let retained = |ruby_thread_id: u64| ruby_thread_ids.contains(&ruby_thread_id);
+
+        let mut merger = ProfileMerger::default();
+        let mut location_indices: HashMap<LocationIndex, LocationIndex> = HashMap::new();
+        let mut remap = |stack: &[LocationIndex]| -> Vec<LocationIndex> {
+            stack
+                .iter()
+                .map(|&index| {
+                    *location_indices
+                        .entry(index)
+                        .or_insert_with(|| merger.location_index_for(self, &self.locations[index]))
+                })
+                .collect()
+        };
+
+        let samples: Vec<Sample> = self
+            .samples
+            .iter()
+            .filter(|sample| sample.ruby_thread_id.is_some_and(retained))
+            .map(|sample| Sample {
+                stack: remap(&sample.stack),
+                native_stack: remap(&sample.native_stack),
+                ..sample.clone()
+            })
+            .collect();
+
+        let mut metadata = self.metadata.clone();
+        metadata
+            .thread_summary
+            .retain(|thread| retained(thread.ruby_thread_id));
+        metadata
+            .thread_cpu_times
+            .retain(|thread_cpu_time| retained(thread_cpu_time.ruby_thread_id));
+        metadata.total_thread_cpu_time_ns = metadata
+            .thread_cpu_times
+            .iter()
+            .filter_map(|thread_cpu_time| thread_cpu_time.cpu_time_ns)
+            .sum();
+
+        let mut filtered = Profile {
+            samples,
+            stacks: None,
+            locations: merger.locations,
+            functions: merger.functions,
+            strings: merger.strings,
+            metadata,
+            sample_types: self.sample_types.clone(),
+            markers: self
+                .markers
+                .iter()
+                .filter(|marker| retained(marker.ruby_thread_id))
+                .cloned()
+                .collect(),
+            line_hits: vec![],
+            ..*self
+        };
+        filtered.measure_state_breakdown();
+        filtered
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+
+use super::profile::{IntervalStats, Profile};
+
+impl Profile {
+    /// Recount `sample_count` of each entry in `Metadata.thread_summary` from `samples`,
+    /// e.g. after samples have been added or removed.
+    pub fn recount_thread_samples(&mut self) {
+        let mut counts: HashMap<u64, u64> = HashMap::new();
+        for sample in self.samples.iter() {
+            if let Some(ruby_thread_id) = sample.ruby_thread_id {
+                *counts.entry(ruby_thread_id).or_default() += sample.sample_count();
+            }
+        }
+        for thread in self.metadata.thread_summary.iter_mut() {
+            thread.sample_count = counts.get(&thread.ruby_thread_id).copied().unwrap_or(0);
+        }
+    }
+
+    /// Fill in `observed_interval` of each entry in `Metadata.thread_summary` from the
+    /// timestamps of consecutive samples of the thread.
+    ///
+    /// Meant to be called on freshly serialized profiles, since removing samples
+    /// (e.g. by downsampling) widens the gaps between the remaining ones.
+    pub fn measure_sampling_intervals(&mut self) {
+        let mut timestamps: HashMap<u64, Vec<u64>> = HashMap::new();
+        for sample in self.samples.iter() {
+            if let Some(ruby_thread_id) = sample.ruby_thread_id {
+                timestamps
+                    .entry(ruby_thread_id)
+                    .or_default()
+                    .push(sample.elapsed_ns);
+            }
+        }
+        for thread in self.metadata.thread_summary.iter_mut() {
+            thread.observed_interval = timestamps
+                .get_mut(&thread.ruby_thread_id)
+                .and_then(|timestamps| IntervalStats::from_timestamps(timestamps));
+        }
+    }
+}
+
+impl IntervalStats {
+    /// None if there are fewer than two timestamps.
+    fn from_timestamps(timestamps: &mut [u64]) -> Option<Self> {
+        timestamps.sort_unstable();
+        let mut intervals: Vec<u64> = timestamps
+            .windows(2)
+            .map(|pair| pair[1] - pair[0])
+            .collect();
+        if intervals.is_empty() {
+            return None;
+        }
+        intervals.sort_unstable();
+
+        let count = intervals.len() as f64;
+        let mean = intervals.iter().sum::<u64>() as f64 / count;
+        let variance = intervals
+            .iter()
+            .map(|&interval| (interval as f64 - mean).powi(2))
+            .sum::<f64>()
+            / count;
+        let middle = intervals.len() / 2;
+        let median_ns = match intervals.len() % 2 {
+            0 => (intervals[middle - 1] + intervals[middle]) / 2,
+            _ => intervals[middle],
+        };
+
+        Some(Self {
+            mean_ns: mean.round() as u64,
+            median_ns,
+            jitter_ns: variance.sqrt().round() as u64,
+        })
+    }
+}
@@ -0,0 +1,125 @@
+use super::profile::Profile;
+
+impl Profile {
+    /// Check that every index refers to an existing entry of its table, so that a profile given
+    /// by the user (e.g. to `Pf2.diff`) can be processed without panicking.
+    pub fn check_indices(&self) -> Result<(), String> {
+        let check = |kind: &str, index: usize, len: usize| match index < len {
+            true => Ok(()),
+            false => Err(format!(
+                "{} index {} is out of range (only {} entries)",
+                kind, index, len
+            )),
+        };
+
+        for sample in self.samples.iter() {
+            for &location_index in sample.stack.iter().chain(sample.native_stack.iter()) {
+                check("location", location_index, self.locations.len())?;
+            }
+            if let Some(stack_index) = sample.stack_index {
+                let stack_count = self.stacks.as_ref().map_or(0, |stacks| stacks.len());
+                check("stack", stack_index, stack_count)?;
+            }
+        }
+        for stack in self.stacks.iter().flatten() {
+            for &location_index in stack.iter() {
+                check("location", location_index, self.locations.len())?;
+            }
+        }
+        for location in self.locations.iter() {
+            check("function", location.function_index, self.functions.len())?;
+        }
+        for function in self.functions.iter() {
+            let string_indices = [
+                function.name,
+                function.filename,
+                function.class_path,
+                function.method_name,
+                function.category,
+                function.mapping,
+            ];
+            for string_index in string_indices.into_iter().flatten() {
+                check("string", string_index, self.strings.len())?;
+            }
+        }
+        for line_hit in self.line_hits.iter() {
+            if let Some(filename) = line_hit.filename {
+                check("string", filename, self.strings.len())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::serialization::profile::{
+        Function, FunctionImplementation, Location, Metadata, Profile, Sample,
+    };
+
+    fn profile() -> Profile {
+        Profile {
+            schema_version: 0,
+            stacks: None,
+            locations: vec![Location {
+                function_index: 0,
+                lineno: 1,
+                address: None,
+                stats: None,
+            }],
+            functions: vec![Function {
+                implementation: FunctionImplementation::Ruby,
+                name: Some(0),
+                filename: None,
+                class_path: None,
+                method_name: None,
+                start_lineno: None,
+                start_address: None,
+                jit: false,
+                eval: false,
+                category: None,
+                mapping: None,
+            }],
+            strings: vec!["main".to_owned()],
+            start_timestamp_ns: 0,
+            duration_ns: 0,
+            metadata: Metadata::default(),
+            sample_types: vec![],
+            markers: vec![],
+            line_hits: vec![],
+            samples: vec![Sample::with_stack(vec![0])],
+        }
+    }
+
+    #[test]
+    fn test_check_indices() {
+        assert_eq!(profile().check_indices(), Ok(()));
+
+        let mut profile = profile();
+        profile.dedup_stacks();
+        assert_eq!(profile.check_indices(), Ok(()));
+
+        let mut out_of_range = profile.clone();
+        out_of_range.samples[0].stack_index = Some(1);
+        assert!(out_of_range.check_indices().is_err());
+
+        let mut out_of_range = profile.clone();
+        out_of_range.stacks = Some(vec![vec![1]]);
+        assert!(out_of_range.check_indices().is_err());
+
+        let mut out_of_range = profile.clone();
+        out_of_range.samples[0].native_stack = vec![5];
+        assert!(out_of_range.check_indices().is_err());
+
+        let mut out_of_range = profile.clone();
+        out_of_range.locations[0].function_index = 1;
+        assert!(out_of_range.check_indices().is_err());
+
+        let mut out_of_range = profile.clone();
+        out_of_range.functions[0].method_name = Some(1);
+        assert_eq!(
+            out_of_range.check_indices(),
+            Err("string index 1 is out of range (only 1 entries)".to_owned())
+        );
+    }
+}
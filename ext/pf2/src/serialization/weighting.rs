@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use super::profile::Profile;
+
+/// How much time each sample stands for (`Pf2.stop(weighting:)`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Weighting {
+    /// Every sample weighs the sampling interval in effect when it was captured (default)
+    Count,
+    /// Every sample weighs the time until the next sample of the same thread
+    Time,
+}
+
+impl FromStr for Weighting {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "count" => Ok(Self::Count),
+            "time" => Ok(Self::Time),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Time weights are clamped to this many sampling intervals, so that a long gap between two
+/// samples (e.g. a thread descheduled in CPU time mode) is not attributed to a single stack.
+pub const MAX_TIME_WEIGHT_INTERVALS: u64 = 2;
+
+impl Profile {
+    /// Weight each sample by the time elapsed until the next sample of the same thread, instead
+    /// of the sampling interval, to account for samples arriving late or early.
+    ///
+    /// Weights are clamped to `MAX_TIME_WEIGHT_INTERVALS` times the interval. The last sample of
+    /// each thread has no next sample, and keeps the interval as its weight. Samples without a
+    /// weight (e.g. with `trigger: :calls`) are left as they are.
+    pub fn weight_by_elapsed_time(&mut self) {
+        // The elapsed_ns of the next sample of each thread
+        let mut next_elapsed_ns: HashMap<Option<u64>, u64> = HashMap::new();
+        for sample in self.samples.iter_mut().rev() {
+            let interval_ns = match sample.weight_ns {
+                Some(weight_ns) => weight_ns,
+                None => continue,
+            };
+            if let Some(next) = next_elapsed_ns.insert(sample.ruby_thread_id, sample.elapsed_ns) {
+                sample.weight_ns = Some(
+                    next.saturating_sub(sample.elapsed_ns)
+                        .min(interval_ns * MAX_TIME_WEIGHT_INTERVALS),
+                );
+            }
+        }
+    }
+
+    /// Weight samples captured during GC by the GC pause they landed in (`Metadata.gc_pauses`),
+    /// so that GC shows up with what it cost rather than with its chance of being sampled.
+    ///
+    /// The duration of each pause is shared among the GC samples within it. Samples which fall
+    /// in no recorded pause, and samples without a weight, are left as they are.
+    pub fn weight_gc_samples_by_pause(&mut self) {
+        let pauses = &self.metadata.gc_pauses;
+        let pause_of = |elapsed_ns: u64| -> Option<usize> {
+            // The last pause starting before the sample, if it had not ended yet
+            let index = pauses
+                .partition_point(|pause| pause.elapsed_ns <= elapsed_ns)
+                .checked_sub(1)?;
+            let pause = &pauses[index];
+            (elapsed_ns <= pause.elapsed_ns + pause.duration_ns).then_some(index)
+        };
+
+        let mut sample_pauses: Vec<Option<usize>> = Vec::with_capacity(self.samples.len());
+        let mut samples_per_pause: Vec<u64> = vec![0; pauses.len()];
+        for sample in self.samples.iter() {
+            let pause = match sample.during_gc && sample.weight_ns.is_some() {
+                true => pause_of(sample.elapsed_ns),
+                false => None,
+            };
+            if let Some(index) = pause {
+                samples_per_pause[index] += 1;
+            }
+            sample_pauses.push(pause);
+        }
+
+        for (sample, pause) in self.samples.iter_mut().zip(sample_pauses) {
+            if let Some(index) = pause {
+                sample.weight_ns = Some(pauses[index].duration_ns / samples_per_pause[index]);
+            }
+        }
+    }
+}
@@ -1,124 +1,362 @@
 pub mod configuration;
+mod flush_callback;
+mod io_writer;
 mod new_thread_watcher;
+mod overhead_governor;
 pub mod ruby_object;
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::ffi::{c_int, CStr, CString};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::str::FromStr as _;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use rb_sys::*;
+use regex::Regex;
 
-use self::configuration::Configuration;
+use self::configuration::{Configuration, ConfigurationBuilder};
+use self::flush_callback::FlushCallback;
+use self::io_writer::StopIo;
 use self::new_thread_watcher::NewThreadWatcher;
-use crate::profile::Profile;
+use self::overhead_governor::OverheadGovernor;
+use crate::call_count_scheduler::CallCountScheduler;
+use crate::crash_dump::CrashDumpHook;
+use crate::error::Pf2Error;
+use crate::gc_pauses::GcPausesHook;
+use crate::logging;
+use crate::profile::{CaptureStats, Marker, Profile, TEMPORARY_SAMPLE_BUFFER_MAX_CAPACITY};
+use crate::profile_serializer::ProfileSerializer;
+use crate::sample::{Sample, ThreadState, MAX_STACK_DEPTH};
+use crate::sample_filter::{SampleFilter, SampleFilterKind};
 use crate::scheduler::Scheduler;
+use crate::serialization::format::{self, Format};
+use crate::serialization::granularity::Granularity;
+use crate::serialization::histogram::SampleHistogram;
+use crate::serialization::recursion::CollapseRecursion;
+use crate::serialization::serializer::ProfileSerializer2;
+use crate::serialization::summary::FunctionSummary;
+use crate::serialization::weighting::Weighting;
 #[cfg(target_os = "linux")]
 use crate::signal_scheduler::SignalScheduler;
 #[cfg(not(target_os = "linux"))]
-use crate::signal_scheduler_unsupported_platform::SignalScheduler;
+use crate::signal_scheduler_setitimer::SignalScheduler;
 use crate::timer_thread_scheduler::TimerThreadScheduler;
 use crate::util::*;
 
+/// The size of the chunks passed to `#write` of the IO given to `Session#stop(io:)`.
+const IO_WRITE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How many times `Session#snapshot` tries to take the profile lock, 1ms apart.
+const SNAPSHOT_LOCK_ATTEMPTS: usize = 50;
+/// The number of functions listed in the summary returned by `stop(summary: true)`.
+const SUMMARY_TOP_FUNCTIONS: usize = 5;
+
+/// Options accepted by `Session#stop`.
+struct StopOptions {
+    output: Option<PathBuf>,
+    compress: bool,
+    /// Sort functions and locations so that the output does not depend on sample order.
+    deterministic: bool,
+    /// Store identical Ruby stacks once in a shared `stacks` table (experimental serializer only).
+    dedup_stacks: bool,
+    /// Replace file paths with their basenames.
+    redact_paths: bool,
+    /// Indent JSON output for readability.
+    pretty: bool,
+    /// Make file paths under this directory relative to it.
+    relative_to: Option<PathBuf>,
+    /// Drop functions appearing in fewer samples than this.
+    min_samples: Option<u64>,
+    /// Label functions whose class path or file path starts with a prefix: (prefix, label).
+    categories: Vec<(String, String)>,
+    /// Whether to keep a location per line, or collapse them into one per function.
+    granularity: Granularity,
+    /// What to output in place of the profile.
+    format: StopFormat,
+    /// Merge consecutive samples of a thread with identical stacks (experimental serializer only).
+    coalesce: bool,
+    /// How much time each sample stands for.
+    weighting: Weighting,
+    /// Stream the profile into an IO instead of returning it.
+    io: Option<StopIo>,
+    /// Keep only the samples of these threads (by `ruby_thread_id`).
+    threads: Option<HashSet<u64>>,
+    /// Precompute sample counts of each location (experimental serializer only).
+    include_stats: bool,
+    /// Count samples per line of source (experimental serializer only).
+    line_hits: bool,
+    /// Return `{profile:, summary:}` with quick stats alongside the profile.
+    summary: bool,
+    /// Collapse recursive frames in Ruby stacks.
+    collapse_recursion: Option<CollapseRecursion>,
+}
+
+/// What `Session#stop` does without any option.
+impl Default for StopOptions {
+    fn default() -> Self {
+        Self {
+            output: None,
+            compress: false,
+            deterministic: false,
+            dedup_stacks: false,
+            redact_paths: false,
+            pretty: false,
+            relative_to: None,
+            min_samples: None,
+            categories: vec![],
+            granularity: Granularity::Line,
+            format: StopFormat::Profile,
+            coalesce: false,
+            weighting: Weighting::Count,
+            io: None,
+            threads: None,
+            include_stats: false,
+            line_hits: false,
+            summary: false,
+            collapse_recursion: None,
+        }
+    }
+}
+
+/// The output of `Session#stop` (`format:`).
+#[derive(Clone, Copy, PartialEq)]
+enum StopFormat {
+    /// The serialized profile: a Hash with the experimental serializer, or else a JSON String
+    Profile,
+    /// The profile in a named format (see `serialization::format::serialize`)
+    Serialized(Format),
+    /// Nothing, e.g. when only the summary is wanted
+    None,
+}
+
 pub struct Session {
     pub configuration: Configuration,
     pub scheduler: Arc<dyn Scheduler>,
     pub profile: Arc<RwLock<Profile>>,
     pub running: Arc<AtomicBool>,
     pub new_thread_watcher: Option<NewThreadWatcher>,
+    /// Delivers flushed samples to `on_flush`, if given.
+    flush_callback: Option<FlushCallback>,
+    last_sample_count: AtomicUsize,
+    last_memsize: AtomicUsize,
+    /// Measures GC pauses while running (`measure_gc_pauses: true`).
+    gc_pauses_hook: Option<GcPausesHook>,
+    /// Dumps the profile on a crash while running (`crash_dump:`).
+    crash_dump_hook: Option<CrashDumpHook>,
+    /// Shared with the profile, so that drop counts can be read without locking it.
+    capture_stats: Arc<CaptureStats>,
 }
 
 impl Session {
-    pub fn new_from_rb_initialize(argc: c_int, argv: *const VALUE, rbself: VALUE) -> Self {
+    pub fn new_from_rb_initialize(argc: c_int, argv: *const VALUE, _rbself: VALUE) -> Self {
         // Parse arguments
-        let kwargs: VALUE = Qnil.into();
-        unsafe {
-            rb_scan_args(argc, argv, cstr!(":"), &kwargs);
-        };
-        let mut kwargs_values: [VALUE; 5] = [Qnil.into(); 5];
-        unsafe {
-            rb_get_kwargs(
-                kwargs,
-                [
-                    rb_intern(cstr!("interval_ms")),
-                    rb_intern(cstr!("threads")),
-                    rb_intern(cstr!("time_mode")),
-                    rb_intern(cstr!("scheduler")),
-                    rb_intern(cstr!("use_experimental_serializer")),
-                ]
-                .as_mut_ptr(),
-                0,
-                5,
-                kwargs_values.as_mut_ptr(),
-            );
-        };
+        let kwargs_values = scan_kwargs(
+            argc,
+            argv,
+            [
+                cstr!("interval_ms"),
+                cstr!("threads"),
+                cstr!("time_mode"),
+                cstr!("scheduler"),
+                cstr!("use_experimental_serializer"),
+                cstr!("max_stack_depth"),
+                cstr!("timeline_resolution_ms"),
+                cstr!("max_duration_ms"),
+                cstr!("max_samples"),
+                cstr!("max_samples_policy"),
+                cstr!("exclude_paths"),
+                cstr!("thread_name_filter"),
+                cstr!("include_unnamed_threads"),
+                cstr!("warmup_ms"),
+                cstr!("clock"),
+                cstr!("include_idle"),
+                cstr!("strategy"),
+                cstr!("all_threads"),
+                cstr!("flush_interval_ms"),
+                cstr!("flush_mode"),
+                cstr!("on_flush"),
+                cstr!("mode"),
+                cstr!("window_ms"),
+                cstr!("target_overhead_pct"),
+                cstr!("labels"),
+                cstr!("trigger"),
+                cstr!("every"),
+                cstr!("jitter_pct"),
+                cstr!("measure_gc_pauses"),
+                cstr!("max_sampling_rate_hz"),
+                cstr!("sample_filter"),
+                cstr!("sample_probability"),
+                cstr!("crash_dump"),
+                cstr!("interval_us"),
+                cstr!("max_functions"),
+            ],
+        );
 
-        let interval = Self::parse_option_interval_ms(kwargs_values[0]);
+        let interval = match Self::parse_option_interval_us(kwargs_values[33]) {
+            Some(_) if kwargs_values[0] != Qundef as VALUE => Pf2Error::InvalidOption(
+                "interval_ms and interval_us cannot be given at the same time".to_owned(),
+            )
+            .raise(),
+            Some(interval) => interval,
+            None => Self::parse_option_interval_ms(kwargs_values[0]),
+        };
         let threads = Self::parse_option_threads(kwargs_values[1]);
         let time_mode = Self::parse_option_time_mode(kwargs_values[2]);
         let scheduler = Self::parse_option_scheduler(kwargs_values[3]);
         let use_experimental_serializer =
             Self::parse_option_use_experimental_serializer(kwargs_values[4]);
+        let max_stack_depth = Self::parse_option_max_stack_depth(kwargs_values[5]);
+        let timeline_resolution = Self::parse_option_timeline_resolution_ms(kwargs_values[6]);
+        let max_duration = Self::parse_option_max_duration_ms(kwargs_values[7]);
+        let max_samples = Self::parse_option_max_samples(kwargs_values[8]);
+        let max_samples_policy = Self::parse_option_max_samples_policy(kwargs_values[9]);
+        let exclude_paths = Self::parse_option_exclude_paths(kwargs_values[10]);
+        let thread_name_filter = Self::parse_option_thread_name_filter(kwargs_values[11]);
+        let include_unnamed_threads = Self::parse_option_include_unnamed_threads(kwargs_values[12]);
+        let warmup = Self::parse_option_warmup_ms(kwargs_values[13]);
+        let clock = Self::parse_option_clock(kwargs_values[14]);
+        let idle_samples = Self::parse_option_include_idle(kwargs_values[15]);
+        let strategy = Self::parse_option_strategy(kwargs_values[16]);
+        let all_threads = Self::parse_option_all_threads(kwargs_values[17]);
+        let flush_interval = Self::parse_option_flush_interval_ms(kwargs_values[18]);
+        let flush_mode = Self::parse_option_flush_mode(kwargs_values[19]);
+        let on_flush = Self::parse_option_on_flush(kwargs_values[20]);
+        let mode = Self::parse_option_mode(kwargs_values[21]);
+        let window = Self::parse_option_window_ms(kwargs_values[22]);
+        let target_overhead_pct = Self::parse_option_target_overhead_pct(kwargs_values[23]);
+        let labels = Self::parse_option_labels(kwargs_values[24]);
+        let trigger = Self::parse_option_trigger(kwargs_values[25]);
+        let every = Self::parse_option_every(kwargs_values[26]);
+        let jitter_pct = Self::parse_option_jitter_pct(kwargs_values[27]);
+        let measure_gc_pauses = Self::parse_option_measure_gc_pauses(kwargs_values[28]);
+        let max_sampling_rate_hz = Self::parse_option_max_sampling_rate_hz(kwargs_values[29]);
+        let sample_filters = Self::parse_option_sample_filter(kwargs_values[30]);
+        let sample_probability = Self::parse_option_sample_probability(kwargs_values[31]);
+        let crash_dump = Self::parse_option_output(kwargs_values[32]);
+        let max_functions = Self::parse_option_max_functions(kwargs_values[34]);
+        let threads =
+            Self::filter_threads_by_name(threads, &thread_name_filter, include_unnamed_threads);
 
-        let configuration = Configuration {
-            scheduler,
-            interval,
-            target_ruby_threads: threads.clone(),
-            time_mode,
-            use_experimental_serializer,
-        };
+        let configuration = ConfigurationBuilder::new()
+            .scheduler(scheduler)
+            .strategy(strategy)
+            .interval(interval)
+            .target_ruby_threads(threads)
+            .all_threads(all_threads)
+            .time_mode(time_mode)
+            .clock(clock)
+            .use_experimental_serializer(use_experimental_serializer)
+            .max_stack_depth(max_stack_depth)
+            .timeline_resolution(timeline_resolution)
+            .max_duration(max_duration)
+            .max_samples(max_samples)
+            .max_samples_policy(max_samples_policy)
+            .exclude_paths(exclude_paths)
+            .thread_name_filter(thread_name_filter)
+            .include_unnamed_threads(include_unnamed_threads)
+            .warmup(warmup)
+            .idle_samples(idle_samples)
+            .flush_interval(flush_interval)
+            .flush_mode(flush_mode)
+            .mode(mode)
+            .window(window)
+            .target_overhead_pct(target_overhead_pct)
+            .jitter_pct(jitter_pct)
+            .measure_gc_pauses(measure_gc_pauses)
+            .labels(labels)
+            .trigger(trigger)
+            .every(every)
+            .sample_probability(sample_probability)
+            .max_sampling_rate_hz(max_sampling_rate_hz)
+            .sample_filters(sample_filters)
+            .crash_dump(crash_dump)
+            .max_functions(max_functions)
+            .build()
+            .unwrap_or_else(|msg| Pf2Error::InvalidOption(msg).raise());
 
-        match configuration.validate() {
-            Ok(_) => {}
-            Err(msg) => unsafe {
-                rb_raise(rb_eArgError, CString::new(msg).unwrap().as_c_str().as_ptr());
-            },
-        };
+        // Create a new Profile
+        let profile = Arc::new(RwLock::new(Profile::new(
+            configuration.interval,
+            configuration.max_samples,
+            configuration.max_samples_policy.clone(),
+            Self::build_sample_filters(&configuration),
+        )));
 
-        // Store configuration as a Ruby Hash for convenience
-        unsafe {
-            rb_iv_set(rbself, cstr!("@configuration"), configuration.to_rb_hash());
+        let scheduler = Self::new_scheduler(&configuration, &profile);
+        let running = Arc::new(AtomicBool::new(false));
+        let new_thread_watcher = Self::new_thread_watcher(&configuration, &scheduler, &running);
+        let capture_stats = Arc::clone(&profile.read().unwrap().capture_stats);
+
+        let flush_callback = on_flush
+            .map(|callback| FlushCallback::new(callback, &configuration, Arc::clone(&profile)));
+
+        Session {
+            configuration,
+            scheduler,
+            profile,
+            running,
+            new_thread_watcher,
+            flush_callback,
+            last_sample_count: AtomicUsize::new(0),
+            last_memsize: AtomicUsize::new(0),
+            gc_pauses_hook: None,
+            crash_dump_hook: None,
+            capture_stats,
         }
+    }
 
-        // Create a new Profile
-        let profile = Arc::new(RwLock::new(Profile::new()));
+    fn build_sample_filters(configuration: &Configuration) -> Vec<Box<dyn SampleFilter>> {
+        configuration
+            .sample_filters
+            .iter()
+            .map(SampleFilterKind::build)
+            .collect()
+    }
 
-        // Initialize the specified Scheduler
-        let scheduler: Arc<dyn Scheduler> = match configuration.scheduler {
-            configuration::Scheduler::Signal => {
-                Arc::new(SignalScheduler::new(&configuration, Arc::clone(&profile)))
+    /// Initialize the specified Scheduler. Counting calls replaces timers altogether.
+    fn new_scheduler(
+        configuration: &Configuration,
+        profile: &Arc<RwLock<Profile>>,
+    ) -> Arc<dyn Scheduler> {
+        match (&configuration.trigger, &configuration.scheduler) {
+            (configuration::Trigger::Calls, _) => {
+                Arc::new(CallCountScheduler::new(configuration, Arc::clone(profile)))
             }
-            configuration::Scheduler::TimerThread => Arc::new(TimerThreadScheduler::new(
-                &configuration,
-                Arc::clone(&profile),
-            )),
-        };
-
-        let running = Arc::new(AtomicBool::new(false));
+            (configuration::Trigger::Time, configuration::Scheduler::Signal) => {
+                Arc::new(SignalScheduler::new(configuration, Arc::clone(profile)))
+            }
+            (configuration::Trigger::Time, configuration::Scheduler::TimerThread) => Arc::new(
+                TimerThreadScheduler::new(configuration, Arc::clone(profile)),
+            ),
+        }
+    }
 
-        let new_thread_watcher = match threads {
+    /// With `threads: :all`, report threads created while running to the scheduler.
+    fn new_thread_watcher(
+        configuration: &Configuration,
+        scheduler: &Arc<dyn Scheduler>,
+        running: &Arc<AtomicBool>,
+    ) -> Option<NewThreadWatcher> {
+        match configuration.target_ruby_threads {
             configuration::Threads::All => {
-                let scheduler = Arc::clone(&scheduler);
-                let running = Arc::clone(&running);
+                let scheduler = Arc::clone(scheduler);
+                let running = Arc::clone(running);
                 Some(NewThreadWatcher::watch(move |thread: VALUE| {
-                    if running.load(Ordering::Relaxed) {
-                        log::debug!("New Ruby thread detected: {:?}", thread);
+                    // Pf2's own threads are never profiled
+                    if running.load(Ordering::Relaxed) && !is_internal_thread(thread) {
+                        logging::debug!("New Ruby thread detected: {:?}", thread);
                         scheduler.on_new_thread(thread);
                     }
                 }))
             }
             configuration::Threads::Targeted(_) => None,
-        };
-
-        Session {
-            configuration,
-            scheduler,
-            profile,
-            running,
-            new_thread_watcher,
         }
     }
 
@@ -128,7 +366,7 @@ impl Session {
             return configuration::DEFAULT_INTERVAL;
         }
 
-        let interval_ms = unsafe { rb_num2long(value) };
+        let interval_ms = integer_option(value, "interval_ms");
         Duration::from_millis(interval_ms.try_into().unwrap_or_else(|_| {
             eprintln!(
                 "[Pf2] Warning: Specified interval ({}) is not valid. Using default value (9ms).",
@@ -138,6 +376,19 @@ impl Session {
         }))
     }
 
+    fn parse_option_interval_us(value: VALUE) -> Option<Duration> {
+        if value == Qundef as VALUE {
+            return None;
+        }
+
+        let interval_us = integer_option(value, "interval_us");
+        Some(Duration::from_micros(
+            interval_us.try_into().unwrap_or_else(|_| {
+                Pf2Error::InvalidOption("interval_us must be positive.".to_owned()).raise()
+            }),
+        ))
+    }
+
     fn parse_option_threads(value: VALUE) -> configuration::Threads {
         if (value == Qundef as VALUE)
             || (value == Qnil as VALUE)
@@ -146,10 +397,38 @@ impl Session {
             return configuration::Threads::All;
         }
 
+        if !RTEST(unsafe { rb_obj_is_kind_of(value, rb_cArray) }) {
+            unsafe {
+                rb_raise(
+                    rb_eTypeError,
+                    cstr!("threads must be an Array of Threads or :all, not %s"),
+                    rb_obj_classname(value),
+                )
+            }
+        }
+
+        // Duplicates are collapsed by the set
         let mut set: HashSet<VALUE> = HashSet::new();
         unsafe {
             for i in 0..RARRAY_LEN(value) {
-                set.insert(rb_ary_entry(value, i));
+                let thread = rb_ary_entry(value, i);
+                // Timers would otherwise be armed on arbitrary objects treated as Threads
+                if !RTEST(rb_obj_is_kind_of(thread, rb_cThread)) {
+                    rb_raise(
+                        rb_eTypeError,
+                        cstr!("threads[%ld] must be a Thread, not %s"),
+                        i,
+                        rb_obj_classname(thread),
+                    );
+                }
+                // Dead threads have no native thread to be sampled, and Pf2's own threads
+                // (e.g. from `Thread.list`) are never profiled
+                if !RTEST(rb_funcall(thread, rb_intern(cstr!("alive?")), 0))
+                    || is_internal_thread(thread)
+                {
+                    continue;
+                }
+                set.insert(thread);
             }
         }
         configuration::Threads::Targeted(set)
@@ -161,98 +440,1746 @@ impl Session {
             return configuration::DEFAULT_TIME_MODE;
         }
 
-        let specified_mode = unsafe {
-            let mut str = rb_funcall(value, rb_intern(cstr!("to_s")), 0);
-            let ptr = rb_string_value_ptr(&mut str);
-            CStr::from_ptr(ptr).to_str().unwrap()
-        };
-        configuration::TimeMode::from_str(specified_mode).unwrap_or_else(|_| {
+        let specified_mode = string_option(value, "time_mode");
+        configuration::TimeMode::from_str(&specified_mode).unwrap_or_else(|_| {
             // Raise an ArgumentError if the mode is invalid
-            unsafe {
-                rb_raise(
-                    rb_eArgError,
-                    cstr!("Invalid time mode. Valid values are 'cpu' and 'wall'."),
-                )
-            }
+            Pf2Error::InvalidOption(
+                "Invalid time mode. Valid values are 'cpu' and 'wall'.".to_owned(),
+            )
+            .raise()
         })
     }
 
+    fn parse_option_clock(value: VALUE) -> Option<configuration::Clock> {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            // Defaults to the clock matching the time mode
+            return None;
+        }
+
+        let specified_clock = string_option(value, "clock");
+        let clock = configuration::Clock::from_str(&specified_clock).unwrap_or_else(|_| {
+            // Raise an ArgumentError if the clock is invalid
+            Pf2Error::InvalidOption("Invalid clock. Valid values are ':monotonic', ':monotonic_raw', ':thread_cputime' and ':process_cputime'.".to_owned()).raise()
+        });
+        Some(clock)
+    }
+
     fn parse_option_scheduler(value: VALUE) -> configuration::Scheduler {
         if value == Qundef as VALUE {
             // Return default
             return configuration::DEFAULT_SCHEDULER;
         }
 
-        let specified_scheduler = unsafe {
-            let mut str = rb_funcall(value, rb_intern(cstr!("to_s")), 0);
-            let ptr = rb_string_value_ptr(&mut str);
-            CStr::from_ptr(ptr).to_str().unwrap()
-        };
+        let specified_scheduler = string_option(value, "scheduler");
         let scheduler =
-            configuration::Scheduler::from_str(specified_scheduler).unwrap_or_else(|_| {
+            configuration::Scheduler::from_str(&specified_scheduler).unwrap_or_else(|_| {
                 // Raise an ArgumentError if the mode is invalid
-                unsafe {
-                    rb_raise(
-                        rb_eArgError,
-                        cstr!("Invalid scheduler. Valid values are ':signal' and ':timer_thread'."),
-                    )
-                }
+                Pf2Error::InvalidOption(
+                    "Invalid scheduler. Valid values are ':signal' and ':timer_thread'.".to_owned(),
+                )
+                .raise()
             });
 
-        // Raise an ArgumentError if the scheduler is not supported on the current platform
-        if !cfg!(target_os = "linux") && scheduler == configuration::Scheduler::Signal {
-            unsafe {
-                rb_raise(
-                    rb_eArgError,
-                    cstr!("Signal scheduler is not supported on this platform."),
+        scheduler
+    }
+
+    fn parse_option_strategy(value: VALUE) -> Option<configuration::Strategy> {
+        if value == Qundef as VALUE {
+            // Defaults to the strategy supported by the platform
+            return None;
+        }
+
+        let specified_strategy = string_option(value, "strategy");
+        let strategy =
+            configuration::Strategy::from_str(&specified_strategy).unwrap_or_else(|_| {
+                // Raise an ArgumentError if the strategy is invalid
+                Pf2Error::InvalidOption(
+                    "Invalid strategy. Valid values are ':per_thread' and ':global_timer'."
+                        .to_owned(),
                 )
-            }
+                .raise()
+            });
+        Some(strategy)
+    }
+
+    fn parse_option_all_threads(value: VALUE) -> bool {
+        if value == Qundef as VALUE {
+            return false;
         }
-        scheduler
+        RTEST(value)
     }
 
-    fn parse_option_use_experimental_serializer(value: VALUE) -> bool {
+    fn parse_option_flush_interval_ms(value: VALUE) -> Duration {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return configuration::DEFAULT_FLUSH_INTERVAL;
+        }
+
+        let flush_interval_ms = integer_option(value, "flush_interval_ms");
+        Duration::from_millis(flush_interval_ms.try_into().unwrap_or(0))
+    }
+
+    fn parse_option_flush_mode(value: VALUE) -> configuration::FlushMode {
+        if value == Qundef as VALUE {
+            return configuration::FlushMode::default();
+        }
+
+        let specified_mode = string_option(value, "flush_mode");
+        configuration::FlushMode::from_str(&specified_mode).unwrap_or_else(|_| {
+            // Raise an ArgumentError if the mode is invalid
+            Pf2Error::InvalidOption(
+                "Invalid flush_mode. Valid values are ':periodic' and ':event_driven'.".to_owned(),
+            )
+            .raise()
+        })
+    }
+
+    fn parse_option_mode(value: VALUE) -> configuration::Mode {
+        if value == Qundef as VALUE {
+            return configuration::Mode::default();
+        }
+
+        let specified_mode = string_option(value, "mode");
+        configuration::Mode::from_str(&specified_mode).unwrap_or_else(|_| {
+            // Raise an ArgumentError if the mode is invalid
+            Pf2Error::InvalidOption(
+                "Invalid mode. Valid values are ':standard' and ':continuous'.".to_owned(),
+            )
+            .raise()
+        })
+    }
+
+    fn parse_option_window_ms(value: VALUE) -> Option<Duration> {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return None;
+        }
+
+        let window_ms = integer_option(value, "window_ms");
+        Some(Duration::from_millis(window_ms.try_into().unwrap_or(0)))
+    }
+
+    fn parse_option_target_overhead_pct(value: VALUE) -> Option<f64> {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return None;
+        }
+
+        if !RTEST(unsafe { rb_obj_is_kind_of(value, rb_cNumeric) }) {
+            Pf2Error::InvalidOption("target_overhead_pct must be a Numeric".to_owned()).raise();
+        }
+        Some(unsafe { rb_num2dbl(value) })
+    }
+
+    fn parse_option_jitter_pct(value: VALUE) -> f64 {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return 0.0;
+        }
+
+        if !RTEST(unsafe { rb_obj_is_kind_of(value, rb_cNumeric) }) {
+            Pf2Error::InvalidOption("jitter_pct must be a Numeric".to_owned()).raise();
+        }
+        unsafe { rb_num2dbl(value) }
+    }
+
+    fn parse_option_sample_probability(value: VALUE) -> Option<f64> {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return None;
+        }
+
+        if !RTEST(unsafe { rb_obj_is_kind_of(value, rb_cNumeric) }) {
+            Pf2Error::InvalidOption("sample_probability must be a Numeric".to_owned()).raise();
+        }
+        Some(unsafe { rb_num2dbl(value) })
+    }
+
+    fn parse_option_measure_gc_pauses(value: VALUE) -> bool {
         if value == Qundef as VALUE {
             return false;
         }
         RTEST(value)
     }
 
-    pub fn start(&mut self) -> VALUE {
-        self.running.store(true, Ordering::Relaxed);
-        self.start_profile_buffer_flusher_thread();
-        self.scheduler.start()
+    fn parse_option_trigger(value: VALUE) -> configuration::Trigger {
+        if value == Qundef as VALUE {
+            return configuration::Trigger::default();
+        }
+
+        let specified_trigger = string_option(value, "trigger");
+        configuration::Trigger::from_str(&specified_trigger).unwrap_or_else(|_| {
+            Pf2Error::InvalidOption(
+                "Invalid trigger. Valid values are ':time' and ':calls'.".to_owned(),
+            )
+            .raise()
+        })
     }
 
-    fn start_profile_buffer_flusher_thread(&self) {
-        let profile = Arc::clone(&self.profile);
-        let running = Arc::clone(&self.running);
-        log::debug!("flusher: Starting");
-        thread::spawn(move || loop {
-            if !running.load(Ordering::Relaxed) {
-                log::debug!("flusher: Exiting");
-                break;
+    /// A Symbol naming a built-in filter, or an Array of them (all of which must keep a sample).
+    fn parse_option_sample_filter(value: VALUE) -> Vec<SampleFilterKind> {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return vec![];
+        }
+
+        let mut sample_filters = vec![];
+        unsafe {
+            let value = rb_Array(value);
+            for i in 0..RARRAY_LEN(value) {
+                let name = string_option(rb_ary_entry(value, i), "sample_filter");
+                sample_filters.push(SampleFilterKind::from_str(&name).unwrap_or_else(|_| {
+                    Pf2Error::InvalidOption(
+                        "Invalid sample_filter. Valid values are :app_code and :drop_idle."
+                            .to_owned(),
+                    )
+                    .raise()
+                }));
             }
+        }
+        sample_filters
+    }
 
-            log::trace!("flusher: Flushing temporary sample buffer");
-            match profile.try_write() {
-                Ok(mut profile) => {
-                    profile.flush_temporary_sample_buffer();
+    fn parse_option_every(value: VALUE) -> Option<u64> {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return None;
+        }
+
+        let every = integer_option(value, "every");
+        Some(u64::try_from(every).unwrap_or_else(|_| {
+            Pf2Error::InvalidOption("every must be positive.".to_owned()).raise()
+        }))
+    }
+
+    fn parse_option_max_sampling_rate_hz(value: VALUE) -> Option<u64> {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return None;
+        }
+
+        let max_sampling_rate_hz = integer_option(value, "max_sampling_rate_hz");
+        Some(u64::try_from(max_sampling_rate_hz).unwrap_or_else(|_| {
+            Pf2Error::InvalidOption("max_sampling_rate_hz must be positive.".to_owned()).raise()
+        }))
+    }
+
+    /// A Hash of String (or Symbol) keys to String values.
+    fn parse_option_labels(value: VALUE) -> BTreeMap<String, String> {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return BTreeMap::new();
+        }
+
+        if !RTEST(unsafe { rb_obj_is_kind_of(value, rb_cHash) }) {
+            Pf2Error::InvalidOption("labels must be a Hash".to_owned()).raise();
+        }
+        let mut labels = BTreeMap::new();
+        unsafe {
+            let pairs = rb_funcall(value, rb_intern(cstr!("to_a")), 0);
+            for i in 0..RARRAY_LEN(pairs) {
+                let pair = rb_ary_entry(pairs, i);
+                let key = rb_ary_entry(pair, 0);
+                let mut value = rb_ary_entry(pair, 1);
+                if !RTEST(rb_obj_is_kind_of(key, rb_cString))
+                    && !RTEST(rb_obj_is_kind_of(key, rb_cSymbol))
+                {
+                    Pf2Error::InvalidOption("labels keys must be Strings or Symbols".to_owned())
+                        .raise();
                 }
-                Err(_) => {
-                    log::debug!("flusher: Failed to acquire profile lock");
+                let mut key = rb_funcall(key, rb_intern(cstr!("to_s")), 0);
+                let key = CStr::from_ptr(rb_string_value_cstr(&mut key))
+                    .to_string_lossy()
+                    .into_owned();
+                if !RTEST(rb_obj_is_kind_of(value, rb_cString)) {
+                    Pf2Error::InvalidOption(format!("labels[{:?}] must be a String", key)).raise();
                 }
+                let value = CStr::from_ptr(rb_string_value_cstr(&mut value))
+                    .to_string_lossy()
+                    .into_owned();
+                labels.insert(key, value);
             }
-            thread::sleep(Duration::from_millis(500));
-        });
+        }
+        labels
     }
 
-    pub fn stop(&mut self) -> VALUE {
-        self.running.store(false, Ordering::Relaxed);
-        self.scheduler.stop()
+    fn parse_option_on_flush(value: VALUE) -> Option<VALUE> {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return None;
+        }
+
+        if unsafe { rb_respond_to(value, rb_intern(cstr!("call"))) } == 0 {
+            Pf2Error::InvalidOption("on_flush must respond to #call.".to_owned()).raise();
+        }
+        Some(value)
     }
 
-    pub fn dmark(&self) {
-        self.scheduler.dmark()
+    fn parse_option_use_experimental_serializer(value: VALUE) -> bool {
+        if value == Qundef as VALUE {
+            return false;
+        }
+        RTEST(value)
+    }
+
+    fn parse_option_max_stack_depth(value: VALUE) -> usize {
+        if value == Qundef as VALUE {
+            // Mirror Ruby's backtrace limit (`--backtrace-limit`) when not explicitly specified
+            let backtrace_limit = unsafe {
+                let backtrace_class = rb_const_get(rb_cThread, rb_intern(cstr!("Backtrace")));
+                rb_num2long(rb_funcall(backtrace_class, rb_intern(cstr!("limit")), 0))
+            };
+            // Thread::Backtrace.limit returns -1 when unlimited
+            return match usize::try_from(backtrace_limit) {
+                Ok(limit) if limit > 0 => limit.min(MAX_STACK_DEPTH),
+                _ => MAX_STACK_DEPTH,
+            };
+        }
+
+        let max_stack_depth = integer_option(value, "max_stack_depth");
+        usize::try_from(max_stack_depth).unwrap_or(0)
+    }
+
+    fn parse_option_timeline_resolution_ms(value: VALUE) -> Duration {
+        if value == Qundef as VALUE {
+            return configuration::DEFAULT_TIMELINE_RESOLUTION;
+        }
+
+        let resolution_ms = integer_option(value, "timeline_resolution_ms");
+        Duration::from_millis(resolution_ms.try_into().unwrap_or(0))
+    }
+
+    fn parse_option_max_duration_ms(value: VALUE) -> Option<Duration> {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return None;
+        }
+
+        let max_duration_ms = integer_option(value, "max_duration_ms");
+        Some(Duration::from_millis(
+            max_duration_ms.try_into().unwrap_or(0),
+        ))
+    }
+
+    fn parse_option_warmup_ms(value: VALUE) -> Duration {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return Duration::ZERO;
+        }
+
+        let warmup_ms = integer_option(value, "warmup_ms");
+        Duration::from_millis(warmup_ms.try_into().unwrap_or_else(|_| {
+            Pf2Error::InvalidOption("warmup_ms must not be negative.".to_owned()).raise()
+        }))
+    }
+
+    fn parse_option_max_samples(value: VALUE) -> Option<usize> {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return None;
+        }
+
+        let max_samples = integer_option(value, "max_samples");
+        Some(usize::try_from(max_samples).unwrap_or(0))
+    }
+
+    fn parse_option_max_functions(value: VALUE) -> Option<usize> {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return None;
+        }
+
+        let max_functions = integer_option(value, "max_functions");
+        Some(usize::try_from(max_functions).unwrap_or(0))
+    }
+
+    fn parse_option_max_samples_policy(value: VALUE) -> configuration::MaxSamplesPolicy {
+        if value == Qundef as VALUE {
+            return configuration::MaxSamplesPolicy::Stop;
+        }
+
+        let specified_policy = string_option(value, "max_samples_policy");
+        configuration::MaxSamplesPolicy::from_str(&specified_policy).unwrap_or_else(|_| {
+            // Raise an ArgumentError if the policy is invalid
+            Pf2Error::InvalidOption(
+                "Invalid max_samples_policy. Valid values are ':stop' and ':ring'.".to_owned(),
+            )
+            .raise()
+        })
+    }
+
+    fn parse_option_exclude_paths(value: VALUE) -> Vec<configuration::Pattern> {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return vec![];
+        }
+
+        let mut patterns = vec![];
+        unsafe {
+            let value = rb_Array(value);
+            for i in 0..RARRAY_LEN(value) {
+                patterns.push(Self::parse_pattern(rb_ary_entry(value, i)));
+            }
+        }
+        patterns
+    }
+
+    fn parse_option_thread_name_filter(value: VALUE) -> Option<configuration::Pattern> {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return None;
+        }
+        Some(Self::parse_pattern(value))
+    }
+
+    fn parse_option_include_unnamed_threads(value: VALUE) -> bool {
+        if value == Qundef as VALUE {
+            return false;
+        }
+        RTEST(value)
+    }
+
+    /// `true` keeps samples of idle threads, `false` drops them, and `:tag` marks them.
+    fn parse_option_include_idle(value: VALUE) -> configuration::IdleSamples {
+        if value == Qundef as VALUE || value == Qtrue as VALUE {
+            return configuration::IdleSamples::Keep;
+        }
+        if value == Qfalse as VALUE {
+            return configuration::IdleSamples::Drop;
+        }
+        if value == unsafe { rb_id2sym(rb_intern(cstr!("tag"))) } {
+            return configuration::IdleSamples::Tag;
+        }
+        Pf2Error::InvalidOption("include_idle must be true, false or :tag".to_owned()).raise()
+    }
+
+    /// Parse a Ruby String (matched as a substring) or Regexp.
+    fn parse_pattern(value: VALUE) -> configuration::Pattern {
+        if RTEST(unsafe { rb_obj_is_kind_of(value, rb_cRegexp) }) {
+            return configuration::Pattern::Regex(Self::regex_from_ruby_regexp(value));
+        }
+
+        let substring = unsafe {
+            let mut str = rb_funcall(value, rb_intern(cstr!("to_s")), 0);
+            CStr::from_ptr(rb_string_value_cstr(&mut str))
+                .to_string_lossy()
+                .into_owned()
+        };
+        configuration::Pattern::Substring(substring)
+    }
+
+    /// Keep only the threads whose name matches `filter`.
+    ///
+    /// Names are matched once, here. Threads renamed afterwards are not reconsidered, and threads
+    /// started later are not matched at all, which is why `threads: :all` is rejected along with
+    /// a filter: new threads are usually named only after they have started.
+    fn filter_threads_by_name(
+        threads: configuration::Threads,
+        filter: &Option<configuration::Pattern>,
+        include_unnamed_threads: bool,
+    ) -> configuration::Threads {
+        let filter = match filter {
+            Some(filter) => filter,
+            None => return threads,
+        };
+        let threads = match threads {
+            configuration::Threads::Targeted(threads) => threads,
+            configuration::Threads::All => return configuration::Threads::All,
+        };
+
+        let filtered = threads
+            .into_iter()
+            .filter(|&thread| unsafe {
+                let mut name = rb_funcall(thread, rb_intern(cstr!("name")), 0);
+                if !RTEST(name) {
+                    return include_unnamed_threads;
+                }
+                let name = CStr::from_ptr(rb_string_value_cstr(&mut name)).to_string_lossy();
+                filter.matches(&name)
+            })
+            .collect();
+        configuration::Threads::Targeted(filtered)
+    }
+
+    /// Translate a Ruby Regexp into a Regex, raising an ArgumentError if it cannot be represented.
+    fn regex_from_ruby_regexp(regexp: VALUE) -> Regex {
+        let (source, options) = unsafe {
+            let mut source = rb_funcall(regexp, rb_intern(cstr!("source")), 0);
+            let source = CStr::from_ptr(rb_string_value_cstr(&mut source))
+                .to_string_lossy()
+                .into_owned();
+            let options = rb_num2long(rb_funcall(regexp, rb_intern(cstr!("options")), 0));
+            (source, options)
+        };
+
+        let mut flags = String::new();
+        if options & 1 != 0 {
+            flags.push('i'); // Regexp::IGNORECASE
+        }
+        if options & 2 != 0 {
+            flags.push('x'); // Regexp::EXTENDED
+        }
+        if options & 4 != 0 {
+            flags.push('s'); // Regexp::MULTILINE (`.` matches newlines)
+        }
+        let pattern = if flags.is_empty() {
+            source
+        } else {
+            format!("(?{}){}", flags, source)
+        };
+
+        Regex::new(&pattern).unwrap_or_else(|e| {
+            Pf2Error::InvalidOption(format!("Unsupported pattern /{}/: {}", pattern, e)).raise()
+        })
+    }
+
+    pub fn start(&mut self) -> VALUE {
+        // Starting again would install a second set of timers and flusher thread
+        if self.running.load(Ordering::Relaxed) {
+            Pf2Error::AlreadyRunning.raise();
+        }
+        // Otherwise, no timer would be armed and the profile would silently stay empty
+        if matches!(
+            &self.configuration.target_ruby_threads,
+            configuration::Threads::Targeted(threads) if threads.is_empty()
+        ) {
+            unsafe {
+                rb_warn(cstr!(
+                    "[Pf2] No threads to profile: `threads` is empty (possibly after filtering). The profile will be empty."
+                ))
+            };
+        }
+        if let Some(path) = &self.configuration.crash_dump {
+            match CrashDumpHook::install(path, &self.profile) {
+                Ok(hook) => self.crash_dump_hook = Some(hook),
+                Err(e) => e.raise(),
+            }
+        }
+        // Samples captured during the warmup window are discarded
+        self.profile
+            .write()
+            .unwrap()
+            .restart_clock(self.configuration.warmup);
+        self.running.store(true, Ordering::Relaxed);
+        if let Err(e) = self.scheduler.start() {
+            // Leave the session not started, so that the caller can rescue and fall back
+            self.running.store(false, Ordering::Relaxed);
+            self.crash_dump_hook = None;
+            self.profile.write().unwrap().reset();
+            e.raise();
+        }
+        if self.configuration.all_threads {
+            self.track_live_threads();
+        }
+        self.warn_if_sampling_rate_too_high();
+        if self.configuration.measure_gc_pauses {
+            // Watching allocates a TracePoint, which must not happen under the lock (see `Profile`)
+            let gc_pauses = Arc::clone(&self.profile.read().unwrap().gc_pauses);
+            self.gc_pauses_hook = Some(gc_pauses.watch());
+        }
+        self.start_profile_buffer_flusher_thread();
+        if let Some(flush_callback) = &mut self.flush_callback {
+            flush_callback.start(Arc::clone(&self.running));
+        }
+        Qtrue.into()
+    }
+
+    /// Warn, while still profiling as requested, if samples are to be captured faster than
+    /// `max_sampling_rate_hz` over all target threads. Beyond it, the flusher falls behind and
+    /// samples get dropped, as counted in `metadata[:overhead]`.
+    fn warn_if_sampling_rate_too_high(&self) {
+        if self.configuration.trigger != configuration::Trigger::Time {
+            return;
+        }
+        let thread_count = match &self.configuration.target_ruby_threads {
+            configuration::Threads::Targeted(threads) => threads.len() as u64,
+            configuration::Threads::All => unsafe {
+                RARRAY_LEN(rb_funcall(rb_cThread, rb_intern(cstr!("list")), 0)) as u64
+            },
+        };
+        let interval_ns = self.configuration.interval.as_nanos().max(1) as u64;
+        let rate_hz = thread_count * 1_000_000_000 / interval_ns;
+        let max_sampling_rate_hz = self.configuration.max_sampling_rate_hz;
+        if rate_hz <= max_sampling_rate_hz {
+            return;
+        }
+
+        // The shortest interval keeping the rate within the ceiling
+        let suggested_interval_ms = (thread_count * 1000).div_ceil(max_sampling_rate_hz);
+        let message = format!(
+            concat!(
+                "[Pf2] Sampling {} threads every {:?} (about {} samples/s) exceeds ",
+                "max_sampling_rate_hz ({}), and samples may be dropped ",
+                "(see `dropped_by_full_buffer` in the overhead metadata). ",
+                "Consider `interval_ms: {}`, or pass a higher `max_sampling_rate_hz`."
+            ),
+            thread_count,
+            self.configuration.interval,
+            rate_hz,
+            max_sampling_rate_hz,
+            suggested_interval_ms
+        );
+        logging::warn!("{}", message);
+        let message = CString::new(message).unwrap();
+        unsafe { rb_warn(cstr!("%s"), message.as_ptr()) };
+    }
+
+    /// Start profiling the threads alive at this moment (`all_threads: true`).
+    /// Threads created afterwards are picked up by the NewThreadWatcher, which also ensures that
+    /// no thread is reported to the scheduler twice.
+    fn track_live_threads(&self) {
+        let watcher = match &self.new_thread_watcher {
+            Some(watcher) => watcher,
+            None => return,
+        };
+        unsafe {
+            let threads = rb_funcall(rb_cThread, rb_intern(cstr!("list")), 0);
+            for i in 0..RARRAY_LEN(threads) {
+                let thread = rb_ary_entry(threads, i);
+                // Threads without an underlying pthread yet are picked up once they resume
+                if rb_funcall(thread, rb_intern(cstr!("native_thread_id")), 0) == Qnil as VALUE {
+                    continue;
+                }
+                watcher.track(thread);
+            }
+        }
+    }
+
+    fn start_profile_buffer_flusher_thread(&self) {
+        let profile = Arc::clone(&self.profile);
+        let running = Arc::clone(&self.running);
+        let max_duration = self.configuration.max_duration;
+        let flush_interval = self.configuration.flush_interval;
+        let window = self.configuration.window;
+        let (flush_signal, flush_batch, mut overhead_governor) = {
+            let profile = self.profile.read().unwrap();
+            (
+                Arc::clone(&profile.flush_signal),
+                Arc::clone(&profile.flush_batch),
+                self.configuration
+                    .target_overhead_pct
+                    .map(|target_pct| OverheadGovernor::new(target_pct, &profile)),
+            )
+        };
+        let batch_signal = self
+            .flush_callback
+            .as_ref()
+            .map(|flush_callback| flush_callback.batch_signal());
+        logging::debug!("flusher: Starting");
+        thread::spawn(move || loop {
+            if !running.load(Ordering::Relaxed) {
+                logging::debug!("flusher: Exiting");
+                break;
+            }
+
+            let mut wait_duration = flush_interval;
+            logging::trace!("flusher: Flushing temporary sample buffer");
+            // Swap the pending samples out and resolve them, holding the profile lock only for
+            // the swap. Samples left in the batch (e.g. if the lock is contended below) are
+            // appended by the next flush.
+            let taken = match profile.try_write() {
+                Ok(mut profile) => {
+                    profile.take_pending_samples(&mut flush_batch.lock().unwrap());
+                    true
+                }
+                Err(_) => false,
+            };
+            if taken {
+                let resolve_started_at = Instant::now();
+                flush_batch.lock().unwrap().resolve();
+                logging::trace!(
+                    "flusher: Resolved samples in {:?} without the profile lock",
+                    resolve_started_at.elapsed()
+                );
+            }
+            match profile.try_write() {
+                Ok(mut profile) => {
+                    profile.append_flushed_samples(&mut flush_batch.lock().unwrap());
+                    if let Some(window) = window {
+                        profile.evict_samples_older_than(window);
+                    }
+                    if let Some(overhead_governor) = &mut overhead_governor {
+                        overhead_governor.check(&mut profile);
+                    }
+                    if let Some(batch_signal) = &batch_signal {
+                        batch_signal.notify();
+                    }
+
+                    if let Some(max_duration) = max_duration {
+                        let elapsed = profile.elapsed_at(Instant::now());
+                        if elapsed >= max_duration {
+                            // Auto-stop, unless stop() has been called in the meantime
+                            if running.swap(false, Ordering::Relaxed) {
+                                logging::debug!(
+                                    "flusher: max_duration reached. Stopping the profile."
+                                );
+                                profile.finish();
+                            }
+                            break;
+                        }
+                        wait_duration = wait_duration.min(max_duration - elapsed);
+                    }
+                }
+                Err(_) => {
+                    logging::debug!("flusher: Failed to acquire profile lock");
+                }
+            }
+            if flush_signal.wait(wait_duration) {
+                logging::trace!(
+                    "flusher: Woken up by a sample buffer reaching its high-water mark"
+                );
+            }
+        });
+    }
+
+    pub fn stop(&mut self, argc: c_int, argv: *const VALUE) -> VALUE {
+        let (options, output) = Self::scan_stop_options(argc, argv);
+
+        self.running.store(false, Ordering::Relaxed);
+        self.scheduler.stop();
+        self.gc_pauses_hook = None;
+        self.crash_dump_hook = None;
+        if let Some(flush_callback) = &mut self.flush_callback {
+            flush_callback.stop();
+        }
+
+        // Finalize
+        let finished = match self.profile.try_write() {
+            Ok(mut profile) => {
+                profile.flush_temporary_sample_buffer();
+                if let Some(window) = self.configuration.window {
+                    profile.evict_samples_older_than(window);
+                }
+                // The profile may have already been stopped by max_duration
+                profile.finish();
+                // Let the flusher exit without waiting for the rest of its interval
+                profile.flush_signal.notify();
+                true
+            }
+            Err(_) => false,
+        };
+        if !finished {
+            Pf2Error::ProfileLocked.raise();
+        }
+        if let Some(flush_callback) = &self.flush_callback {
+            flush_callback.deliver_remaining();
+        }
+
+        self.output_profile(&options, output)
+    }
+
+    /// Parse the keyword arguments of `stop` (also accepted by `snapshot`).
+    /// Returns the `output` (or `io`) argument as given, to be returned when writing to it.
+    fn scan_stop_options(argc: c_int, argv: *const VALUE) -> (StopOptions, VALUE) {
+        let kwargs_values = scan_kwargs(
+            argc,
+            argv,
+            [
+                cstr!("output"),
+                cstr!("compress"),
+                cstr!("deterministic"),
+                cstr!("dedup_stacks"),
+                cstr!("redact_paths"),
+                cstr!("pretty"),
+                cstr!("relative_to"),
+                cstr!("min_samples"),
+                cstr!("categories"),
+                cstr!("granularity"),
+                cstr!("format"),
+                cstr!("coalesce"),
+                cstr!("weighting"),
+                cstr!("io"),
+                cstr!("threads"),
+                cstr!("include_stats"),
+                cstr!("line_hits"),
+                cstr!("summary"),
+                cstr!("collapse_recursion"),
+            ],
+        );
+        let mut options = StopOptions {
+            output: Self::parse_option_output(kwargs_values[0]),
+            compress: Self::parse_option_compress(kwargs_values[1]),
+            deterministic: Self::parse_option_deterministic(kwargs_values[2]),
+            dedup_stacks: Self::parse_option_dedup_stacks(kwargs_values[3]),
+            redact_paths: Self::parse_option_redact_paths(kwargs_values[4]),
+            pretty: Self::parse_option_pretty(kwargs_values[5]),
+            relative_to: Self::parse_option_relative_to(kwargs_values[6]),
+            min_samples: Self::parse_option_min_samples(kwargs_values[7]),
+            categories: Self::parse_option_categories(kwargs_values[8]),
+            granularity: Self::parse_option_granularity(kwargs_values[9]),
+            format: Self::parse_option_stop_format(kwargs_values[10]),
+            coalesce: Self::parse_option_coalesce(kwargs_values[11]),
+            weighting: Self::parse_option_weighting(kwargs_values[12]),
+            io: StopIo::parse(kwargs_values[13]),
+            threads: Self::parse_option_retained_threads(kwargs_values[14]),
+            include_stats: Self::parse_option_include_stats(kwargs_values[15]),
+            line_hits: Self::parse_option_line_hits(kwargs_values[16]),
+            summary: Self::parse_option_summary(kwargs_values[17]),
+            collapse_recursion: Self::parse_option_collapse_recursion(kwargs_values[18]),
+        };
+        if let StopFormat::Serialized(Format::Json { pretty }) = &mut options.format {
+            *pretty = options.pretty;
+        }
+        if options.output.is_some() && options.io.is_some() {
+            Pf2Error::InvalidOption("output and io cannot be given at the same time".to_owned())
+                .raise();
+        }
+        let output = match options.io {
+            Some(_) => kwargs_values[13],
+            None => kwargs_values[0],
+        };
+        (options, output)
+    }
+
+    /// Serialize the profile as requested by `options`: into the file at `output`, into `io`,
+    /// gzipped, or as a Ruby object.
+    fn output_profile(&self, options: &StopOptions, output: VALUE) -> VALUE {
+        if !options.summary {
+            return self.output_serialized_profile(options, output);
+        }
+        // Built separately from the serialized profile, which may be streamed
+        let ser = match self.profile.try_read() {
+            Ok(profile) => self.build_profile(&profile, options),
+            Err(_) => Pf2Error::ProfileLocked.raise(),
+        };
+        let summary = self.stop_summary(&ser);
+        unsafe {
+            let hash = rb_hash_new();
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("profile"))),
+                self.output_serialized_profile(options, output),
+            );
+            rb_hash_aset(hash, rb_id2sym(rb_intern(cstr!("summary"))), summary);
+            hash
+        }
+    }
+
+    /// Quick stats of a built profile for `stop(summary: true)`: `sample_count`, `duration_ns`,
+    /// `drops` (as `drop_stats`), `threads` (sample counts by `ruby_thread_id`) and
+    /// `top_functions` (the 5 hottest functions by self samples).
+    fn stop_summary(&self, ser: &ProfileSerializer2) -> VALUE {
+        let profile = ser.profile();
+        let sample_count: u64 = profile
+            .samples
+            .iter()
+            .map(|sample| sample.sample_count())
+            .sum();
+        unsafe {
+            let hash = rb_hash_new();
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("sample_count"))),
+                rb_ull2inum(sample_count),
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("duration_ns"))),
+                rb_ull2inum(profile.duration_ns),
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("drops"))),
+                self.drop_stats(),
+            );
+            let threads = rb_hash_new();
+            for thread in profile.metadata.thread_summary.iter() {
+                rb_hash_aset(
+                    threads,
+                    rb_ull2inum(thread.ruby_thread_id),
+                    rb_ull2inum(thread.sample_count),
+                );
+            }
+            rb_hash_aset(hash, rb_id2sym(rb_intern(cstr!("threads"))), threads);
+            let top_functions = rb_ary_new();
+            for row in FunctionSummary::build(profile)
+                .iter()
+                .take(SUMMARY_TOP_FUNCTIONS)
+            {
+                let row_hash = rb_hash_new();
+                rb_hash_aset(
+                    row_hash,
+                    rb_id2sym(rb_intern(cstr!("function"))),
+                    rb_str_from_bytes(row.function.as_bytes()),
+                );
+                rb_hash_aset(
+                    row_hash,
+                    rb_id2sym(rb_intern(cstr!("filename"))),
+                    match &row.filename {
+                        Some(filename) => rb_str_from_bytes(filename.as_bytes()),
+                        None => Qnil as VALUE,
+                    },
+                );
+                rb_hash_aset(
+                    row_hash,
+                    rb_id2sym(rb_intern(cstr!("self"))),
+                    rb_ull2inum(row.self_samples),
+                );
+                rb_hash_aset(
+                    row_hash,
+                    rb_id2sym(rb_intern(cstr!("total"))),
+                    rb_ull2inum(row.total_samples),
+                );
+                rb_ary_push(top_functions, row_hash);
+            }
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("top_functions"))),
+                top_functions,
+            );
+            hash
+        }
+    }
+
+    fn output_serialized_profile(&self, options: &StopOptions, output: VALUE) -> VALUE {
+        if options.format == StopFormat::None {
+            return Qnil.into();
+        }
+        if let Some(io) = options.io {
+            if let Err(e) = self.write_profile_to_io(io, options) {
+                e.raise();
+            }
+            return output;
+        }
+        match options.output {
+            Some(ref path) => {
+                // Write the profile to a file instead of returning it as a (potentially huge) String
+                if let Err(e) = self.write_profile_to(path, options) {
+                    e.raise();
+                }
+                output
+            }
+            None if options.compress => self.serialize_profile_compressed(options),
+            None => self.serialize_profile(options),
+        }
+    }
+
+    fn parse_option_compress(value: VALUE) -> bool {
+        if value == Qundef as VALUE {
+            return false;
+        }
+        RTEST(value)
+    }
+
+    fn parse_option_deterministic(value: VALUE) -> bool {
+        if value == Qundef as VALUE {
+            return false;
+        }
+        RTEST(value)
+    }
+
+    fn parse_option_dedup_stacks(value: VALUE) -> bool {
+        if value == Qundef as VALUE {
+            return false;
+        }
+        RTEST(value)
+    }
+
+    fn parse_option_coalesce(value: VALUE) -> bool {
+        if value == Qundef as VALUE {
+            return false;
+        }
+        RTEST(value)
+    }
+
+    fn parse_option_include_stats(value: VALUE) -> bool {
+        if value == Qundef as VALUE {
+            return false;
+        }
+        RTEST(value)
+    }
+
+    fn parse_option_summary(value: VALUE) -> bool {
+        if value == Qundef as VALUE {
+            return false;
+        }
+        RTEST(value)
+    }
+
+    fn parse_option_line_hits(value: VALUE) -> bool {
+        if value == Qundef as VALUE {
+            return false;
+        }
+        RTEST(value)
+    }
+
+    fn parse_option_redact_paths(value: VALUE) -> bool {
+        if value == Qundef as VALUE {
+            return false;
+        }
+        RTEST(value)
+    }
+
+    fn parse_option_pretty(value: VALUE) -> bool {
+        if value == Qundef as VALUE {
+            return false;
+        }
+        RTEST(value)
+    }
+
+    fn parse_option_min_samples(value: VALUE) -> Option<u64> {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return None;
+        }
+
+        let min_samples = integer_option(value, "min_samples");
+        if min_samples <= 0 {
+            Pf2Error::InvalidOption("min_samples must be positive.".to_owned()).raise();
+        }
+        Some(min_samples as u64)
+    }
+
+    /// An Array of Threads, or of thread IDs (Integer) as in `ruby_thread_id` of samples.
+    fn parse_option_retained_threads(value: VALUE) -> Option<HashSet<u64>> {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return None;
+        }
+        if !RTEST(unsafe { rb_obj_is_kind_of(value, rb_cArray) }) {
+            Pf2Error::InvalidOption("threads must be an Array of Threads or thread IDs".to_owned())
+                .raise();
+        }
+
+        let mut ruby_thread_ids = HashSet::new();
+        unsafe {
+            for i in 0..RARRAY_LEN(value) {
+                let thread = rb_ary_entry(value, i);
+                if RTEST(rb_obj_is_kind_of(thread, rb_cThread)) {
+                    ruby_thread_ids.insert(thread as u64);
+                } else if RTEST(rb_obj_is_kind_of(thread, rb_cInteger)) {
+                    ruby_thread_ids.insert(rb_num2ull(thread));
+                } else {
+                    Pf2Error::InvalidOption(
+                        "threads must be an Array of Threads or thread IDs".to_owned(),
+                    )
+                    .raise();
+                }
+            }
+        }
+        Some(ruby_thread_ids)
+    }
+
+    /// A Hash of prefixes (String) to category labels (String).
+    fn parse_option_categories(value: VALUE) -> Vec<(String, String)> {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return vec![];
+        }
+
+        let mut categories = vec![];
+        unsafe {
+            let pairs = rb_funcall(rb_Hash(value), rb_intern(cstr!("to_a")), 0);
+            for i in 0..RARRAY_LEN(pairs) {
+                let pair = rb_ary_entry(pairs, i);
+                let mut prefix = rb_funcall(rb_ary_entry(pair, 0), rb_intern(cstr!("to_s")), 0);
+                let mut label = rb_funcall(rb_ary_entry(pair, 1), rb_intern(cstr!("to_s")), 0);
+                categories.push((
+                    CStr::from_ptr(rb_string_value_cstr(&mut prefix))
+                        .to_string_lossy()
+                        .into_owned(),
+                    CStr::from_ptr(rb_string_value_cstr(&mut label))
+                        .to_string_lossy()
+                        .into_owned(),
+                ));
+            }
+        }
+        categories
+    }
+
+    fn parse_option_collapse_recursion(value: VALUE) -> Option<CollapseRecursion> {
+        if value == Qundef as VALUE || !RTEST(value) {
+            return None;
+        }
+        if value == Qtrue as VALUE {
+            return Some(CollapseRecursion::Direct);
+        }
+
+        let specified = string_option(value, "collapse_recursion");
+        Some(CollapseRecursion::from_str(&specified).unwrap_or_else(|_| {
+            Pf2Error::InvalidOption(
+                "Invalid collapse_recursion. Valid values are true, false, :direct and :cycles."
+                    .to_owned(),
+            )
+            .raise()
+        }))
+    }
+
+    fn parse_option_granularity(value: VALUE) -> Granularity {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return Granularity::Line;
+        }
+
+        let specified = string_option(value, "granularity");
+        Granularity::from_str(&specified).unwrap_or_else(|_| {
+            Pf2Error::InvalidOption(
+                "Invalid granularity. Valid values are 'line' and 'function'.".to_owned(),
+            )
+            .raise()
+        })
+    }
+
+    fn parse_option_weighting(value: VALUE) -> Weighting {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return Weighting::Count;
+        }
+
+        let specified = string_option(value, "weighting");
+        Weighting::from_str(&specified).unwrap_or_else(|_| {
+            Pf2Error::InvalidOption(
+                "Invalid weighting. Valid values are 'count' and 'time'.".to_owned(),
+            )
+            .raise()
+        })
+    }
+
+    fn parse_option_stop_format(value: VALUE) -> StopFormat {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return StopFormat::Profile;
+        }
+
+        let format = string_option(value, "format");
+        match format.as_str() {
+            "profile" => StopFormat::Profile,
+            "none" => StopFormat::None,
+            _ => StopFormat::Serialized(Format::from_str(&format).unwrap_or_else(|_| {
+                Pf2Error::InvalidOption(
+                    "Invalid format. Valid values are ':profile', ':json', ':folded', ':otlp', ':top', ':ndjson' and ':none'."
+                        .to_owned(),
+                )
+                .raise()
+            })),
+        }
+    }
+
+    /// Expanded into an absolute path (`File.expand_path`), as frame paths are usually absolute.
+    fn parse_option_relative_to(value: VALUE) -> Option<PathBuf> {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return None;
+        }
+
+        let path = unsafe {
+            let mut path = rb_file_expand_path(
+                rb_funcall(value, rb_intern(cstr!("to_s")), 0),
+                Qnil as VALUE,
+            );
+            CStr::from_ptr(rb_string_value_cstr(&mut path))
+                .to_string_lossy()
+                .into_owned()
+        };
+        Some(PathBuf::from(path))
+    }
+
+    fn parse_option_output(value: VALUE) -> Option<PathBuf> {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return None;
+        }
+
+        let path = unsafe {
+            let mut path = rb_funcall(value, rb_intern(cstr!("to_s")), 0);
+            CStr::from_ptr(rb_string_value_cstr(&mut path))
+                .to_string_lossy()
+                .into_owned()
+        };
+        Some(PathBuf::from(path))
+    }
+
+    fn serialize_profile(&self, options: &StopOptions) -> VALUE {
+        let ser = match self.profile.try_read() {
+            Ok(profile) => {
+                logging::debug!(
+                    "Number of samples: {}, duration: {:?}",
+                    profile.samples.len(),
+                    profile.duration()
+                );
+                self.build_profile(&profile, options)
+            }
+            Err(_) => Pf2Error::ProfileLocked.raise(),
+        };
+        if let StopFormat::Serialized(format) = options.format {
+            match format::serialize(ser.profile(), format) {
+                Ok(output) => output.to_ruby(),
+                Err(e) => e.raise(),
+            }
+        } else if self.configuration.use_experimental_serializer {
+            ser.to_ruby_hash()
+        } else {
+            rb_str_from_bytes(&ProfileSerializer::serialize(ser.profile(), options.pretty))
+        }
+    }
+
+    /// Serialize the profile into a gzipped JSON document and return it as a binary String.
+    fn serialize_profile_compressed(&self, options: &StopOptions) -> VALUE {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        let bytes = match self.write_profile(&mut encoder, options).and_then(|_| {
+            encoder
+                .finish()
+                .map_err(|e| Pf2Error::Serialization(e.to_string()))
+        }) {
+            Ok(bytes) => bytes,
+            Err(e) => e.raise(),
+        };
+        rb_str_from_bytes(&bytes)
+    }
+
+    /// Stream the serialized profile into the file at `path`, optionally gzipped.
+    /// Errors are returned, so that the caller can raise after releasing the lock.
+    fn write_profile_to(&self, path: &Path, options: &StopOptions) -> Result<(), Pf2Error> {
+        let file = File::create(path)
+            .map_err(|e| Pf2Error::Io(format!("Failed to open {}: {}", path.display(), e)))?;
+        self.write_profile_buffered(BufWriter::new(file), options)
+            .map_err(|e| match e {
+                Pf2Error::Serialization(msg) => Pf2Error::Io(format!(
+                    "Failed to write profile to {}: {}",
+                    path.display(),
+                    msg
+                )),
+                e => e,
+            })
+    }
+
+    /// Stream the serialized profile into `io` chunk by chunk, optionally gzipped, so that the
+    /// whole document is never held in memory.
+    fn write_profile_to_io(&self, io: StopIo, options: &StopOptions) -> Result<(), Pf2Error> {
+        let writer = BufWriter::with_capacity(IO_WRITE_CHUNK_SIZE, io.writer());
+        self.write_profile_buffered(writer, options)
+            .map_err(|e| match e {
+                Pf2Error::Serialization(msg) => {
+                    Pf2Error::Io(format!("Failed to write profile to io: {}", msg))
+                }
+                e => e,
+            })
+    }
+
+    /// Stream the serialized profile into `writer`, optionally gzipped, and flush it.
+    fn write_profile_buffered<W: Write>(
+        &self,
+        mut writer: BufWriter<W>,
+        options: &StopOptions,
+    ) -> Result<(), Pf2Error> {
+        let result = if options.compress {
+            let mut encoder = GzEncoder::new(&mut writer, Compression::default());
+            self.write_profile(&mut encoder, options).and_then(|_| {
+                encoder
+                    .finish()
+                    .map(|_| ())
+                    .map_err(|e| Pf2Error::Serialization(e.to_string()))
+            })
+        } else {
+            self.write_profile(&mut writer, options)
+        };
+        result.and_then(|_| {
+            writer
+                .flush()
+                .map_err(|e| Pf2Error::Serialization(e.to_string()))
+        })
+    }
+
+    fn write_profile<W: Write>(
+        &self,
+        writer: &mut W,
+        options: &StopOptions,
+    ) -> Result<(), Pf2Error> {
+        // Release the profile lock before writing, since an IO's #write runs arbitrary Ruby
+        // code, which may wait for the lock itself
+        let ser = {
+            let profile = self
+                .profile
+                .try_read()
+                .map_err(|_| Pf2Error::ProfileLocked)?;
+            logging::debug!(
+                "Number of samples: {}, duration: {:?}",
+                profile.samples.len(),
+                profile.duration()
+            );
+            self.build_profile(&profile, options)
+        };
+        if let StopFormat::Serialized(format) = options.format {
+            return writer
+                .write_all(format::serialize(ser.profile(), format)?.as_bytes())
+                .map_err(|e| Pf2Error::Serialization(e.to_string()));
+        }
+        let result = if self.configuration.use_experimental_serializer {
+            ser.to_writer(writer, options.pretty)
+        } else {
+            ProfileSerializer::serialize_to_writer(ser.profile(), writer, options.pretty)
+        };
+        result.map_err(|e| Pf2Error::Serialization(e.to_string()))
+    }
+
+    /// Build the canonical serialized profile, from which every output format is derived.
+    fn build_profile(&self, profile: &Profile, options: &StopOptions) -> ProfileSerializer2 {
+        let mut ser = ProfileSerializer2::new(&self.configuration);
+        without_auto_compaction(|| ser.serialize(profile));
+        if let Some(threads) = &options.threads {
+            ser.retain_threads(threads);
+        }
+        if options.weighting == Weighting::Time {
+            ser.weight_by_elapsed_time();
+        }
+        if self.configuration.measure_gc_pauses {
+            ser.weight_gc_samples_by_pause();
+        }
+        if let Some(min_samples) = options.min_samples {
+            ser.prune_rare_functions(min_samples);
+        }
+        if !options.categories.is_empty() {
+            ser.categorize(&options.categories);
+        }
+        if let Some(root) = &options.relative_to {
+            ser.relativize_paths(root);
+        }
+        if options.redact_paths {
+            ser.redact_paths();
+        }
+        if options.granularity == Granularity::Function {
+            ser.aggregate_by_function();
+        }
+        if options.deterministic {
+            ser.sort_deterministically();
+        }
+        if let Some(mode) = options.collapse_recursion {
+            ser.collapse_recursion(mode);
+        }
+        // The legacy serializer has one sample per capture
+        if options.coalesce && self.configuration.use_experimental_serializer {
+            ser.coalesce();
+        }
+        // The legacy serializer only understands flat stacks
+        if options.dedup_stacks && self.configuration.use_experimental_serializer {
+            ser.dedup_stacks();
+        }
+        // Counted last, over the samples and locations as they are output
+        if options.include_stats && self.configuration.use_experimental_serializer {
+            ser.compute_location_stats();
+        }
+        if options.line_hits && self.configuration.use_experimental_serializer {
+            ser.compute_line_hits();
+        }
+        ser
+    }
+
+    /// Discard the collected profile, so that the session can be started again.
+    pub fn reset(&mut self) -> VALUE {
+        if self.is_running() {
+            Pf2Error::Running("reset").raise();
+        }
+
+        let reset = match self.profile.try_write() {
+            Ok(mut profile) => {
+                profile.reset();
+                true
+            }
+            Err(_) => false,
+        };
+        if !reset {
+            Pf2Error::ProfileLocked.raise();
+        }
+        self.last_sample_count.store(0, Ordering::Relaxed);
+        self.last_memsize.store(0, Ordering::Relaxed);
+
+        Qnil.into()
+    }
+
+    /// Bring the session back to a clean, stopped state in a child process after `fork(2)`, with an
+    /// empty profile. Returns whether the session was running in the parent, so that the caller
+    /// can start it again. Must be called in the child before anything else touches the session.
+    ///
+    /// Only the forking thread survives a fork: the flusher, the timer thread of the scheduler and
+    /// the `on_flush` delivery thread are gone, and POSIX timers are not inherited. The state they
+    /// shared is abandoned rather than reused, since they may have been holding its locks.
+    /// It is never freed either, as the references held by the vanished threads are never released.
+    pub fn post_fork(&mut self) -> VALUE {
+        let was_running = self.running.swap(false, Ordering::Relaxed);
+
+        // Remove what the scheduler installed in the VM and the process (event hooks, TracePoints
+        // and the signal handler), which were inherited. Deleting inherited timer IDs just fails.
+        self.scheduler.stop();
+        // Removes its event hook. Its data is only touched with the GVL held, so it is not locked.
+        self.new_thread_watcher = None;
+        // Likewise for the GC TracePoint
+        self.gc_pauses_hook = None;
+        // Restore the crash signal handlers; the child dumps nothing until started again
+        self.crash_dump_hook = None;
+
+        self.profile = Arc::new(RwLock::new(Profile::new(
+            self.configuration.interval,
+            self.configuration.max_samples,
+            self.configuration.max_samples_policy.clone(),
+            Self::build_sample_filters(&self.configuration),
+        )));
+        self.scheduler = Self::new_scheduler(&self.configuration, &self.profile);
+        self.capture_stats = Arc::clone(&self.profile.read().unwrap().capture_stats);
+        self.running = Arc::new(AtomicBool::new(false));
+        self.new_thread_watcher =
+            Self::new_thread_watcher(&self.configuration, &self.scheduler, &self.running);
+        self.flush_callback = self
+            .flush_callback
+            .as_ref()
+            .map(|flush_callback| flush_callback.post_fork(&self.configuration, &self.profile));
+        self.last_sample_count.store(0, Ordering::Relaxed);
+        self.last_memsize.store(0, Ordering::Relaxed);
+
+        if was_running {
+            Qtrue.into()
+        } else {
+            Qfalse.into()
+        }
+    }
+
+    /// The configuration in effect, after defaults have been applied, along with settings which
+    /// are not configurable (the signal and the capacity of the sample buffer) and the interval
+    /// currently sampled at, which `target_overhead_pct` may have lengthened.
+    pub fn configuration(&self) -> VALUE {
+        let hash = self.configuration.to_rb_hash();
+        let current_interval = self.profile.read().unwrap().sampling_interval.get();
+        unsafe {
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("current_interval_ms"))),
+                rb_int2inum(current_interval.as_millis() as isize),
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("signal"))),
+                match self.configuration.scheduler {
+                    configuration::Scheduler::Signal => rb_id2sym(rb_intern(cstr!("SIGALRM"))),
+                    configuration::Scheduler::TimerThread => Qnil as VALUE,
+                },
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("buffer_capacity"))),
+                rb_int2inum(TEMPORARY_SAMPLE_BUFFER_MAX_CAPACITY as isize),
+            );
+        }
+        hash
+    }
+
+    /// The number of samples collected so far.
+    /// Never blocks; if the profile is locked, the last known value is returned.
+    pub fn sample_count(&self) -> VALUE {
+        let count = match self.profile.try_read() {
+            Ok(profile) => {
+                let count = profile.sample_count();
+                self.last_sample_count.store(count, Ordering::Relaxed);
+                count
+            }
+            Err(_) => self.last_sample_count.load(Ordering::Relaxed),
+        };
+        unsafe { rb_int2inum(count as isize) }
+    }
+
+    /// An estimate of the memory used by the profile so far (samples, buffers and the tables
+    /// serialization will build), in bytes. Unlike `dsize`, which reports to GC, it is meant
+    /// for applications deciding when to snapshot or reset.
+    /// Never blocks; if the profile is locked, the last known value is returned.
+    pub fn memsize(&self) -> VALUE {
+        let memsize = match self.profile.try_read() {
+            Ok(profile) => {
+                let memsize = profile.estimated_memsize();
+                self.last_memsize.store(memsize, Ordering::Relaxed);
+                memsize
+            }
+            Err(_) => self.last_memsize.load(Ordering::Relaxed),
+        };
+        unsafe { rb_int2inum(memsize as isize) }
+    }
+
+    /// The numbers of samples dropped since the start of the profile, or the last
+    /// `reset_drop_stats`: `{buffer_full:, lock_contention:, truncated:, coalesced_jobs:}`.
+    /// Samples with a truncated Ruby stack are kept, but counted as a partial loss.
+    /// `coalesced_jobs` counts ticks of TimerThreadScheduler lost to Ruby coalescing postponed
+    /// jobs, and stays 0 with other schedulers. Never blocks.
+    pub fn drop_stats(&self) -> VALUE {
+        let counts = self.capture_stats.drop_counts();
+        unsafe {
+            let hash = rb_hash_new();
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("buffer_full"))),
+                rb_ull2inum(counts.buffer_full),
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("lock_contention"))),
+                rb_ull2inum(counts.lock_contention),
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("truncated"))),
+                rb_ull2inum(counts.truncated),
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("coalesced_jobs"))),
+                rb_ull2inum(counts.coalesced_jobs),
+            );
+            hash
+        }
+    }
+
+    /// Count drops from zero again in `drop_stats`, e.g. after each poll of a monitoring loop.
+    /// The counts in the profile's `overhead` metadata are left as they are.
+    pub fn reset_drop_stats(&self) -> VALUE {
+        self.capture_stats.reset_drop_counts();
+        Qnil.into()
+    }
+
+    /// Move the samples captured so far into the profile right away, rather than waiting for the
+    /// flusher. Returns the number of samples added to the profile; samples captured during
+    /// warmup, or beyond `max_samples` with `max_samples_policy: :stop`, are discarded.
+    /// Does nothing and returns 0 when the session is not running.
+    pub fn flush(&self) -> VALUE {
+        if !self.is_running() {
+            return unsafe { rb_int2inum(0) };
+        }
+
+        // Waiting for the lock with the GVL held is fine (see `Profile`)
+        let flushed = {
+            let mut profile = self.profile.write().unwrap();
+            let flushed_before = profile.flushed_sample_count;
+            profile.flush_temporary_sample_buffer();
+            if let Some(window) = self.configuration.window {
+                profile.evict_samples_older_than(window);
+            }
+            profile.flushed_sample_count - flushed_before
+        };
+        unsafe { rb_ull2inum(flushed) }
+    }
+
+    /// Record a marker named `label` (a String or Symbol) on the timeline, e.g. at the start of a
+    /// phase of the program. Returns whether it was recorded: it is not if the session is not
+    /// running or is still warming up.
+    ///
+    /// Meant to be cheap enough for hot paths: the profile is only read-locked, and markers
+    /// have a lock of their own.
+    pub fn mark(&self, label: VALUE) -> VALUE {
+        let label = unsafe {
+            let mut str = rb_funcall(label, rb_intern(cstr!("to_s")), 0);
+            CStr::from_ptr(rb_string_value_cstr(&mut str))
+                .to_string_lossy()
+                .into_owned()
+        };
+        if !self.is_running() {
+            return Qfalse.into();
+        }
+
+        let timestamp = Instant::now();
+        // Waiting for the lock with the GVL held is fine (see `Profile`)
+        let profile = self.profile.read().unwrap();
+        if timestamp < profile.start_instant || profile.end_instant.is_some() {
+            return Qfalse.into();
+        }
+        profile.markers.lock().unwrap().push(Marker {
+            timestamp,
+            label,
+            ruby_thread: unsafe { rb_thread_current() },
+        });
+        Qtrue.into()
+    }
+
+    /// Capture a sample of `thread` right away, regardless of the scheduler (e.g. on an event
+    /// of the embedding application), and add it to the profile.
+    ///
+    /// Returns whether the sample was recorded: it is not if the session is not running, is
+    /// still warming up, has reached `max_samples` (with `max_samples_policy: :stop`), or the
+    /// sample buffer is full. Native stacks are only captured when `thread` is the calling one.
+    pub fn sample_thread(&self, thread: VALUE) -> VALUE {
+        unsafe {
+            if !RTEST(rb_obj_is_kind_of(thread, rb_cThread)) {
+                rb_raise(
+                    rb_eTypeError,
+                    cstr!("thread must be a Thread, not %s"),
+                    rb_obj_classname(thread),
+                );
+            }
+            // A dead thread has no stack to be sampled
+            if !RTEST(rb_funcall(thread, rb_intern(cstr!("alive?")), 0)) {
+                Pf2Error::InvalidOption("thread must be alive".to_owned()).raise();
+            }
+        }
+        if !self.is_running() {
+            return Qfalse.into();
+        }
+
+        let is_current = thread == unsafe { rb_thread_current() };
+        // Per-thread clocks refer to the calling thread, which may not be `thread`
+        let clockid = match (&self.configuration.clock, is_current) {
+            #[cfg(target_os = "linux")]
+            (configuration::Clock::ThreadCpuTime, false) => unsafe {
+                crate::ruby_internal_apis::rb_thread_getcpuclockid(thread)
+            },
+            (clock, _) => clock.clockid(),
+        };
+
+        // Waiting for the lock with the GVL held is fine (see `Profile`). Capturing a sample
+        // allocates no Ruby objects.
+        let mut profile = self.profile.write().unwrap();
+        if !profile.accepts_samples() {
+            return Qfalse.into();
+        }
+        let capture_started_at = Instant::now();
+        let mut sample = match is_current {
+            true => Sample::capture(
+                thread,
+                &profile.backtrace_state,
+                self.configuration.max_stack_depth,
+                clockid,
+            ),
+            false => Sample::capture_without_native_stack(
+                thread,
+                self.configuration.max_stack_depth,
+                clockid,
+            ),
+        };
+        sample.ruby_ractor_id = current_ractor_id();
+        // The calling thread holds the GVL. Whether another thread does is unknown.
+        sample.state = is_current.then(|| ThreadState::from_gvl(true, sample.during_gc));
+        profile
+            .capture_stats
+            .record(capture_started_at.elapsed(), sample.omitted_frames > 0);
+
+        if profile.temporary_sample_buffer.push(sample).is_err() {
+            profile.capture_stats.record_dropped_by_full_buffer();
+            return Qfalse.into();
+        }
+        Qtrue.into()
+    }
+
+    /// The wall-clock time at which profiling started, in nanoseconds since the UNIX epoch.
+    pub fn start_timestamp_ns(&self) -> VALUE {
+        let start_timestamp_ns = self.profile.read().unwrap().start_timestamp_ns();
+        unsafe { rb_ull2inum(start_timestamp_ns as u64) }
+    }
+
+    /// The time elapsed from start to stop (or until now while running), in nanoseconds.
+    pub fn duration_ns(&self) -> VALUE {
+        let duration_ns = self.profile.read().unwrap().duration().as_nanos();
+        unsafe { rb_ull2inum(duration_ns as u64) }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Export per-thread sample counts bucketed by `timeline_resolution` as a JSON string.
+    pub fn histogram(&self) -> VALUE {
+        let histogram = match self.profile.try_read() {
+            Ok(profile) => SampleHistogram::build(&profile, self.configuration.timeline_resolution),
+            Err(_) => {
+                logging::debug!("histogram: Failed to acquire profile lock");
+                return Qnil.into();
+            }
+        };
+        rb_str_from_bytes(&serde_json::to_vec(&histogram).unwrap())
+    }
+
+    /// Export a flat table of per-function self and total samples.
+    /// Returns a JSON array by default, or a plain text table with `format: :text`.
+    pub fn summary(&self, argc: c_int, argv: *const VALUE) -> VALUE {
+        let [format] = scan_kwargs(argc, argv, [cstr!("format")]);
+        let text = Self::parse_option_summary_format(format);
+
+        let ser = match self.profile.try_read() {
+            Ok(profile) => {
+                let options = StopOptions::default();
+                self.build_profile(&profile, &options)
+            }
+            Err(_) => {
+                logging::debug!("summary: Failed to acquire profile lock");
+                return Qnil.into();
+            }
+        };
+        let rows = FunctionSummary::build(ser.profile());
+        if text {
+            let text = FunctionSummary::to_text(&rows, ser.profile().samples.len());
+            rb_str_from_bytes(text.as_bytes())
+        } else {
+            rb_str_from_bytes(&serde_json::to_vec(&rows).unwrap())
+        }
+    }
+
+    /// Serialize the samples collected so far without stopping, in the same format as `stop`
+    /// (accepting the same options). With `mode: :continuous`, this is the most recent window.
+    ///
+    /// Samples still in the sample buffers are flushed first. If the background flusher holds
+    /// the profile lock at the moment, this waits for it to finish instead of failing.
+    pub fn snapshot(&self, argc: c_int, argv: *const VALUE) -> VALUE {
+        let (options, output) = Self::scan_stop_options(argc, argv);
+
+        let mut flushed = false;
+        for _ in 0..SNAPSHOT_LOCK_ATTEMPTS {
+            if let Ok(mut profile) = self.profile.try_write() {
+                profile.flush_temporary_sample_buffer();
+                if let Some(window) = self.configuration.window {
+                    profile.evict_samples_older_than(window);
+                }
+                flushed = true;
+                break;
+            }
+            // The flusher lets go of the lock without needing the GVL (see `Profile`)
+            thread::sleep(Duration::from_millis(1));
+        }
+        if !flushed {
+            Pf2Error::ProfileLocked.raise();
+        }
+
+        self.output_profile(&options, output)
+    }
+
+    /// Export the samples collected so far as a Ruby Array of Hashes
+    /// (see `ProfileSerializer2::to_ruby_samples`), for post-processing in Ruby.
+    pub fn samples(&self) -> VALUE {
+        let ser = match self.profile.try_read() {
+            Ok(profile) => {
+                let options = StopOptions::default();
+                self.build_profile(&profile, &options)
+            }
+            Err(_) => Pf2Error::ProfileLocked.raise(),
+        };
+        ser.to_ruby_samples()
+    }
+
+    /// The raw attributes of each frame of the `index`th sample in the profile, as Ruby reported
+    /// them, next to the Function Pf2 resolves them to (see `debug_frames::sample_frames_to_ruby`).
+    /// nil if there is no such sample. Only available with the `debug` feature.
+    #[cfg(feature = "debug")]
+    pub fn debug_frames(&self, index: VALUE) -> VALUE {
+        let index = unsafe { rb_num2long(index) };
+        match self.profile.try_read() {
+            Ok(profile) => match usize::try_from(index)
+                .ok()
+                .and_then(|index| profile.samples.get(index))
+            {
+                Some(sample) => without_auto_compaction(|| {
+                    crate::debug_frames::sample_frames_to_ruby(sample, &self.configuration)
+                }),
+                None => Qnil.into(),
+            },
+            Err(_) => Pf2Error::ProfileLocked.raise(),
+        }
+    }
+
+    /// Whether the summary should be formatted as text (as opposed to JSON).
+    fn parse_option_summary_format(value: VALUE) -> bool {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return false;
+        }
+
+        let format = unsafe {
+            let mut str = rb_funcall(value, rb_intern(cstr!("to_s")), 0);
+            CStr::from_ptr(rb_string_value_cstr(&mut str))
+                .to_string_lossy()
+                .into_owned()
+        };
+        match format.as_str() {
+            "json" => false,
+            "text" => true,
+            _ => Pf2Error::InvalidOption(
+                "Invalid format. Valid values are ':json' and ':text'.".to_owned(),
+            )
+            .raise(),
+        }
+    }
+
+    pub fn dmark(&self) {
+        self.scheduler.dmark();
+        if let Some(gc_pauses_hook) = &self.gc_pauses_hook {
+            gc_pauses_hook.dmark();
+        }
+        if let Some(flush_callback) = &self.flush_callback {
+            flush_callback.dmark();
+        }
+    }
+
+    pub fn dcompact(&self) {
+        self.scheduler.dcompact()
+    }
+
+    /// Bytes held outside of the Session struct itself (which is embedded in SessionRubyObject).
+    pub fn dsize(&self) -> size_t {
+        self.scheduler.dsize()
     }
 }
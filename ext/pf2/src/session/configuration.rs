@@ -1,29 +1,376 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::{c_char, c_long};
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::time::Duration;
 
 use rb_sys::*;
+use regex::Regex;
 
-use crate::util::cstr;
+use crate::sample::MAX_STACK_DEPTH;
+use crate::sample_filter::SampleFilterKind;
+use crate::util::{cstr, rb_str_from_bytes};
 
 #[cfg(target_os = "linux")]
 pub const DEFAULT_SCHEDULER: Scheduler = Scheduler::Signal;
 #[cfg(target_os = "linux")]
 pub const DEFAULT_TIME_MODE: TimeMode = TimeMode::CpuTime;
 #[cfg(not(target_os = "linux"))]
-pub const DEFAULT_SCHEDULER: Scheduler = Scheduler::TimerThread;
+pub const DEFAULT_SCHEDULER: Scheduler = Scheduler::Signal;
 #[cfg(not(target_os = "linux"))]
 pub const DEFAULT_TIME_MODE: TimeMode = TimeMode::WallTime;
 
 pub const DEFAULT_INTERVAL: Duration = Duration::from_millis(9);
+pub const DEFAULT_TIMELINE_RESOLUTION: Duration = Duration::from_millis(100);
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+pub const DEFAULT_CALLS_PER_SAMPLE: u64 = 1000;
+/// Beyond this many samples per second over all threads, the flusher tends to fall behind and
+/// samples are dropped. Starting a profile above it only warns.
+pub const DEFAULT_MAX_SAMPLING_RATE_HZ: u64 = 2000;
+/// Far more distinct functions than normal programs run, even large Rails applications.
+pub const DEFAULT_MAX_FUNCTIONS: usize = 100_000;
 
 #[derive(Clone, Debug)]
 pub struct Configuration {
     pub scheduler: Scheduler,
+    /// How the signal scheduler arms timers.
+    pub strategy: Strategy,
     pub interval: Duration,
     pub time_mode: TimeMode,
+    /// The clock used for sample timestamps and, where possible, for arming timers.
+    pub clock: Clock,
     pub target_ruby_threads: Threads,
+    /// With `threads: :all`, also profile the threads alive when the profile starts
+    /// (not only those created or resumed afterwards).
+    pub all_threads: bool,
     pub use_experimental_serializer: bool,
+    /// The maximum number of Ruby frames captured per sample.
+    pub max_stack_depth: usize,
+    /// The bucket width used when exporting per-thread sample histograms.
+    pub timeline_resolution: Duration,
+    /// Stop collecting samples automatically once this much time has elapsed since the profile started.
+    pub max_duration: Option<Duration>,
+    /// The maximum number of samples retained in the profile.
+    pub max_samples: Option<usize>,
+    /// What to do once `max_samples` samples have been collected.
+    pub max_samples_policy: MaxSamplesPolicy,
+    /// Ruby frames whose file path matches any of these patterns are omitted from serialized stacks.
+    pub exclude_paths: Vec<Pattern>,
+    /// Only threads whose name matches this pattern are profiled.
+    /// Matched against `target_ruby_threads` once, when the session is created.
+    pub thread_name_filter: Option<Pattern>,
+    /// Whether threads without a name pass `thread_name_filter`.
+    pub include_unnamed_threads: bool,
+    /// Samples captured within this duration after `start` are discarded.
+    pub warmup: Duration,
+    /// What to do with samples of idle (sleeping or blocked) threads.
+    pub idle_samples: IdleSamples,
+    /// How often captured samples are moved into the profile.
+    /// With `FlushMode::EventDriven`, this is the fallback for buffers which fill up slowly.
+    pub flush_interval: Duration,
+    pub flush_mode: FlushMode,
+    pub mode: Mode,
+    /// With `Mode::Continuous`, samples older than this are evicted from the profile.
+    pub window: Option<Duration>,
+    /// Lengthen the sampling interval whenever capturing samples takes more than this
+    /// percentage of wall time.
+    pub target_overhead_pct: Option<f64>,
+    /// Randomize each sampling interval by up to this percentage of the nominal interval.
+    /// 0 keeps intervals fixed.
+    pub jitter_pct: f64,
+    /// Measure GC pauses, and weight samples captured during GC by the pause they landed in.
+    pub measure_gc_pauses: bool,
+    /// Arbitrary key/value pairs (e.g. `env`, `service`) written into the profile's metadata.
+    pub labels: BTreeMap<String, String>,
+    /// What triggers the capture of samples.
+    pub trigger: Trigger,
+    /// With `Trigger::Calls`, a sample is captured every this many method calls on each thread.
+    pub every: Option<u64>,
+    /// With `Trigger::Calls`, each method call is sampled with this probability instead of
+    /// counting `every` calls.
+    pub sample_probability: Option<f64>,
+    /// The sampling rate over all target threads above which `start` warns.
+    pub max_sampling_rate_hz: u64,
+    /// Samples are kept only if all of these filters keep them, as they are flushed.
+    pub sample_filters: Vec<SampleFilterKind>,
+    /// Write the samples collected so far into this file if the process crashes
+    /// (SIGSEGV or SIGABRT) while profiling.
+    pub crash_dump: Option<PathBuf>,
+    /// The number of distinct functions in a serialized profile, beyond which further functions
+    /// are folded into a single `(overflow)` function.
+    pub max_functions: usize,
+}
+
+/// Builds a `Configuration`, filling in defaults for options which are not set.
+#[derive(Clone, Debug, Default)]
+pub struct ConfigurationBuilder {
+    scheduler: Option<Scheduler>,
+    strategy: Option<Strategy>,
+    interval: Option<Duration>,
+    time_mode: Option<TimeMode>,
+    clock: Option<Clock>,
+    target_ruby_threads: Option<Threads>,
+    all_threads: bool,
+    use_experimental_serializer: bool,
+    max_stack_depth: Option<usize>,
+    timeline_resolution: Option<Duration>,
+    max_duration: Option<Duration>,
+    max_samples: Option<usize>,
+    max_samples_policy: Option<MaxSamplesPolicy>,
+    exclude_paths: Vec<Pattern>,
+    thread_name_filter: Option<Pattern>,
+    include_unnamed_threads: bool,
+    warmup: Duration,
+    idle_samples: IdleSamples,
+    flush_interval: Option<Duration>,
+    flush_mode: FlushMode,
+    mode: Mode,
+    window: Option<Duration>,
+    target_overhead_pct: Option<f64>,
+    jitter_pct: f64,
+    measure_gc_pauses: bool,
+    labels: BTreeMap<String, String>,
+    trigger: Trigger,
+    every: Option<u64>,
+    sample_probability: Option<f64>,
+    max_sampling_rate_hz: Option<u64>,
+    sample_filters: Vec<SampleFilterKind>,
+    crash_dump: Option<PathBuf>,
+    max_functions: Option<usize>,
+}
+
+impl ConfigurationBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn scheduler(mut self, scheduler: Scheduler) -> Self {
+        self.scheduler = Some(scheduler);
+        self
+    }
+
+    /// Defaults to the strategy supported by the platform (see `Strategy::default_for`).
+    pub fn strategy(mut self, strategy: Option<Strategy>) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    pub fn time_mode(mut self, time_mode: TimeMode) -> Self {
+        self.time_mode = Some(time_mode);
+        self
+    }
+
+    /// Defaults to the clock matching the time mode.
+    pub fn clock(mut self, clock: Option<Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn target_ruby_threads(mut self, threads: Threads) -> Self {
+        self.target_ruby_threads = Some(threads);
+        self
+    }
+
+    pub fn all_threads(mut self, all_threads: bool) -> Self {
+        self.all_threads = all_threads;
+        self
+    }
+
+    pub fn use_experimental_serializer(mut self, enabled: bool) -> Self {
+        self.use_experimental_serializer = enabled;
+        self
+    }
+
+    pub fn max_stack_depth(mut self, max_stack_depth: usize) -> Self {
+        self.max_stack_depth = Some(max_stack_depth);
+        self
+    }
+
+    pub fn timeline_resolution(mut self, resolution: Duration) -> Self {
+        self.timeline_resolution = Some(resolution);
+        self
+    }
+
+    pub fn max_duration(mut self, max_duration: Option<Duration>) -> Self {
+        self.max_duration = max_duration;
+        self
+    }
+
+    pub fn max_samples(mut self, max_samples: Option<usize>) -> Self {
+        self.max_samples = max_samples;
+        self
+    }
+
+    pub fn max_samples_policy(mut self, policy: MaxSamplesPolicy) -> Self {
+        self.max_samples_policy = Some(policy);
+        self
+    }
+
+    pub fn exclude_paths(mut self, patterns: Vec<Pattern>) -> Self {
+        self.exclude_paths = patterns;
+        self
+    }
+
+    pub fn thread_name_filter(mut self, filter: Option<Pattern>) -> Self {
+        self.thread_name_filter = filter;
+        self
+    }
+
+    pub fn include_unnamed_threads(mut self, include: bool) -> Self {
+        self.include_unnamed_threads = include;
+        self
+    }
+
+    pub fn warmup(mut self, warmup: Duration) -> Self {
+        self.warmup = warmup;
+        self
+    }
+
+    pub fn idle_samples(mut self, idle_samples: IdleSamples) -> Self {
+        self.idle_samples = idle_samples;
+        self
+    }
+
+    pub fn flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = Some(flush_interval);
+        self
+    }
+
+    pub fn flush_mode(mut self, flush_mode: FlushMode) -> Self {
+        self.flush_mode = flush_mode;
+        self
+    }
+
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Defaults to `DEFAULT_WINDOW` in continuous mode.
+    pub fn window(mut self, window: Option<Duration>) -> Self {
+        self.window = window;
+        self
+    }
+
+    pub fn target_overhead_pct(mut self, target_overhead_pct: Option<f64>) -> Self {
+        self.target_overhead_pct = target_overhead_pct;
+        self
+    }
+
+    pub fn jitter_pct(mut self, jitter_pct: f64) -> Self {
+        self.jitter_pct = jitter_pct;
+        self
+    }
+
+    pub fn measure_gc_pauses(mut self, measure_gc_pauses: bool) -> Self {
+        self.measure_gc_pauses = measure_gc_pauses;
+        self
+    }
+
+    pub fn labels(mut self, labels: BTreeMap<String, String>) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    pub fn trigger(mut self, trigger: Trigger) -> Self {
+        self.trigger = trigger;
+        self
+    }
+
+    /// Defaults to `DEFAULT_CALLS_PER_SAMPLE` with `Trigger::Calls`, unless `sample_probability`
+    /// is set.
+    pub fn every(mut self, every: Option<u64>) -> Self {
+        self.every = every;
+        self
+    }
+
+    pub fn sample_probability(mut self, sample_probability: Option<f64>) -> Self {
+        self.sample_probability = sample_probability;
+        self
+    }
+
+    /// Defaults to `DEFAULT_MAX_SAMPLING_RATE_HZ`.
+    pub fn max_sampling_rate_hz(mut self, max_sampling_rate_hz: Option<u64>) -> Self {
+        self.max_sampling_rate_hz = max_sampling_rate_hz;
+        self
+    }
+
+    pub fn sample_filters(mut self, sample_filters: Vec<SampleFilterKind>) -> Self {
+        self.sample_filters = sample_filters;
+        self
+    }
+
+    pub fn crash_dump(mut self, crash_dump: Option<PathBuf>) -> Self {
+        self.crash_dump = crash_dump;
+        self
+    }
+
+    /// Defaults to `DEFAULT_MAX_FUNCTIONS`.
+    pub fn max_functions(mut self, max_functions: Option<usize>) -> Self {
+        self.max_functions = max_functions;
+        self
+    }
+
+    /// Build and validate the Configuration.
+    pub fn build(self) -> Result<Configuration, String> {
+        let time_mode = self.time_mode.unwrap_or(DEFAULT_TIME_MODE);
+        let scheduler = self.scheduler.unwrap_or(DEFAULT_SCHEDULER);
+        let configuration = Configuration {
+            strategy: self.strategy.unwrap_or(Strategy::default_for(&scheduler)),
+            scheduler,
+            interval: self.interval.unwrap_or(DEFAULT_INTERVAL),
+            clock: self.clock.unwrap_or(Clock::default_for(&time_mode)),
+            time_mode,
+            target_ruby_threads: self.target_ruby_threads.unwrap_or(Threads::All),
+            all_threads: self.all_threads,
+            use_experimental_serializer: self.use_experimental_serializer,
+            max_stack_depth: self.max_stack_depth.unwrap_or(MAX_STACK_DEPTH),
+            timeline_resolution: self
+                .timeline_resolution
+                .unwrap_or(DEFAULT_TIMELINE_RESOLUTION),
+            max_duration: self.max_duration,
+            max_samples: self.max_samples,
+            max_samples_policy: self.max_samples_policy.unwrap_or(MaxSamplesPolicy::Stop),
+            exclude_paths: self.exclude_paths,
+            thread_name_filter: self.thread_name_filter,
+            include_unnamed_threads: self.include_unnamed_threads,
+            warmup: self.warmup,
+            idle_samples: self.idle_samples,
+            flush_interval: self.flush_interval.unwrap_or(DEFAULT_FLUSH_INTERVAL),
+            flush_mode: self.flush_mode,
+            window: match self.mode {
+                Mode::Continuous => Some(self.window.unwrap_or(DEFAULT_WINDOW)),
+                Mode::Standard => self.window,
+            },
+            mode: self.mode,
+            target_overhead_pct: self.target_overhead_pct,
+            jitter_pct: self.jitter_pct,
+            measure_gc_pauses: self.measure_gc_pauses,
+            labels: self.labels,
+            every: match self.trigger {
+                Trigger::Calls if self.sample_probability.is_none() => {
+                    Some(self.every.unwrap_or(DEFAULT_CALLS_PER_SAMPLE))
+                }
+                _ => self.every,
+            },
+            sample_probability: self.sample_probability,
+            trigger: self.trigger,
+            max_sampling_rate_hz: self
+                .max_sampling_rate_hz
+                .unwrap_or(DEFAULT_MAX_SAMPLING_RATE_HZ),
+            sample_filters: self.sample_filters,
+            crash_dump: self.crash_dump,
+            max_functions: self.max_functions.unwrap_or(DEFAULT_MAX_FUNCTIONS),
+        };
+        configuration.validate()?;
+        Ok(configuration)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -44,6 +391,37 @@ impl FromStr for Scheduler {
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub enum Strategy {
+    /// A timer per thread, delivering signals to the thread itself. Linux-only.
+    PerThread,
+    /// A single process-wide timer, whose signal handler samples all target threads
+    GlobalTimer,
+}
+
+impl Strategy {
+    /// Per-thread timers where available. Elsewhere (e.g. macOS), the signal scheduler falls
+    /// back to a process-wide `setitimer(2)` timer.
+    pub fn default_for(scheduler: &Scheduler) -> Self {
+        if !cfg!(target_os = "linux") && *scheduler == Scheduler::Signal {
+            return Self::GlobalTimer;
+        }
+        Self::PerThread
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "per_thread" => Ok(Self::PerThread),
+            "global_timer" => Ok(Self::GlobalTimer),
+            _ => Err(()),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum TimeMode {
     CpuTime,
@@ -62,23 +440,385 @@ impl FromStr for TimeMode {
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub enum Clock {
+    Monotonic,
+    MonotonicRaw,
+    ThreadCpuTime,
+    ProcessCpuTime,
+}
+
+impl Clock {
+    pub fn default_for(time_mode: &TimeMode) -> Self {
+        match time_mode {
+            TimeMode::CpuTime => Self::ThreadCpuTime,
+            TimeMode::WallTime => Self::Monotonic,
+        }
+    }
+
+    /// The clock ID to read timestamps from. Per-thread clocks refer to the calling thread.
+    pub fn clockid(&self) -> libc::clockid_t {
+        match self {
+            Self::Monotonic => libc::CLOCK_MONOTONIC,
+            Self::MonotonicRaw => libc::CLOCK_MONOTONIC_RAW,
+            Self::ThreadCpuTime => libc::CLOCK_THREAD_CPUTIME_ID,
+            Self::ProcessCpuTime => libc::CLOCK_PROCESS_CPUTIME_ID,
+        }
+    }
+
+    /// Whether the clock measures CPU time rather than wall time.
+    pub fn is_cpu_time(&self) -> bool {
+        matches!(self, Self::ThreadCpuTime | Self::ProcessCpuTime)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Monotonic => "monotonic",
+            Self::MonotonicRaw => "monotonic_raw",
+            Self::ThreadCpuTime => "thread_cputime",
+            Self::ProcessCpuTime => "process_cputime",
+        }
+    }
+}
+
+impl FromStr for Clock {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "monotonic" => Ok(Self::Monotonic),
+            "monotonic_raw" => Ok(Self::MonotonicRaw),
+            "thread_cputime" => Ok(Self::ThreadCpuTime),
+            "process_cputime" => Ok(Self::ProcessCpuTime),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Threads are considered idle while sleeping or blocked (e.g. in IO).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum IdleSamples {
+    /// Keep samples of idle threads as they are
+    #[default]
+    Keep,
+    /// Discard samples of idle threads
+    Drop,
+    /// Keep samples of idle threads, with a synthetic `(idle)` leaf frame
+    Tag,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum FlushMode {
+    /// Flush every `flush_interval`
+    #[default]
+    Periodic,
+    /// Flush as soon as a sample buffer crosses its high-water mark,
+    /// and every `flush_interval` otherwise
+    EventDriven,
+}
+
+impl FromStr for FlushMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "periodic" => Ok(Self::Periodic),
+            "event_driven" => Ok(Self::EventDriven),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Mode {
+    /// Keep every sample from start to stop
+    #[default]
+    Standard,
+    /// Keep a rolling window of the most recent samples, for always-on profiling
+    Continuous,
+}
+
+impl FromStr for Mode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "standard" => Ok(Self::Standard),
+            "continuous" => Ok(Self::Continuous),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Trigger {
+    /// Sample at every interval, driven by the scheduler
+    #[default]
+    Time,
+    /// Sample at every `every` Ruby method calls (including methods implemented in C)
+    Calls,
+}
+
+impl FromStr for Trigger {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "time" => Ok(Self::Time),
+            "calls" => Ok(Self::Calls),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Trigger {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Time => "time",
+            Self::Calls => "calls",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MaxSamplesPolicy {
+    /// Stop recording new samples
+    Stop,
+    /// Discard the oldest samples to make room for new ones
+    Ring,
+}
+
+impl FromStr for MaxSamplesPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stop" => Ok(Self::Stop),
+            "ring" => Ok(Self::Ring),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A String pattern given as a Ruby String (substring match) or Regexp.
+#[derive(Clone, Debug)]
+pub enum Pattern {
+    Substring(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub fn matches(&self, string: &str) -> bool {
+        match self {
+            Self::Substring(substring) => string.contains(substring.as_str()),
+            Self::Regex(regex) => regex.is_match(string),
+        }
+    }
+
+    pub fn to_rb_value(&self) -> VALUE {
+        unsafe {
+            match self {
+                Self::Substring(substring) => rb_str_new(
+                    substring.as_ptr() as *const c_char,
+                    substring.len() as c_long,
+                ),
+                Self::Regex(regex) => {
+                    let source = regex.as_str();
+                    rb_reg_new(source.as_ptr() as *const c_char, source.len() as c_long, 0)
+                }
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Threads {
     All,
     Targeted(HashSet<VALUE>),
 }
 
+/// A combination of two options which cannot be used together.
+struct OptionConflict {
+    /// The options involved, as passed to `Session.new` (e.g. "`trigger: :calls`").
+    options: [&'static str; 2],
+    /// Whether a configuration combines them.
+    applies: fn(&Configuration) -> bool,
+    /// Why they conflict, or what to do instead.
+    reason: &'static str,
+}
+
+/// Every known conflict between options, checked by `Configuration::validate` in order.
+static OPTION_CONFLICTS: &[OptionConflict] = &[
+    OptionConflict {
+        options: ["`scheduler: :timer_thread`", "`time_mode: :cpu`"],
+        applies: |c| c.scheduler == Scheduler::TimerThread && c.time_mode == TimeMode::CpuTime,
+        reason: "the timer thread can only measure wall time",
+    },
+    OptionConflict {
+        options: ["`scheduler: :timer_thread`", "`threads: :all`"],
+        applies: |c| c.scheduler == Scheduler::TimerThread && c.target_ruby_threads == Threads::All,
+        reason: "consider using `threads: Thread.list` for watching all threads at profiler start",
+    },
+    OptionConflict {
+        options: ["`scheduler: :timer_thread`", "`trigger: :calls`"],
+        applies: |c| c.scheduler == Scheduler::TimerThread && c.trigger == Trigger::Calls,
+        reason: "calls are counted in place of any scheduler",
+    },
+    OptionConflict {
+        options: ["`strategy: :global_timer`", "`scheduler: :timer_thread`"],
+        applies: |c| c.strategy == Strategy::GlobalTimer && c.scheduler != Scheduler::Signal,
+        reason: "strategies are those of the signal scheduler",
+    },
+    OptionConflict {
+        options: ["`strategy: :global_timer`", "`time_mode: :cpu`"],
+        // setitimer(2) timers cannot tick with process CPU time as timer_create(2) ones can
+        applies: |c| {
+            !cfg!(target_os = "linux")
+                && c.strategy == Strategy::GlobalTimer
+                && c.time_mode != TimeMode::WallTime
+        },
+        reason: "the global timer can only measure wall time on this platform",
+    },
+    OptionConflict {
+        options: ["`all_threads: true`", "an explicit list of `threads`"],
+        applies: |c| c.all_threads && c.target_ruby_threads != Threads::All,
+        reason: "all_threads only applies to `threads: :all`",
+    },
+    OptionConflict {
+        options: ["`clock`", "`time_mode`"],
+        applies: |c| c.clock.is_cpu_time() != (c.time_mode == TimeMode::CpuTime),
+        reason: concat!(
+            "use :thread_cputime or :process_cputime with `time_mode: :cpu`, ",
+            "and :monotonic or :monotonic_raw with `time_mode: :wall`"
+        ),
+    },
+    OptionConflict {
+        options: ["`thread_name_filter`", "`threads: :all`"],
+        applies: |c| c.thread_name_filter.is_some() && c.target_ruby_threads == Threads::All,
+        reason: "pass an explicit list of threads (e.g. `threads: Thread.list`)",
+    },
+    OptionConflict {
+        options: ["`include_idle`", "`time_mode: :cpu`"],
+        applies: |c| c.idle_samples != IdleSamples::Keep && c.time_mode != TimeMode::WallTime,
+        reason: "idle threads consume no CPU time, and are never sampled in CPU time mode",
+    },
+    OptionConflict {
+        options: ["`include_idle`", "`trigger: :calls`"],
+        applies: |c| c.idle_samples != IdleSamples::Keep && c.trigger == Trigger::Calls,
+        reason: "idle threads make no calls, and are never sampled",
+    },
+    OptionConflict {
+        options: ["`window_ms`", "`mode: :standard`"],
+        applies: |c| c.mode != Mode::Continuous && c.window.is_some(),
+        reason: "window_ms requires `mode: :continuous`",
+    },
+    OptionConflict {
+        options: ["`every`", "`trigger: :time`"],
+        applies: |c| c.trigger != Trigger::Calls && c.every.is_some(),
+        reason: "every requires `trigger: :calls`",
+    },
+    OptionConflict {
+        options: ["`sample_probability`", "`trigger: :time`"],
+        applies: |c| c.trigger != Trigger::Calls && c.sample_probability.is_some(),
+        reason: "sample_probability requires `trigger: :calls`",
+    },
+    OptionConflict {
+        options: ["`sample_probability`", "`every`"],
+        applies: |c| c.sample_probability.is_some() && c.every.is_some(),
+        reason: "calls are either counted or sampled at random",
+    },
+    OptionConflict {
+        options: ["`target_overhead_pct`", "`trigger: :calls`"],
+        applies: |c| c.trigger == Trigger::Calls && c.target_overhead_pct.is_some(),
+        reason: "there is no sampling interval to adjust",
+    },
+    OptionConflict {
+        options: ["`jitter_pct`", "`trigger: :calls`"],
+        applies: |c| c.trigger == Trigger::Calls && c.jitter_pct > 0.0,
+        reason: "there is no sampling interval to randomize",
+    },
+];
+
 impl Configuration {
     pub fn validate(&self) -> Result<(), String> {
-        if self.scheduler == Scheduler::TimerThread && self.time_mode == TimeMode::CpuTime {
-            return Err("TimerThread scheduler does not support `time_mode: :cpu`.".to_owned());
+        if let Some(conflict) = OPTION_CONFLICTS
+            .iter()
+            .find(|conflict| (conflict.applies)(self))
+        {
+            return Err(format!(
+                "{} cannot be combined with {}: {}.",
+                conflict.options[0], conflict.options[1], conflict.reason
+            ));
+        }
+
+        if !cfg!(target_os = "linux")
+            && self.scheduler == Scheduler::Signal
+            && self.strategy == Strategy::PerThread
+        {
+            return Err(
+                "`strategy: :per_thread` requires per-thread timers, which are only available on Linux."
+                    .to_owned(),
+            );
+        }
+
+        if self.interval.is_zero() {
+            return Err("interval_ms (or interval_us) must be positive.".to_owned());
         }
-        if self.scheduler == Scheduler::TimerThread && self.target_ruby_threads == Threads::All {
-            return Err(concat!(
-                "TimerThread scheduler does not support `threads: :all` at the moment. ",
-                "Consider using `threads: Thread.list` for watching all threads at profiler start."
-            )
-            .to_owned());
+
+        if self.max_stack_depth == 0 || self.max_stack_depth > MAX_STACK_DEPTH {
+            return Err(format!(
+                "max_stack_depth must be between 1 and {}.",
+                MAX_STACK_DEPTH
+            ));
+        }
+
+        if self.timeline_resolution.is_zero() {
+            return Err("timeline_resolution_ms must be positive.".to_owned());
+        }
+
+        if self.max_duration.is_some_and(|d| d.is_zero()) {
+            return Err("max_duration_ms must be positive.".to_owned());
+        }
+
+        if self.max_samples == Some(0) {
+            return Err("max_samples must be positive.".to_owned());
+        }
+        if self.max_functions == 0 {
+            return Err("max_functions must be positive.".to_owned());
+        }
+
+        if self.flush_interval.is_zero() {
+            return Err("flush_interval_ms must be positive.".to_owned());
+        }
+
+        if self.window.is_some_and(|window| window.is_zero()) {
+            return Err("window_ms must be positive.".to_owned());
+        }
+
+        if self
+            .target_overhead_pct
+            .is_some_and(|pct| !(pct > 0.0 && pct <= 100.0))
+        {
+            return Err("target_overhead_pct must be greater than 0 and at most 100.".to_owned());
+        }
+
+        if !(0.0..100.0).contains(&self.jitter_pct) {
+            return Err("jitter_pct must be at least 0 and less than 100.".to_owned());
+        }
+
+        if self.every == Some(0) {
+            return Err("every must be positive.".to_owned());
+        }
+
+        if self
+            .sample_probability
+            .is_some_and(|probability| !(probability > 0.0 && probability <= 1.0))
+        {
+            return Err("sample_probability must be greater than 0 and at most 1.".to_owned());
+        }
+
+        if self.max_sampling_rate_hz == 0 {
+            return Err("max_sampling_rate_hz must be positive.".to_owned());
         }
 
         Ok(())
@@ -95,11 +835,24 @@ impl Configuration {
                     Scheduler::TimerThread => cstr!("timer_thread"),
                 })),
             );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("strategy"))),
+                rb_id2sym(rb_intern(match self.strategy {
+                    Strategy::PerThread => cstr!("per_thread"),
+                    Strategy::GlobalTimer => cstr!("global_timer"),
+                })),
+            );
             rb_hash_aset(
                 hash,
                 rb_id2sym(rb_intern(cstr!("interval_ms"))),
                 rb_int2inum(self.interval.as_millis().try_into().unwrap()),
             );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("interval_us"))),
+                rb_int2inum(self.interval.as_micros().try_into().unwrap()),
+            );
             rb_hash_aset(
                 hash,
                 rb_id2sym(rb_intern(cstr!("time_mode"))),
@@ -108,6 +861,214 @@ impl Configuration {
                     TimeMode::WallTime => cstr!("wall"),
                 })),
             );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("clock"))),
+                rb_id2sym(rb_intern2(
+                    self.clock.as_str().as_ptr() as *const c_char,
+                    self.clock.as_str().len() as c_long,
+                )),
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("all_threads"))),
+                if self.all_threads {
+                    Qtrue as VALUE
+                } else {
+                    Qfalse as VALUE
+                },
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("max_stack_depth"))),
+                rb_int2inum(self.max_stack_depth.try_into().unwrap()),
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("timeline_resolution_ms"))),
+                rb_int2inum(self.timeline_resolution.as_millis().try_into().unwrap()),
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("max_duration_ms"))),
+                match self.max_duration {
+                    Some(max_duration) => rb_int2inum(max_duration.as_millis().try_into().unwrap()),
+                    None => Qnil as VALUE,
+                },
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("max_samples"))),
+                match self.max_samples {
+                    Some(max_samples) => rb_int2inum(max_samples.try_into().unwrap()),
+                    None => Qnil as VALUE,
+                },
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("max_samples_policy"))),
+                rb_id2sym(rb_intern(match self.max_samples_policy {
+                    MaxSamplesPolicy::Stop => cstr!("stop"),
+                    MaxSamplesPolicy::Ring => cstr!("ring"),
+                })),
+            );
+            let exclude_paths = rb_ary_new();
+            for pattern in self.exclude_paths.iter() {
+                rb_ary_push(exclude_paths, pattern.to_rb_value());
+            }
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("exclude_paths"))),
+                exclude_paths,
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("thread_name_filter"))),
+                match &self.thread_name_filter {
+                    Some(pattern) => pattern.to_rb_value(),
+                    None => Qnil as VALUE,
+                },
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("include_unnamed_threads"))),
+                if self.include_unnamed_threads {
+                    Qtrue as VALUE
+                } else {
+                    Qfalse as VALUE
+                },
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("warmup_ms"))),
+                rb_int2inum(self.warmup.as_millis().try_into().unwrap()),
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("include_idle"))),
+                match self.idle_samples {
+                    IdleSamples::Keep => Qtrue as VALUE,
+                    IdleSamples::Drop => Qfalse as VALUE,
+                    IdleSamples::Tag => rb_id2sym(rb_intern(cstr!("tag"))),
+                },
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("flush_interval_ms"))),
+                rb_int2inum(self.flush_interval.as_millis().try_into().unwrap()),
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("flush_mode"))),
+                rb_id2sym(rb_intern(match self.flush_mode {
+                    FlushMode::Periodic => cstr!("periodic"),
+                    FlushMode::EventDriven => cstr!("event_driven"),
+                })),
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("mode"))),
+                rb_id2sym(rb_intern(match self.mode {
+                    Mode::Standard => cstr!("standard"),
+                    Mode::Continuous => cstr!("continuous"),
+                })),
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("window_ms"))),
+                match self.window {
+                    Some(window) => rb_int2inum(window.as_millis().try_into().unwrap()),
+                    None => Qnil as VALUE,
+                },
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("target_overhead_pct"))),
+                match self.target_overhead_pct {
+                    Some(pct) => rb_float_new(pct),
+                    None => Qnil as VALUE,
+                },
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("jitter_pct"))),
+                rb_float_new(self.jitter_pct),
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("measure_gc_pauses"))),
+                if self.measure_gc_pauses {
+                    Qtrue as VALUE
+                } else {
+                    Qfalse as VALUE
+                },
+            );
+            let labels = rb_hash_new();
+            for (key, value) in self.labels.iter() {
+                rb_hash_aset(
+                    labels,
+                    rb_str_from_bytes(key.as_bytes()),
+                    rb_str_from_bytes(value.as_bytes()),
+                );
+            }
+            rb_hash_aset(hash, rb_id2sym(rb_intern(cstr!("labels"))), labels);
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("trigger"))),
+                rb_id2sym(rb_intern2(
+                    self.trigger.as_str().as_ptr() as *const c_char,
+                    self.trigger.as_str().len() as c_long,
+                )),
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("every"))),
+                match self.every {
+                    Some(every) => rb_ull2inum(every),
+                    None => Qnil as VALUE,
+                },
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("sample_probability"))),
+                match self.sample_probability {
+                    Some(sample_probability) => rb_float_new(sample_probability),
+                    None => Qnil as VALUE,
+                },
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("max_sampling_rate_hz"))),
+                rb_ull2inum(self.max_sampling_rate_hz),
+            );
+            let sample_filters = rb_ary_new();
+            for sample_filter in self.sample_filters.iter() {
+                rb_ary_push(
+                    sample_filters,
+                    rb_id2sym(rb_intern2(
+                        sample_filter.as_str().as_ptr() as *const c_char,
+                        sample_filter.as_str().len() as c_long,
+                    )),
+                );
+            }
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("sample_filter"))),
+                sample_filters,
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("crash_dump"))),
+                match &self.crash_dump {
+                    Some(path) => rb_str_from_bytes(path.to_string_lossy().as_bytes()),
+                    None => Qnil as VALUE,
+                },
+            );
+            rb_hash_aset(
+                hash,
+                rb_id2sym(rb_intern(cstr!("max_functions"))),
+                rb_int2inum(self.max_functions.try_into().unwrap()),
+            );
         }
         hash
     }
@@ -0,0 +1,207 @@
+#![deny(unsafe_op_in_unsafe_fn)]
+
+use std::ffi::c_void;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use rb_sys::*;
+
+use super::configuration::Configuration;
+use crate::logging;
+use crate::profile::{FlushSignal, Profile};
+use crate::serialization::serializer::ProfileSerializer2;
+use crate::util::{cstr, mark_internal_thread, without_auto_compaction};
+
+/// Hands samples over to a Ruby callable (`on_flush:`) as soon as they are flushed into the
+/// profile, in the format of `Session#samples`.
+///
+/// The flusher runs on a native thread which must not call into Ruby. Instead, a dedicated Ruby
+/// Thread waits (without the GVL) until the flusher signals `batch_signal`, then serializes the
+/// samples flushed since the previous batch and calls the callback with them.
+pub struct FlushCallback {
+    delivery: Arc<Delivery>,
+    /// Signalled by the flusher after each flush.
+    batch_signal: Arc<FlushSignal>,
+    /// The Ruby Thread delivering batches, while the session is running.
+    thread: Option<VALUE>,
+}
+
+struct Delivery {
+    callback: VALUE,
+    configuration: Configuration,
+    profile: Arc<RwLock<Profile>>,
+    /// `Profile.flushed_sample_count` as of the last batch.
+    delivered: AtomicU64,
+}
+
+/// Owned by the delivering Ruby Thread.
+struct DeliveryThreadArgs {
+    delivery: Arc<Delivery>,
+    batch_signal: Arc<FlushSignal>,
+    running: Arc<AtomicBool>,
+}
+
+impl FlushCallback {
+    pub fn new(
+        callback: VALUE,
+        configuration: &Configuration,
+        profile: Arc<RwLock<Profile>>,
+    ) -> Self {
+        Self {
+            delivery: Arc::new(Delivery {
+                callback,
+                configuration: configuration.clone(),
+                profile,
+                delivered: AtomicU64::new(0),
+            }),
+            batch_signal: Arc::new(FlushSignal::default()),
+            thread: None,
+        }
+    }
+
+    /// A FlushCallback calling the same callback with samples of `profile`, for the child process
+    /// after `fork(2)` (see `Session::post_fork`). The delivering Thread did not survive the fork.
+    pub fn post_fork(&self, configuration: &Configuration, profile: &Arc<RwLock<Profile>>) -> Self {
+        Self::new(self.delivery.callback, configuration, Arc::clone(profile))
+    }
+
+    pub fn batch_signal(&self) -> Arc<FlushSignal> {
+        Arc::clone(&self.batch_signal)
+    }
+
+    /// Start delivering batches from a new Ruby Thread, until `running` is cleared.
+    /// Samples flushed before this call are not delivered.
+    pub fn start(&mut self, running: Arc<AtomicBool>) {
+        let flushed = self.delivery.profile.read().unwrap().flushed_sample_count;
+        self.delivery.delivered.store(flushed, Ordering::Relaxed);
+
+        let args = Box::new(DeliveryThreadArgs {
+            delivery: Arc::clone(&self.delivery),
+            batch_signal: Arc::clone(&self.batch_signal),
+            running,
+        });
+        let thread = unsafe {
+            rb_thread_create(
+                Some(Self::deliver_batches),
+                Box::into_raw(args) as *mut c_void,
+            )
+        };
+        // Marked before the thread gets a chance to run, as the GVL is still held
+        mark_internal_thread(thread);
+        self.thread = Some(thread);
+    }
+
+    /// Wait for the delivering Thread to exit. The caller must have cleared `running`.
+    ///
+    /// Samples flushed afterwards (e.g. by the final flush in `Session#stop`) are left to
+    /// `deliver_remaining()`.
+    pub fn stop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.batch_signal.notify();
+            unsafe { rb_funcall(thread, rb_intern(cstr!("join")), 0) };
+        }
+    }
+
+    /// Deliver the samples flushed since the last batch. Must be called with the GVL held.
+    pub fn deliver_remaining(&self) {
+        self.delivery.deliver();
+    }
+
+    pub fn dmark(&self) {
+        unsafe {
+            rb_gc_mark(self.delivery.callback);
+            if let Some(thread) = self.thread {
+                rb_gc_mark(thread);
+            }
+        }
+    }
+
+    unsafe extern "C" fn deliver_batches(ptr: *mut c_void) -> VALUE {
+        let args = unsafe { Box::from_raw(ptr as *mut DeliveryThreadArgs) };
+        while args.running.load(Ordering::Relaxed) {
+            let args_ptr = &*args as *const DeliveryThreadArgs as *mut c_void;
+            unsafe {
+                rb_thread_call_without_gvl(
+                    Some(Self::wait_for_batch),
+                    args_ptr,
+                    Some(Self::interrupt_wait),
+                    args_ptr,
+                );
+            }
+            if !args.running.load(Ordering::Relaxed) {
+                break;
+            }
+            args.delivery.deliver();
+        }
+        Qnil.into()
+    }
+
+    // Runs without the GVL
+    unsafe extern "C" fn wait_for_batch(args: *mut c_void) -> *mut c_void {
+        let args = unsafe { &*(args as *const DeliveryThreadArgs) };
+        // The flusher signals after every flush; the timeout is only a fallback
+        args.batch_signal
+            .wait(args.delivery.configuration.flush_interval);
+        null_mut()
+    }
+
+    /// Called by Ruby to interrupt `wait_for_batch` (e.g. on `Thread#kill`).
+    unsafe extern "C" fn interrupt_wait(args: *mut c_void) {
+        let args = unsafe { &*(args as *const DeliveryThreadArgs) };
+        args.batch_signal.notify();
+    }
+}
+
+impl Delivery {
+    /// Serialize the samples flushed since the last batch, and pass them to the callback.
+    /// Exceptions raised by the callback are reported as warnings, and do not stop delivery.
+    fn deliver(&self) {
+        // The profile lock is released before calling the callback
+        let batch = match self.profile.try_read() {
+            Ok(profile) => {
+                let flushed = profile.flushed_sample_count;
+                let count = flushed - self.delivered.swap(flushed, Ordering::Relaxed);
+                if count == 0 {
+                    return;
+                }
+                let mut ser = ProfileSerializer2::new(&self.configuration);
+                // Samples evicted (`max_samples_policy: :ring`) in the meantime are skipped
+                without_auto_compaction(|| {
+                    ser.serialize_latest(&profile, (count as usize).min(profile.samples.len()))
+                });
+                ser
+            }
+            Err(_) => {
+                // Retried at the next flush
+                logging::debug!("on_flush: Failed to acquire profile lock");
+                return;
+            }
+        };
+        let samples = batch.to_ruby_samples();
+
+        let mut state: i32 = 0;
+        let args = [self.callback, samples];
+        unsafe {
+            rb_protect(
+                Some(Self::call_callback),
+                args.as_ptr() as VALUE,
+                &mut state,
+            );
+            if state != 0 {
+                let exception = rb_errinfo();
+                rb_set_errinfo(Qnil.into());
+                let mut message = rb_funcall(exception, rb_intern(cstr!("inspect")), 0);
+                rb_warn(
+                    cstr!("[Pf2] on_flush raised an exception: %s"),
+                    rb_string_value_cstr(&mut message),
+                );
+            }
+        }
+    }
+
+    unsafe extern "C" fn call_callback(args: VALUE) -> VALUE {
+        let args = unsafe { &*(args as *const [VALUE; 2]) };
+        unsafe { rb_funcall(args[0], rb_intern(cstr!("call")), 1, args[1]) }
+    }
+}
@@ -0,0 +1,104 @@
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem::ManuallyDrop;
+use std::os::fd::{FromRawFd, RawFd};
+
+use rb_sys::*;
+
+use crate::error::Pf2Error;
+use crate::util::*;
+
+/// The destination given to `Session#stop(io:)`.
+#[derive(Clone, Copy)]
+pub enum StopIo {
+    /// An object responding to `#write`, such as an IO or a StringIO.
+    Object(VALUE),
+    /// A file descriptor owned by the caller. It is written to directly, and left open.
+    Fd(RawFd),
+}
+
+impl StopIo {
+    pub fn parse(value: VALUE) -> Option<Self> {
+        if value == Qundef as VALUE || value == Qnil as VALUE {
+            return None;
+        }
+
+        if RTEST(unsafe { rb_obj_is_kind_of(value, rb_cInteger) }) {
+            return match RawFd::try_from(integer_option(value, "io")) {
+                Ok(fd) if fd >= 0 => Some(Self::Fd(fd)),
+                _ => {
+                    Pf2Error::InvalidOption("io must be a valid file descriptor".to_owned()).raise()
+                }
+            };
+        }
+        if unsafe { rb_respond_to(value, rb_intern(cstr!("write"))) } == 0 {
+            Pf2Error::InvalidOption(
+                "io must respond to #write, or be a file descriptor".to_owned(),
+            )
+            .raise();
+        }
+        Some(Self::Object(value))
+    }
+
+    pub fn writer(&self) -> IoWriter {
+        match *self {
+            Self::Object(io) => IoWriter::Object(io),
+            // The caller keeps ownership of the file descriptor, so it must not be closed on drop
+            Self::Fd(fd) => IoWriter::Fd(ManuallyDrop::new(unsafe { File::from_raw_fd(fd) })),
+        }
+    }
+}
+
+/// Writes bytes to a `StopIo` as they are produced. Wrap in a BufWriter, so that Ruby's
+/// `#write` is called once per chunk rather than once per JSON token.
+///
+/// Must be used with the GVL held.
+pub enum IoWriter {
+    Object(VALUE),
+    Fd(ManuallyDrop<File>),
+}
+
+impl Write for IoWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Object(io) => Self::write_to_ruby_io(*io, buf).map(|_| buf.len()),
+            Self::Fd(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            // Buffering in Ruby's IO is left to the caller, as with any other #write
+            Self::Object(_) => Ok(()),
+            Self::Fd(file) => file.flush(),
+        }
+    }
+}
+
+impl IoWriter {
+    /// Call `io.write(bytes)`. An exception raised by it is rescued and returned as an error,
+    /// so that the caller can unwind before raising.
+    fn write_to_ruby_io(io: VALUE, bytes: &[u8]) -> io::Result<()> {
+        let args = [io, rb_str_from_bytes(bytes)];
+        let mut state: i32 = 0;
+        unsafe {
+            rb_protect(Some(Self::call_write), args.as_ptr() as VALUE, &mut state);
+            if state == 0 {
+                return Ok(());
+            }
+            let exception = rb_errinfo();
+            rb_set_errinfo(Qnil.into());
+            let mut message = rb_funcall(exception, rb_intern(cstr!("message")), 0);
+            let message = CStr::from_ptr(rb_string_value_cstr(&mut message))
+                .to_string_lossy()
+                .into_owned();
+            Err(io::Error::new(io::ErrorKind::Other, message))
+        }
+    }
+
+    unsafe extern "C" fn call_write(args: VALUE) -> VALUE {
+        let args = unsafe { &*(args as *const [VALUE; 2]) };
+        unsafe { rb_funcall(args[0], rb_intern(cstr!("write")), 1, args[1]) }
+    }
+}
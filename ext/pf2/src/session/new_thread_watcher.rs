@@ -1,12 +1,12 @@
 use std::collections::HashSet;
 use std::ffi::c_void;
-use std::mem::ManuallyDrop;
 use std::ptr::null_mut;
-use std::rc::Rc;
 use std::sync::Mutex;
 
 use rb_sys::*;
 
+use crate::logging;
+
 /// A helper to watch new Ruby threads.
 ///
 /// `NewThreadWatcher` operates on the Events Hooks API.
@@ -18,7 +18,9 @@ use rb_sys::*;
 /// `THREAD_EVENT_STARTED` is triggered; i.e. the underlying pthread has not
 /// been created yet and `Thread#native_thread_id` returns `nil`.
 pub struct NewThreadWatcher {
-    inner: Rc<Mutex<Inner>>,
+    /// State shared with the event hook, passed as custom data.
+    /// Owned by the watcher and released on drop, after the hook is removed.
+    inner: *mut Mutex<Inner>,
     event_hook: *mut rb_internal_thread_event_hook_t,
 }
 
@@ -33,25 +35,31 @@ impl NewThreadWatcher {
         F: Fn(VALUE) + 'static,
     {
         let mut watcher = Self {
-            inner: Rc::new(Mutex::new(Inner {
+            inner: Box::into_raw(Box::new(Mutex::new(Inner {
                 known_threads: HashSet::new(),
                 on_new_thread: Box::new(callback),
-            })),
+            }))),
             event_hook: null_mut(),
         };
 
-        let inner_ptr = Rc::into_raw(Rc::clone(&watcher.inner));
         unsafe {
             watcher.event_hook = rb_internal_thread_add_event_hook(
                 Some(Self::on_thread_resume),
                 RUBY_INTERNAL_THREAD_EVENT_RESUMED,
-                inner_ptr as *mut c_void,
+                watcher.inner as *mut c_void,
             );
         };
 
         watcher
     }
 
+    /// Report `ruby_thread` to the callback unless it has been seen already.
+    /// Used for threads which existed before the watcher, and may never resume.
+    pub fn track(&self, ruby_thread: VALUE) {
+        let inner = unsafe { &*self.inner };
+        inner.lock().unwrap().notify(ruby_thread);
+    }
+
     unsafe extern "C" fn on_thread_resume(
         _flag: rb_event_flag_t,
         data: *const rb_internal_thread_event_data,
@@ -59,22 +67,27 @@ impl NewThreadWatcher {
     ) {
         let ruby_thread: VALUE = unsafe { (*data).thread };
 
-        // A pointer to Box<Inner> is passed as custom_data
-        let inner = unsafe { ManuallyDrop::new(Box::from_raw(custom_data as *mut Mutex<Inner>)) };
-        let mut inner = inner.lock().unwrap();
+        // A pointer to Mutex<Inner> (owned by the watcher) is passed as custom_data
+        let inner = unsafe { &*(custom_data as *const Mutex<Inner>) };
+        inner.lock().unwrap().notify(ruby_thread);
+    }
+}
 
-        if !inner.known_threads.contains(&ruby_thread) {
-            inner.known_threads.insert(ruby_thread);
-            (inner.on_new_thread)(ruby_thread);
+impl Inner {
+    fn notify(&mut self, ruby_thread: VALUE) {
+        if self.known_threads.insert(ruby_thread) {
+            (self.on_new_thread)(ruby_thread);
         }
     }
 }
 
 impl Drop for NewThreadWatcher {
     fn drop(&mut self) {
-        log::trace!("Cleaning up event hook");
+        logging::trace!("Cleaning up event hook");
         unsafe {
             rb_internal_thread_remove_event_hook(self.event_hook);
+            // The hook is gone, so nobody else refers to Inner anymore
+            drop(Box::from_raw(self.inner));
         }
     }
 }
@@ -0,0 +1,69 @@
+use std::time::{Duration, Instant};
+
+use crate::logging;
+use crate::profile::{IntervalChange, Profile};
+
+/// Overhead is measured over windows at least this long, to smooth out bursts.
+const MIN_MEASUREMENT_WINDOW: Duration = Duration::from_millis(200);
+/// The interval is multiplied by this factor each time the overhead exceeds the target.
+const BACKOFF_FACTOR: u32 = 2;
+/// The interval is never lengthened beyond this.
+const MAX_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Lengthens the sampling interval whenever the time spent capturing samples exceeds
+/// `target_overhead_pct` percent of wall time (`target_overhead_pct:`).
+///
+/// Driven by the flusher. The interval is only ever lengthened, so that the profile settles
+/// on a rate within budget instead of oscillating.
+pub struct OverheadGovernor {
+    target_pct: f64,
+    measured_at: Instant,
+    /// `CaptureStats::total_ns()` as of `measured_at`.
+    capture_ns: u64,
+}
+
+impl OverheadGovernor {
+    pub fn new(target_pct: f64, profile: &Profile) -> Self {
+        Self {
+            target_pct,
+            measured_at: Instant::now(),
+            capture_ns: profile.capture_stats.total_ns(),
+        }
+    }
+
+    /// Measure the overhead since the previous call, and back off if it exceeds the target.
+    /// Called by the flusher with the profile locked.
+    pub fn check(&mut self, profile: &mut Profile) {
+        let window = self.measured_at.elapsed();
+        if window < MIN_MEASUREMENT_WINDOW {
+            return;
+        }
+        let capture_ns = profile.capture_stats.total_ns();
+        let overhead_pct =
+            capture_ns.saturating_sub(self.capture_ns) as f64 / window.as_nanos() as f64 * 100.0;
+        self.measured_at = Instant::now();
+        self.capture_ns = capture_ns;
+        if overhead_pct <= self.target_pct {
+            return;
+        }
+
+        let current = profile.sampling_interval.get();
+        let next = (current * BACKOFF_FACTOR).min(MAX_INTERVAL);
+        if next <= current {
+            return;
+        }
+        logging::debug!(
+            "Overhead {:.2}% exceeds target {:.2}%. Sampling interval: {:?} -> {:?}",
+            overhead_pct,
+            self.target_pct,
+            current,
+            next
+        );
+        profile.sampling_interval.set(next);
+        profile.interval_changes.push(IntervalChange {
+            elapsed: profile.elapsed_at(Instant::now()),
+            interval: next,
+            overhead_pct,
+        });
+    }
+}
@@ -1,11 +1,11 @@
 use std::ffi::{c_int, c_void};
 use std::mem;
 use std::mem::ManuallyDrop;
-use std::ptr::{addr_of, null_mut};
 
 use rb_sys::*;
 
-use crate::util::cstr;
+use crate::error::Pf2Error;
+use crate::util::{cstr, RubyDataType};
 
 use super::Session;
 
@@ -28,38 +28,177 @@ impl SessionRubyObject {
         let mut obj = Self::get_struct_from(rbself);
         match &mut obj.session {
             Some(session) => session.start(),
-            None => panic!("Session is not initialized"),
+            None => Pf2Error::NotInitialized.raise(),
         }
     }
 
-    pub unsafe extern "C" fn rb_stop(rbself: VALUE) -> VALUE {
+    pub unsafe extern "C" fn rb_stop(argc: c_int, argv: *const VALUE, rbself: VALUE) -> VALUE {
         let mut obj = Self::get_struct_from(rbself);
         match &mut obj.session {
-            Some(session) => session.stop(),
-            None => panic!("Session is not initialized"),
+            Some(session) => session.stop(argc, argv),
+            None => Pf2Error::NotInitialized.raise(),
+        }
+    }
+
+    pub unsafe extern "C" fn rb_reset(rbself: VALUE) -> VALUE {
+        let mut obj = Self::get_struct_from(rbself);
+        match &mut obj.session {
+            Some(session) => session.reset(),
+            None => Pf2Error::NotInitialized.raise(),
+        }
+    }
+
+    pub unsafe extern "C" fn rb_post_fork(rbself: VALUE) -> VALUE {
+        let mut obj = Self::get_struct_from(rbself);
+        match &mut obj.session {
+            Some(session) => session.post_fork(),
+            None => Pf2Error::NotInitialized.raise(),
+        }
+    }
+
+    pub unsafe extern "C" fn rb_running(rbself: VALUE) -> VALUE {
+        let obj = Self::get_struct_from(rbself);
+        match &obj.session {
+            Some(session) if session.is_running() => Qtrue.into(),
+            _ => Qfalse.into(),
+        }
+    }
+
+    pub unsafe extern "C" fn rb_configuration(rbself: VALUE) -> VALUE {
+        let obj = Self::get_struct_from(rbself);
+        match &obj.session {
+            Some(session) => session.configuration(),
+            None => Pf2Error::NotInitialized.raise(),
+        }
+    }
+
+    pub unsafe extern "C" fn rb_sample_count(rbself: VALUE) -> VALUE {
+        let obj = Self::get_struct_from(rbself);
+        match &obj.session {
+            Some(session) => session.sample_count(),
+            None => Pf2Error::NotInitialized.raise(),
+        }
+    }
+
+    pub unsafe extern "C" fn rb_drop_stats(rbself: VALUE) -> VALUE {
+        let obj = Self::get_struct_from(rbself);
+        match &obj.session {
+            Some(session) => session.drop_stats(),
+            None => Pf2Error::NotInitialized.raise(),
+        }
+    }
+
+    pub unsafe extern "C" fn rb_reset_drop_stats(rbself: VALUE) -> VALUE {
+        let obj = Self::get_struct_from(rbself);
+        match &obj.session {
+            Some(session) => session.reset_drop_stats(),
+            None => Pf2Error::NotInitialized.raise(),
+        }
+    }
+
+    pub unsafe extern "C" fn rb_memsize(rbself: VALUE) -> VALUE {
+        let obj = Self::get_struct_from(rbself);
+        match &obj.session {
+            Some(session) => session.memsize(),
+            None => Pf2Error::NotInitialized.raise(),
+        }
+    }
+
+    pub unsafe extern "C" fn rb_mark(rbself: VALUE, label: VALUE) -> VALUE {
+        let obj = Self::get_struct_from(rbself);
+        match &obj.session {
+            Some(session) => session.mark(label),
+            None => Pf2Error::NotInitialized.raise(),
+        }
+    }
+
+    #[cfg(feature = "debug")]
+    pub unsafe extern "C" fn rb_debug_frames(rbself: VALUE, index: VALUE) -> VALUE {
+        let obj = Self::get_struct_from(rbself);
+        match &obj.session {
+            Some(session) => session.debug_frames(index),
+            None => Pf2Error::NotInitialized.raise(),
+        }
+    }
+
+    pub unsafe extern "C" fn rb_flush(rbself: VALUE) -> VALUE {
+        let obj = Self::get_struct_from(rbself);
+        match &obj.session {
+            Some(session) => session.flush(),
+            None => Pf2Error::NotInitialized.raise(),
+        }
+    }
+
+    pub unsafe extern "C" fn rb_start_timestamp_ns(rbself: VALUE) -> VALUE {
+        let obj = Self::get_struct_from(rbself);
+        match &obj.session {
+            Some(session) => session.start_timestamp_ns(),
+            None => Pf2Error::NotInitialized.raise(),
+        }
+    }
+
+    pub unsafe extern "C" fn rb_duration_ns(rbself: VALUE) -> VALUE {
+        let obj = Self::get_struct_from(rbself);
+        match &obj.session {
+            Some(session) => session.duration_ns(),
+            None => Pf2Error::NotInitialized.raise(),
+        }
+    }
+
+    pub unsafe extern "C" fn rb_histogram(rbself: VALUE) -> VALUE {
+        let obj = Self::get_struct_from(rbself);
+        match &obj.session {
+            Some(session) => session.histogram(),
+            None => Pf2Error::NotInitialized.raise(),
+        }
+    }
+
+    pub unsafe extern "C" fn rb_summary(argc: c_int, argv: *const VALUE, rbself: VALUE) -> VALUE {
+        let obj = Self::get_struct_from(rbself);
+        match &obj.session {
+            Some(session) => session.summary(argc, argv),
+            None => Pf2Error::NotInitialized.raise(),
+        }
+    }
+
+    pub unsafe extern "C" fn rb_snapshot(argc: c_int, argv: *const VALUE, rbself: VALUE) -> VALUE {
+        let obj = Self::get_struct_from(rbself);
+        match &obj.session {
+            Some(session) => session.snapshot(argc, argv),
+            None => Pf2Error::NotInitialized.raise(),
+        }
+    }
+
+    pub unsafe extern "C" fn rb_samples(rbself: VALUE) -> VALUE {
+        let obj = Self::get_struct_from(rbself);
+        match &obj.session {
+            Some(session) => session.samples(),
+            None => Pf2Error::NotInitialized.raise(),
+        }
+    }
+
+    pub unsafe extern "C" fn rb_sample_thread(rbself: VALUE, thread: VALUE) -> VALUE {
+        let obj = Self::get_struct_from(rbself);
+        match &obj.session {
+            Some(session) => session.sample_thread(thread),
+            None => Pf2Error::NotInitialized.raise(),
         }
     }
 
     // Extract the SessionRubyObject struct from a Ruby object
     unsafe fn get_struct_from(obj: VALUE) -> ManuallyDrop<Box<Self>> {
         unsafe {
-            let ptr = rb_check_typeddata(obj, addr_of!(RBDATA));
+            let ptr = rb_check_typeddata(obj, RBDATA.as_ptr());
             ManuallyDrop::new(Box::from_raw(ptr as *mut SessionRubyObject))
         }
     }
 
-    #[allow(non_snake_case)]
-    pub unsafe extern "C" fn rb_alloc(_rbself: VALUE) -> VALUE {
+    pub unsafe extern "C" fn rb_alloc(klass: VALUE) -> VALUE {
         let obj = Box::new(SessionRubyObject { session: None });
 
-        let rb_mPf2: VALUE = rb_define_module(cstr!("Pf2"));
-        let rb_cSession = rb_define_class_under(rb_mPf2, cstr!("Session"), rb_cObject);
-        // Wrap the struct into a Ruby object
-        rb_data_typed_object_wrap(
-            rb_cSession,
-            Box::into_raw(obj) as *mut c_void,
-            addr_of!(RBDATA),
-        )
+        // Wrap the struct into a Ruby object.
+        // The Ruby object is the sole owner of the Box; it is released in dfree().
+        rb_data_typed_object_wrap(klass, Box::into_raw(obj) as *mut c_void, RBDATA.as_ptr())
     }
 
     unsafe extern "C" fn dmark(ptr: *mut c_void) {
@@ -69,26 +208,31 @@ impl SessionRubyObject {
         }
     }
 
+    unsafe extern "C" fn dcompact(ptr: *mut c_void) {
+        let obj = ManuallyDrop::new(Box::from_raw(ptr as *mut SessionRubyObject));
+        if let Some(session) = &obj.session {
+            session.dcompact()
+        }
+    }
+
     unsafe extern "C" fn dfree(ptr: *mut c_void) {
         drop(Box::from_raw(ptr as *mut SessionRubyObject));
     }
 
-    unsafe extern "C" fn dsize(_: *const c_void) -> size_t {
-        // FIXME: Report something better
-        mem::size_of::<SessionRubyObject>() as size_t
+    unsafe extern "C" fn dsize(ptr: *const c_void) -> size_t {
+        let obj = &*(ptr as *const SessionRubyObject);
+        let session_size = match &obj.session {
+            Some(session) => session.dsize(),
+            None => 0,
+        };
+        mem::size_of::<SessionRubyObject>() as size_t + session_size
     }
 }
 
-static mut RBDATA: rb_data_type_t = rb_data_type_t {
-    wrap_struct_name: cstr!("SessionRubyObject"),
-    function: rb_data_type_struct__bindgen_ty_1 {
-        dmark: Some(SessionRubyObject::dmark),
-        dfree: Some(SessionRubyObject::dfree),
-        dsize: Some(SessionRubyObject::dsize),
-        dcompact: None,
-        reserved: [null_mut(); 1],
-    },
-    parent: null_mut(),
-    data: null_mut(),
-    flags: 0,
-};
+static RBDATA: RubyDataType = RubyDataType::new(
+    cstr!("SessionRubyObject"),
+    SessionRubyObject::dmark,
+    SessionRubyObject::dfree,
+    SessionRubyObject::dsize,
+    Some(SessionRubyObject::dcompact),
+);
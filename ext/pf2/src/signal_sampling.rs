@@ -0,0 +1,204 @@
+#![deny(unsafe_op_in_unsafe_fn)]
+
+//! What the signal schedulers (`signal_scheduler` on Linux, `signal_scheduler_setitimer`
+//! elsewhere) share: handing samples over to the flusher, keeping track of the interval their
+//! timers are armed with, and freeing their signal handler args once the timers are gone.
+
+use crate::jitter::Jitter;
+use crate::logging;
+use crate::profile::{
+    CaptureStats, FlushSignal, Profile, SamplingInterval, SAMPLE_RING_HIGH_WATER_MARK,
+};
+use crate::ruby_internal_apis::rb_thread_current_ec;
+use crate::sample::{Sample, ThreadState};
+use crate::session::configuration::{self, Configuration};
+use crate::spsc_ringbuffer::SpscRingbuffer;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Signals of a deleted timer may still be pending, or being handled, for a short while.
+// The signal handler args they refer to are freed once this has elapsed.
+const RETIRED_ARGS_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Where a signal handler puts the samples it captures.
+pub struct SampleSink {
+    pub configuration: Arc<Configuration>,
+    profile: Arc<RwLock<Profile>>,
+    /// Samples are handed over to the flusher through this ring, without locking the profile.
+    sample_ring: Arc<SpscRingbuffer>,
+    capture_stats: Arc<CaptureStats>,
+    flush_signal: Arc<FlushSignal>,
+    /// Samples are discarded until this instant (see `warmup_ms`).
+    sampling_starts_at: Instant,
+}
+
+impl SampleSink {
+    /// Register a sample ring of its own in `profile`.
+    pub fn new(configuration: &Arc<Configuration>, profile: &Arc<RwLock<Profile>>) -> Self {
+        let (sample_ring, capture_stats, flush_signal, sampling_starts_at) = {
+            let mut profile = profile.write().unwrap();
+            (
+                profile.register_sample_ring(),
+                Arc::clone(&profile.capture_stats),
+                Arc::clone(&profile.flush_signal),
+                profile.start_instant,
+            )
+        };
+        Self {
+            configuration: Arc::clone(configuration),
+            profile: Arc::clone(profile),
+            sample_ring,
+            capture_stats,
+            flush_signal,
+            sampling_starts_at,
+        }
+    }
+
+    // async-signal-safe
+    /// Whether the profile has been stopped (possibly by max_duration).
+    pub fn is_closed(&self) -> bool {
+        self.sample_ring.is_closed()
+    }
+
+    /// Whether samples captured now would be discarded as part of the warmup.
+    pub fn warming_up(&self) -> bool {
+        Instant::now() < self.sampling_starts_at
+    }
+
+    /// Fill in what the scheduler knows about the thread `sample` was captured from:
+    /// its Ractor, the Fiber it is running (given the `root_ec` of its root Fiber), and
+    /// whether it holds the GVL, if known.
+    pub fn annotate(
+        &self,
+        sample: &mut Sample,
+        ruby_ractor_id: Option<u64>,
+        root_ec: usize,
+        has_gvl: Option<bool>,
+    ) {
+        sample.ruby_ractor_id = ruby_ractor_id;
+        sample.idle = self.configuration.idle_samples != configuration::IdleSamples::Keep
+            && has_gvl == Some(false);
+        sample.state = has_gvl.map(|has_gvl| ThreadState::from_gvl(has_gvl, sample.during_gc));
+        sample.set_fiber(unsafe { rb_thread_current_ec(sample.ruby_thread) }, root_ec);
+    }
+
+    /// Record the cost of capturing `sample` since `capture_started_at`, and hand it over to
+    /// the flusher.
+    pub fn push(&self, sample: Sample, capture_started_at: Instant) {
+        self.capture_stats
+            .record(capture_started_at.elapsed(), sample.omitted_frames > 0);
+
+        // Fall back to the (locked) temporary sample buffer if the flusher has fallen behind
+        let sample = match self.sample_ring.push(sample) {
+            Ok(()) => {
+                if self.configuration.flush_mode == configuration::FlushMode::EventDriven
+                    && self.sample_ring.len() >= SAMPLE_RING_HIGH_WATER_MARK
+                {
+                    self.flush_signal.notify();
+                }
+                return;
+            }
+            Err(sample) => sample,
+        };
+        let mut profile = match self.profile.try_write() {
+            Ok(profile) => profile,
+            Err(_) => {
+                logging::trace!("Failed to acquire profile lock. Dropping sample.");
+                self.capture_stats.record_dropped_by_lock_contention(1);
+                return;
+            }
+        };
+        if profile.temporary_sample_buffer.push(sample).is_err() {
+            logging::debug!("Temporary sample buffer full. Dropping sample.");
+            self.capture_stats.record_dropped_by_full_buffer();
+        }
+        if self.configuration.flush_mode == configuration::FlushMode::EventDriven {
+            // The ring is full, which is well past its high-water mark.
+            // Release the lock first, so that the flusher can take it as soon as it wakes up.
+            drop(profile);
+            self.flush_signal.notify();
+        }
+    }
+}
+
+/// The interval a timer is armed with, following changes to the sampling interval
+/// (`target_overhead_pct`) and randomized at every expiry (`jitter_pct`).
+pub struct TimerInterval {
+    /// The interval to sample at, which may be lengthened while profiling.
+    sampling_interval: Arc<SamplingInterval>,
+    /// The sampling interval the timer is currently armed with, in nanoseconds.
+    armed_interval_ns: AtomicU64,
+    jitter: Option<Jitter>,
+}
+
+impl TimerInterval {
+    pub fn new(sampling_interval: Arc<SamplingInterval>, jitter: Option<Jitter>) -> Self {
+        // Timers are armed with the current interval, which may already have been lengthened
+        let armed_interval_ns = sampling_interval.get().as_nanos() as u64;
+        Self {
+            sampling_interval,
+            armed_interval_ns: AtomicU64::new(armed_interval_ns),
+            jitter,
+        }
+    }
+
+    /// The interval to arm the timer with first.
+    pub fn initial(&self) -> Duration {
+        self.jittered(Duration::from_nanos(
+            self.armed_interval_ns.load(Ordering::Relaxed),
+        ))
+    }
+
+    // async-signal-safe
+    /// The interval to re-arm the timer with at its expiry, if it is to be re-armed: when the
+    /// sampling interval has been changed, or at every expiry with `jitter_pct`.
+    /// Jittered timers stay periodic, so that they keep firing even if an expiry is not
+    /// followed by a re-arm (e.g. when it is dropped as a nested signal).
+    pub fn rearm(&self) -> Option<Duration> {
+        let interval = self.sampling_interval.get();
+        let interval_ns = interval.as_nanos() as u64;
+        let changed = self.armed_interval_ns.swap(interval_ns, Ordering::Relaxed) != interval_ns;
+        if !changed && self.jitter.is_none() {
+            return None;
+        }
+        Some(self.jittered(interval))
+    }
+
+    // async-signal-safe
+    fn jittered(&self, interval: Duration) -> Duration {
+        match &self.jitter {
+            Some(jitter) => jitter.apply(interval),
+            None => interval,
+        }
+    }
+}
+
+/// Signal handler args whose timers have been deleted (or whose handlers have been uninstalled).
+pub struct RetiredArgs<T>(pub Vec<*mut T>);
+
+// Nothing else refers to the args once their timers are gone, except for signals in flight
+unsafe impl<T> Send for RetiredArgs<T> {}
+
+impl<T: 'static> RetiredArgs<T> {
+    /// Free the args from another thread once `RETIRED_ARGS_GRACE_PERIOD` has elapsed.
+    pub fn free_after_grace_period(self) {
+        if self.0.is_empty() {
+            return;
+        }
+        thread::spawn(move || {
+            thread::sleep(RETIRED_ARGS_GRACE_PERIOD);
+            self.free();
+        });
+    }
+
+    /// Free the args right away, when no signal can refer to them anymore.
+    pub fn free(self) {
+        for args in self.0 {
+            // Releases the args' references to the Profile and its sample ring
+            drop(unsafe { Box::from_raw(args) });
+        }
+    }
+}
@@ -1,76 +1,240 @@
 #![deny(unsafe_op_in_unsafe_fn)]
 
+use crate::backtrace::BacktraceState;
+use crate::cpu_activity::CpuActivity;
+use crate::error::Pf2Error;
+use crate::gvl_holder::{GvlHolder, GvlHolderHook};
+use crate::jitter::Jitter;
+use crate::logging;
 use crate::profile::Profile;
-use crate::profile_serializer::ProfileSerializer;
-use crate::ruby_internal_apis::rb_thread_getcpuclockid;
+use crate::ruby_internal_apis::{rb_thread_getcpuclockid, rb_thread_root_ec};
 use crate::sample::Sample;
 use crate::scheduler::Scheduler;
-use crate::serialization::serializer::ProfileSerializer2;
 use crate::session::configuration::{self, Configuration};
+use crate::signal_sampling::{RetiredArgs, SampleSink, TimerInterval};
 
 use core::panic;
-use std::ffi::{c_int, c_void, CString};
-use std::mem::ManuallyDrop;
-use std::sync::{Arc, RwLock};
+use std::ffi::{c_int, c_void};
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use std::{mem, ptr::null_mut};
 
 use rb_sys::*;
 
 use crate::util::*;
 
+extern "C" {
+    // ruby/thread.h
+    fn ruby_thread_has_gvl_p() -> c_int;
+}
+
 #[derive(Debug)]
 pub struct SignalScheduler {
-    configuration: Configuration,
+    configuration: Arc<Configuration>,
     profile: Arc<RwLock<Profile>>,
+    /// Timers armed by create_timer(), to be deleted in stop() (or once their thread exits).
+    /// Each thread has at most one per-thread timer (see install_timer_to_ruby_thread()).
+    timers: Arc<Mutex<Vec<ArmedTimer>>>,
+    /// The SIGALRM action replaced by install_signal_handler(), to be restored in stop().
+    previous_sigaction: Mutex<Option<SavedSigaction>>,
+    /// Threads sampled by the process-wide timer (`strategy: :global_timer`).
+    global_targets: Arc<RwLock<Vec<SignalTarget>>>,
+    /// Removes exited threads from `global_targets`, and deletes their timers.
+    /// Removed in stop().
+    thread_exit_hook: Mutex<Option<ThreadExitHook>>,
+    /// The thread holding the GVL, which the global timer attributes on-CPU samples to.
+    gvl_holder: Arc<GvlHolder>,
+    /// Keeps `gvl_holder` up to date while the global timer is armed. Removed in stop().
+    gvl_holder_hook: Mutex<Option<GvlHolderHook>>,
+}
+
+#[derive(Debug)]
+struct ThreadExitHook {
+    event_hook: *mut rb_internal_thread_event_hook_t,
+    /// Passed as custom data, and freed with the hook.
+    exited_threads: *mut ExitedThreads,
+}
+
+/// What the thread exit hook cleans up after.
+struct ExitedThreads {
+    global_targets: Arc<RwLock<Vec<SignalTarget>>>,
+    timers: Arc<Mutex<Vec<ArmedTimer>>>,
+}
+
+/// A timer created by create_timer().
+#[derive(Debug)]
+struct ArmedTimer {
+    timer: libc::timer_t,
+    /// The thread the timer is directed to (`strategy: :per_thread`), if any.
+    ruby_thread: Option<VALUE>,
+    kernel_thread_id: i32,
+    /// Passed to the signal handler along with the timer's signals.
+    args: *mut SignalHandlerArgs,
+}
+
+/// libc::sigaction does not implement Debug.
+struct SavedSigaction(libc::sigaction);
+
+impl std::fmt::Debug for SavedSigaction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("SavedSigaction")
+            .field(&self.0.sa_sigaction)
+            .finish()
+    }
+}
+
+/// A Ruby thread to be sampled by the signal handler.
+#[derive(Clone, Debug)]
+struct SignalTarget {
+    ruby_thread: VALUE,
+    ruby_ractor_id: Option<u64>,
+    /// The execution context of the thread's root Fiber.
+    root_ec: usize,
+    kernel_thread_id: i32,
+    /// The CPU-time clock of the thread, read in place of `CLOCK_THREAD_CPUTIME_ID` when the
+    /// thread is sampled from another one.
+    cpu_clockid: libc::clockid_t,
+    /// Tells whether the thread has been on CPU between samples.
+    cpu_activity: Arc<CpuActivity>,
+}
+
+enum HandlerTargets {
+    /// The thread the timer belongs to, which is also the one receiving the signal.
+    Current(SignalTarget),
+    /// Every target thread, sampled from whichever thread receives the signal.
+    Snapshot(Arc<RwLock<Vec<SignalTarget>>>),
 }
 
 pub struct SignalHandlerArgs {
-    profile: Arc<RwLock<Profile>>,
-    context_ruby_thread: VALUE,
+    sink: SampleSink,
+    backtrace_state: BacktraceState,
+    targets: HandlerTargets,
+    /// Tells which of the `Snapshot` targets is running.
+    gvl_holder: Arc<GvlHolder>,
+    /// The interval `timer` is armed with.
+    timer_interval: TimerInterval,
+    /// The timer delivering signals with these args. Set once it has been created.
+    timer: AtomicPtr<c_void>,
+    /// Set while signal_handler() is running for this timer.
+    /// With `strategy: :per_thread`, each SignalHandlerArgs belongs to a single thread, so this
+    /// acts as a per-thread guard. With the global timer, it also keeps signals delivered to
+    /// different threads from sampling concurrently.
+    in_handler: AtomicBool,
+}
+
+/// Clears `SignalHandlerArgs.in_handler` when the handler returns.
+struct ReentrancyGuard<'a>(&'a AtomicBool);
+
+impl<'a> ReentrancyGuard<'a> {
+    // async-signal-safe
+    /// Returns None if the flag is already set, i.e. the handler is being reentered.
+    fn enter(flag: &'a AtomicBool) -> Option<Self> {
+        flag.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| Self(flag))
+    }
+}
+
+impl Drop for ReentrancyGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
 }
 
 impl Scheduler for SignalScheduler {
-    fn start(&self) -> VALUE {
-        self.install_signal_handler();
+    fn start(&self) -> Result<(), Pf2Error> {
+        self.install_signal_handler()?;
+        self.install_thread_exit_hook();
+
+        if self.configuration.strategy == configuration::Strategy::GlobalTimer {
+            if let Err(e) = self.install_global_timer() {
+                self.stop();
+                return Err(e);
+            }
+            return Ok(());
+        }
 
         if let configuration::Threads::Targeted(threads) = &self.configuration.target_ruby_threads {
+            let mut installed = 0;
             for ruby_thread in threads.iter() {
-                self.install_timer_to_ruby_thread(*ruby_thread);
+                match self.install_timer_to_ruby_thread(*ruby_thread) {
+                    Ok(true) => installed += 1,
+                    Ok(false) => {}
+                    Err(e) => {
+                        // Disarm the timers installed so far and restore the signal handler
+                        self.stop();
+                        return Err(e);
+                    }
+                }
             }
+            logging::debug!(
+                "{} timers installed for {} threads",
+                installed,
+                threads.len()
+            );
         }
 
-        Qtrue.into()
+        Ok(())
     }
 
-    fn stop(&self) -> VALUE {
-        // Finalize
-        match self.profile.try_write() {
-            Ok(mut profile) => {
-                profile.flush_temporary_sample_buffer();
-                profile.end_instant = Some(std::time::Instant::now());
-            }
-            Err(_) => {
-                println!("[pf2 ERROR] stop: Failed to acquire profile lock.");
-                return Qfalse.into();
+    fn stop(&self) {
+        // Disarm timers. SignalHandlerArgs are freed after a grace period, since signals may
+        // still be pending.
+        logging::debug!("Deleting the timers of threads {:?}", self.timed_threads());
+        let mut timers = self.timers.lock().unwrap();
+        let mut retired = RetiredArgs(Vec::with_capacity(timers.len()));
+        for armed in timers.drain(..) {
+            let err = unsafe { libc::timer_delete(armed.timer) };
+            if err != 0 {
+                logging::debug!("timer_delete failed: {}", err);
             }
+            retired.0.push(armed.args);
         }
+        drop(timers);
+        retired.free_after_grace_period();
 
-        let profile = self.profile.try_read().unwrap();
-        log::debug!("Number of samples: {}", profile.samples.len());
+        self.gvl_holder_hook.lock().unwrap().take();
+        if let Some(hook) = self.thread_exit_hook.lock().unwrap().take() {
+            unsafe {
+                // Hooks are not running once removed, so their data can be freed
+                rb_internal_thread_remove_event_hook(hook.event_hook);
+                drop(Box::from_raw(hook.exited_threads));
+            }
+        }
 
-        if self.configuration.use_experimental_serializer {
-            let mut ser = ProfileSerializer2::new();
-            ser.serialize(&profile);
-            ser.to_ruby_hash()
-        } else {
-            let serialized = ProfileSerializer::serialize(&profile);
-            let string = CString::new(serialized).unwrap();
-            unsafe { rb_str_new_cstr(string.as_ptr()) }
+        // Restore the previous SIGALRM action (e.g. the handler of an enclosing profile).
+        // The default action, which terminates the process, is not restored, since signals
+        // from the deleted timers may still be pending. Our handler ignores them.
+        if let Some(SavedSigaction(previous)) = self.previous_sigaction.lock().unwrap().take() {
+            if previous.sa_sigaction != libc::SIG_DFL {
+                let err = unsafe { libc::sigaction(libc::SIGALRM, &previous, null_mut()) };
+                if err != 0 {
+                    logging::debug!("sigaction failed: {}", err);
+                }
+            }
         }
     }
 
     fn on_new_thread(&self, thread: VALUE) {
-        self.install_timer_to_ruby_thread(thread);
+        if self.configuration.strategy == configuration::Strategy::GlobalTimer {
+            let mut global_targets = self.global_targets.write().unwrap();
+            if global_targets
+                .iter()
+                .any(|target| target.ruby_thread == thread)
+            {
+                return;
+            }
+            let target = Self::signal_target(thread);
+            self.record_thread_start(&target);
+            global_targets.push(target);
+            return;
+        }
+
+        // Raising is not an option within a thread event hook
+        if let Err(e) = self.install_timer_to_ruby_thread(thread) {
+            logging::warn!("Failed to start profiling thread {}: {}", thread, e);
+        }
     }
 
     fn dmark(&self) {
@@ -84,34 +248,68 @@ impl Scheduler for SignalScheduler {
         }
     }
 
+    fn dcompact(&self) {
+        match self.profile.write() {
+            Ok(mut profile) => unsafe {
+                profile.dcompact();
+            },
+            Err(_) => {
+                panic!("[pf2 FATAL] dcompact: Failed to acquire profile lock.");
+            }
+        }
+    }
+
     fn dfree(&self) {
         // No-op
     }
 
     fn dsize(&self) -> size_t {
-        // FIXME: Report something better
-        mem::size_of::<Self>() as size_t
+        // Best-effort: the Profile is not accounted for if its lock is held elsewhere
+        let profile_size = match self.profile.try_read() {
+            Ok(profile) => profile.memsize(),
+            Err(_) => 0,
+        };
+        (mem::size_of::<Self>() + profile_size) as size_t
     }
 }
 
 impl SignalScheduler {
     pub fn new(configuration: &Configuration, profile: Arc<RwLock<Profile>>) -> Self {
         Self {
-            configuration: configuration.clone(),
+            configuration: Arc::new(configuration.clone()),
             profile,
+            timers: Arc::new(Mutex::new(vec![])),
+            previous_sigaction: Mutex::new(None),
+            global_targets: Arc::new(RwLock::new(vec![])),
+            thread_exit_hook: Mutex::new(None),
+            gvl_holder: Arc::new(GvlHolder::default()),
+            gvl_holder_hook: Mutex::new(None),
         }
     }
 
+    /// The threads which currently have a per-thread timer.
+    pub fn timed_threads(&self) -> Vec<VALUE> {
+        self.timers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|armed| armed.ruby_thread)
+            .collect()
+    }
+
     // Install signal handler for profiling events to the current process.
-    fn install_signal_handler(&self) {
+    fn install_signal_handler(&self) -> Result<(), Pf2Error> {
         let mut sa: libc::sigaction = unsafe { mem::zeroed() };
         sa.sa_sigaction = Self::signal_handler as usize;
         sa.sa_flags = libc::SA_SIGINFO;
-        let err = unsafe { libc::sigaction(libc::SIGALRM, &sa, null_mut()) };
+        let mut previous: libc::sigaction = unsafe { mem::zeroed() };
+        let err = unsafe { libc::sigaction(libc::SIGALRM, &sa, &mut previous) };
         if err != 0 {
-            panic!("sigaction failed: {}", err);
+            return Err(Pf2Error::last_os_error("sigaction"));
         }
-        log::debug!("Signal handler installed");
+        *self.previous_sigaction.lock().unwrap() = Some(SavedSigaction(previous));
+        logging::debug!("Signal handler installed");
+        Ok(())
     }
 
     // Respond to the signal and collect a sample.
@@ -121,35 +319,158 @@ impl SignalScheduler {
     extern "C" fn signal_handler(
         _sig: c_int,
         info: *mut libc::siginfo_t,
-        _ucontext: *mut libc::ucontext_t,
+        ucontext: *mut libc::ucontext_t,
     ) {
-        let args = unsafe {
-            let ptr = extract_si_value_sival_ptr(info) as *mut SignalHandlerArgs;
-            ManuallyDrop::new(Box::from_raw(ptr))
+        // SignalHandlerArgs outlive their timer (see on_thread_exit())
+        let args = unsafe { &*(extract_si_value_sival_ptr(info) as *const SignalHandlerArgs) };
+
+        // Nested invocations (e.g. a signal arriving mid-capture) return immediately
+        let _guard = match ReentrancyGuard::enter(&args.in_handler) {
+            Some(guard) => guard,
+            None => return,
         };
 
-        let mut profile = match args.profile.try_write() {
-            Ok(profile) => profile,
-            Err(_) => {
-                // FIXME: Do we want to properly collect GC samples? I don't know yet.
-                log::trace!("Failed to acquire profile lock (garbage collection possibly in progress). Dropping sample.");
-                return;
+        // The profile has been stopped (possibly by max_duration)
+        if args.sink.is_closed() {
+            return;
+        }
+        Self::rearm(args);
+        if args.sink.warming_up() {
+            return;
+        }
+
+        let interrupted_pc = Self::interrupted_pc(ucontext);
+        match &args.targets {
+            HandlerTargets::Current(target) => {
+                let has_gvl = unsafe { ruby_thread_has_gvl_p() } != 0;
+                // Threads which have released the GVL are sleeping or blocked (in wall time mode)
+                if !has_gvl
+                    && args.sink.configuration.idle_samples == configuration::IdleSamples::Drop
+                {
+                    return;
+                }
+                Self::capture_and_push(args, target, true, Some(has_gvl), interrupted_pc);
             }
+            HandlerTargets::Snapshot(targets) => {
+                // The snapshot is being updated (a thread has started or exited). Skip this tick.
+                let targets = match targets.try_read() {
+                    Ok(targets) => targets,
+                    Err(_) => return,
+                };
+                let current_kernel_thread_id = unsafe { libc::syscall(libc::SYS_gettid) } as i32;
+                // Not rb_thread_current(), which is unsafe to call here (see GvlHolder)
+                let gvl_holder = args.gvl_holder.current();
+                for target in targets.iter() {
+                    let native = target.kernel_thread_id == current_kernel_thread_id;
+                    let has_gvl = match native {
+                        true => (unsafe { ruby_thread_has_gvl_p() }) != 0,
+                        false => gvl_holder == Some(target.ruby_thread),
+                    };
+                    // Only the running thread is on CPU. The others are walked as off-CPU
+                    // samples in wall time mode, unless dropped by `include_idle: false`.
+                    if !has_gvl
+                        && (args.sink.configuration.time_mode == configuration::TimeMode::CpuTime
+                            || args.sink.configuration.idle_samples
+                                == configuration::IdleSamples::Drop)
+                    {
+                        continue;
+                    }
+                    Self::capture_and_push(args, target, native, Some(has_gvl), interrupted_pc);
+                }
+            }
+        }
+    }
+
+    // async-signal-safe
+    /// The program counter of the thread at the moment it was interrupted by the signal,
+    /// read from the register state saved by the kernel. None on unsupported architectures.
+    fn interrupted_pc(ucontext: *const libc::ucontext_t) -> Option<usize> {
+        if ucontext.is_null() {
+            return None;
+        }
+        #[cfg(target_arch = "x86_64")]
+        return Some(unsafe { (*ucontext).uc_mcontext.gregs[libc::REG_RIP as usize] } as usize);
+        #[cfg(target_arch = "aarch64")]
+        return Some(unsafe { (*ucontext).uc_mcontext.pc } as usize);
+        #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+        return None;
+    }
+
+    // async-signal-safe (timer_settime(2) is)
+    /// Re-arm the timer if needed (see `TimerInterval::rearm`).
+    /// Each timer is re-armed by its own handler, so that no other thread has to touch timers
+    /// while they may be firing.
+    fn rearm(args: &SignalHandlerArgs) {
+        let interval = match args.timer_interval.rearm() {
+            Some(interval) => interval,
+            None => return,
         };
+        let timer = args.timer.load(Ordering::Acquire);
+        if timer.is_null() {
+            return;
+        }
+        let itimerspec = Self::duration_to_itimerspec(&interval);
+        unsafe { libc::timer_settime(timer, 0, &itimerspec, null_mut()) };
+    }
 
-        let sample = Sample::capture(args.context_ruby_thread, &profile.backtrace_state); // NOT async-signal-safe
-        if profile.temporary_sample_buffer.push(sample).is_err() {
-            log::debug!("Temporary sample buffer full. Dropping sample.");
+    /// Capture a sample of `target` and hand it over to the flusher.
+    /// The native stack can only be captured when `target` is the thread running the handler.
+    /// `has_gvl` tells whether `target` holds the GVL, if known.
+    /// `interrupted_pc` is the pc of the thread running the handler, where it was interrupted.
+    fn capture_and_push(
+        args: &SignalHandlerArgs,
+        target: &SignalTarget,
+        native: bool,
+        has_gvl: Option<bool>,
+        interrupted_pc: Option<usize>,
+    ) {
+        let capture_started_at = Instant::now();
+        let mut sample = match native {
+            true => Sample::capture(
+                target.ruby_thread,
+                &args.backtrace_state,
+                args.sink.configuration.max_stack_depth,
+                args.sink.configuration.clock.clockid(),
+            ),
+            // The calling thread's CPU-time clock would not be the target's
+            false => Sample::capture_without_native_stack(
+                target.ruby_thread,
+                args.sink.configuration.max_stack_depth,
+                match args.sink.configuration.clock {
+                    configuration::Clock::ThreadCpuTime => target.cpu_clockid,
+                    _ => args.sink.configuration.clock.clockid(),
+                },
+            ),
+        }; // NOT async-signal-safe
+        if native {
+            sample.interrupted_pc = interrupted_pc;
         }
+        args.sink
+            .annotate(&mut sample, target.ruby_ractor_id, target.root_ec, has_gvl);
+        sample.on_cpu = Self::on_cpu(args, target);
+        args.sink.push(sample, capture_started_at);
     }
 
-    fn install_timer_to_ruby_thread(&self, ruby_thread: VALUE) {
-        // NOTE: This Box never gets dropped
-        let signal_handler_args = Box::new(SignalHandlerArgs {
-            profile: Arc::clone(&self.profile),
-            context_ruby_thread: ruby_thread,
-        });
+    // async-signal-safe (clock_gettime(2) is)
+    /// Whether `target` has been running on a CPU, if it can be told.
+    fn on_cpu(args: &SignalHandlerArgs, target: &SignalTarget) -> Option<bool> {
+        // A timer ticking with the thread's own CPU time only expires while it is running
+        if matches!(args.targets, HandlerTargets::Current(_))
+            && args.sink.configuration.clock == configuration::Clock::ThreadCpuTime
+        {
+            return Some(true);
+        }
+        let wall_ns = read_clock_ns(libc::CLOCK_MONOTONIC)?;
+        let cpu_ns = read_clock_ns(target.cpu_clockid)?;
+        target.cpu_activity.observe(wall_ns, cpu_ns)
+    }
 
+    /// Collect what the signal handler needs to know about `ruby_thread`.
+    ///
+    /// Called either from start() for threads in the calling Ractor, or from the
+    /// THREAD_EVENT_RESUMED hook which runs on the new thread itself.
+    /// Either way, the current Ractor is the one the thread belongs to.
+    fn signal_target(ruby_thread: VALUE) -> SignalTarget {
         // rb_funcall deadlocks when called within a THREAD_EVENT_STARTED hook
         let kernel_thread_id: i32 = i32::try_from(unsafe {
             rb_num2int(rb_funcall(
@@ -160,36 +481,256 @@ impl SignalScheduler {
         })
         .unwrap();
 
-        // Create a signal event
+        SignalTarget {
+            ruby_thread,
+            ruby_ractor_id: current_ractor_id(),
+            root_ec: unsafe { rb_thread_root_ec(ruby_thread) },
+            kernel_thread_id,
+            cpu_clockid: unsafe { rb_thread_getcpuclockid(ruby_thread) },
+            cpu_activity: Arc::new(CpuActivity::default()),
+        }
+    }
+
+    /// Start measuring the CPU time of a thread sampled by the global timer,
+    /// and remember its native thread id.
+    fn record_thread_start(&self, target: &SignalTarget) {
+        let mut profile = self.profile.write().unwrap();
+        profile.record_native_thread_id(target.ruby_thread, target.kernel_thread_id as u64);
+        if self.configuration.time_mode == configuration::TimeMode::CpuTime {
+            profile.record_thread_cpu_start(target.ruby_thread, target.cpu_clockid);
+        }
+    }
+
+    fn signal_handler_args(&self, targets: HandlerTargets) -> Box<SignalHandlerArgs> {
+        // Each timer draws its own intervals, so that timers of different threads drift apart
+        let jitter_salt = match &targets {
+            HandlerTargets::Current(target) => target.kernel_thread_id as u64,
+            HandlerTargets::Snapshot(_) => 0,
+        };
+        let (backtrace_state, sampling_interval) = {
+            let profile = self.profile.read().unwrap();
+            (
+                profile.backtrace_state,
+                Arc::clone(&profile.sampling_interval),
+            )
+        };
+
+        Box::new(SignalHandlerArgs {
+            sink: SampleSink::new(&self.configuration, &self.profile),
+            backtrace_state,
+            targets,
+            gvl_holder: Arc::clone(&self.gvl_holder),
+            timer_interval: TimerInterval::new(
+                sampling_interval,
+                Jitter::new(self.configuration.jitter_pct, jitter_salt),
+            ),
+            timer: AtomicPtr::new(null_mut()),
+            in_handler: AtomicBool::new(false),
+        })
+    }
+
+    /// Install a single process-wide timer sampling all target threads (`strategy: :global_timer`).
+    fn install_global_timer(&self) -> Result<(), Pf2Error> {
+        if let configuration::Threads::Targeted(threads) = &self.configuration.target_ruby_threads {
+            let mut targets = self.global_targets.write().unwrap();
+            for ruby_thread in threads.iter() {
+                let target = Self::signal_target(*ruby_thread);
+                self.record_thread_start(&target);
+                targets.push(target);
+            }
+        }
+        *self.gvl_holder_hook.lock().unwrap() = Some(self.gvl_holder.watch());
+
+        // NOTE: This Box is dropped after the profile stops (see RetiredArgs)
+        let signal_handler_args =
+            self.signal_handler_args(HandlerTargets::Snapshot(Arc::clone(&self.global_targets)));
+
+        // The signal is directed to the process, and delivered to any thread not blocking it
         let mut sigevent: libc::sigevent = unsafe { mem::zeroed() };
-        // Note: SIGEV_THREAD_ID is Linux-specific. In other platforms, we would need to
-        // "trampoline" the signal as any pthread can receive the signal.
-        sigevent.sigev_notify = libc::SIGEV_THREAD_ID;
-        sigevent.sigev_notify_thread_id = kernel_thread_id;
+        sigevent.sigev_notify = libc::SIGEV_SIGNAL;
         sigevent.sigev_signo = libc::SIGALRM;
-        // Pass required args to the signal handler
-        sigevent.sigev_value.sival_ptr = Box::into_raw(signal_handler_args) as *mut c_void;
 
-        // Create and configure timer to fire every _interval_ ms of CPU time
-        let mut timer: libc::timer_t = unsafe { mem::zeroed() };
+        // In CPU time mode, the timer ticks with the CPU time of the whole process, and each tick
+        // is attributed to the thread holding the GVL.
+        // timer_create() does not accept CLOCK_MONOTONIC_RAW.
         let clockid = match self.configuration.time_mode {
-            configuration::TimeMode::CpuTime => unsafe { rb_thread_getcpuclockid(ruby_thread) },
+            configuration::TimeMode::CpuTime => libc::CLOCK_PROCESS_CPUTIME_ID,
             configuration::TimeMode::WallTime => libc::CLOCK_MONOTONIC,
         };
+        let armed = self.create_timer(clockid, sigevent, signal_handler_args, None)?;
+        self.timers.lock().unwrap().push(armed);
+
+        logging::debug!("global timer registered");
+        Ok(())
+    }
+
+    /// Stop sampling threads once they exit, as their execution contexts are freed.
+    fn install_thread_exit_hook(&self) {
+        let exited_threads = Box::into_raw(Box::new(ExitedThreads {
+            global_targets: Arc::clone(&self.global_targets),
+            timers: Arc::clone(&self.timers),
+        }));
+        let event_hook = unsafe {
+            rb_internal_thread_add_event_hook(
+                Some(Self::on_thread_exit),
+                RUBY_INTERNAL_THREAD_EVENT_EXITED,
+                exited_threads as *mut c_void,
+            )
+        };
+        *self.thread_exit_hook.lock().unwrap() = Some(ThreadExitHook {
+            event_hook,
+            exited_threads,
+        });
+    }
+
+    unsafe extern "C" fn on_thread_exit(
+        _flag: rb_event_flag_t,
+        data: *const rb_internal_thread_event_data,
+        custom_data: *mut c_void,
+    ) {
+        let ruby_thread: VALUE = unsafe { (*data).thread };
+
+        // Owned by ThreadExitHook, which outlives the hook
+        let exited_threads = unsafe { &*(custom_data as *const ExitedThreads) };
+        exited_threads
+            .global_targets
+            .write()
+            .unwrap()
+            .retain(|target| target.ruby_thread != ruby_thread);
+
+        let mut timers = exited_threads.timers.lock().unwrap();
+        let position = timers
+            .iter()
+            .position(|armed| armed.ruby_thread == Some(ruby_thread));
+        let armed = match position {
+            Some(position) => timers.swap_remove(position),
+            None => return,
+        };
+        drop(timers);
+
+        // Samples already taken stay in the profile, which holds its own reference to the ring
+        unsafe { libc::timer_delete(armed.timer) };
+        // The timer's signals were directed to the native thread the Ruby thread ran on.
+        // If the hook runs on that thread, the handler is not running (this thread is), and
+        // timer_delete() has discarded any pending signal, so nothing refers to the args anymore.
+        // Otherwise (e.g. with M:N threads), the handler may still be running over there.
+        let retired = RetiredArgs(vec![armed.args]);
+        let current_kernel_thread_id = unsafe { libc::syscall(libc::SYS_gettid) } as i32;
+        if armed.kernel_thread_id == current_kernel_thread_id {
+            retired.free();
+        } else {
+            retired.free_after_grace_period();
+        }
+        logging::debug!("timer deleted for exited thread {}", ruby_thread);
+    }
+
+    /// Create and arm a timer delivering `sigevent` every _interval_, passing
+    /// `signal_handler_args` to the signal handler.
+    /// `owner` is the target of a per-thread timer, which is deleted once the thread exits.
+    /// The timer is to be registered in `timers` by the caller.
+    fn create_timer(
+        &self,
+        clockid: libc::clockid_t,
+        mut sigevent: libc::sigevent,
+        signal_handler_args: Box<SignalHandlerArgs>,
+        owner: Option<(VALUE, i32)>,
+    ) -> Result<ArmedTimer, Pf2Error> {
+        // Pass required args to the signal handler
+        let signal_handler_args = Box::into_raw(signal_handler_args);
+        sigevent.sigev_value.sival_ptr = signal_handler_args as *mut c_void;
+
+        let mut timer: libc::timer_t = unsafe { mem::zeroed() };
         let err = unsafe { libc::timer_create(clockid, &mut sigevent, &mut timer) };
         if err != 0 {
-            panic!("timer_create failed: {}", err);
+            let error = Pf2Error::last_os_error("timer_create");
+            // No signal can refer to the args, since the timer was never created
+            drop(unsafe { Box::from_raw(signal_handler_args) });
+            return Err(error);
         }
-        let itimerspec = Self::duration_to_itimerspec(&self.configuration.interval);
+        // Let the handler re-arm the timer when the interval changes
+        let interval = unsafe {
+            (*signal_handler_args).timer.store(timer, Ordering::Release);
+            (*signal_handler_args).timer_interval.initial()
+        };
+        let itimerspec = Self::duration_to_itimerspec(&interval);
         let err = unsafe { libc::timer_settime(timer, 0, &itimerspec, null_mut()) };
         if err != 0 {
-            panic!("timer_settime failed: {}", err);
+            let error = Pf2Error::last_os_error("timer_settime");
+            unsafe { libc::timer_delete(timer) };
+            return Err(error);
+        }
+        let (ruby_thread, kernel_thread_id) = match owner {
+            Some((ruby_thread, kernel_thread_id)) => (Some(ruby_thread), kernel_thread_id),
+            None => (None, 0),
+        };
+        Ok(ArmedTimer {
+            timer,
+            ruby_thread,
+            kernel_thread_id,
+            args: signal_handler_args,
+        })
+    }
+
+    /// Arm a timer directed to `ruby_thread`, unless it already has one (e.g. when the new
+    /// thread watcher reports a thread which start() has just covered).
+    /// Returns whether a timer has been installed.
+    fn install_timer_to_ruby_thread(&self, ruby_thread: VALUE) -> Result<bool, Pf2Error> {
+        // Held until the timer is registered, so that concurrent calls for the same thread
+        // cannot both install one. Timers would otherwise double the sampling rate.
+        let mut timers = self.timers.lock().unwrap();
+        if timers
+            .iter()
+            .any(|armed| armed.ruby_thread == Some(ruby_thread))
+        {
+            logging::debug!("thread {} already has a timer", ruby_thread);
+            return Ok(false);
+        }
+
+        let target = Self::signal_target(ruby_thread);
+        let kernel_thread_id = target.kernel_thread_id;
+        // NOTE: This Box is dropped once the thread exits, or after the profile stops
+        // (see RetiredArgs)
+        let signal_handler_args = self.signal_handler_args(HandlerTargets::Current(target));
+
+        // Create a signal event
+        let mut sigevent: libc::sigevent = unsafe { mem::zeroed() };
+        // Note: SIGEV_THREAD_ID is Linux-specific. In other platforms, we would need to
+        // "trampoline" the signal as any pthread can receive the signal.
+        sigevent.sigev_notify = libc::SIGEV_THREAD_ID;
+        sigevent.sigev_notify_thread_id = kernel_thread_id;
+        sigevent.sigev_signo = libc::SIGALRM;
+
+        // Create and configure timer to fire every _interval_ ms of CPU time
+        let clockid = match self.configuration.clock {
+            configuration::Clock::ThreadCpuTime => unsafe { rb_thread_getcpuclockid(ruby_thread) },
+            configuration::Clock::ProcessCpuTime => libc::CLOCK_PROCESS_CPUTIME_ID,
+            // timer_create() does not accept CLOCK_MONOTONIC_RAW
+            configuration::Clock::Monotonic | configuration::Clock::MonotonicRaw => {
+                libc::CLOCK_MONOTONIC
+            }
+        };
+        let armed = self.create_timer(
+            clockid,
+            sigevent,
+            signal_handler_args,
+            Some((ruby_thread, kernel_thread_id)),
+        )?;
+        timers.push(armed);
+        drop(timers);
+
+        let mut profile = self.profile.write().unwrap();
+        profile.record_native_thread_id(ruby_thread, kernel_thread_id as u64);
+        if self.configuration.time_mode == configuration::TimeMode::CpuTime {
+            let thread_clockid = unsafe { rb_thread_getcpuclockid(ruby_thread) };
+            profile.record_thread_cpu_start(ruby_thread, thread_clockid);
         }
+        drop(profile);
 
-        log::debug!("timer registered for thread {}", ruby_thread);
+        logging::debug!("timer registered for thread {}", ruby_thread);
+        Ok(true)
     }
 
-    fn duration_to_itimerspec(duration: &std::time::Duration) -> libc::itimerspec {
+    fn duration_to_itimerspec(duration: &Duration) -> libc::itimerspec {
         let nanos = duration.as_nanos();
         let seconds_part: i64 = (nanos / 1_000_000_000).try_into().unwrap();
         let nanos_part: i64 = (nanos % 1_000_000_000).try_into().unwrap();
@@ -0,0 +1,387 @@
+#![deny(unsafe_op_in_unsafe_fn)]
+
+//! The signal scheduler for platforms without per-thread POSIX timers (`timer_create(2)` with
+//! `SIGEV_THREAD_ID`), such as macOS.
+//!
+//! A single process-wide interval timer is armed with `setitimer(2)`, and the signal handler
+//! samples every target thread, as with `strategy: :global_timer` on Linux.
+
+use crate::error::Pf2Error;
+use crate::gvl_holder::{GvlHolder, GvlHolderHook};
+use crate::jitter::Jitter;
+use crate::logging;
+use crate::profile::Profile;
+use crate::ruby_internal_apis::rb_thread_root_ec;
+use crate::sample::Sample;
+use crate::scheduler::Scheduler;
+use crate::session::configuration::{self, Configuration};
+use crate::signal_sampling::{RetiredArgs, SampleSink, TimerInterval};
+
+use std::ffi::{c_int, c_void};
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use std::{mem, ptr::null_mut};
+
+use rb_sys::*;
+
+use crate::util::*;
+
+/// Signals from `setitimer(2)` carry no value, so the signal handler finds its args here.
+/// Points to the args of the innermost running profile, or null if none.
+static HANDLER_ARGS: AtomicPtr<SignalHandlerArgs> = AtomicPtr::new(null_mut());
+
+#[derive(Debug)]
+pub struct SignalScheduler {
+    configuration: Arc<Configuration>,
+    profile: Arc<RwLock<Profile>>,
+    /// Threads sampled by the signal handler.
+    targets: Arc<RwLock<Vec<SignalTarget>>>,
+    /// What start() replaced, to be restored in stop().
+    previous: Mutex<Option<Previous>>,
+    /// Removes exited threads from `targets`. Removed in stop().
+    thread_exit_hook: Mutex<Option<ThreadExitHook>>,
+    /// The thread holding the GVL, which is the only target running at each tick.
+    gvl_holder: Arc<GvlHolder>,
+    /// Keeps `gvl_holder` up to date. Removed in stop().
+    gvl_holder_hook: Mutex<Option<GvlHolderHook>>,
+}
+
+/// The state of an enclosing profile (or of the process) replaced by start().
+struct Previous {
+    sigaction: libc::sigaction,
+    itimerval: libc::itimerval,
+    handler_args: *mut SignalHandlerArgs,
+    /// The args installed by start(), freed once stop() has restored `handler_args`.
+    own_handler_args: *mut SignalHandlerArgs,
+}
+
+/// libc::sigaction and libc::itimerval do not implement Debug.
+impl std::fmt::Debug for Previous {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Previous")
+            .field("sigaction", &self.sigaction.sa_sigaction)
+            .field("handler_args", &self.handler_args)
+            .field("own_handler_args", &self.own_handler_args)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+struct ThreadExitHook {
+    event_hook: *mut rb_internal_thread_event_hook_t,
+    /// A strong reference to `targets` passed as custom data, released with the hook.
+    targets: *const RwLock<Vec<SignalTarget>>,
+}
+
+/// A Ruby thread to be sampled by the signal handler.
+#[derive(Clone, Copy, Debug)]
+struct SignalTarget {
+    ruby_thread: VALUE,
+    ruby_ractor_id: Option<u64>,
+    /// The execution context of the thread's root Fiber.
+    root_ec: usize,
+}
+
+struct SignalHandlerArgs {
+    sink: SampleSink,
+    targets: Arc<RwLock<Vec<SignalTarget>>>,
+    /// Tells which of `targets` is running.
+    gvl_holder: Arc<GvlHolder>,
+    /// The interval the timer is armed with.
+    timer_interval: TimerInterval,
+    /// Set while signal_handler() is running, so that signals delivered to different threads
+    /// do not sample concurrently.
+    in_handler: AtomicBool,
+}
+
+impl Scheduler for SignalScheduler {
+    fn start(&self) -> Result<(), Pf2Error> {
+        if let configuration::Threads::Targeted(threads) = &self.configuration.target_ruby_threads {
+            let mut targets = self.targets.write().unwrap();
+            for ruby_thread in threads.iter() {
+                targets.push(Self::signal_target(*ruby_thread));
+            }
+        }
+
+        // Stop sampling threads once they exit, as their execution contexts are freed
+        let targets = Arc::into_raw(Arc::clone(&self.targets));
+        let event_hook = unsafe {
+            rb_internal_thread_add_event_hook(
+                Some(Self::on_thread_exit),
+                RUBY_INTERNAL_THREAD_EVENT_EXITED,
+                targets as *mut c_void,
+            )
+        };
+        *self.thread_exit_hook.lock().unwrap() = Some(ThreadExitHook {
+            event_hook,
+            targets,
+        });
+        *self.gvl_holder_hook.lock().unwrap() = Some(self.gvl_holder.watch());
+
+        // NOTE: This Box is dropped after a grace period once stopped, since signals may still be
+        // pending after stop()
+        let handler_args = Box::into_raw(self.signal_handler_args());
+        let previous_handler_args = HANDLER_ARGS.swap(handler_args, Ordering::AcqRel);
+
+        let mut sa: libc::sigaction = unsafe { mem::zeroed() };
+        sa.sa_sigaction = Self::signal_handler as usize;
+        sa.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        let mut previous_sigaction: libc::sigaction = unsafe { mem::zeroed() };
+        if unsafe { libc::sigaction(libc::SIGALRM, &sa, &mut previous_sigaction) } != 0 {
+            let error = Pf2Error::last_os_error("sigaction");
+            HANDLER_ARGS.store(previous_handler_args, Ordering::Release);
+            self.remove_event_hooks();
+            return Err(error);
+        }
+
+        // Validation ensures wall time mode, which ITIMER_REAL (delivering SIGALRM) measures
+        // The interval may already have been lengthened (`target_overhead_pct`)
+        let interval = unsafe { (*handler_args).timer_interval.initial() };
+        let itimerval = Self::duration_to_itimerval(&interval);
+        let mut previous_itimerval: libc::itimerval = unsafe { mem::zeroed() };
+        if unsafe { libc::setitimer(libc::ITIMER_REAL, &itimerval, &mut previous_itimerval) } != 0 {
+            let error = Pf2Error::last_os_error("setitimer");
+            unsafe { libc::sigaction(libc::SIGALRM, &previous_sigaction, null_mut()) };
+            HANDLER_ARGS.store(previous_handler_args, Ordering::Release);
+            self.remove_event_hooks();
+            return Err(error);
+        }
+
+        *self.previous.lock().unwrap() = Some(Previous {
+            sigaction: previous_sigaction,
+            itimerval: previous_itimerval,
+            handler_args: previous_handler_args,
+            own_handler_args: handler_args,
+        });
+        logging::debug!("setitimer armed");
+        Ok(())
+    }
+
+    fn stop(&self) {
+        self.remove_event_hooks();
+
+        let previous = match self.previous.lock().unwrap().take() {
+            Some(previous) => previous,
+            None => return,
+        };
+
+        // Hand the timer back to the enclosing profile (or disarm it)
+        let err = unsafe { libc::setitimer(libc::ITIMER_REAL, &previous.itimerval, null_mut()) };
+        if err != 0 {
+            logging::debug!("setitimer failed: {}", err);
+        }
+        let current_handler_args = HANDLER_ARGS.swap(previous.handler_args, Ordering::AcqRel);
+
+        // As in the Linux scheduler, the default action (terminating the process) is not
+        // restored, since a signal may still be pending. Our handler ignores it.
+        if previous.sigaction.sa_sigaction != libc::SIG_DFL {
+            let err = unsafe { libc::sigaction(libc::SIGALRM, &previous.sigaction, null_mut()) };
+            if err != 0 {
+                logging::debug!("sigaction failed: {}", err);
+            }
+        }
+
+        // A profile started after this one and still running may restore our args when it
+        // stops, in which case they are leaked
+        if current_handler_args != previous.own_handler_args {
+            logging::debug!("Leaking the signal handler args of a profile stopped out of order");
+            return;
+        }
+        RetiredArgs(vec![previous.own_handler_args]).free_after_grace_period();
+    }
+
+    fn on_new_thread(&self, thread: VALUE) {
+        let target = Self::signal_target(thread);
+        self.targets.write().unwrap().push(target);
+    }
+
+    fn dmark(&self) {
+        match self.profile.read() {
+            Ok(profile) => unsafe {
+                profile.dmark();
+            },
+            Err(_) => {
+                panic!("[pf2 FATAL] dmark: Failed to acquire profile lock.");
+            }
+        }
+    }
+
+    fn dcompact(&self) {
+        match self.profile.write() {
+            Ok(mut profile) => unsafe {
+                profile.dcompact();
+            },
+            Err(_) => {
+                panic!("[pf2 FATAL] dcompact: Failed to acquire profile lock.");
+            }
+        }
+    }
+
+    fn dfree(&self) {
+        // No-op
+    }
+
+    fn dsize(&self) -> size_t {
+        // Best-effort: the Profile is not accounted for if its lock is held elsewhere
+        let profile_size = match self.profile.try_read() {
+            Ok(profile) => profile.memsize(),
+            Err(_) => 0,
+        };
+        (mem::size_of::<Self>() + profile_size) as size_t
+    }
+}
+
+impl SignalScheduler {
+    pub fn new(configuration: &Configuration, profile: Arc<RwLock<Profile>>) -> Self {
+        Self {
+            configuration: Arc::new(configuration.clone()),
+            profile,
+            targets: Arc::new(RwLock::new(vec![])),
+            previous: Mutex::new(None),
+            thread_exit_hook: Mutex::new(None),
+            gvl_holder: Arc::new(GvlHolder::default()),
+            gvl_holder_hook: Mutex::new(None),
+        }
+    }
+
+    // Respond to the signal and collect a sample of every target thread.
+    // This function is called when the timer fires.
+    //
+    // Expected to be async-signal-safe, but the current implementation is not.
+    extern "C" fn signal_handler(_sig: c_int, _info: *mut libc::siginfo_t, _ucontext: *mut c_void) {
+        // No profile is running. The signal was pending when the timer was disarmed.
+        let args = HANDLER_ARGS.load(Ordering::Acquire);
+        if args.is_null() {
+            return;
+        }
+        // SignalHandlerArgs outlive the profile by a grace period (see RetiredArgs)
+        let args = unsafe { &*args };
+
+        // Signals delivered to another thread mid-capture are dropped
+        if args
+            .in_handler
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+        Self::capture_all(args);
+        args.in_handler.store(false, Ordering::Release);
+    }
+
+    fn capture_all(args: &SignalHandlerArgs) {
+        // The profile has been stopped (possibly by max_duration)
+        if args.sink.is_closed() {
+            return;
+        }
+        Self::rearm(args);
+        if args.sink.warming_up() {
+            return;
+        }
+        // The targets are being updated (a thread has started or exited). Skip this tick.
+        let targets = match args.targets.try_read() {
+            Ok(targets) => targets,
+            Err(_) => return,
+        };
+        // Not rb_thread_current(), which is unsafe to call here (see GvlHolder)
+        let gvl_holder = args.gvl_holder.current();
+        for target in targets.iter() {
+            let has_gvl = gvl_holder == Some(target.ruby_thread);
+            // Threads other than the running one are sleeping or blocked
+            if !has_gvl && args.sink.configuration.idle_samples == configuration::IdleSamples::Drop
+            {
+                continue;
+            }
+            Self::capture_and_push(args, target, has_gvl);
+        }
+    }
+
+    /// Re-arm the timer if needed (see `TimerInterval::rearm`).
+    fn rearm(args: &SignalHandlerArgs) {
+        if let Some(interval) = args.timer_interval.rearm() {
+            let itimerval = Self::duration_to_itimerval(&interval);
+            unsafe { libc::setitimer(libc::ITIMER_REAL, &itimerval, null_mut()) };
+        }
+    }
+
+    /// Capture a sample of `target` and hand it over to the flusher.
+    /// Since the signal may be delivered to any thread, native stacks are not captured.
+    fn capture_and_push(args: &SignalHandlerArgs, target: &SignalTarget, has_gvl: bool) {
+        let capture_started_at = Instant::now();
+        let mut sample = Sample::capture_without_native_stack(
+            target.ruby_thread,
+            args.sink.configuration.max_stack_depth,
+            args.sink.configuration.clock.clockid(),
+        ); // NOT async-signal-safe
+        args.sink.annotate(
+            &mut sample,
+            target.ruby_ractor_id,
+            target.root_ec,
+            Some(has_gvl),
+        );
+        args.sink.push(sample, capture_started_at);
+    }
+
+    /// Collect what the signal handler needs to know about `ruby_thread`.
+    /// The current Ractor is expected to be the one the thread belongs to.
+    fn signal_target(ruby_thread: VALUE) -> SignalTarget {
+        SignalTarget {
+            ruby_thread,
+            ruby_ractor_id: current_ractor_id(),
+            root_ec: unsafe { rb_thread_root_ec(ruby_thread) },
+        }
+    }
+
+    fn signal_handler_args(&self) -> Box<SignalHandlerArgs> {
+        let sampling_interval = Arc::clone(&self.profile.read().unwrap().sampling_interval);
+        Box::new(SignalHandlerArgs {
+            sink: SampleSink::new(&self.configuration, &self.profile),
+            targets: Arc::clone(&self.targets),
+            gvl_holder: Arc::clone(&self.gvl_holder),
+            timer_interval: TimerInterval::new(
+                sampling_interval,
+                Jitter::new(self.configuration.jitter_pct, 0),
+            ),
+            in_handler: AtomicBool::new(false),
+        })
+    }
+
+    fn remove_event_hooks(&self) {
+        self.gvl_holder_hook.lock().unwrap().take();
+        if let Some(hook) = self.thread_exit_hook.lock().unwrap().take() {
+            unsafe {
+                rb_internal_thread_remove_event_hook(hook.event_hook);
+                // The hook is gone, so its reference can be released
+                drop(Arc::from_raw(hook.targets));
+            }
+        }
+    }
+
+    unsafe extern "C" fn on_thread_exit(
+        _flag: rb_event_flag_t,
+        data: *const rb_internal_thread_event_data,
+        custom_data: *mut c_void,
+    ) {
+        let ruby_thread: VALUE = unsafe { (*data).thread };
+
+        // A strong reference to the targets (owned by ThreadExitHook) is passed as custom_data
+        let targets = unsafe { &*(custom_data as *const RwLock<Vec<SignalTarget>>) };
+        targets
+            .write()
+            .unwrap()
+            .retain(|target| target.ruby_thread != ruby_thread);
+    }
+
+    fn duration_to_itimerval(duration: &Duration) -> libc::itimerval {
+        let mut itv: libc::itimerval = unsafe { mem::zeroed() };
+        itv.it_interval.tv_sec = duration.as_secs() as libc::time_t;
+        itv.it_interval.tv_usec = duration.subsec_micros() as libc::suseconds_t;
+        // A zero it_value would disarm the timer, so round sub-microsecond intervals up
+        if itv.it_interval.tv_sec == 0 && itv.it_interval.tv_usec == 0 {
+            itv.it_interval.tv_usec = 1;
+        }
+        itv.it_value = itv.it_interval;
+        itv
+    }
+}
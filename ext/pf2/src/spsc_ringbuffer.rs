@@ -0,0 +1,176 @@
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::sample::Sample;
+
+/// A lock-free single-producer/single-consumer ring buffer of samples.
+///
+/// Used to hand samples over from a signal handler (the producer) to the
+/// flusher thread (the consumer) without taking any lock. `push()` must only
+/// be called from one thread at a time, and so must `pop()`.
+#[derive(Debug)]
+pub struct SpscRingbuffer {
+    buffer: Box<[UnsafeCell<MaybeUninit<Sample>>]>,
+    /// The position of the next sample to be popped. Only written by the consumer.
+    read_index: AtomicUsize,
+    /// The position of the next sample to be pushed. Only written by the producer.
+    write_index: AtomicUsize,
+    /// Set once the profile has been stopped. Producers should stop pushing.
+    closed: AtomicBool,
+}
+
+unsafe impl Send for SpscRingbuffer {}
+unsafe impl Sync for SpscRingbuffer {}
+
+impl SpscRingbuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            // One slot is kept empty to distinguish a full buffer from an empty one
+            buffer: std::iter::repeat_with(|| UnsafeCell::new(MaybeUninit::uninit()))
+                .take(capacity + 1)
+                .collect(),
+            read_index: AtomicUsize::new(0),
+            write_index: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    // async-signal-safe
+    /// Hands the sample back if the buffer is full.
+    pub fn push(&self, sample: Sample) -> Result<(), Sample> {
+        let write_index = self.write_index.load(Ordering::Relaxed);
+        let next = (write_index + 1) % self.buffer.len();
+        if next == self.read_index.load(Ordering::Acquire) {
+            return Err(sample);
+        }
+        unsafe {
+            (*self.buffer[write_index].get()).write(sample);
+        }
+        self.write_index.store(next, Ordering::Release);
+        Ok(())
+    }
+
+    pub fn pop(&self) -> Option<Sample> {
+        let read_index = self.read_index.load(Ordering::Relaxed);
+        if read_index == self.write_index.load(Ordering::Acquire) {
+            return None;
+        }
+        let sample = unsafe { (*self.buffer[read_index].get()).assume_init_read() };
+        self.read_index
+            .store((read_index + 1) % self.buffer.len(), Ordering::Release);
+        Some(sample)
+    }
+
+    /// The number of samples currently in the buffer.
+    pub fn len(&self) -> usize {
+        let read_index = self.read_index.load(Ordering::Acquire);
+        let write_index = self.write_index.load(Ordering::Acquire);
+        (write_index + self.buffer.len() - read_index) % self.buffer.len()
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// The number of bytes allocated for the buffer.
+    pub fn memsize(&self) -> usize {
+        self.buffer.len() * std::mem::size_of::<Sample>()
+    }
+
+    /// Must not be called while a consumer is popping samples.
+    pub unsafe fn dmark(&self) {
+        let mut index = self.read_index.load(Ordering::Acquire);
+        let write_index = self.write_index.load(Ordering::Acquire);
+        while index != write_index {
+            unsafe {
+                (*self.buffer[index].get()).assume_init_ref().dmark();
+            }
+            index = (index + 1) % self.buffer.len();
+        }
+    }
+}
+
+impl Drop for SpscRingbuffer {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rb_sys::VALUE;
+    use std::time::Instant;
+
+    fn sample(ruby_thread: VALUE) -> Sample {
+        Sample {
+            ruby_thread,
+            ruby_ractor_id: None,
+            ruby_fiber_id: 0,
+            timestamp: Instant::now(),
+            clock_ns: 0,
+            line_count: 0,
+            omitted_frames: 0,
+            during_gc: false,
+            idle: false,
+            state: None,
+            on_cpu: None,
+            frames: [0; 500],
+            linenos: [0; 500],
+            c_backtrace_pcs: [0; 1001],
+            interrupted_pc: None,
+        }
+    }
+
+    #[test]
+    fn test_spsc_ringbuffer() {
+        let ringbuffer = SpscRingbuffer::new(2);
+        assert_eq!(ringbuffer.pop(), None);
+
+        ringbuffer.push(sample(1)).unwrap();
+        ringbuffer.push(sample(2)).unwrap();
+        assert_eq!(ringbuffer.len(), 2);
+        assert_eq!(ringbuffer.push(sample(3)).unwrap_err().ruby_thread, 3);
+
+        assert_eq!(ringbuffer.pop().unwrap().ruby_thread, 1);
+        ringbuffer.push(sample(3)).unwrap(); // wraps around
+        assert_eq!(ringbuffer.pop().unwrap().ruby_thread, 2);
+        assert_eq!(ringbuffer.pop().unwrap().ruby_thread, 3);
+        assert_eq!(ringbuffer.pop(), None);
+        assert_eq!(ringbuffer.len(), 0);
+    }
+
+    #[test]
+    fn test_spsc_ringbuffer_across_threads() {
+        let ringbuffer = std::sync::Arc::new(SpscRingbuffer::new(4));
+        let producer = {
+            let ringbuffer = std::sync::Arc::clone(&ringbuffer);
+            std::thread::spawn(move || {
+                for ruby_thread in 0..100 {
+                    let mut pending = sample(ruby_thread);
+                    while let Err(rejected) = ringbuffer.push(pending) {
+                        pending = rejected;
+                        std::thread::yield_now();
+                    }
+                }
+            })
+        };
+
+        let mut expected = 0;
+        while expected < 100 {
+            match ringbuffer.pop() {
+                Some(sample) => {
+                    assert_eq!(sample.ruby_thread, expected);
+                    expected += 1;
+                }
+                None => std::thread::yield_now(),
+            }
+        }
+        producer.join().unwrap();
+    }
+}
@@ -1,18 +1,24 @@
 #![deny(unsafe_op_in_unsafe_fn)]
 
-use std::ffi::{c_void, CString};
+use std::collections::HashMap;
+use std::ffi::c_void;
 use std::mem::ManuallyDrop;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::Instant;
 
 use rb_sys::*;
 
-use crate::profile::Profile;
-use crate::profile_serializer::ProfileSerializer;
-use crate::sample::Sample;
+use crate::error::Pf2Error;
+use crate::jitter::Jitter;
+use crate::logging;
+use crate::profile::{
+    CaptureStats, Profile, SamplingInterval, TEMPORARY_SAMPLE_BUFFER_HIGH_WATER_MARK,
+};
+use crate::ruby_internal_apis::{rb_thread_current_ec, rb_thread_getcpuclockid, rb_thread_root_ec};
+use crate::sample::{Sample, ThreadState};
 use crate::scheduler::Scheduler;
-use crate::serialization::serializer::ProfileSerializer2;
 use crate::session::configuration::{self, Configuration};
 use crate::util::*;
 
@@ -20,21 +26,38 @@ use crate::util::*;
 pub struct TimerThreadScheduler {
     configuration: Arc<Configuration>,
     profile: Arc<RwLock<Profile>>,
-    stop_requested: Arc<AtomicBool>,
+    /// Incremented on every stop(). A timer thread exits once this differs from the value
+    /// observed when it was started, so that a restarted scheduler never runs two timer threads.
+    generation: Arc<AtomicUsize>,
 }
 
 #[derive(Debug)]
 struct PostponedJobArgs {
     configuration: Arc<Configuration>,
     profile: Arc<RwLock<Profile>>,
+    /// Shared with the profile, so that drops can be recorded without the profile lock.
+    capture_stats: Arc<CaptureStats>,
+    /// Target threads belong to the Ractor which started the scheduler.
+    ruby_ractor_id: Option<u64>,
+    /// The execution context of each target thread's root Fiber.
+    root_ecs: HashMap<VALUE, usize>,
 }
 
 impl Scheduler for TimerThreadScheduler {
-    fn start(&self) -> VALUE {
+    fn start(&self) -> Result<(), Pf2Error> {
         // Register the Postponed Job which does the actual work of collecting samples
         let postponed_job_args: Box<PostponedJobArgs> = Box::new(PostponedJobArgs {
             configuration: Arc::clone(&self.configuration),
             profile: Arc::clone(&self.profile),
+            capture_stats: Arc::clone(&self.profile.read().unwrap().capture_stats),
+            ruby_ractor_id: current_ractor_id(),
+            root_ecs: match &self.configuration.target_ruby_threads {
+                configuration::Threads::All => HashMap::new(),
+                configuration::Threads::Targeted(threads) => threads
+                    .iter()
+                    .map(|&thread| (thread, unsafe { rb_thread_root_ec(thread) }))
+                    .collect(),
+            },
         });
         let postponed_job_handle: rb_postponed_job_handle_t = unsafe {
             rb_postponed_job_preregister(
@@ -46,42 +69,27 @@ impl Scheduler for TimerThreadScheduler {
 
         // Start a timer thread that periodically triggers postponed jobs based on configuration
         let configuration = Arc::clone(&self.configuration);
-        let stop_requested = Arc::clone(&self.stop_requested);
+        let sampling_interval = Arc::clone(&self.profile.read().unwrap().sampling_interval);
+        let capture_stats = Arc::clone(&self.profile.read().unwrap().capture_stats);
+        let generation = Arc::clone(&self.generation);
+        let started_generation = generation.load(Ordering::Relaxed);
         thread::spawn(move || {
-            Self::thread_main_loop(configuration, stop_requested, postponed_job_handle)
+            Self::thread_main_loop(
+                configuration,
+                sampling_interval,
+                capture_stats,
+                generation,
+                started_generation,
+                postponed_job_handle,
+            )
         });
 
-        Qtrue.into()
+        Ok(())
     }
 
-    fn stop(&self) -> VALUE {
+    fn stop(&self) {
         // Stop the collector thread
-        self.stop_requested.store(true, Ordering::Relaxed);
-
-        // Finalize
-        match self.profile.try_write() {
-            Ok(mut profile) => {
-                profile.flush_temporary_sample_buffer();
-                profile.end_instant = Some(std::time::Instant::now());
-            }
-            Err(_) => {
-                println!("[pf2 ERROR] stop: Failed to acquire profile lock.");
-                return Qfalse.into();
-            }
-        }
-
-        let profile = self.profile.try_read().unwrap();
-        log::debug!("Number of samples: {}", profile.samples.len());
-
-        if self.configuration.use_experimental_serializer {
-            let mut ser = ProfileSerializer2::new();
-            ser.serialize(&profile);
-            ser.to_ruby_hash()
-        } else {
-            let serialized = ProfileSerializer::serialize(&profile);
-            let string = CString::new(serialized).unwrap();
-            unsafe { rb_str_new_cstr(string.as_ptr()) }
-        }
+        self.generation.fetch_add(1, Ordering::Relaxed);
     }
 
     fn on_new_thread(&self, _thread: VALUE) {
@@ -99,13 +107,28 @@ impl Scheduler for TimerThreadScheduler {
         }
     }
 
+    fn dcompact(&self) {
+        match self.profile.write() {
+            Ok(mut profile) => unsafe {
+                profile.dcompact();
+            },
+            Err(_) => {
+                panic!("[pf2 FATAL] dcompact: Failed to acquire profile lock.");
+            }
+        }
+    }
+
     fn dfree(&self) {
         // No-op
     }
 
     fn dsize(&self) -> size_t {
-        // FIXME: Report something better
-        std::mem::size_of::<TimerThreadScheduler>() as size_t
+        // Best-effort: the Profile is not accounted for if its lock is held elsewhere
+        let profile_size = match self.profile.try_read() {
+            Ok(profile) => profile.memsize(),
+            Err(_) => 0,
+        };
+        (std::mem::size_of::<TimerThreadScheduler>() + profile_size) as size_t
     }
 }
 
@@ -114,7 +137,7 @@ impl TimerThreadScheduler {
         Self {
             configuration: Arc::new(configuration.clone()),
             profile,
-            stop_requested: Arc::new(AtomicBool::new(false)),
+            generation: Arc::new(AtomicUsize::new(0)),
         }
 
         // cstr!("TimerThreadScheduler only supports :wall mode."),
@@ -122,19 +145,38 @@ impl TimerThreadScheduler {
 
     fn thread_main_loop(
         configuration: Arc<Configuration>,
-        stop_requested: Arc<AtomicBool>,
+        sampling_interval: Arc<SamplingInterval>,
+        capture_stats: Arc<CaptureStats>,
+        generation: Arc<AtomicUsize>,
+        started_generation: usize,
         postponed_job_handle: rb_postponed_job_handle_t,
     ) {
+        let started_at = Instant::now();
+        let jitter = Jitter::new(configuration.jitter_pct, 0);
         loop {
-            if stop_requested.fetch_and(true, Ordering::Relaxed) {
+            if generation.load(Ordering::Relaxed) != started_generation {
+                break;
+            }
+            if configuration
+                .max_duration
+                .is_some_and(|max_duration| started_at.elapsed() >= max_duration)
+            {
+                logging::debug!("max_duration reached. Stopping the timer thread.");
                 break;
             }
             unsafe {
-                log::trace!("Triggering postponed job");
+                logging::trace!("Triggering postponed job");
                 rb_postponed_job_trigger(postponed_job_handle);
             }
+            // Compared with the jobs which actually run, to tell how many were coalesced
+            capture_stats.record_postponed_job_triggered();
 
-            thread::sleep(configuration.interval);
+            // The interval may be lengthened while profiling (`target_overhead_pct`)
+            let interval = sampling_interval.get();
+            thread::sleep(match &jitter {
+                Some(jitter) => jitter.apply(interval),
+                None => interval,
+            });
         }
     }
 
@@ -143,35 +185,104 @@ impl TimerThreadScheduler {
             rb_gc_disable();
         }
         let args = unsafe { ManuallyDrop::new(Box::from_raw(ptr as *mut PostponedJobArgs)) };
+        args.capture_stats.record_postponed_job_run();
 
         let mut profile = match args.profile.try_write() {
             Ok(profile) => profile,
             Err(_) => {
                 // FIXME: Do we want to properly collect GC samples? I don't know yet.
-                log::trace!("Failed to acquire profile lock (garbage collection possibly in progress). Dropping sample.");
+                logging::trace!("Failed to acquire profile lock (garbage collection possibly in progress). Dropping sample.");
+                // A sample of each target thread is lost
+                args.capture_stats
+                    .record_dropped_by_lock_contention(args.root_ecs.len() as u64);
                 return;
             }
         };
 
+        // The profile has been stopped (possibly by max_duration)
+        if profile.end_instant.is_some() {
+            unsafe {
+                rb_gc_enable();
+            }
+            return;
+        }
+
+        // Still warming up
+        if Instant::now() < profile.start_instant {
+            unsafe {
+                rb_gc_enable();
+            }
+            return;
+        }
+
         // Collect stack information from specified Ruby Threads
         match &args.configuration.target_ruby_threads {
             configuration::Threads::All => todo!(),
             configuration::Threads::Targeted(threads) => {
                 for ruby_thread in threads.iter() {
                     // Check if the thread is still alive
-                    if unsafe { rb_funcall(*ruby_thread, rb_intern(cstr!("status")), 0) }
-                        == Qfalse as u64
-                    {
+                    let status = unsafe { rb_funcall(*ruby_thread, rb_intern(cstr!("status")), 0) };
+                    if status == Qfalse as u64 {
+                        continue;
+                    }
+                    // "sleep" covers both sleeping and blocking (e.g. in IO)
+                    let sleeping = RTEST(unsafe {
+                        rb_funcall(
+                            status,
+                            rb_intern(cstr!("==")),
+                            1,
+                            rb_str_new_cstr(cstr!("sleep")),
+                        )
+                    });
+                    let idle = args.configuration.idle_samples != configuration::IdleSamples::Keep
+                        && sleeping;
+                    if idle && args.configuration.idle_samples == configuration::IdleSamples::Drop {
                         continue;
                     }
 
-                    let sample = Sample::capture(*ruby_thread, &profile.backtrace_state);
+                    // CLOCK_THREAD_CPUTIME_ID would read the CPU time of the thread running
+                    // this job, not of the thread being sampled
+                    let clockid = match &args.configuration.clock {
+                        configuration::Clock::ThreadCpuTime => unsafe {
+                            rb_thread_getcpuclockid(*ruby_thread)
+                        },
+                        clock => clock.clockid(),
+                    };
+                    let capture_started_at = Instant::now();
+                    let mut sample = Sample::capture(
+                        *ruby_thread,
+                        &profile.backtrace_state,
+                        args.configuration.max_stack_depth,
+                        clockid,
+                    );
+                    sample.ruby_ractor_id = args.ruby_ractor_id;
+                    sample.idle = idle;
+                    // Postponed jobs never run during GC
+                    sample.state = Some(match sleeping {
+                        true => ThreadState::Sleeping,
+                        false => ThreadState::Running,
+                    });
+                    if let Some(&root_ec) = args.root_ecs.get(ruby_thread) {
+                        sample.set_fiber(unsafe { rb_thread_current_ec(*ruby_thread) }, root_ec);
+                    }
+                    profile
+                        .capture_stats
+                        .record(capture_started_at.elapsed(), sample.omitted_frames > 0);
                     if profile.temporary_sample_buffer.push(sample).is_err() {
-                        log::debug!("Temporary sample buffer full. Dropping sample.");
+                        logging::debug!("Temporary sample buffer full. Dropping sample.");
+                        profile.capture_stats.record_dropped_by_full_buffer();
                     }
                 }
             }
         }
+        if args.configuration.flush_mode == configuration::FlushMode::EventDriven
+            && profile.temporary_sample_buffer.len() >= TEMPORARY_SAMPLE_BUFFER_HIGH_WATER_MARK
+        {
+            // Release the lock first, so that the flusher can take it as soon as it wakes up
+            let flush_signal = Arc::clone(&profile.flush_signal);
+            drop(profile);
+            flush_signal.notify();
+        }
         unsafe {
             rb_gc_enable();
         }
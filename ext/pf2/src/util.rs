@@ -1,6 +1,8 @@
 use core::mem::transmute;
 use rb_sys::*;
-use std::ffi::c_void;
+use std::ffi::{c_char, c_int, c_long, c_void, CStr};
+
+use crate::error::Pf2Error;
 
 // Convert str literal to C string literal
 macro_rules! cstr {
@@ -16,6 +18,9 @@ pub type RubyCFunc = unsafe extern "C" fn() -> VALUE;
 pub fn to_ruby_cfunc_with_no_args<T>(f: unsafe extern "C" fn(T) -> VALUE) -> RubyCFunc {
     unsafe { transmute::<unsafe extern "C" fn(T) -> VALUE, RubyCFunc>(f) }
 }
+pub fn to_ruby_cfunc_with_one_arg<T, U>(f: unsafe extern "C" fn(T, U) -> VALUE) -> RubyCFunc {
+    unsafe { transmute::<unsafe extern "C" fn(T, U) -> VALUE, RubyCFunc>(f) }
+}
 pub fn to_ruby_cfunc_with_args<T, U, V>(f: unsafe extern "C" fn(T, U, V) -> VALUE) -> RubyCFunc {
     unsafe { transmute::<unsafe extern "C" fn(T, U, V) -> VALUE, RubyCFunc>(f) }
 }
@@ -25,6 +30,164 @@ pub fn RTEST(v: VALUE) -> bool {
     v != Qfalse as VALUE && v != Qnil as VALUE
 }
 
+/// An `rb_data_type_t` describing a Rust struct wrapped in a Ruby object
+/// (`rb_data_typed_object_wrap`), which can be kept in an immutable `static`.
+///
+/// Ruby only ever reads the data type through a `*const` pointer, so sharing it between threads
+/// is sound. This replaces `static mut` definitions, taking references to which is UB-prone.
+#[repr(transparent)]
+pub struct RubyDataType(rb_data_type_t);
+
+unsafe impl Sync for RubyDataType {}
+
+impl RubyDataType {
+    pub const fn new(
+        wrap_struct_name: *const c_char,
+        dmark: unsafe extern "C" fn(*mut c_void),
+        dfree: unsafe extern "C" fn(*mut c_void),
+        dsize: unsafe extern "C" fn(*const c_void) -> size_t,
+        dcompact: Option<unsafe extern "C" fn(*mut c_void)>,
+    ) -> Self {
+        Self(rb_data_type_t {
+            wrap_struct_name,
+            function: rb_data_type_struct__bindgen_ty_1 {
+                dmark: Some(dmark),
+                dfree: Some(dfree),
+                dsize: Some(dsize),
+                dcompact,
+                reserved: [std::ptr::null_mut(); 1],
+            },
+            parent: std::ptr::null_mut(),
+            data: std::ptr::null_mut(),
+            flags: 0,
+        })
+    }
+
+    pub fn as_ptr(&self) -> *const rb_data_type_t {
+        &self.0
+    }
+}
+
+/// Extract keyword arguments given to a method defined with argc = -1.
+///
+/// Options may also be given as a positional Hash. A missing or nil Hash means "all defaults".
+/// Values of keys which are not given are `Qundef`.
+/// Raises an ArgumentError on unknown keys.
+pub fn scan_kwargs<const N: usize>(
+    argc: c_int,
+    argv: *const VALUE,
+    keys: [*const c_char; N],
+) -> [VALUE; N] {
+    let mut options: VALUE = Qnil.into();
+    let mut kwargs: VALUE = Qnil.into();
+    unsafe {
+        rb_scan_args(argc, argv, cstr!("01:"), &mut options, &mut kwargs);
+    }
+    if !RTEST(kwargs) && RTEST(options) {
+        kwargs = unsafe { rb_check_hash_type(options) };
+        if !RTEST(kwargs) {
+            unsafe {
+                rb_raise(rb_eArgError, cstr!("options must be a Hash"));
+            }
+        }
+    }
+
+    let mut ids = keys.map(|key| unsafe { rb_intern(key) });
+    let mut values: [VALUE; N] = [Qundef as VALUE; N];
+    if RTEST(kwargs) {
+        unsafe {
+            rb_get_kwargs(kwargs, ids.as_mut_ptr(), 0, N as c_int, values.as_mut_ptr());
+        }
+    }
+    values
+}
+
+/// Convert an Integer option, raising an ArgumentError naming the option for other types.
+pub fn integer_option(value: VALUE, name: &str) -> c_long {
+    if !RTEST(unsafe { rb_obj_is_kind_of(value, rb_cInteger) }) {
+        Pf2Error::InvalidOption(format!("{} must be an Integer", name)).raise();
+    }
+    unsafe { rb_num2long(value) }
+}
+
+/// Convert an option to a String through `#to_s`, raising an ArgumentError naming the option if
+/// it is not valid UTF-8.
+pub fn string_option(value: VALUE, name: &str) -> String {
+    let mut str = unsafe { rb_funcall(value, rb_intern(cstr!("to_s")), 0) };
+    let str = unsafe { CStr::from_ptr(rb_string_value_cstr(&mut str)) };
+    match str.to_str() {
+        Ok(str) => str.to_owned(),
+        Err(_) => Pf2Error::InvalidOption(format!("{} must be valid UTF-8", name)).raise(),
+    }
+}
+
+/// Create a Ruby String (ASCII-8BIT) from bytes.
+/// Unlike rb_str_new_cstr(), interior NUL bytes are preserved.
+pub fn rb_str_from_bytes(bytes: &[u8]) -> VALUE {
+    unsafe { rb_str_new(bytes.as_ptr() as *const c_char, bytes.len() as c_long) }
+}
+
+/// Read `clockid` in nanoseconds, or None if the clock is no longer valid
+/// (e.g. the CPU-time clock of a thread which has exited).
+pub fn read_clock_ns(clockid: libc::clockid_t) -> Option<u64> {
+    let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+    if unsafe { libc::clock_gettime(clockid, &mut ts) } != 0 {
+        return None;
+    }
+    Some(ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64)
+}
+
+/// Mark `thread` as created by Pf2 itself (e.g. the `on_flush` delivery thread), so that it is
+/// never profiled. Must be called with the GVL held.
+///
+/// The mark is an instance variable without the `@` prefix, which Ruby code can neither see
+/// nor set. Unlike thread names, it cannot collide with user threads.
+pub fn mark_internal_thread(thread: VALUE) {
+    unsafe {
+        rb_ivar_set(
+            thread,
+            rb_intern(cstr!("__pf2_internal_thread__")),
+            Qtrue as VALUE,
+        )
+    };
+}
+
+/// Whether `thread` has been marked by `mark_internal_thread()`. Must be called with the GVL held.
+pub fn is_internal_thread(thread: VALUE) -> bool {
+    RTEST(unsafe { rb_ivar_get(thread, rb_intern(cstr!("__pf2_internal_thread__"))) })
+}
+
+/// Run `f` with `GC.auto_compact` turned off, so that GC triggered by allocations in `f` does
+/// not compact the heap. For reading a locked Profile through Ruby (see `Profile`).
+/// Must be called with the GVL held.
+pub fn without_auto_compaction<T>(f: impl FnOnce() -> T) -> T {
+    let auto_compact = unsafe { rb_funcall(rb_mGC, rb_intern(cstr!("auto_compact")), 0) };
+    let set_auto_compact = |value: VALUE| unsafe {
+        rb_funcall(rb_mGC, rb_intern(cstr!("auto_compact=")), 1, value);
+    };
+    if RTEST(auto_compact) {
+        set_auto_compact(Qfalse.into());
+    }
+    let result = f();
+    if RTEST(auto_compact) {
+        set_auto_compact(Qtrue.into());
+    }
+    result
+}
+
+/// The object ID of the current Ractor, or None if Ractors are not available.
+/// Must be called with the GVL held.
+pub fn current_ractor_id() -> Option<u64> {
+    unsafe {
+        if rb_const_defined(rb_cObject, rb_intern(cstr!("Ractor"))) == 0 {
+            return None;
+        }
+        let ractor_class = rb_const_get(rb_cObject, rb_intern(cstr!("Ractor")));
+        let ractor = rb_funcall(ractor_class, rb_intern(cstr!("current")), 0);
+        Some(rb_num2ull(rb_obj_id(ractor)))
+    }
+}
+
 extern "C" {
     pub fn extract_si_value_sival_ptr(info: *mut libc::siginfo_t) -> *mut c_void;
 }
@@ -0,0 +1,462 @@
+//! Tests running the profiler end-to-end on an embedded Ruby VM.
+//!
+//! Unlike the unit tests next to each module, these exercise the FFI boundary: schedulers
+//! capture real Ruby stacks, and the serializer resolves their frames through Ruby's APIs.
+//! `#[ruby_test]` boots a VM once per process and runs each test on the thread owning it,
+//! so these run headless with a plain `cargo test`.
+
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use rb_sys::*;
+use rb_sys_test_helpers::ruby_test;
+
+use crate::profile::Profile;
+use crate::scheduler::Scheduler;
+use crate::serialization::serializer::ProfileSerializer2;
+use crate::session::configuration::{
+    Configuration, ConfigurationBuilder, MaxSamplesPolicy, Threads, TimeMode,
+};
+use crate::util::cstr;
+
+/// Profile `code` on the current thread with `scheduler`, and return the stopped profile.
+fn capture_with(
+    configuration: &Configuration,
+    new_scheduler: impl FnOnce(&Configuration, Arc<RwLock<Profile>>) -> Box<dyn Scheduler>,
+    code: *const std::ffi::c_char,
+) -> Arc<RwLock<Profile>> {
+    let profile = Arc::new(RwLock::new(Profile::new(
+        configuration.interval,
+        None,
+        MaxSamplesPolicy::Stop,
+        vec![],
+    )));
+    let scheduler = new_scheduler(configuration, Arc::clone(&profile));
+
+    profile.write().unwrap().restart_clock(Duration::ZERO);
+    scheduler.start().unwrap();
+    unsafe { rb_eval_string(code) };
+    scheduler.stop();
+
+    {
+        let mut profile = profile.write().unwrap();
+        profile.finish();
+        profile.flush_temporary_sample_buffer();
+    }
+    profile
+}
+
+/// Profile `code` on the current thread with `scheduler`, and serialize the result.
+fn profile_with(
+    configuration: &Configuration,
+    new_scheduler: impl FnOnce(&Configuration, Arc<RwLock<Profile>>) -> Box<dyn Scheduler>,
+    code: *const std::ffi::c_char,
+) -> ProfileSerializer2 {
+    let profile = capture_with(configuration, new_scheduler, code);
+    let mut ser = ProfileSerializer2::new(configuration);
+    ser.serialize(&profile.read().unwrap());
+    ser
+}
+
+fn wall_time_configuration() -> Configuration {
+    ConfigurationBuilder::new()
+        .time_mode(TimeMode::WallTime)
+        .interval(Duration::from_millis(1))
+        .target_ruby_threads(Threads::Targeted(HashSet::from([unsafe {
+            rb_thread_current()
+        }])))
+        .build()
+        .unwrap()
+}
+
+/// The names of the functions appearing in at least one sample.
+fn sampled_function_names(ser: &ProfileSerializer2) -> HashSet<String> {
+    let profile = ser.profile();
+    profile
+        .samples
+        .iter()
+        .flat_map(|sample| sample.stack.iter())
+        .filter_map(|&location| {
+            let function = &profile.functions[profile.locations[location].function_index];
+            function.name.map(|name| profile.strings[name].clone())
+        })
+        .collect()
+}
+
+const BUSY_LOOP: *const std::ffi::c_char = cstr!(
+    "def pf2_vm_test_busy_loop
+       start = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+       nil while Process.clock_gettime(Process::CLOCK_MONOTONIC) - start < 0.1
+     end
+     pf2_vm_test_busy_loop"
+);
+
+#[cfg(target_os = "linux")]
+#[ruby_test]
+fn test_signal_scheduler_captures_ruby_methods() {
+    use crate::signal_scheduler::SignalScheduler;
+
+    let configuration = wall_time_configuration();
+    let ser = profile_with(
+        &configuration,
+        |configuration, profile| Box::new(SignalScheduler::new(configuration, profile)),
+        BUSY_LOOP,
+    );
+
+    assert!(!ser.profile().samples.is_empty());
+    let names = sampled_function_names(&ser);
+    assert!(
+        names.contains("Object#pf2_vm_test_busy_loop"),
+        "{:?}",
+        names
+    );
+    assert!(names.contains("Process.clock_gettime"), "{:?}", names);
+}
+
+#[cfg(target_os = "linux")]
+#[ruby_test]
+fn test_signal_scheduler_installs_one_timer_per_thread() {
+    use crate::signal_scheduler::SignalScheduler;
+
+    let configuration = wall_time_configuration();
+    let profile = Arc::new(RwLock::new(Profile::new(
+        configuration.interval,
+        None,
+        MaxSamplesPolicy::Stop,
+        vec![],
+    )));
+    let scheduler = SignalScheduler::new(&configuration, profile);
+    scheduler.start().unwrap();
+    // e.g. the new thread watcher reporting a thread already targeted
+    let current = unsafe { rb_thread_current() };
+    scheduler.on_new_thread(current);
+    scheduler.on_new_thread(current);
+    let timed_threads = scheduler.timed_threads();
+    scheduler.stop();
+
+    assert_eq!(timed_threads, vec![current]);
+}
+
+#[ruby_test]
+fn test_timer_thread_scheduler_captures_ruby_methods() {
+    use crate::timer_thread_scheduler::TimerThreadScheduler;
+
+    let configuration = wall_time_configuration();
+    let ser = profile_with(
+        &configuration,
+        |configuration, profile| Box::new(TimerThreadScheduler::new(configuration, profile)),
+        BUSY_LOOP,
+    );
+
+    let names = sampled_function_names(&ser);
+    assert!(
+        names.contains("Object#pf2_vm_test_busy_loop"),
+        "{:?}",
+        names
+    );
+}
+
+#[ruby_test]
+fn test_serializer_can_be_reused() {
+    use crate::timer_thread_scheduler::TimerThreadScheduler;
+
+    let configuration = wall_time_configuration();
+    let profile = capture_with(
+        &configuration,
+        |configuration, profile| Box::new(TimerThreadScheduler::new(configuration, profile)),
+        BUSY_LOOP,
+    );
+    let profile = profile.read().unwrap();
+
+    let mut ser = ProfileSerializer2::new(&configuration);
+    let to_json = |ser: &ProfileSerializer2| {
+        let mut json = vec![];
+        ser.to_writer(&mut json, false).unwrap();
+        String::from_utf8(json).unwrap()
+    };
+    ser.serialize(&profile);
+    let first = to_json(&ser);
+    ser.serialize(&profile);
+    let second = to_json(&ser);
+
+    assert!(!ser.profile().samples.is_empty());
+    assert_eq!(first, second);
+}
+
+#[ruby_test]
+fn test_samples_without_ruby_frames_get_a_root_frame() {
+    use crate::sample::Sample;
+
+    let configuration = wall_time_configuration();
+    let mut profile = Profile::new(configuration.interval, None, MaxSamplesPolicy::Stop, vec![]);
+    let mut sample = Sample::capture_without_native_stack(
+        unsafe { rb_thread_current() },
+        configuration.max_stack_depth,
+        configuration.clock.clockid(),
+    );
+    sample.line_count = 0;
+    profile.samples.push_back(sample);
+    profile.finish();
+
+    let mut ser = ProfileSerializer2::new(&configuration);
+    ser.serialize(&profile);
+
+    let profile = ser.profile();
+    assert_eq!(profile.samples.len(), 1);
+    let stack = &profile.samples[0].stack;
+    assert_eq!(stack.len(), 1);
+    let function = &profile.functions[profile.locations[stack[0]].function_index];
+    assert_eq!(profile.strings[function.name.unwrap()], "(no Ruby frames)");
+}
+
+#[ruby_test]
+fn test_frame_attributes_which_are_not_strings() {
+    use crate::serialization::resolved_frame::ResolvedFrame;
+
+    let attribute = ResolvedFrame::string_attribute;
+    unsafe {
+        assert_eq!(attribute(Qnil as VALUE), None);
+        assert_eq!(
+            attribute(rb_str_new_cstr(cstr!("Foo#bar"))),
+            Some("Foo#bar".to_owned())
+        );
+        assert_eq!(
+            attribute(rb_id2sym(rb_intern(cstr!("bar")))),
+            Some("bar".to_owned())
+        );
+        assert_eq!(attribute(rb_int2inum(42)), Some("42".to_owned()));
+        // A NUL byte would make rb_string_value_cstr() raise
+        assert_eq!(
+            attribute(rb_str_new("a\0b".as_ptr() as *const std::ffi::c_char, 3)),
+            Some("a\0b".to_owned())
+        );
+    }
+}
+
+#[ruby_test]
+fn test_dead_threads_get_an_empty_sample() {
+    use crate::sample::Sample;
+
+    let configuration = wall_time_configuration();
+    let thread = unsafe { rb_eval_string(cstr!("Thread.new { 1 }.tap(&:join)")) };
+    let sample = Sample::capture_without_native_stack(
+        thread,
+        configuration.max_stack_depth,
+        configuration.clock.clockid(),
+    );
+    assert_eq!(sample.ruby_frame_count(), 0);
+    assert_eq!(sample.omitted_frames, 0);
+}
+
+#[ruby_test]
+fn test_samples_preceding_the_start_get_a_zero_elapsed_time() {
+    use crate::sample::Sample;
+
+    let configuration = wall_time_configuration();
+    let mut profile = Profile::new(configuration.interval, None, MaxSamplesPolicy::Stop, vec![]);
+    let sample = Sample::capture_without_native_stack(
+        unsafe { rb_thread_current() },
+        configuration.max_stack_depth,
+        configuration.clock.clockid(),
+    );
+    profile.samples.push_back(sample);
+    // The sample and the end of the profile now precede its start
+    profile.restart_clock(Duration::from_secs(60));
+    profile.finish();
+    assert_eq!(profile.duration(), Duration::ZERO);
+
+    let mut ser = ProfileSerializer2::new(&configuration);
+    ser.serialize(&profile);
+
+    let profile = ser.profile();
+    assert_eq!(profile.duration_ns, 0);
+    assert_eq!(profile.samples[0].elapsed_ns, 0);
+}
+
+#[ruby_test]
+fn test_flush_resolves_samples_outside_of_the_profile() {
+    use crate::profile::FlushBatch;
+    use crate::sample::Sample;
+
+    let configuration = wall_time_configuration();
+    let mut profile = Profile::new(configuration.interval, None, MaxSamplesPolicy::Stop, vec![]);
+    let sample = Sample::capture_without_native_stack(
+        unsafe { rb_thread_current() },
+        configuration.max_stack_depth,
+        configuration.clock.clockid(),
+    );
+    profile.temporary_sample_buffer.push(sample).unwrap();
+
+    let mut flush_batch = FlushBatch::default();
+    profile.take_pending_samples(&mut flush_batch);
+    assert!(profile.samples.is_empty());
+    // Not resolved yet
+    profile.append_flushed_samples(&mut flush_batch);
+    assert!(profile.samples.is_empty());
+
+    flush_batch.resolve();
+    profile.append_flushed_samples(&mut flush_batch);
+    assert_eq!(profile.samples.len(), 1);
+    assert_eq!(profile.flushed_sample_count, 1);
+}
+
+#[ruby_test]
+fn test_evicted_samples_release_their_threads_and_frames() {
+    use crate::sample::Sample;
+
+    let configuration = wall_time_configuration();
+    let mut profile = Profile::new(configuration.interval, None, MaxSamplesPolicy::Stop, vec![]);
+    let window = Duration::from_millis(10);
+    for i in 0..4 {
+        // Each window samples a different thread, sleeping in a different method
+        let code = std::ffi::CString::new(format!(
+            "def pf2_vm_test_window_{i} = sleep
+             ($pf2_vm_test_threads ||= []) << Thread.new {{ pf2_vm_test_window_{i} }}
+             Thread.pass until $pf2_vm_test_threads.last.status == 'sleep'
+             $pf2_vm_test_threads.last"
+        ))
+        .unwrap();
+        let thread = unsafe { rb_eval_string(code.as_ptr()) };
+
+        // Move past the window of the previous sample
+        std::thread::sleep(window * 2);
+        profile.evict_samples_older_than(window);
+
+        let sample = Sample::capture_without_native_stack(
+            thread,
+            configuration.max_stack_depth,
+            configuration.clock.clockid(),
+        );
+        profile.temporary_sample_buffer.push(sample).unwrap();
+        profile.flush_temporary_sample_buffer();
+
+        assert_eq!(profile.samples.len(), 1);
+        let sample = &profile.samples[0];
+        assert_eq!(
+            profile.known_threads.keys().collect::<Vec<_>>(),
+            vec![&thread]
+        );
+        assert_eq!(
+            profile.known_frames.keys().copied().collect::<HashSet<_>>(),
+            sample.frames[..sample.ruby_frame_count()]
+                .iter()
+                .copied()
+                .collect::<HashSet<_>>()
+        );
+    }
+    unsafe { rb_eval_string(cstr!("$pf2_vm_test_threads.each(&:kill)")) };
+}
+
+#[ruby_test]
+fn test_crash_dump_hook_releases_the_profile_after_a_grace_period() {
+    use crate::crash_dump::CrashDumpHook;
+
+    let configuration = wall_time_configuration();
+    let profile = Arc::new(RwLock::new(Profile::new(
+        configuration.interval,
+        None,
+        MaxSamplesPolicy::Stop,
+        vec![],
+    )));
+    let hook =
+        CrashDumpHook::install(std::path::Path::new("/tmp/pf2-vm-test.folded"), &profile).unwrap();
+    assert_eq!(Arc::strong_count(&profile), 2);
+
+    drop(hook);
+    // A crash dump being written on another thread may still read the profile for a while
+    std::thread::sleep(Duration::from_secs(2));
+    assert_eq!(Arc::strong_count(&profile), 1);
+}
+
+#[ruby_test]
+fn test_root_ec_matches_the_layout_of_the_running_ruby() {
+    use crate::ruby_internal_apis::{rb_thread_current_ec, rb_thread_root_ec};
+    use crate::util::to_ruby_cfunc_with_no_args;
+    use std::sync::Mutex;
+
+    // (current, root) execution contexts of the calling thread, in the order they were recorded
+    static ECS: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+    unsafe extern "C" fn record_ecs(_self: VALUE) -> VALUE {
+        let thread = unsafe { rb_thread_current() };
+        ECS.lock()
+            .unwrap()
+            .push(unsafe { (rb_thread_current_ec(thread), rb_thread_root_ec(thread)) });
+        Qnil as VALUE
+    }
+    unsafe {
+        rb_define_global_function(
+            cstr!("pf2_vm_test_record_ecs"),
+            Some(to_ruby_cfunc_with_no_args(record_ecs)),
+            0,
+        );
+        rb_eval_string(cstr!(
+            "pf2_vm_test_record_ecs
+             Fiber.new { pf2_vm_test_record_ecs }.resume
+             pf2_vm_test_record_ecs"
+        ));
+    }
+
+    // `rb_thread_root_ec` reads `rb_thread_struct.root_fiber` and `fiber_ptr`, whose offsets
+    // differ across Ruby versions: a wrong offset would point anywhere but the root Fiber
+    let ecs = ECS.lock().unwrap();
+    let (root_ec, _) = ecs[0];
+    assert_eq!(ecs[0], (root_ec, root_ec));
+    assert_ne!(ecs[1].0, root_ec);
+    assert_eq!(ecs[1].1, root_ec);
+    assert_eq!(ecs[2], (root_ec, root_ec));
+}
+
+#[ruby_test]
+fn test_sample_sink_counts_each_dropped_sample() {
+    use crate::profile::TEMPORARY_SAMPLE_BUFFER_MAX_CAPACITY;
+    use crate::sample::Sample;
+    use crate::signal_sampling::SampleSink;
+    use std::time::Instant;
+
+    let configuration = Arc::new(wall_time_configuration());
+    let profile = Arc::new(RwLock::new(Profile::new(
+        configuration.interval,
+        None,
+        MaxSamplesPolicy::Stop,
+        vec![],
+    )));
+    let sink = SampleSink::new(&configuration, &profile);
+    let capture = || {
+        Sample::capture_without_native_stack(
+            unsafe { rb_thread_current() },
+            configuration.max_stack_depth,
+            configuration.clock.clockid(),
+        )
+    };
+
+    // Fill the sample ring, past which samples go into the temporary sample buffer
+    while profile.read().unwrap().temporary_sample_buffer.is_empty() {
+        sink.push(capture(), Instant::now());
+    }
+    // Then fill the temporary sample buffer
+    {
+        let mut profile = profile.write().unwrap();
+        for _ in 0..TEMPORARY_SAMPLE_BUFFER_MAX_CAPACITY {
+            profile.temporary_sample_buffer.reserve_chunk();
+            let _ = profile.temporary_sample_buffer.push(capture());
+        }
+    }
+    let capture_stats = Arc::clone(&profile.read().unwrap().capture_stats);
+    assert_eq!(capture_stats.dropped_by_full_buffer(), 0);
+    assert_eq!(capture_stats.dropped_by_lock_contention(), 0);
+
+    let count = capture_stats.count();
+    sink.push(capture(), Instant::now());
+    assert_eq!(capture_stats.count(), count + 1);
+    assert_eq!(capture_stats.dropped_by_full_buffer(), 1);
+    assert_eq!(capture_stats.dropped_by_lock_contention(), 0);
+
+    // Samples are dropped, rather than waiting for the lock, while it is held elsewhere
+    let guard = profile.read().unwrap();
+    sink.push(capture(), Instant::now());
+    drop(guard);
+    assert_eq!(capture_stats.count(), count + 2);
+    assert_eq!(capture_stats.dropped_by_full_buffer(), 1);
+    assert_eq!(capture_stats.dropped_by_lock_contention(), 1);
+}
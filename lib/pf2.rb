@@ -10,15 +10,71 @@ module Pf2
     @@session.start
   end
 
-  def self.stop
-    @@session.stop
+  def self.stop(...)
+    @@session.stop(...)
   end
 
-  def self.profile(&block)
+  # Returns the profile collected so far by the current session, without stopping it.
+  # With `mode: :continuous`, this is the most recent `window_ms` of samples.
+  # Accepts the same options as Pf2.stop.
+  def self.snapshot(...)
+    @@session.snapshot(...)
+  end
+
+  # Returns the number of samples collected so far by the current session.
+  # Safe to call from a monitoring thread while profiling.
+  def self.sample_count
+    @@session.sample_count
+  end
+
+  # Returns the numbers of samples dropped so far by the current session (since the last
+  # Pf2.reset_drop_stats): `{buffer_full:, lock_contention:, truncated:}`. Never blocks the profiler.
+  def self.drop_stats
+    @@session.drop_stats
+  end
+
+  # Counts drops from zero again in Pf2.drop_stats, without affecting the profile.
+  def self.reset_drop_stats
+    @@session.reset_drop_stats
+  end
+
+  # Returns an estimate of the memory used by the profile of the current session so far, in bytes.
+  # Safe to call from a monitoring thread while profiling, e.g. to decide when to snapshot or reset.
+  def self.memsize
+    @@session.memsize
+  end
+
+  # Records a marker named `label` on the timeline of the current session (e.g. "request start").
+  # Returns whether it was recorded.
+  def self.mark(label)
+    @@session.mark(label)
+  end
+
+  # Moves the samples captured so far into the profile of the current session, without waiting
+  # for the background flusher. Returns the number of samples added.
+  def self.flush
+    @@session.flush
+  end
+
+  # Resets the current session in a child process after fork, and starts it again if it was
+  # running in the parent. Call this first thing in the child, e.g. from Puma's on_worker_boot.
+  def self.post_fork
+    return unless defined?(@@session) && @@session
+    @@session.start if @@session.post_fork
+  end
+
+  # Profiles the given block and returns the serialized profile.
+  # Accepts the same options as Pf2.start; `threads` defaults to all live threads.
+  # The profiler is stopped even if the block raises.
+  def self.profile(**options, &block)
     raise ArgumentError, "block required" unless block_given?
-    start(threads: Thread.list)
-    yield
-    stop
-    @@session = nil # let GC clean up the session
+    start(**{ threads: Thread.list }.merge(options))
+    begin
+      yield
+    ensure
+      profile = stop
+      @@session = nil # let GC clean up the session
+    end
+    profile
   end
 end
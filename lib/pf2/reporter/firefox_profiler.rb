@@ -340,8 +340,9 @@ module Pf2
           @string_table[str]
         end
 
+        # Markers recorded with Session#mark, as instant markers
         def markers
-          {
+          ret = {
             data: [],
             name: [],
             time: [],
@@ -349,8 +350,20 @@ module Pf2
             end_time: [],
             phase: [],
             category: [],
-            length: 0
           }
+
+          (@thread[:markers] || []).each do |marker|
+            ret[:data] << nil
+            ret[:name] << string_id(marker[:label])
+            ret[:time] << marker[:elapsed_ns] / 1000000 # ns -> ms
+            ret[:start_time] << marker[:elapsed_ns] / 1000000
+            ret[:end_time] << nil
+            ret[:phase] << 0 # Instant
+            ret[:category] << 0
+          end
+
+          ret[:length] = ret[:name].length
+          ret
         end
       end
 
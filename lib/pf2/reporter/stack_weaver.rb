@@ -47,7 +47,7 @@ module Pf2
       def should_switch_to_native?(location_index, native_stack_remainder)
         location = @profile[:locations][location_index]
         function = @profile[:functions][location[:function_index]]
-        raise if function[:implementation] != :ruby # assert
+        raise unless [:ruby, :cfunc].include?(function[:implementation]) # assert
 
         # Is the current Ruby function a cfunc?
         return false if function[:start_address] == nil
@@ -72,7 +72,14 @@ module Pf2
 
         # If the next function is a vm_exec_core() (= VM_EXEC in vm_exec.h),
         # we switch to the Ruby stack.
-        function[:name] == 'vm_exec_core'
+        function_name(function) == 'vm_exec_core'
+      end
+
+      # Function names are interned into the profile's string table.
+      # Accept raw strings as well for hand-crafted profiles.
+      def function_name(function)
+        name = function[:name]
+        name.is_a?(Integer) ? @profile[:strings][name] : name
       end
     end
   end
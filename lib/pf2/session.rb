@@ -1,7 +1,5 @@
 module Pf2
   class Session
-    attr_reader :configuration
-
     # Implementation is in Rust code.
   end
 end
@@ -0,0 +1,33 @@
+require 'minitest/autorun'
+
+require 'pf2'
+
+class FeaturesTest < Minitest::Test
+  def test_features_contains_expected_keys
+    features = Pf2.features
+    %i[signal_scheduler per_thread_timers timer_thread_scheduler cpu_time wall_time native_frames gc_tracking debug].each do |key|
+      assert_includes(features.keys, key)
+      assert_includes([true, false], features[key])
+    end
+  end
+
+  def test_features_reflect_platform
+    features = Pf2.features
+    linux = RUBY_PLATFORM.include?('linux')
+    assert_equal(true, features[:wall_time])
+    assert_equal(true, features[:timer_thread_scheduler])
+    assert_equal(linux, features[:cpu_time])
+    # Without per-thread timers, the signal scheduler falls back to setitimer(2)
+    assert_equal(true, features[:signal_scheduler]) unless linux
+    assert_equal(false, features[:per_thread_timers]) unless linux
+  end
+
+  def test_native_version
+    native_version = Pf2.native_version
+    assert_match(/\A\d+\.\d+\.\d+/, native_version[:version])
+    refute_empty(native_version[:target])
+    # Compiled against the running Ruby's API
+    assert_equal(RbConfig::CONFIG['ruby_version'], native_version[:ruby_api_version])
+    assert(native_version.frozen?)
+  end
+end
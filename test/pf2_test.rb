@@ -0,0 +1,298 @@
+require 'json'
+require 'stringio'
+require 'zlib'
+require 'minitest/autorun'
+require 'tmpdir'
+
+require 'pf2'
+
+class Pf2Test < Minitest::Test
+  def test_profile_returns_serialized_profile
+    profile = Pf2.profile(time_mode: :wall) { sleep 0.1 }
+    assert_kind_of(String, profile)
+    assert_kind_of(Hash, JSON.parse(profile))
+  end
+
+  def test_default_format_has_per_thread_stack_trees
+    profile = JSON.parse(Pf2.profile(time_mode: :wall, interval_ms: 1) { sleep 0.1 }, symbolize_names: true)
+    profile[:threads].each_value do |thread|
+      assert_equal(0, thread[:stack_tree][:node_id])
+      thread[:samples].each do |sample|
+        assert_kind_of(Integer, sample[:elapsed_ns])
+        assert_kind_of(Integer, sample[:stack_tree_id])
+      end
+    end
+  end
+
+  def test_profile_requires_block
+    assert_raises(ArgumentError) { Pf2.profile }
+  end
+
+  def test_profile_stops_when_block_raises
+    assert_raises(RuntimeError) do
+      Pf2.profile { raise 'boom' }
+    end
+
+    # The previous session must have been stopped, so profiling again works
+    assert_kind_of(String, Pf2.profile { sleep 0.01 })
+  end
+
+  def test_profile_block
+    profile = Pf2.profile_block(time_mode: :wall, interval_ms: 1) { sleep 0.05 }
+    assert_kind_of(String, profile)
+    assert_kind_of(Hash, JSON.parse(profile))
+
+    assert_raises(ArgumentError) { Pf2.profile_block }
+    assert_raises(ArgumentError) { Pf2.profile_block(time_mode: :invalid) { } }
+  end
+
+  def test_profile_block_stops_when_block_raises
+    assert_raises(RuntimeError) do
+      Pf2.profile_block(time_mode: :wall) { raise 'boom' }
+    end
+    assert_kind_of(String, Pf2.profile_block(time_mode: :wall) { sleep 0.01 })
+  end
+
+  def test_profile_block_is_reentrant
+    inner = nil
+    outer = Pf2.profile_block(time_mode: :wall, interval_ms: 1, use_experimental_serializer: true) do
+      inner = Pf2.profile_block(time_mode: :wall, interval_ms: 1, use_experimental_serializer: true) { sleep 0.05 }
+      sleep 0.05
+    end
+    refute_empty(inner[:samples])
+    assert_operator(outer[:samples].size, :>, inner[:samples].size)
+  end
+
+  def test_profile_thread
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 5, use_experimental_serializer: true)
+    session.start
+    profile = Pf2.profile_thread(Thread.current, time_mode: :wall, interval_us: 200, use_experimental_serializer: true) do
+      sleep 0.05
+    end
+    outer = session.stop
+
+    # Sampled far more often than the enclosing profile, which keeps running meanwhile
+    assert_equal(200_000, profile[:metadata][:interval_ns])
+    refute_empty(outer[:samples])
+    assert_operator(profile[:samples].size, :>, outer[:samples].size)
+
+    assert_raises(ArgumentError) { Pf2.profile_thread(Thread.current) }
+    assert_raises(TypeError) { Pf2.profile_thread(:main) { } }
+    assert_raises(ArgumentError) { Pf2.profile_thread(Thread.current, interval_ms: 1, interval_us: 100) { } }
+  end
+
+  def test_drop_stats
+    Pf2.start(threads: [Thread.current], time_mode: :wall, interval_ms: 1, max_stack_depth: 1)
+    sleep 0.05
+    Pf2.stop
+    stats = Pf2.drop_stats
+
+    assert_equal(%i[buffer_full coalesced_jobs lock_contention truncated], stats.keys.sort)
+    assert_operator(stats[:truncated], :>, 0)
+    Pf2.reset_drop_stats
+    assert_equal({buffer_full: 0, lock_contention: 0, truncated: 0, coalesced_jobs: 0}, Pf2.drop_stats)
+  end
+
+  def test_memsize_grows_with_samples
+    Pf2.start(threads: [Thread.current], time_mode: :wall, interval_ms: 1)
+    initial = Pf2.memsize
+    sleep 0.1
+    Pf2.flush
+    grown = Pf2.memsize
+    Pf2.stop
+
+    assert_operator(initial, :>, 0)
+    assert_operator(grown, :>, initial)
+  end
+
+  def test_sample_count_grows_while_profiling
+    Pf2.start(threads: [Thread.current], time_mode: :wall, interval_ms: 1)
+    counts = []
+    monitor = Thread.new do
+      3.times do
+        sleep 0.05
+        counts << Pf2.sample_count
+      end
+    end
+    monitor.join
+    Pf2.stop
+
+    assert_equal(counts.sort, counts)
+    assert_operator(counts.last, :>, 0)
+  end
+
+  def test_stop_writes_profile_to_output_path
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, 'profile.json')
+      Pf2.start(threads: [Thread.current], time_mode: :wall)
+      sleep 0.1
+      assert_equal(path, Pf2.stop(output: path))
+      assert_kind_of(Hash, JSON.parse(File.read(path)))
+    end
+  end
+
+  def test_stop_pretty_prints_profile
+    Pf2.start(threads: [Thread.current], time_mode: :wall)
+    sleep 0.1
+    profile = Pf2.stop(pretty: true)
+    assert_operator(profile.lines.size, :>, 1)
+    assert_kind_of(Hash, JSON.parse(profile))
+  end
+
+  def test_stop_compresses_profile
+    Pf2.start(threads: [Thread.current], time_mode: :wall)
+    sleep 0.1
+    profile = Pf2.stop(compress: true)
+    assert_equal(Encoding::ASCII_8BIT, profile.encoding)
+    assert_kind_of(Hash, JSON.parse(Zlib.gunzip(profile)))
+  end
+
+  def test_stop_writes_compressed_profile_to_output_path
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, 'profile.json.gz')
+      Pf2.start(threads: [Thread.current], time_mode: :wall)
+      sleep 0.1
+      Pf2.stop(output: path, compress: true)
+      assert_kind_of(Hash, JSON.parse(Zlib.gunzip(File.binread(path))))
+    end
+  end
+
+  def test_stop_streams_profile_to_io
+    Pf2.start(threads: [Thread.current], time_mode: :wall, use_experimental_serializer: true)
+    sleep 0.1
+    io = StringIO.new
+    assert_same(io, Pf2.stop(io: io))
+    profile = JSON.parse(io.string)
+    assert_operator(profile.keys.index('functions'), :<, profile.keys.index('samples'))
+  end
+
+  def test_stop_streams_compressed_profile_to_file_descriptor
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, 'profile.json.gz')
+      File.open(path, 'wb') do |file|
+        Pf2.start(threads: [Thread.current], time_mode: :wall)
+        sleep 0.1
+        Pf2.stop(io: file.fileno, compress: true)
+      end
+      assert_kind_of(Hash, JSON.parse(Zlib.gunzip(File.binread(path))))
+    end
+  end
+
+  def test_stop_rejects_io_along_with_output
+    Pf2.start(threads: [Thread.current], time_mode: :wall)
+    assert_raises(ArgumentError) { Pf2.stop(io: StringIO.new, output: 'profile.json') }
+  ensure
+    Pf2.stop
+  end
+
+  def test_diff
+    Dir.mktmpdir do |dir|
+      before_path = File.join(dir, 'before.json')
+      after_path = File.join(dir, 'after.json')
+      Pf2.start(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+      sleep 0.05
+      Pf2.stop(output: before_path)
+      Pf2.start(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+      sleep 0.1
+      Pf2.stop(output: after_path)
+
+      before = File.read(before_path)
+      after = File.read(after_path)
+      diff = JSON.parse(Pf2.diff(before, after), symbolize_names: true)
+      assert_equal(
+        JSON.parse(after)['samples'].size - JSON.parse(before)['samples'].size,
+        diff[:samples].sum { |sample| sample[:delta] },
+      )
+      diff[:samples].each { |sample| refute_equal(0, sample[:delta]) }
+
+      assert_empty(JSON.parse(Pf2.diff(after, after))['samples'])
+      assert_raises(ArgumentError) { Pf2.diff('{}', after) }
+
+      # Profiles of an older schema_version are read, but not those of a newer one
+      older = JSON.parse(after).merge('schema_version' => 0)
+      assert_empty(JSON.parse(Pf2.diff(JSON.generate(older), after))['samples'])
+      newer = JSON.parse(after).merge('schema_version' => JSON.parse(after)['schema_version'] + 1)
+      error = assert_raises(ArgumentError) { Pf2.diff(before, JSON.generate(newer)) }
+      assert_match(/schema_version/, error.message)
+
+      # Out-of-range indices raise instead of crashing the process
+      broken = JSON.parse(after)
+      broken['locations'][0]['function_index'] = broken['functions'].size
+      error = assert_raises(ArgumentError) { Pf2.diff(before, JSON.generate(broken)) }
+      assert_match(/function index/, error.message)
+    end
+  end
+
+  def test_merge
+    Dir.mktmpdir do |dir|
+      paths = 2.times.map do |i|
+        path = File.join(dir, "worker#{i}.json")
+        Pf2.start(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+        sleep 0.05
+        Pf2.stop(output: path)
+        path
+      end
+      profiles = paths.map { |path| File.read(path) }
+      parsed = profiles.map { |profile| JSON.parse(profile, symbolize_names: true) }
+
+      merged = JSON.parse(Pf2.merge(*profiles), symbolize_names: true)
+      assert_equal(parsed.sum { |profile| profile[:samples].size }, merged[:samples].size)
+      # Both profiles were taken in the same process, so functions should be deduplicated
+      assert_equal(merged[:functions].uniq.size, merged[:functions].size)
+      assert_equal(merged[:strings].uniq.size, merged[:strings].size)
+      assert_operator(merged[:functions].size, :<=, parsed.sum { |profile| profile[:functions].size })
+      # Thread IDs are namespaced by profile, even though the same thread was profiled twice
+      assert_equal(2, merged[:samples].map { |sample| sample[:ruby_thread_id] }.uniq.size)
+
+      assert_raises(ArgumentError) { Pf2.merge }
+      assert_raises(ArgumentError) { Pf2.merge(profiles[0], '{}') }
+
+      # Out-of-range indices raise instead of crashing the process
+      broken = JSON.parse(profiles[0])
+      broken['locations'][0]['function_index'] = broken['functions'].size
+      error = assert_raises(ArgumentError) { Pf2.merge(profiles[0], JSON.generate(broken)) }
+      assert_match(/function index/, error.message)
+      assert_raises(ArgumentError) { Pf2.to_otlp(JSON.generate(broken)) }
+    end
+  end
+
+  def test_downsample
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, 'profile.json')
+      Pf2.start(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+      sleep 0.1
+      Pf2.stop(output: path)
+      profile = File.read(path)
+      original = JSON.parse(profile, symbolize_names: true)
+      assert_operator(original[:samples].size, :>, 10)
+
+      downsampled = JSON.parse(Pf2.downsample(profile, 10), symbolize_names: true)
+      assert_equal(10, downsampled[:samples].size)
+      assert_equal(
+        original[:samples].sum { |sample| sample[:weight_ns] },
+        downsampled[:samples].sum { |sample| sample[:weight_ns] },
+      )
+      # Only referenced locations are kept
+      referenced = downsampled[:samples].flat_map { |sample| sample[:stack] + sample[:native_stack] }.uniq
+      assert_equal(downsampled[:locations].size, referenced.size)
+
+      assert_equal(original[:samples].size, JSON.parse(Pf2.downsample(profile, 1_000_000))['samples'].size)
+      assert_raises(ArgumentError) { Pf2.downsample(profile, -1) }
+    end
+  end
+
+  def test_to_otlp
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, 'profile.json')
+      Pf2.start(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+      sleep 0.05
+      Pf2.stop(output: path)
+
+      otlp = Pf2.to_otlp(File.read(path))
+      assert_equal(Encoding::ASCII_8BIT, otlp.encoding)
+      assert_operator(otlp.bytesize, :>, 0)
+      assert_includes(otlp, 'process.runtime.name'.b)
+      assert_raises(ArgumentError) { Pf2.to_otlp('{}') }
+    end
+  end
+end
@@ -1,6 +1,10 @@
+require 'json'
+require 'stringio'
+require 'tmpdir'
 require 'minitest/autorun'
 
 require 'pf2'
+require 'pf2/reporter'
 
 class SessionTest < Minitest::Test
   def test_default_options
@@ -10,24 +14,1643 @@ class SessionTest < Minitest::Test
     assert_equal(:cpu, config[:time_mode])
   end
 
+  def test_configuration_reflects_effective_settings
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 5)
+    session.start
+    config = session.configuration
+    session.stop
+
+    assert_equal(:wall, config[:time_mode])
+    assert_equal(5, config[:interval_ms])
+    assert_equal(5, config[:current_interval_ms])
+    assert_equal(:SIGALRM, config[:signal])
+    assert_operator(config[:buffer_capacity], :>, 0)
+    assert_nil(Pf2::Session.new(scheduler: :timer_thread, time_mode: :wall, threads: []).configuration[:signal])
+  end
+
   def test_scheduler_option
     config = Pf2::Session.new(scheduler: :timer_thread, time_mode: :wall, threads: []).configuration
     assert_equal(:timer_thread, config[:scheduler])
   end
 
+  def test_threads_option_rejects_non_threads
+    error = assert_raises(TypeError) { Pf2::Session.new(threads: [Thread.current, 'main']) }
+    assert_match(/threads\[1\]/, error.message)
+    assert_raises(TypeError) { Pf2::Session.new(threads: Thread.current) }
+  end
+
+  def test_threads_option_skips_dead_threads
+    dead = Thread.new {}
+    dead.join
+    session = Pf2::Session.new(threads: [dead, Thread.current, Thread.current], time_mode: :wall, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop
+
+    thread_ids = profile[:samples].map { |sample| sample[:ruby_thread_id] }.uniq
+    assert_equal(1, thread_ids.size)
+  end
+
+  def test_start_warns_without_target_threads
+    session = Pf2::Session.new(threads: [Thread.current], thread_name_filter: 'no such thread', time_mode: :wall)
+    assert_output(nil, /No threads to profile/) { session.start }
+  ensure
+    session&.stop
+  end
+
+  def test_target_overhead_pct_option
+    assert_nil(Pf2::Session.new(threads: []).configuration[:target_overhead_pct])
+    assert_equal(1.5, Pf2::Session.new(target_overhead_pct: 1.5, threads: []).configuration[:target_overhead_pct])
+    assert_raises(ArgumentError) { Pf2::Session.new(target_overhead_pct: 0, threads: []) }
+    assert_raises(ArgumentError) { Pf2::Session.new(target_overhead_pct: 101, threads: []) }
+    assert_raises(ArgumentError) { Pf2::Session.new(target_overhead_pct: '1', threads: []) }
+  end
+
+  def test_conflicting_options
+    error = assert_raises(ArgumentError) { Pf2::Session.new(include_idle: false, trigger: :calls, time_mode: :wall, threads: []) }
+    assert_match(/`include_idle` cannot be combined with `trigger: :calls`/, error.message)
+    error = assert_raises(ArgumentError) { Pf2::Session.new(scheduler: :timer_thread, trigger: :calls, time_mode: :wall, threads: [Thread.current]) }
+    assert_match(/`scheduler: :timer_thread` cannot be combined with `trigger: :calls`/, error.message)
+    error = assert_raises(ArgumentError) { Pf2::Session.new(window_ms: 1000, threads: []) }
+    assert_match(/`window_ms` cannot be combined with `mode: :standard`/, error.message)
+  end
+
+  def test_warns_when_sampling_rate_exceeds_max
+    assert_equal(2000, Pf2::Session.new(threads: []).configuration[:max_sampling_rate_hz])
+    assert_raises(ArgumentError) { Pf2::Session.new(max_sampling_rate_hz: 0, threads: []) }
+
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, max_sampling_rate_hz: 500)
+    assert_output(nil, /about 1000 samples\/s exceeds max_sampling_rate_hz \(500\).*`interval_ms: 2`/) { session.start }
+    session.stop
+
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1)
+    assert_output(nil, "") { session.start }
+    session.stop
+  end
+
+  def test_sample_filter
+    assert_equal([], Pf2::Session.new(threads: []).configuration[:sample_filter])
+    assert_equal([:app_code, :drop_idle], Pf2::Session.new(threads: [], sample_filter: [:app_code, :drop_idle]).configuration[:sample_filter])
+    assert_raises(ArgumentError) { Pf2::Session.new(threads: [], sample_filter: :unknown) }
+
+    sleeper = Thread.new { sleep }
+    Thread.pass until sleeper.status == 'sleep'
+    session = Pf2::Session.new(threads: [Thread.current, sleeper], time_mode: :wall, interval_ms: 1, sample_filter: :drop_idle, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop
+    refute_empty(profile[:samples])
+    profile[:samples].each { |sample| refute_equal(:sleeping, sample[:state]) }
+
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, sample_filter: :app_code, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop
+    refute_empty(profile[:samples])
+    profile[:samples].each do |sample|
+      filenames = sample[:stack].map { |index| profile[:functions][profile[:locations][index][:function_index]][:filename] }
+      assert(filenames.compact.any? { |filename| profile[:strings][filename].start_with?(Dir.pwd) })
+    end
+  ensure
+    sleeper&.kill
+  end
+
+  def test_measure_gc_pauses
+    refute(Pf2::Session.new(threads: []).configuration[:measure_gc_pauses])
+
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, measure_gc_pauses: true, use_experimental_serializer: true)
+    session.start
+    20.times do
+      Array.new(100_000) { Object.new }
+      GC.start
+    end
+    profile = session.stop
+
+    pauses = profile[:metadata][:gc_pauses]
+    refute_empty(pauses)
+    pauses.each { |pause| assert_operator(pause[:duration_ns], :>=, 0) }
+    # The pause a GC sample landed in is shared among its samples
+    gc_samples = profile[:samples].select { |sample| sample[:during_gc] }
+    total_pause_ns = pauses.sum { |pause| pause[:duration_ns] }
+    assert_operator(gc_samples.sum { |sample| sample[:weight_ns] }, :<=, total_pause_ns + 1_000_000 * gc_samples.size)
+  end
+
+  def test_jitter_pct_option
+    assert_equal(0.0, Pf2::Session.new(threads: []).configuration[:jitter_pct])
+    assert_equal(10.0, Pf2::Session.new(jitter_pct: 10, threads: []).configuration[:jitter_pct])
+    assert_raises(ArgumentError) { Pf2::Session.new(jitter_pct: -1, threads: []) }
+    assert_raises(ArgumentError) { Pf2::Session.new(jitter_pct: 100, threads: []) }
+    assert_raises(ArgumentError) { Pf2::Session.new(jitter_pct: 10, trigger: :calls, threads: []) }
+
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, jitter_pct: 50)
+    session.start
+    busy_loop(0.1)
+    refute_empty(session.samples)
+  ensure
+    session&.stop
+  end
+
+  def test_target_overhead_pct_lengthens_interval
+    session = Pf2::Session.new(
+      threads: [Thread.current], time_mode: :wall, interval_ms: 1, flush_interval_ms: 50,
+      target_overhead_pct: 0.0001, use_experimental_serializer: true,
+    )
+    session.start
+    busy_loop(0.6)
+    profile = session.stop
+
+    changes = profile[:metadata][:interval_changes]
+    refute_empty(changes)
+    assert_operator(changes.first[:interval_ns], :>, profile[:metadata][:interval_ns])
+    changes.each_cons(2) { |a, b| assert_operator(b[:interval_ns], :>, a[:interval_ns]) }
+    # Samples are weighted by the interval in effect when they were captured
+    profile[:samples].each do |sample|
+      change = changes.reverse.find { |c| c[:elapsed_ns] <= sample[:elapsed_ns] }
+      assert_equal(change ? change[:interval_ns] : profile[:metadata][:interval_ns], sample[:weight_ns])
+    end
+  end
+
+  def test_labels_option
+    assert_equal({}, Pf2::Session.new(threads: []).configuration[:labels])
+    labels = { env: 'prod', 'service' => 'api' }
+    assert_equal({ 'env' => 'prod', 'service' => 'api' }, Pf2::Session.new(labels: labels, threads: []).configuration[:labels])
+    assert_raises(ArgumentError) { Pf2::Session.new(labels: { commit: 123 }, threads: []) }
+    assert_raises(ArgumentError) { Pf2::Session.new(labels: 'env=prod', threads: []) }
+  end
+
+  def test_labels_are_written_into_every_format
+    labels = { env: 'prod', commit: 'abc123' }
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, labels: labels, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.01)
+    assert_equal({ 'commit' => 'abc123', 'env' => 'prod' }, session.stop[:metadata][:labels])
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, 'profile.json')
+      session.stop(output: path)
+      assert_equal('abc123', JSON.parse(File.read(path)).dig('metadata', 'labels', 'commit'))
+      assert_includes(Pf2.to_otlp(File.read(path)), 'abc123'.b)
+    end
+
+    legacy = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, labels: labels)
+    legacy.start
+    assert_equal({ 'commit' => 'abc123', 'env' => 'prod' }, JSON.parse(legacy.stop)['labels'])
+  end
+
   def test_interval_ms_option
     config = Pf2::Session.new(interval_ms: 1, threads: []).configuration
     assert_equal(1, config[:interval_ms])
   end
 
+  def test_clock_option
+    assert_equal(:monotonic, Pf2::Session.new(time_mode: :wall, threads: []).configuration[:clock])
+    config = Pf2::Session.new(time_mode: :wall, clock: :monotonic_raw, threads: []).configuration
+    assert_equal(:monotonic_raw, config[:clock])
+    assert_raises(ArgumentError) { Pf2::Session.new(time_mode: :wall, clock: :thread_cputime, threads: []) }
+    assert_raises(ArgumentError) { Pf2::Session.new(time_mode: :wall, clock: :realtime, threads: []) }
+  end
+
+  def test_samples_carry_clock_readings
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, clock: :monotonic, interval_ms: 1, use_experimental_serializer: true)
+    started_at_ns = Process.clock_gettime(Process::CLOCK_MONOTONIC, :nanosecond)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop
+    stopped_at_ns = Process.clock_gettime(Process::CLOCK_MONOTONIC, :nanosecond)
+
+    assert_equal(:monotonic, profile[:metadata][:clock])
+    refute_empty(profile[:samples])
+    profile[:samples].each do |sample|
+      assert_operator(sample[:clock_ns], :>=, started_at_ns)
+      assert_operator(sample[:clock_ns], :<=, stopped_at_ns)
+    end
+  end
+
+  def test_options_hash
+    assert_equal(1, Pf2::Session.new({ interval_ms: 1, threads: [] }).configuration[:interval_ms])
+    assert_equal(9, Pf2::Session.new(nil).configuration[:interval_ms])
+    assert_raises(ArgumentError) { Pf2::Session.new(1) }
+  end
+
+  def test_unknown_option
+    assert_raises(ArgumentError) { Pf2::Session.new(unknown_option: 1, threads: []) }
+  end
+
+  def test_option_type_mismatch
+    assert_raises(ArgumentError) { Pf2::Session.new(interval_ms: "1", threads: []) }
+    assert_raises(ArgumentError) { Pf2::Session.new(max_samples: 1.5, threads: []) }
+    assert_raises(ArgumentError) { Pf2::Session.new(warmup_ms: -1, threads: []) }
+    assert_raises(ArgumentError) { Pf2::Session.new(max_samples_policy: :unknown, threads: []) }
+  end
+
+  def test_option_invalid_utf8
+    error = assert_raises(ArgumentError) { Pf2::Session.new(time_mode: "\xff".b, threads: []) }
+    assert_match(/time_mode must be valid UTF-8/, error.message)
+  end
+
+  def test_stop_right_after_start_returns_empty_profile
+    session = Pf2::Session.new(threads: [], use_experimental_serializer: true)
+    session.start
+    profile = session.stop
+
+    assert_equal([], profile[:samples])
+    assert_equal([], profile[:functions])
+    assert_operator(profile[:duration_ns], :>=, 0)
+
+    session = Pf2::Session.new(threads: [])
+    session.start
+    profile = JSON.parse(session.stop, symbolize_names: true)
+    assert_equal({}, profile[:threads])
+    assert_equal([], Pf2::Reporter::FirefoxProfiler.new(profile).emit[:threads])
+  end
+
+  def test_timing_accessors
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, use_experimental_serializer: true)
+    started_at_ns = Process.clock_gettime(Process::CLOCK_REALTIME, :nanosecond)
+    session.start
+    sleep 0.1
+    assert_operator(session.duration_ns, :>=, 100_000_000) # still running
+    profile = session.stop
+
+    assert_equal(profile[:start_timestamp_ns], session.start_timestamp_ns)
+    assert_equal(profile[:duration_ns], session.duration_ns)
+    assert_operator(session.start_timestamp_ns, :>=, started_at_ns)
+    assert_operator(session.duration_ns, :>=, 100_000_000)
+  end
+
+  def test_metadata_anchors_start_to_wall_clock
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, use_experimental_serializer: true)
+    started_at_ns = Process.clock_gettime(Process::CLOCK_REALTIME, :nanosecond)
+    started_at_monotonic_ns = Process.clock_gettime(Process::CLOCK_MONOTONIC, :nanosecond)
+    session.start
+    stopped_at_monotonic_ns = Process.clock_gettime(Process::CLOCK_MONOTONIC, :nanosecond)
+    profile = session.stop
+
+    metadata = profile[:metadata]
+    assert_equal(profile[:start_timestamp_ns], metadata[:start_timestamp_ns])
+    assert_operator(metadata[:start_timestamp_ns], :>=, started_at_ns)
+    assert_operator(metadata[:start_monotonic_ns], :>=, started_at_monotonic_ns)
+    assert_operator(metadata[:start_monotonic_ns], :<=, stopped_at_monotonic_ns)
+  end
+
+  def test_metadata_includes_overhead
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    overhead = session.stop[:metadata][:overhead]
+
+    assert_operator(overhead[:sample_count], :>, 0)
+    assert_operator(overhead[:total_capture_ns], :>, 0)
+    assert_operator(overhead[:max_capture_ns], :>=, overhead[:mean_capture_ns])
+    assert_equal(overhead[:total_capture_ns] / overhead[:sample_count], overhead[:mean_capture_ns])
+    # Exact drop counts are tested in vm_tests.rs, as drops cannot be provoked from Ruby
+    assert_kind_of(Integer, overhead[:dropped_by_lock_contention])
+    assert_kind_of(Integer, overhead[:dropped_by_full_buffer])
+    assert_operator(overhead[:dropped_by_lock_contention] + overhead[:dropped_by_full_buffer], :<=, overhead[:sample_count])
+    # Postponed jobs are only used by the timer thread scheduler
+    assert_equal(0, overhead[:coalesced_postponed_jobs]) if session.configuration[:scheduler] == :signal
+  end
+
+  def test_metadata_counts_coalesced_postponed_jobs
+    floats = Array.new(1_000_000) { rand }
+    session = Pf2::Session.new(scheduler: :timer_thread, threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    # Sorting in C holds the GVL without running postponed jobs, while the timer keeps ticking
+    3.times { floats.sort }
+    drop_stats = session.drop_stats
+    profile = session.stop
+
+    assert_operator(profile[:metadata][:overhead][:coalesced_postponed_jobs], :>, 0)
+    assert_operator(drop_stats[:coalesced_jobs], :>, 0)
+  end
+
+  def test_metadata_includes_thread_summary
+    previous_name = Thread.current.name
+    Thread.current.name = 'pf2-test-main'
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop
+
+    thread_summary = profile[:metadata][:thread_summary]
+    assert_equal(1, thread_summary.size)
+    assert_equal('pf2-test-main', thread_summary[0][:name])
+    assert_equal(profile[:samples].size, thread_summary[0][:sample_count])
+    assert_equal(profile[:samples][0][:ruby_thread_id], thread_summary[0][:ruby_thread_id])
+    assert_nil(thread_summary[0][:cpu_time_ns])
+    assert_equal(Thread.current.native_thread_id, thread_summary[0][:native_thread_id])
+  ensure
+    Thread.current.name = previous_name
+  end
+
+  def test_thread_summary_includes_observed_interval
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 5, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.2)
+    profile = session.stop
+
+    interval = profile[:metadata][:thread_summary][0][:observed_interval]
+    refute_nil(interval)
+    # Allow for scheduling delays, but the achieved interval should be close to the requested one
+    assert_operator(interval[:median_ns], :>=, 4_000_000)
+    assert_operator(interval[:median_ns], :<=, 20_000_000)
+    assert_operator(interval[:mean_ns], :>, 0)
+    assert_kind_of(Integer, interval[:jitter_ns])
+  end
+
+  def test_metadata_includes_thread_cpu_times
+    skip 'CPU time accounting requires the signal scheduler' unless RUBY_PLATFORM.include?('linux')
+
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :cpu, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.1)
+    metadata = session.stop[:metadata]
+
+    assert_equal(1, metadata[:thread_cpu_times].size)
+    thread_cpu_time = metadata[:thread_cpu_times][0]
+    assert_equal(thread_cpu_time[:end_ns] - thread_cpu_time[:start_ns], thread_cpu_time[:cpu_time_ns])
+    assert_operator(thread_cpu_time[:cpu_time_ns], :>=, 50_000_000)
+    assert_equal(thread_cpu_time[:cpu_time_ns], metadata[:total_thread_cpu_time_ns])
+  end
+
+  def test_metadata_has_no_thread_cpu_times_in_wall_mode
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    metadata = session.stop[:metadata]
+
+    assert_empty(metadata[:thread_cpu_times])
+    assert_equal(0, metadata[:total_thread_cpu_time_ns])
+  end
+
+  def test_summary
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1)
+    session.start
+    busy_loop(0.05)
+    session.stop
+
+    rows = JSON.parse(session.summary, symbolize_names: true)
+    refute_empty(rows)
+    rows.each { |row| assert_operator(row[:total], :>=, row[:self]) }
+    assert_equal(rows.map { |row| row[:self] }.sort.reverse, rows.map { |row| row[:self] })
+    assert(rows.any? { |row| row[:function].include?('busy_loop') })
+
+    text = session.summary(format: :text)
+    assert_match(/self%.*total%.*function/, text.lines.first)
+    assert_raises(ArgumentError) { session.summary(format: :xml) }
+  end
+
+  def test_stop_format_top
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    sample_count = session.stop[:samples].size
+
+    lines = session.stop(format: :top).lines
+    assert_equal("Showing #{lines.size - 2} functions, #{sample_count} samples\n", lines[0])
+    assert_match(/flat\s+flat%\s+sum%\s+cum\s+cum%\s+function\s+file/, lines[1])
+    rows = lines.drop(2)
+    refute_empty(rows)
+    assert(rows.any? { |row| row.include?('busy_loop') })
+    # Sorted by flat in descending order, with file names aligned in a column
+    flats = rows.map { |row| row.split.first.to_i }
+    assert_equal(flats.sort.reverse, flats)
+    file_column = lines[1].index(' file')
+    rows.select { |row| row.include?(__FILE__) }.each { |row| assert_equal(file_column + 1, row.index(__FILE__)) }
+    # Stacks shared through dedup_stacks are aggregated as well
+    assert_equal(lines, session.stop(format: :top, dedup_stacks: true).lines)
+    assert_raises(ArgumentError) { session.stop(format: :xml) }
+  end
+
+  def test_stop_with_named_formats
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop
+
+    json = session.stop(format: :json)
+    assert_equal(Encoding::UTF_8, json.encoding)
+    assert_equal(profile[:samples].size, JSON.parse(json)['samples'].size)
+    assert_includes(session.stop(format: :json, pretty: true), "\n  ")
+
+    folded = session.stop(format: :folded).lines
+    refute_empty(folded)
+    assert_equal(profile[:samples].size, folded.sum { |line| line[/ (\d+)$/, 1].to_i })
+    assert(folded.any? { |line| line.include?('busy_loop') })
+
+    otlp = session.stop(format: :otlp)
+    assert_equal(Encoding::ASCII_8BIT, otlp.encoding)
+    assert_equal(Pf2.to_otlp(json), otlp)
+
+    ndjson = session.stop(format: :ndjson).lines.map { |line| JSON.parse(line) }
+    assert_equal(profile[:samples].size, ndjson.size)
+    assert(ndjson.any? { |sample| sample['stack'].any? { |name| name.include?('busy_loop') } })
+    ndjson.each { |sample| assert_kind_of(Integer, sample['elapsed_ns']) }
+  end
+
+  def test_schedulers_share_the_serialization_pipeline
+    profiles = %i[signal timer_thread].map do |scheduler|
+      session = Pf2::Session.new(scheduler: scheduler, threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+      session.start
+      busy_loop(0.05)
+      session.stop(format: :json)
+    end
+    signal, timer_thread = profiles.map { |profile| JSON.parse(profile, symbolize_names: true) }
+
+    refute_empty(signal[:samples])
+    refute_empty(timer_thread[:samples])
+    assert_equal(signal.keys.sort, timer_thread.keys.sort)
+    assert_equal(signal[:metadata].keys.sort, timer_thread[:metadata].keys.sort)
+    assert_equal(signal[:functions].first.keys.sort, timer_thread[:functions].first.keys.sort)
+    # Read back as the same schema
+    merged = JSON.parse(Pf2.merge(*profiles), symbolize_names: true)
+    assert_equal(signal[:samples].size + timer_thread[:samples].size, merged[:samples].size)
+  end
+
+  def test_stop_with_summary
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop
+
+    result = session.stop(summary: true)
+    assert_equal(profile[:samples].size, result[:profile][:samples].size)
+    summary = result[:summary]
+    assert_equal(profile[:samples].size, summary[:sample_count])
+    assert_equal(profile[:duration_ns], summary[:duration_ns])
+    assert_equal([:buffer_full, :lock_contention, :truncated, :coalesced_jobs], summary[:drops].keys)
+    assert_equal({profile[:samples][0][:ruby_thread_id] => profile[:samples].size}, summary[:threads])
+    assert_operator(summary[:top_functions].size, :<=, 5)
+    assert(summary[:top_functions].any? { |row| row[:function].include?('busy_loop') })
+    selfs = summary[:top_functions].map { |row| row[:self] }
+    assert_equal(selfs.sort.reverse, selfs)
+
+    result = session.stop(summary: true, format: :none)
+    assert_nil(result[:profile])
+    assert_equal(summary, result[:summary])
+    assert_nil(session.stop(format: :none))
+  end
+
+  def test_samples
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1)
+    session.start
+    busy_loop(0.05)
+    session.stop
+
+    samples = session.samples
+    refute_empty(samples)
+    samples.each do |sample|
+      assert_equal(sample[:frames].size, sample[:linenos].size)
+      assert_kind_of(Integer, sample[:ruby_thread_id])
+      assert_kind_of(Integer, sample[:elapsed_ns])
+    end
+    assert(samples.any? { |sample| sample[:frames].any? { |label| label&.include?('busy_loop') } })
+  end
+
+  def test_debug_frames
+    skip 'Only available in debug builds' unless Pf2.features[:debug]
+
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1)
+    session.start
+    busy_loop(0.05)
+    session.stop
+
+    frames = (0...session.sample_count).flat_map { |index| session.debug_frames(index) }
+    refute_empty(frames)
+    frames.each do |frame|
+      assert_kind_of(Integer, frame[:lineno])
+      assert_kind_of(Hash, frame[:function])
+    end
+    frame = frames.find { |f| f[:method_name] == 'busy_loop' }
+    assert_equal('SessionTest#busy_loop', frame[:full_label])
+    assert_equal(frame[:full_label], frame[:function][:name])
+    assert_equal(__FILE__, frame[:path])
+    refute(frame[:function][:cfunc])
+    assert_nil(session.debug_frames(session.sample_count))
+  end
+
+  def test_uninitialized_session_raises
+    session = Pf2::Session.allocate
+    assert_raises(RuntimeError) { session.start }
+    assert_raises(RuntimeError) { session.stop }
+  end
+
+  def test_interval_ms_must_be_positive
+    assert_raises(ArgumentError) { Pf2::Session.new(interval_ms: 0, threads: []) }
+  end
+
   def test_time_mode_option
     config = Pf2::Session.new(time_mode: :wall, threads: []).configuration
     assert_equal(:wall, config[:time_mode])
   end
 
+  def test_max_stack_depth_option
+    config = Pf2::Session.new(max_stack_depth: 100, threads: []).configuration
+    assert_equal(100, config[:max_stack_depth])
+  end
+
+  def test_truncated_stacks_record_omitted_frames
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, max_stack_depth: 5, use_experimental_serializer: true)
+    session.start
+    recurse = ->(depth) { depth.zero? ? busy_loop(0.05) : recurse.(depth - 1) }
+    recurse.(20)
+    profile = session.stop
+
+    truncated = profile[:samples].select { |sample| sample[:omitted_frames] }
+    refute_empty(truncated)
+    truncated.each { |sample| assert_operator(sample[:omitted_frames], :>=, 15) }
+    assert_equal(truncated.size, profile[:metadata][:truncated_sample_count])
+  end
+
+  def test_max_stack_depth_defaults_to_backtrace_limit
+    script = 'print Pf2::Session.new(threads: []).configuration[:max_stack_depth]'
+    output = IO.popen([RbConfig.ruby, '--backtrace-limit=42', '-I', File.expand_path('../lib', __dir__), '-rpf2', '-e', script], &:read)
+    assert_equal('42', output)
+  end
+
   def test_timer_thread_scheduler_does_not_accept_cpu_time_mode
     assert_raises(ArgumentError) do
       config = Pf2::Session.new(scheduler: :timer_thread, time_mode: :cpu, threads: [])
     end
   end
+
+  def test_max_duration_ms_option
+    config = Pf2::Session.new(max_duration_ms: 1000, threads: []).configuration
+    assert_equal(1000, config[:max_duration_ms])
+    assert_nil(Pf2::Session.new(threads: []).configuration[:max_duration_ms])
+  end
+
+  def test_max_duration_stops_profiling_automatically
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, max_duration_ms: 100, use_experimental_serializer: true)
+    session.start
+    sleep 0.5
+    assert_equal(false, session.running?)
+
+    # The buffered profile is still available
+    profile = session.stop
+    assert_operator(profile[:duration_ns], :<, 400_000_000)
+  end
+
+  def test_start_twice_raises
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, use_experimental_serializer: true)
+    session.start
+    error = assert_raises(RuntimeError) { session.start }
+    assert_match(/already running/, error.message)
+
+    # The session keeps running, and can be stopped and started again
+    assert_kind_of(Hash, session.stop)
+    session.start
+    session.stop
+  end
+
+  def test_warmup_ms_option
+    assert_equal(200, Pf2::Session.new(warmup_ms: 200, threads: []).configuration[:warmup_ms])
+    assert_equal(0, Pf2::Session.new(threads: []).configuration[:warmup_ms])
+    assert_raises(ArgumentError) { Pf2::Session.new(warmup_ms: -1, threads: []) }
+  end
+
+  def test_warmup_discards_early_samples
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, warmup_ms: 200, use_experimental_serializer: true)
+    started_at_ns = Process.clock_gettime(Process::CLOCK_REALTIME, :nanosecond)
+    session.start
+    busy_loop(0.1)
+    assert_equal(0, session.sample_count)
+    busy_loop(0.2)
+    profile = session.stop
+
+    refute_empty(profile[:samples])
+    assert_operator(profile[:start_timestamp_ns], :>=, started_at_ns + 200_000_000)
+  end
+
+  def test_max_samples_options
+    config = Pf2::Session.new(max_samples: 10, max_samples_policy: :ring, threads: []).configuration
+    assert_equal(10, config[:max_samples])
+    assert_equal(:ring, config[:max_samples_policy])
+    assert_equal(:stop, Pf2::Session.new(threads: []).configuration[:max_samples_policy])
+
+    assert_raises(ArgumentError) { Pf2::Session.new(max_samples_policy: :unknown, threads: []) }
+  end
+
+  def test_max_functions_folds_further_functions
+    assert_equal(100_000, Pf2::Session.new(threads: []).configuration[:max_functions])
+    assert_raises(ArgumentError) { Pf2::Session.new(max_functions: 0, threads: []) }
+
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, max_functions: 2, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop
+
+    assert_equal(3, profile[:functions].size)
+    assert_includes(profile[:functions].map { |function| profile[:strings][function[:name]] }, '(overflow)')
+    assert_operator(profile[:metadata][:overflow_frame_count], :>, 0)
+  end
+
+  def test_max_samples_caps_retained_samples
+    [:stop, :ring].each do |policy|
+      session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, max_samples: 10, max_samples_policy: policy, use_experimental_serializer: true)
+      session.start
+      busy_loop(0.7) # long enough for the buffer to be flushed
+      assert_equal(10, session.sample_count)
+      assert_equal(10, session.stop[:samples].size)
+    end
+  end
+
+  def test_exclude_paths_option
+    config = Pf2::Session.new(exclude_paths: ['/gems/', /stdlib/i], threads: []).configuration
+    assert_equal('/gems/', config[:exclude_paths][0])
+    assert_match(config[:exclude_paths][1], 'STDLIB')
+  end
+
+  def test_exclude_paths_omits_matching_frames
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, exclude_paths: [__FILE__], use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop
+
+    filenames = profile[:functions].map { |f| f[:filename] && profile[:strings][f[:filename]] }
+    refute_includes(filenames, __FILE__)
+  end
+
+  def test_thread_name_filter_option
+    worker = Thread.new { sleep }
+    worker.name = 'worker-1'
+    logger = Thread.new { sleep }
+    logger.name = 'logger'
+    unnamed = Thread.new { sleep }
+
+    config = Pf2::Session.new(threads: [worker, logger, unnamed], thread_name_filter: /\Aworker-/).configuration
+    assert_kind_of(Regexp, config[:thread_name_filter])
+    assert_equal(false, config[:include_unnamed_threads])
+
+    session = Pf2::Session.new(threads: [worker, logger, unnamed], time_mode: :wall, thread_name_filter: 'worker', use_experimental_serializer: true)
+    session.start
+    sleep 0.1
+    profile = session.stop
+
+    # Only the worker thread is sampled
+    names = profile[:metadata][:thread_summary].map { |thread| thread[:name] }
+    assert_equal(['worker-1'], names)
+  ensure
+    [worker, logger, unnamed].each { |thread| thread&.kill }
+  end
+
+  def test_thread_name_filter_requires_explicit_threads
+    assert_raises(ArgumentError) { Pf2::Session.new(thread_name_filter: 'worker') }
+  end
+
+  def test_running
+    session = Pf2::Session.new(threads: [Thread.current])
+    assert_equal(false, session.running?)
+    session.start
+    assert_equal(true, session.running?)
+    session.stop
+    assert_equal(false, session.running?)
+  end
+
+  def test_experimental_serializer_includes_metadata
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 5, use_experimental_serializer: true)
+    session.start
+    sleep 0.05
+    metadata = session.stop[:metadata]
+
+    assert_equal(Process.pid, metadata[:pid])
+    assert_equal(RUBY_VERSION, metadata[:ruby_version])
+    assert_equal(RUBY_DESCRIPTION, metadata[:ruby_description])
+    assert_equal(:wall, metadata[:time_mode])
+    assert_equal(5_000_000, metadata[:interval_ns])
+    assert_equal(defined?(RubyVM::YJIT) ? RubyVM::YJIT.enabled? : false, metadata[:yjit_enabled])
+    assert_equal(Pf2.native_version, metadata[:native_build])
+  end
+
+  def test_legacy_serializer_includes_metadata
+    started_at_ns = Process.clock_gettime(Process::CLOCK_REALTIME, :nanosecond)
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 5)
+    session.start
+    sleep 0.05
+    profile = JSON.parse(session.stop)
+
+    assert_equal(Process.pid, profile['pid'])
+    assert_equal(RUBY_VERSION, profile['ruby_version'])
+    assert_equal('wall', profile['time_mode'])
+    assert_equal(5_000_000, profile['interval_ns'])
+    assert_operator(profile['start_timestamp_ns'], :>=, started_at_ns)
+  end
+
+  def test_experimental_serializer_includes_schema_version
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, use_experimental_serializer: true)
+    session.start
+    assert_equal(1, session.stop[:schema_version])
+  end
+
+  def test_deterministic_serialization_sorts_strings_functions_and_locations
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop(deterministic: true)
+
+    assert_equal(profile[:strings].sort, profile[:strings])
+    names = profile[:functions].map { |f| f[:name] || -1 }
+    assert_equal(names.sort, names)
+    location_keys = profile[:locations].map { |l| [l[:function_index], l[:lineno]] }
+    assert_equal(location_keys.sort, location_keys)
+    profile[:samples].each do |sample|
+      assert(sample[:stack].all? { |index| index < profile[:locations].size })
+    end
+  end
+
+  def test_redact_paths
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop(redact_paths: true)
+
+    filenames = profile[:functions].filter_map { |f| f[:filename] }.map { |index| profile[:strings][index] }
+    refute_empty(filenames)
+    filenames.each { |filename| refute_includes(filename, '/') }
+    refute(profile[:strings].any? { |string| string.include?(__dir__) })
+  end
+
+  def test_relative_to
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop(relative_to: File.dirname(__dir__))
+
+    filenames = profile[:functions].filter_map { |f| f[:filename] }.map { |index| profile[:strings][index] }
+    assert_includes(filenames, 'test/session_test.rb')
+    refute(filenames.any? { |filename| filename.start_with?(File.dirname(__dir__)) })
+  end
+
+  def test_min_samples_prunes_rare_functions
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    min_samples = 5
+    profile = session.stop(min_samples: min_samples)
+
+    counts = Hash.new(0)
+    profile[:samples].each do |sample|
+      sample[:stack].map { |index| profile[:locations][index][:function_index] }.uniq.each { |f| counts[f] += 1 }
+    end
+    other = profile[:functions].index { |f| f[:name] && profile[:strings][f[:name]] == '(other)' }
+    counts.each do |function_index, count|
+      assert_operator(count, :>=, min_samples) unless function_index == other
+    end
+    referenced = profile[:samples].flat_map { |sample| sample[:stack] + sample[:native_stack] }.uniq
+    assert_equal(profile[:locations].size, referenced.size)
+    assert_raises(ArgumentError) { session.stop(min_samples: 0) }
+  end
+
+  def test_categories
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop(categories: { '/' => 'other files', __dir__ => 'tests' })
+
+    string = ->(index) { index && profile[:strings][index] }
+    test_functions = profile[:functions].select { |f| string[f[:filename]]&.start_with?(__dir__) }
+    refute_empty(test_functions)
+    # The longest matching prefix wins
+    test_functions.each { |f| assert_equal('tests', string[f[:category]]) }
+    profile[:functions].each do |f|
+      assert_nil(f[:category]) if f[:filename].nil? && f[:class_path].nil?
+    end
+  end
+
+  def test_granularity_function_collapses_locations
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    line_level = session.stop
+    profile = session.stop(granularity: :function)
+
+    function_indices = profile[:locations].map { |location| location[:function_index] }
+    assert_equal(function_indices.uniq, function_indices)
+    assert_equal(line_level[:samples].size, profile[:samples].size)
+    line_level[:samples].zip(profile[:samples]).each do |line_sample, sample|
+      expected = line_sample[:stack].map { |index| line_level[:locations][index][:function_index] }
+      assert_equal(expected, sample[:stack].map { |index| profile[:locations][index][:function_index] })
+    end
+    assert_raises(ArgumentError) { session.stop(granularity: :statement) }
+  end
+
+  def test_eval_frames_are_told_apart
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    start = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+    eval('nil while Process.clock_gettime(Process::CLOCK_MONOTONIC) - start < 0.05')
+    profile = session.stop
+
+    eval_functions = profile[:functions].select { |f| f[:eval] }
+    refute_empty(eval_functions)
+    eval_functions.each do |f|
+      filename = profile[:strings][f[:filename]]
+      assert_match(/\A\(eval/, filename)
+      assert(profile[:strings][f[:name]].end_with?(filename))
+    end
+    profile[:functions].each do |f|
+      refute_nil(f[:name])
+    end
+  end
+
+  def test_native_functions_carry_mapping
+    skip 'Native stacks require the per-thread signal scheduler' unless RUBY_PLATFORM.include?('linux')
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop
+
+    native_functions = profile[:functions].select { |f| f[:implementation] == :native }
+    refute_empty(native_functions)
+    mapped = native_functions.filter_map { |f| f[:mapping] && profile[:strings][f[:mapping]] }
+    refute_empty(mapped)
+    mapped.each { |path| refute_empty(path) }
+    profile[:functions].each do |f|
+      assert_nil(f[:mapping]) unless f[:implementation] == :native
+    end
+  end
+
+  def test_dedup_stacks
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop(deterministic: true, dedup_stacks: true)
+
+    assert_operator(profile[:stacks].size, :<=, profile[:samples].size)
+    assert_equal(profile[:stacks].uniq.size, profile[:stacks].size)
+    profile[:samples].each do |sample|
+      assert_empty(sample[:stack])
+      assert_operator(sample[:stack_index], :<, profile[:stacks].size)
+    end
+  end
+
+  def test_coalesce
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop(coalesce: true, granularity: :function)
+
+    samples = profile[:samples]
+    refute_empty(samples)
+    total_count = samples.sum { |sample| sample[:count] || 1 }
+    assert_equal(total_count, profile[:metadata][:thread_summary].sum { |thread| thread[:sample_count] })
+    samples.each_cons(2) do |a, b|
+      next unless a[:ruby_thread_id] == b[:ruby_thread_id]
+      refute(a[:stack] == b[:stack] && a[:native_stack] == b[:native_stack] && a[:state] == b[:state] &&
+             a[:on_cpu] == b[:on_cpu] && a[:during_gc] == b[:during_gc])
+    end
+    samples.select { |sample| sample[:count] }.each do |sample|
+      assert_operator(sample[:count], :>, 1)
+      assert_operator(sample[:end_elapsed_ns], :>, sample[:elapsed_ns])
+    end
+  end
+
+  def test_weighting_by_time
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    samples = session.stop(weighting: :time)[:samples]
+
+    refute_empty(samples)
+    samples.each_cons(2) do |a, b|
+      assert_equal([b[:elapsed_ns] - a[:elapsed_ns], 2_000_000].min, a[:weight_ns])
+    end
+    # The last sample has nothing to measure against
+    assert_equal(1_000_000, samples.last[:weight_ns])
+
+    assert_raises(ArgumentError) { Pf2::Session.new(threads: [], time_mode: :wall).stop(weighting: :bogus) }
+  end
+
+  def test_flat_stacks_by_default
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop
+
+    refute(profile.key?(:stacks))
+    profile[:samples].each { |sample| refute(sample.key?(:stack_index)) }
+  end
+
+  def test_reset_allows_restarting_a_session
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    3.times do
+      session.start
+      assert_raises(RuntimeError) { session.reset }
+      busy_loop(0.05)
+      profile = session.stop
+      assert_operator(profile[:duration_ns], :<, 1_000_000_000)
+      session.reset
+      assert_equal(0, session.sample_count)
+    end
+  end
+
+  def test_stop_io_may_call_back_into_the_session
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.02)
+    # The profile is not locked while it is written, so #write may use the session
+    io = StringIO.new
+    io.define_singleton_method(:write) do |chunk|
+      session.reset
+      super(chunk)
+    end
+    session.stop(io: io)
+    assert_kind_of(Hash, JSON.parse(io.string))
+    assert_equal(0, session.sample_count)
+  end
+
+  def test_mark
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    refute(session.mark("before start"))
+
+    session.start
+    assert(session.mark("request start"))
+    busy_loop(0.02)
+    assert(session.mark(:render))
+    profile = session.stop
+    refute(session.mark("after stop"))
+
+    markers = profile[:markers]
+    assert_equal(["request start", "render"], markers.map { |marker| marker[:label] })
+    assert_operator(markers[0][:elapsed_ns], :<, markers[1][:elapsed_ns])
+    assert_operator(markers[1][:elapsed_ns], :<=, profile[:duration_ns])
+    assert_equal(markers[0][:ruby_thread_id], profile[:samples][0][:ruby_thread_id])
+  end
+
+  def test_state_breakdown
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    sleep 0.05
+    profile = session.stop
+
+    breakdown = profile[:metadata][:state_breakdown]
+    assert_equal(profile[:samples].size + breakdown[:dropped_by_lock_contention] + breakdown[:dropped_by_full_buffer], breakdown[:total])
+    assert_operator(breakdown[:running], :>, 0)
+    assert_operator(breakdown[:idle], :>, 0)
+    assert_in_delta(breakdown[:running].fdiv(breakdown[:total]), breakdown[:running_fraction])
+  end
+
+  def test_include_stats
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop(include_stats: true)
+
+    exclusive = Hash.new(0)
+    inclusive = Hash.new(0)
+    profile[:samples].each do |sample|
+      [sample[:stack], sample[:native_stack]].each { |stack| exclusive[stack.first] += 1 unless stack.empty? }
+      (sample[:stack] + sample[:native_stack]).uniq.each { |index| inclusive[index] += 1 }
+    end
+    profile[:locations].each_with_index do |location, index|
+      assert_equal({exclusive: exclusive[index], inclusive: inclusive[index]}, location[:stats])
+    end
+
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.01)
+    refute(session.stop[:locations].any? { |location| location.key?(:stats) })
+  end
+
+  def test_line_hits
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop(line_hits: true)
+
+    line_of = ->(index) do
+      location = profile[:locations][index]
+      [profile[:functions][location[:function_index]][:filename], location[:lineno]]
+    end
+    exclusive = Hash.new(0)
+    inclusive = Hash.new(0)
+    profile[:samples].each do |sample|
+      lines = sample[:stack].map(&line_of).reject { |_, lineno| lineno == 0 }
+      exclusive[line_of.(sample[:stack].first)] += 1 unless sample[:stack].empty?
+      lines.uniq.each { |line| inclusive[line] += 1 }
+    end
+    refute_empty(profile[:line_hits])
+    profile[:line_hits].each do |hit|
+      line = [hit[:filename], hit[:lineno]]
+      assert_equal([exclusive[line], inclusive[line]], [hit[:exclusive], hit[:inclusive]])
+    end
+    assert_equal(inclusive.size, profile[:line_hits].size)
+
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.01)
+    refute(session.stop.key?(:line_hits))
+  end
+
+  def test_stop_with_threads
+    worker = Thread.new { busy_loop(0.1) }
+    worker.name = "worker"
+    session = Pf2::Session.new(threads: [Thread.current, worker], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    worker.join
+    profile = session.stop(threads: [worker])
+
+    refute_empty(profile[:samples])
+    assert(profile[:samples].all? { |sample| sample[:ruby_thread_id] == profile[:samples][0][:ruby_thread_id] })
+    assert_equal(["worker"], profile[:metadata][:thread_summary].map { |thread| thread[:name] })
+    referenced = profile[:samples].flat_map { |sample| sample[:stack] + sample[:native_stack] }.uniq
+    assert_equal(profile[:locations].size, referenced.size)
+
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.01)
+    profile = session.stop(threads: [])
+    assert_empty(profile[:samples])
+    assert_empty(profile[:functions])
+    assert_equal(0, profile[:metadata][:state_breakdown][:running])
+
+    assert_raises(ArgumentError) { session.stop(threads: ["main"]) }
+  end
+
+  def test_sample_types
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.01)
+    profile = session.stop
+    assert_equal([{name: "wall", unit: "nanoseconds"}], profile[:sample_types])
+  end
+
+  def test_flush
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, flush_interval_ms: 60_000)
+    assert_equal(0, session.flush)
+
+    session.start
+    busy_loop(0.05)
+    before = session.samples.size
+    flushed = session.flush
+    assert_operator(flushed, :>, 0)
+    assert_equal(before + flushed, session.samples.size)
+    session.stop
+
+    assert_equal(0, session.flush)
+  end
+
+  def test_serialization_with_gc_auto_compact
+    skip 'GC compaction is not supported' unless GC.respond_to?(:auto_compact=)
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.02)
+    GC.auto_compact = true
+    # Compaction is held off while the profile is read, and restored afterwards
+    refute_empty(session.snapshot[:samples])
+    assert(GC.auto_compact)
+    refute_empty(session.stop[:samples])
+    assert(GC.auto_compact)
+  ensure
+    GC.auto_compact = false if GC.respond_to?(:auto_compact=)
+  end
+
+  def test_post_fork
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.02)
+
+    pid = fork do
+      status = begin
+        if !session.post_fork || session.running? || session.sample_count != 0
+          1
+        else
+          session.start
+          busy_loop(0.05)
+          session.stop[:samples].empty? ? 2 : 0
+        end
+      rescue Exception
+        3
+      end
+      exit!(status)
+    end
+    Process.wait(pid)
+    assert_equal(0, $?.exitstatus)
+
+    # The parent keeps profiling
+    assert(session.running?)
+    busy_loop(0.02)
+    refute_empty(session.stop[:samples])
+  end
+
+  def test_include_idle_option
+    assert_equal(true, Pf2::Session.new(time_mode: :wall, threads: []).configuration[:include_idle])
+    assert_equal(:tag, Pf2::Session.new(time_mode: :wall, include_idle: :tag, threads: []).configuration[:include_idle])
+    assert_raises(ArgumentError) { Pf2::Session.new(time_mode: :wall, include_idle: :invalid, threads: []) }
+    assert_raises(ArgumentError) { Pf2::Session.new(time_mode: :cpu, include_idle: false, threads: []) }
+  end
+
+  def test_include_idle_false_drops_samples_of_sleeping_threads
+    sleeper = Thread.new { sleep }
+    Thread.pass until sleeper.status == 'sleep'
+    session = Pf2::Session.new(threads: [sleeper], time_mode: :wall, interval_ms: 1, include_idle: false, use_experimental_serializer: true)
+    session.start
+    sleep 0.05
+    assert_empty(session.stop[:samples])
+  ensure
+    sleeper&.kill
+  end
+
+  def test_samples_carry_thread_state
+    sleeper = Thread.new { sleep }
+    Thread.pass until sleeper.status == 'sleep'
+    session = Pf2::Session.new(threads: [Thread.current, sleeper], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop
+
+    states = profile[:samples].group_by { |sample| sample[:ruby_thread_id] }.transform_values { |samples| samples.map { |s| s[:state] }.uniq }
+    assert(states.values.any? { |s| s.include?(:sleeping) })
+    assert(states.values.any? { |s| s.include?(:running) || s.include?(:gc) })
+  ensure
+    sleeper&.kill
+  end
+
+  def test_samples_carry_on_cpu
+    skip 'on_cpu requires per-thread CPU clocks' unless RUBY_PLATFORM.include?('linux')
+    sleeper = Thread.new { sleep }
+    Thread.pass until sleeper.status == 'sleep'
+    session = Pf2::Session.new(threads: [Thread.current, sleeper], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop
+
+    by_thread = profile[:samples].group_by { |sample| sample[:ruby_thread_id] }
+    on_cpu = by_thread.transform_values { |samples| samples.map { |sample| sample[:on_cpu] }.compact }
+    # The busy thread is mostly on CPU, and the sleeping one never is
+    assert(on_cpu.values.any? { |values| values.count(true) > values.size / 2 })
+    assert(on_cpu.values.any? { |values| !values.empty? && values.none? })
+    profile[:samples].each { |sample| assert_includes([true, false, nil], sample[:on_cpu]) }
+  ensure
+    sleeper&.kill
+  end
+
+  def test_include_idle_tag_adds_idle_leaf
+    sleeper = Thread.new { sleep }
+    Thread.pass until sleeper.status == 'sleep'
+    session = Pf2::Session.new(threads: [sleeper], time_mode: :wall, interval_ms: 1, include_idle: :tag, use_experimental_serializer: true)
+    session.start
+    sleep 0.05
+    profile = session.stop
+
+    refute_empty(profile[:samples])
+    profile[:samples].each do |sample|
+      leaf = profile[:functions][profile[:locations][sample[:stack][0]][:function_index]]
+      assert_equal('(idle)', profile[:strings][leaf[:name]])
+    end
+  ensure
+    sleeper&.kill
+  end
+
+  def test_all_threads_profiles_existing_threads
+    worker = Thread.new { busy_loop(0.2) }
+    Thread.pass until worker.native_thread_id
+    session = Pf2::Session.new(time_mode: :wall, interval_ms: 1, all_threads: true, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop
+
+    thread_ids = profile[:samples].map { |sample| sample[:ruby_thread_id] }.uniq
+    assert_operator(thread_ids.size, :>=, 2)
+    assert_raises(ArgumentError) { Pf2::Session.new(threads: [Thread.current], all_threads: true) }
+  ensure
+    worker&.kill
+  end
+
+  def test_timers_of_exited_threads_are_deleted
+    skip 'Per-thread timers are only available on Linux' unless RUBY_PLATFORM.include?('linux')
+    skip '/proc/self/timers is not available' unless File.exist?('/proc/self/timers')
+    session = Pf2::Session.new(threads: :all, time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    timers_before = posix_timer_count
+    20.times do
+      5.times.map { Thread.new { busy_loop(0.01) } }.each(&:join)
+    end
+    timers_after = posix_timer_count
+    profile = session.stop
+
+    # Each thread's timer is deleted as the thread exits
+    assert_operator(timers_after, :<=, timers_before)
+    # Samples of the exited threads are kept
+    thread_ids = profile[:samples].map { |sample| sample[:ruby_thread_id] }.uniq
+    assert_operator(thread_ids.size, :>, 2)
+  end
+
+  def test_trigger_calls
+    session = Pf2::Session.new(threads: [Thread.current], trigger: :calls, every: 10, use_experimental_serializer: true)
+    assert_equal(:calls, session.configuration[:trigger])
+    assert_equal(10, session.configuration[:every])
+    session.start
+    1000.times { Object.new.to_s }
+    profile = session.stop
+
+    # Each iteration makes at least 3 calls (new, initialize and to_s). Calls made while the
+    # flusher holds the profile lock are dropped instead of waiting for it.
+    dropped = profile[:metadata][:overhead][:dropped_by_lock_contention]
+    assert_operator(profile[:samples].size + dropped, :>=, 300)
+    assert_equal(:calls, profile[:metadata][:trigger])
+    assert_equal(10, profile[:metadata][:calls_per_sample])
+    profile[:samples].each { |sample| assert_nil(sample[:weight_ns]) }
+    assert_equal([{name: "calls", unit: "count"}], profile[:sample_types])
+
+    assert_equal(1000, Pf2::Session.new(trigger: :calls, threads: []).configuration[:every])
+    assert_raises(ArgumentError) { Pf2::Session.new(every: 10, threads: []) }
+    assert_raises(ArgumentError) { Pf2::Session.new(trigger: :calls, every: 0, threads: []) }
+    assert_raises(ArgumentError) { Pf2::Session.new(trigger: :instructions, threads: []) }
+  end
+
+  def test_sample_probability
+    session = Pf2::Session.new(threads: [Thread.current], trigger: :calls, sample_probability: 0.1, use_experimental_serializer: true)
+    assert_equal(0.1, session.configuration[:sample_probability])
+    assert_nil(session.configuration[:every])
+    session.start
+    3000.times { Object.new.to_s }
+    profile = session.stop
+
+    assert_equal(0.1, profile[:metadata][:sample_probability])
+    assert_nil(profile[:metadata][:calls_per_sample])
+    assert_equal([{name: "calls", unit: "count"}], profile[:sample_types])
+    # Each iteration makes at least 3 calls, i.e. 900 samples are expected at the least
+    estimated_calls = profile[:samples].size / 0.1
+    assert_operator(estimated_calls, :>=, 9000 * 0.8)
+
+    assert_raises(ArgumentError) { Pf2::Session.new(sample_probability: 0.1, threads: []) }
+    assert_raises(ArgumentError) { Pf2::Session.new(trigger: :calls, sample_probability: 0.1, every: 10, threads: []) }
+    assert_raises(ArgumentError) { Pf2::Session.new(trigger: :calls, sample_probability: 0, threads: []) }
+    assert_raises(ArgumentError) { Pf2::Session.new(trigger: :calls, sample_probability: 1.5, threads: []) }
+  end
+
+  def test_crash_dump
+    path = File.join(Dir.mktmpdir, 'crash.folded')
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, crash_dump: path)
+    assert_equal(path, session.configuration[:crash_dump])
+
+    pid = fork do
+      session.start
+      busy_loop(0.05)
+      session.flush
+      Process.kill(:ABRT, Process.pid)
+      exit!(1)
+    end
+    Process.wait(pid)
+    assert($?.signaled?)
+    assert_equal(Signal.list['ABRT'], $?.termsig)
+
+    lines = File.readlines(path)
+    refute_empty(lines)
+    lines.each { |line| assert_match(/ 1$/, line) }
+  end
+
+  def test_collapse_recursion
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    recursive_busy_loop(10, 0.05)
+    profile = session.stop(collapse_recursion: true)
+
+    refute_empty(profile[:samples])
+    profile[:samples].each do |sample|
+      functions = sample[:stack].map { |index| profile[:locations][index][:function_index] }
+      assert_equal(functions.chunk_while { |a, b| a == b }.map(&:first), functions)
+    end
+    assert(profile[:samples].any? { |sample| sample[:collapsed_frames].to_i >= 9 })
+
+    assert_raises(ArgumentError) { Pf2::Session.new(threads: []).stop(collapse_recursion: :invalid) }
+  end
+
+  def test_strategy_option
+    assert_equal(:per_thread, Pf2::Session.new(threads: []).configuration[:strategy])
+    assert_equal(:global_timer, Pf2::Session.new(time_mode: :wall, strategy: :global_timer, threads: []).configuration[:strategy])
+    assert_raises(ArgumentError) { Pf2::Session.new(time_mode: :wall, strategy: :invalid, threads: []) }
+    if RUBY_PLATFORM.include?('linux')
+      assert_equal(:cpu, Pf2::Session.new(time_mode: :cpu, strategy: :global_timer, threads: []).configuration[:time_mode])
+    else
+      assert_raises(ArgumentError) { Pf2::Session.new(time_mode: :cpu, strategy: :global_timer, threads: []) }
+    end
+  end
+
+  def test_flush_options
+    config = Pf2::Session.new(threads: []).configuration
+    assert_equal(500, config[:flush_interval_ms])
+    assert_equal(:periodic, config[:flush_mode])
+    config = Pf2::Session.new(flush_interval_ms: 50, flush_mode: :event_driven, threads: []).configuration
+    assert_equal(50, config[:flush_interval_ms])
+    assert_equal(:event_driven, config[:flush_mode])
+
+    assert_raises(ArgumentError) { Pf2::Session.new(flush_interval_ms: 0, threads: []) }
+    assert_raises(ArgumentError) { Pf2::Session.new(flush_mode: :invalid, threads: []) }
+  end
+
+  def test_event_driven_flush
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, flush_interval_ms: 60_000, flush_mode: :event_driven, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.2)
+    profile = session.stop
+
+    assert_operator(profile[:samples].size, :>, 100)
+  end
+
+  def test_on_flush_receives_batches
+    batches = Queue.new
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, flush_interval_ms: 20, on_flush: ->(samples) { batches << samples }, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.2)
+    # Batches arrive while the session is running
+    refute_predicate(batches, :empty?)
+    profile = session.stop
+
+    delivered = []
+    delivered.concat(batches.pop) until batches.empty?
+    assert_equal(profile[:samples].size, delivered.size)
+    assert_kind_of(Array, delivered[0][:frames])
+    assert_raises(ArgumentError) { Pf2::Session.new(on_flush: 42, threads: []) }
+  end
+
+  def test_on_flush_exceptions_are_reported
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, flush_interval_ms: 20, on_flush: ->(_) { raise 'boom' }, use_experimental_serializer: true)
+    _, err = capture_io do
+      session.start
+      busy_loop(0.1)
+      session.stop
+    end
+    assert_match(/on_flush raised an exception: #<RuntimeError: boom>/, err)
+  end
+
+  def test_internal_threads_are_not_profiled
+    user_thread = Thread.new { sleep }
+    user_thread.name = 'pf2-on-flush'
+    existing = Thread.list
+    session = Pf2::Session.new(threads: :all, all_threads: true, time_mode: :wall, interval_ms: 1, on_flush: ->(_) {}, use_experimental_serializer: true)
+    session.start
+    internal_threads = Thread.list - existing
+    assert_equal(1, internal_threads.size)
+    internal_threads[0].name = 'pf2-internal'
+    sleep 0.1
+    profile = session.stop
+
+    names = profile[:metadata][:thread_summary].map { |thread| thread[:name] }
+    refute_includes(names, 'pf2-internal')
+    # User threads are profiled regardless of their names
+    assert_includes(names, 'pf2-on-flush')
+  ensure
+    user_thread&.kill
+  end
+
+  def test_mode_options
+    config = Pf2::Session.new(threads: []).configuration
+    assert_equal(:standard, config[:mode])
+    assert_nil(config[:window_ms])
+    assert_equal(60_000, Pf2::Session.new(mode: :continuous, threads: []).configuration[:window_ms])
+    assert_equal(1000, Pf2::Session.new(mode: :continuous, window_ms: 1000, threads: []).configuration[:window_ms])
+
+    assert_raises(ArgumentError) { Pf2::Session.new(mode: :invalid, threads: []) }
+    assert_raises(ArgumentError) { Pf2::Session.new(window_ms: 1000, threads: []) }
+    assert_raises(ArgumentError) { Pf2::Session.new(mode: :continuous, window_ms: 0, threads: []) }
+  end
+
+  def test_continuous_mode_keeps_recent_window
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, mode: :continuous, window_ms: 100, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.4)
+    snapshot = session.snapshot
+    assert(session.running?)
+    profile = session.stop
+
+    [snapshot, profile].each do |result|
+      refute_empty(result[:samples])
+      # Samples from the first 0.3 seconds have been evicted (with some slack for scheduling)
+      assert_operator(result[:samples].map { |sample| sample[:elapsed_ns] }.min, :>=, 250_000_000)
+    end
+  end
+
+  def test_snapshot_does_not_stop_session
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    # Samples not flushed yet are included
+    snapshot = session.snapshot
+    refute_empty(snapshot[:samples])
+    assert(session.running?)
+
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, 'snapshot.json')
+      assert_equal(path, session.snapshot(output: path))
+      assert_operator(JSON.parse(File.read(path))['samples'].size, :>=, snapshot[:samples].size)
+    end
+
+    busy_loop(0.05)
+    profile = session.stop
+    assert_operator(profile[:samples].size, :>, snapshot[:samples].size)
+  end
+
+  def test_global_timer_samples_all_target_threads
+    worker = Thread.new { busy_loop(0.2) }
+    session = Pf2::Session.new(threads: [Thread.current, worker], time_mode: :wall, interval_ms: 1, strategy: :global_timer, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop
+
+    thread_ids = profile[:samples].map { |sample| sample[:ruby_thread_id] }.uniq
+    assert_equal(2, thread_ids.size)
+  ensure
+    worker&.kill
+  end
+
+  def test_global_timer_attributes_samples_to_gvl_holder
+    sleeper = Thread.new { sleep }
+    Thread.pass until sleeper.status == 'sleep'
+    session = Pf2::Session.new(threads: [Thread.current, sleeper], time_mode: :wall, interval_ms: 1, strategy: :global_timer, include_idle: false, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop
+
+    refute_empty(profile[:samples])
+    # The sleeping thread never holds the GVL, so only the busy thread is sampled
+    assert_equal(1, profile[:samples].map { |sample| sample[:ruby_thread_id] }.uniq.size)
+    profile[:samples].each { |sample| assert_includes([:running, :gc], sample[:state]) }
+  ensure
+    sleeper&.kill
+  end
+
+  def test_global_timer_in_cpu_time_mode
+    skip 'CPU time mode with the global timer requires Linux' unless RUBY_PLATFORM.include?('linux')
+    sleeper = Thread.new { sleep }
+    Thread.pass until sleeper.status == 'sleep'
+    session = Pf2::Session.new(threads: [Thread.current, sleeper], time_mode: :cpu, interval_ms: 1, strategy: :global_timer, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop
+
+    refute_empty(profile[:samples])
+    assert_equal(1, profile[:samples].map { |sample| sample[:ruby_thread_id] }.uniq.size)
+  ensure
+    sleeper&.kill
+  end
+
+  def test_cfunc_frames_are_labeled_distinctly
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop
+
+    cfuncs = profile[:functions].select { |f| f[:implementation] == :cfunc }
+    refute_empty(cfuncs) # Process.clock_gettime
+    cfuncs.each { |f| assert_nil(f[:filename]) }
+  end
+
+  def test_functions_report_jit
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop
+
+    profile[:functions].each { |f| assert_includes([true, false], f[:jit]) }
+    jit_functions = profile[:functions].select { |f| f[:jit] }
+    if profile[:metadata][:yjit_enabled]
+      # JIT code is attributed to the Ruby method it was compiled from, never to a native frame
+      jit_functions.each { |f| assert_equal(:ruby, f[:implementation]) }
+      jit_functions.each { |f| assert_includes(profile[:strings][f[:name]], 'busy_loop') }
+    else
+      assert_empty(jit_functions)
+    end
+  end
+
+  def test_samples_record_ractor_id
+    skip 'Ractor is not available' unless defined?(Ractor)
+
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    profile = session.stop
+
+    refute_empty(profile[:samples])
+    profile[:samples].each { |sample| assert_equal(Ractor.current.object_id, sample[:ruby_ractor_id]) }
+  end
+
+  def test_samples_record_fiber_id
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    session.start
+    busy_loop(0.05)
+    Fiber.new { busy_loop(0.05) }.resume
+    profile = session.stop
+
+    fiber_ids = profile[:samples].map { |sample| sample[:fiber_id] }.uniq
+    assert_includes(fiber_ids, 0)
+    assert_operator(fiber_ids.size, :>=, 2)
+  end
+
+  def test_samples_record_root_fiber_when_started_in_a_fiber
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1, use_experimental_serializer: true)
+    Fiber.new { session.start }.resume
+    busy_loop(0.05)
+    profile = session.stop
+
+    # Samples taken back in the root Fiber are attributed to it, not to the Fiber which started the session
+    fiber_ids = profile[:samples].map { |sample| sample[:fiber_id] }
+    assert_includes(fiber_ids, 0)
+  end
+
+  def test_sessions_are_released_by_gc
+    allocate_sessions = -> { 200.times { Pf2::Session.new } }
+
+    allocate_sessions.call # warm up
+    GC.start
+    rss_before = current_rss_kb
+    5.times do
+      allocate_sessions.call
+      GC.start
+    end
+
+    assert_operator(ObjectSpace.each_object(Pf2::Session).count, :<, 200)
+    # Each Session owns a few MBs of sample buffer; leaking them would grow RSS by GBs
+    assert_operator(current_rss_kb - rss_before, :<, 200 * 1024) if rss_before
+  end
+
+  def test_memsize_includes_sample_buffers
+    require 'objspace'
+    session = Pf2::Session.new(threads: [])
+    # The temporary sample buffer alone takes up a few MBs
+    assert_operator(ObjectSpace.memsize_of(session), :>, 1024 * 1024)
+  end
+
+  def test_profile_survives_gc_compaction
+    skip 'GC.compact is not supported' unless GC.respond_to?(:compact)
+
+    session = Pf2::Session.new(threads: [Thread.current], time_mode: :wall, interval_ms: 1)
+    session.start
+    busy_loop(0.6) # long enough for the flusher to move samples out of the temporary buffer
+    GC.compact
+    profile = JSON.parse(session.stop)
+
+    assert_operator(profile['threads'].size, :>, 0)
+  end
+
+  def test_histogram_reflects_busy_threads
+    queue1, queue2 = Queue.new, Queue.new
+    th1 = Thread.new { queue1.pop; busy_loop(0.3) }
+    th2 = Thread.new { queue2.pop; busy_loop(0.3) }
+
+    session = Pf2::Session.new(threads: [th1, th2], timeline_resolution_ms: 100)
+    session.start
+    queue1 << true
+    th1.join
+    queue2 << true
+    th2.join
+    session.stop
+
+    histogram = JSON.parse(session.histogram)
+    assert_equal(100, histogram['resolution_ms'])
+    assert_equal(2, histogram['threads'].size)
+
+    # The thread which was busy first should have its samples concentrated in earlier buckets
+    first_busy, last_busy = histogram['threads'].values.map {|counts|
+      counts.each_with_index.sum {|count, i| count * i }.fdiv(counts.sum)
+    }.sort
+    assert_operator(last_busy - first_busy, :>=, 1.0)
+  end
+
+  def test_sample_thread
+    queue = Thread::Queue.new
+    sleeper = Thread.new { queue.pop }
+    Thread.pass until sleeper.status == 'sleep'
+
+    session = Pf2::Session.new(threads: [Thread.current], interval_ms: 60_000, time_mode: :wall)
+    assert_equal(false, session.sample_thread(Thread.current))
+
+    session.start
+    assert_equal(true, session.sample_thread(Thread.current))
+    assert_equal(true, session.sample_thread(sleeper))
+    assert_raise(TypeError) { session.sample_thread(Object.new) }
+    dead = Thread.new {}.tap(&:join)
+    assert_raise(ArgumentError) { session.sample_thread(dead) }
+    profile = session.stop
+
+    assert_equal(2, profile[:samples].size)
+    assert_equal(2, profile[:samples].map { |sample| sample[:ruby_thread_id] }.uniq.size)
+    assert_equal(false, session.sample_thread(Thread.current))
+  ensure
+    queue << true
+    sleeper.join
+  end
+
+  private
+
+  def posix_timer_count
+    File.read('/proc/self/timers').scan(/^ID:/).size
+  end
+
+  def current_rss_kb
+    File.read('/proc/self/status')[/^VmRSS:\s+(\d+)/, 1]&.to_i
+  rescue Errno::ENOENT
+    nil
+  end
+
+  def recursive_busy_loop(depth, seconds)
+    return busy_loop(seconds) if depth == 0
+    recursive_busy_loop(depth - 1, seconds)
+  end
+
+  def busy_loop(seconds)
+    start = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+    nil while Process.clock_gettime(Process::CLOCK_MONOTONIC) - start < seconds
+  end
 end